@@ -64,3 +64,12 @@ pub(crate) async fn list(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	let output = format!("Appservices ({}): {}", appservices.len(), appservices.join(", "));
 	Ok(RoomMessageEventContent::text_plain(output))
 }
+
+pub(crate) async fn rebuild_room_cache(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	services()
+		.rooms
+		.state_cache
+		.clear_appservice_in_room_cache();
+
+	Ok(RoomMessageEventContent::text_plain("Done."))
+}
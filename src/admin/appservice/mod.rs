@@ -2,7 +2,7 @@ use clap::Subcommand;
 use conduit::Result;
 use ruma::events::room::message::RoomMessageEventContent;
 
-use self::appservice_command::{list, register, show, unregister};
+use self::appservice_command::{list, rebuild_room_cache, register, show, unregister};
 
 pub(crate) mod appservice_command;
 
@@ -36,6 +36,12 @@ pub(crate) enum AppserviceCommand {
 
 	/// - List all the currently registered appservices
 	List,
+
+	/// - Forget which rooms appservices are in
+	///
+	/// This is worked out again from room membership the next time it's
+	/// needed.
+	RebuildRoomCache,
 }
 
 pub(crate) async fn process(command: AppserviceCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -48,5 +54,6 @@ pub(crate) async fn process(command: AppserviceCommand, body: Vec<&str>) -> Resu
 			appservice_identifier,
 		} => show(body, appservice_identifier).await?,
 		AppserviceCommand::List => list(body).await?,
+		AppserviceCommand::RebuildRoomCache => rebuild_room_cache(body).await?,
 	})
 }
@@ -14,6 +14,46 @@ pub(crate) async fn enable_room(_body: Vec<&str>, room_id: Box<RoomId>) -> Resul
 	Ok(RoomMessageEventContent::text_plain("Room enabled."))
 }
 
+pub(crate) async fn set_room_federation(
+	_body: Vec<&str>, room_id: Box<RoomId>, enabled: bool,
+) -> Result<RoomMessageEventContent> {
+	if !services().rooms.metadata.exists(&room_id)? {
+		return Ok(RoomMessageEventContent::text_plain("Room is unknown to this server."));
+	}
+
+	if enabled && !services().rooms.state_accessor.is_federated(&room_id)? {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Room was created with m.federate set to false and cannot be federated.",
+		));
+	}
+
+	services()
+		.rooms
+		.metadata
+		.set_local_only(&room_id, !enabled)?;
+	Ok(RoomMessageEventContent::text_plain(if enabled {
+		"Federation enabled for room."
+	} else {
+		"Federation disabled for room, it is now local-only."
+	}))
+}
+
+pub(crate) async fn test_server_acl(
+	_body: Vec<&str>, room_id: Box<RoomId>, server_name: Box<ServerName>,
+) -> Result<RoomMessageEventContent> {
+	if !services().rooms.metadata.exists(&room_id)? {
+		return Ok(RoomMessageEventContent::text_plain("Room is unknown to this server."));
+	}
+
+	let decision = services()
+		.rooms
+		.event_handler
+		.acl_decision(&server_name, &room_id)?;
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"{server_name} would be {decision}."
+	)))
+}
+
 pub(crate) async fn incoming_federation(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	let map = services().globals.roomid_federationhandletime.read().await;
 	let mut msg = format!("Handling {} incoming pdus:\n", map.len());
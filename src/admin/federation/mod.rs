@@ -3,6 +3,7 @@ use ruma::{events::room::message::RoomMessageEventContent, RoomId, ServerName, U
 
 use self::federation_commands::{
 	disable_room, enable_room, fetch_support_well_known, incoming_federation, remote_user_in_rooms,
+	set_room_federation, test_server_acl,
 };
 use crate::Result;
 
@@ -24,6 +25,20 @@ pub(crate) enum FederationCommand {
 		room_id: Box<RoomId>,
 	},
 
+	/// - Enables or disables federation of a room entirely.
+	///
+	/// A room with federation disabled is local-only: its events are not sent
+	/// to other servers, and incoming events and joins from other servers are
+	/// refused. Rooms created with `m.federate` set to false cannot have
+	/// federation enabled.
+	SetRoomFederation {
+		room_id: Box<RoomId>,
+
+		/// Whether the room should federate (true or false)
+		#[arg(action = clap::ArgAction::Set)]
+		enabled: bool,
+	},
+
 	/// - Fetch `/.well-known/matrix/support` from the specified server
 	///
 	/// Despite the name, this is not a federation endpoint and does not go
@@ -41,6 +56,16 @@ pub(crate) enum FederationCommand {
 	RemoteUserInRooms {
 		user_id: Box<UserId>,
 	},
+
+	/// - Reports whether a server would be allowed or denied by a room's
+	///   current server ACL, and which rule decided it
+	///
+	/// Nothing is changed; this is for checking an ACL blocks the intended
+	/// servers.
+	TestServerAcl {
+		room_id: Box<RoomId>,
+		server_name: Box<ServerName>,
+	},
 }
 
 pub(crate) async fn process(command: FederationCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -51,6 +76,10 @@ pub(crate) async fn process(command: FederationCommand, body: Vec<&str>) -> Resu
 		FederationCommand::EnableRoom {
 			room_id,
 		} => enable_room(body, room_id).await?,
+		FederationCommand::SetRoomFederation {
+			room_id,
+			enabled,
+		} => set_room_federation(body, room_id, enabled).await?,
 		FederationCommand::IncomingFederation => incoming_federation(body).await?,
 		FederationCommand::FetchSupportWellKnown {
 			server_name,
@@ -58,5 +87,9 @@ pub(crate) async fn process(command: FederationCommand, body: Vec<&str>) -> Resu
 		FederationCommand::RemoteUserInRooms {
 			user_id,
 		} => remote_user_in_rooms(body, user_id).await?,
+		FederationCommand::TestServerAcl {
+			room_id,
+			server_name,
+		} => test_server_acl(body, room_id, server_name).await?,
 	})
 }
@@ -1,4 +1,6 @@
-use ruma::events::room::message::RoomMessageEventContent;
+use std::fmt::Write;
+
+use ruma::{events::room::message::RoomMessageEventContent, RoomId};
 
 use crate::{services, Result};
 
@@ -23,3 +25,34 @@ pub(crate) async fn check_all_users(_body: Vec<&str>) -> Result<RoomMessageEvent
 
 	Ok(RoomMessageEventContent::notice_html(message, String::new()))
 }
+
+pub(crate) async fn verify_short_ids(
+	_body: Vec<&str>, room_id: Option<Box<RoomId>>, repair: bool,
+) -> Result<RoomMessageEventContent> {
+	let timer = tokio::time::Instant::now();
+	let issues = services()
+		.rooms
+		.short
+		.verify_shorteventids(room_id.as_deref())?;
+	let query_time = timer.elapsed();
+
+	if issues.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"No short event ID inconsistencies found ({query_time:?})."
+		)));
+	}
+
+	let mut repaired: usize = 0;
+	let mut list = String::new();
+	for issue in &issues {
+		let fixed = repair && services().rooms.short.repair_shorteventid(issue)?;
+		repaired = repaired.saturating_add(fixed.into());
+		let note = fixed.then_some(" (repaired)").unwrap_or_default();
+		writeln!(list, "{issue}{note}").expect("should be able to write to string buffer");
+	}
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Found {} short event ID inconsistencies in {query_time:?}, repaired {repaired}:\n```\n{list}```",
+		issues.len()
+	)))
+}
@@ -1,7 +1,7 @@
 use clap::Subcommand;
-use ruma::events::room::message::RoomMessageEventContent;
+use ruma::{events::room::message::RoomMessageEventContent, RoomId};
 
-use self::fsck_commands::check_all_users;
+use self::fsck_commands::{check_all_users, verify_short_ids};
 use crate::Result;
 
 pub(crate) mod fsck_commands;
@@ -10,10 +10,27 @@ pub(crate) mod fsck_commands;
 #[derive(Subcommand)]
 pub(crate) enum FsckCommand {
 	CheckAllUsers,
+
+	/// - Checks the event ID <-> short event ID mappings for entries missing
+	///   their counterpart or pointing at a different event
+	///
+	/// These cause "Shorteventid does not exist" database errors.
+	VerifyShortIds {
+		/// Only check the events in this room
+		room_id: Option<Box<RoomId>>,
+
+		/// Restore the missing half of broken mappings where it's safe to
+		#[arg(short, long)]
+		repair: bool,
+	},
 }
 
 pub(crate) async fn process(command: FsckCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
 	Ok(match command {
 		FsckCommand::CheckAllUsers => check_all_users(body).await?,
+		FsckCommand::VerifyShortIds {
+			room_id,
+			repair,
+		} => verify_short_ids(body, room_id, repair).await?,
 	})
 }
@@ -1,7 +1,10 @@
+use std::fmt::Write;
+
 use ruma::{events::room::message::RoomMessageEventContent, EventId, MxcUri};
+use service::media::MediaUsage;
 use tracing::{debug, info};
 
-use crate::{services, Result};
+use crate::{escape_html, services, utils::parse_local_user_id, Result};
 
 pub(crate) async fn delete(
 	_body: Vec<&str>, mxc: Option<Box<MxcUri>>, event_id: Option<Box<EventId>>,
@@ -176,3 +179,82 @@ pub(crate) async fn delete_past_remote_media(
 		"Deleted {deleted_count} total files.",
 	)))
 }
+
+pub(crate) async fn user_media_usage(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let usage = services().media.media_usage_by_user().await?;
+
+	let Some(MediaUsage {
+		count,
+		bytes,
+	}) = usage.get(user_id.as_str())
+	else {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"{user_id} has not uploaded any media."
+		)));
+	};
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"{user_id} has uploaded {count} file(s) using {} on disk.",
+		format_size(*bytes)
+	)))
+}
+
+pub(crate) async fn top_media_uploaders(_body: Vec<&str>, limit: usize) -> Result<RoomMessageEventContent> {
+	let mut uploaders: Vec<(String, MediaUsage)> = services()
+		.media
+		.media_usage_by_user()
+		.await?
+		.into_iter()
+		.collect();
+
+	if uploaders.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain("No local user has uploaded any media."));
+	}
+
+	uploaders.sort_by(|(_, a), (_, b)| b.bytes.cmp(&a.bytes));
+	uploaders.truncate(limit);
+
+	let output_plain = format!(
+		"Top {} media uploaders:\n{}",
+		uploaders.len(),
+		uploaders
+			.iter()
+			.map(|(user, usage)| format!("{user}\tFiles: {}\tSize: {}", usage.count, format_size(usage.bytes)))
+			.collect::<Vec<_>>()
+			.join("\n")
+	);
+	let output_html = format!(
+		"<table><caption>Top {} media \
+		 uploaders</caption>\n<tr><th>user</th>\t<th>files</th>\t<th>size</th></tr>\n{}</table>",
+		uploaders.len(),
+		uploaders
+			.iter()
+			.fold(String::new(), |mut output, (user, usage)| {
+				writeln!(
+					output,
+					"<tr><td>{}</td>\t<td>{}</td>\t<td>{}</td></tr>",
+					escape_html(user),
+					usage.count,
+					format_size(usage.bytes)
+				)
+				.expect("should be able to write to string buffer");
+				output
+			})
+	);
+
+	Ok(RoomMessageEventContent::text_html(output_plain, output_html))
+}
+
+pub(crate) async fn migrate_media(_body: Vec<&str>, dry_run: bool) -> Result<RoomMessageEventContent> {
+	let count = services().media.migrate_to_sha256(dry_run).await?;
+
+	Ok(RoomMessageEventContent::text_plain(if dry_run {
+		format!("{count} media file(s) would be moved to SHA256 file names.")
+	} else {
+		format!("Moved {count} media file(s) to SHA256 file names.")
+	}))
+}
+
+#[allow(clippy::as_conversions)]
+fn format_size(bytes: u64) -> String { format!("{:.2} MiB", bytes as f64 / 1024.0 / 1024.0) }
@@ -1,7 +1,9 @@
 use clap::Subcommand;
 use ruma::{events::room::message::RoomMessageEventContent, EventId, MxcUri};
 
-use self::media_commands::{delete, delete_list, delete_past_remote_media};
+use self::media_commands::{
+	delete, delete_list, delete_past_remote_media, migrate_media, top_media_uploaders, user_media_usage,
+};
 use crate::Result;
 
 pub(crate) mod media_commands;
@@ -36,6 +38,32 @@ pub(crate) enum MediaCommand {
 		#[arg(short, long)]
 		force: bool,
 	},
+
+	/// - Shows how many files a local user has uploaded and how much disk
+	///   space they use
+	UserMediaUsage {
+		/// The user to total media for
+		user_id: String,
+	},
+
+	/// - Lists the local users whose uploads use the most disk space
+	TopMediaUploaders {
+		/// Number of users to list
+		#[arg(short, long, default_value_t = 10)]
+		limit: usize,
+	},
+
+	/// - Moves media stored under legacy base64 file names to the SHA256 file
+	///   names used by the `sha256_media` feature
+	///
+	/// The startup migration already does this once. Run this if media was
+	/// uploaded by a build without `sha256_media` since, e.g. after a
+	/// temporary downgrade. Builds without the feature refuse to migrate.
+	MigrateMedia {
+		/// Only report how many files would be moved
+		#[arg(long)]
+		dry_run: bool,
+	},
 }
 
 pub(crate) async fn process(command: MediaCommand, body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -49,5 +77,14 @@ pub(crate) async fn process(command: MediaCommand, body: Vec<&str>) -> Result<Ro
 			duration,
 			force,
 		} => delete_past_remote_media(body, duration, force).await?,
+		MediaCommand::UserMediaUsage {
+			user_id,
+		} => user_media_usage(body, user_id).await?,
+		MediaCommand::TopMediaUploaders {
+			limit,
+		} => top_media_uploaders(body, limit).await?,
+		MediaCommand::MigrateMedia {
+			dry_run,
+		} => migrate_media(body, dry_run).await?,
 	})
 }
@@ -1,7 +1,7 @@
 use clap::Subcommand;
-use ruma::{events::room::message::RoomMessageEventContent, RoomId, RoomOrAliasId};
+use ruma::{events::room::message::RoomMessageEventContent, RoomAliasId, RoomId, RoomOrAliasId};
 
-use self::room_commands::list;
+use self::room_commands::{list, recalculate_room_counts};
 use crate::Result;
 
 pub(crate) mod room_alias_commands;
@@ -33,6 +33,14 @@ pub(crate) enum RoomCommand {
 	#[command(subcommand)]
 	/// - Manage the room directory
 	Directory(RoomDirectoryCommand),
+
+	/// - Recount joined and invited members from the room state and fix the
+	///   cached member counts if they are wrong
+	///
+	/// Checks every room we know about when no room is given.
+	RecalculateRoomCounts {
+		room_id: Option<Box<RoomId>>,
+	},
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -86,6 +94,17 @@ pub(crate) enum RoomAliasCommand {
 		/// If set, only list the aliases for this room
 		room_id: Option<Box<RoomId>>,
 	},
+
+	/// - Delete one or more local aliases
+	///
+	/// Unlike `remove`, this takes full aliases (`#alias:servername.tld`),
+	/// bypasses the usual permission checks and also drops the aliases from
+	/// the rooms' canonical alias events. Reports the result for each alias.
+	Delete {
+		#[arg(required = true)]
+		/// The aliases to delete
+		aliases: Vec<Box<RoomAliasId>>,
+	},
 }
 
 #[cfg_attr(test, derive(Debug))]
@@ -180,5 +199,9 @@ pub(crate) async fn process(command: RoomCommand, body: Vec<&str>) -> Result<Roo
 		RoomCommand::List {
 			page,
 		} => list(body, page).await?,
+
+		RoomCommand::RecalculateRoomCounts {
+			room_id,
+		} => recalculate_room_counts(body, room_id).await?,
 	})
 }
@@ -1,6 +1,7 @@
 use std::fmt::Write;
 
 use ruma::{events::room::message::RoomMessageEventContent, RoomAliasId};
+use service::server_is_ours;
 
 use super::RoomAliasCommand;
 use crate::{escape_html, services, Result};
@@ -77,9 +78,15 @@ pub(crate) async fn process(command: RoomAliasCommand, _body: Vec<&str>) -> Resu
 				},
 				RoomAliasCommand::List {
 					..
+				}
+				| RoomAliasCommand::Delete {
+					..
 				} => unreachable!(),
 			}
 		},
+		RoomAliasCommand::Delete {
+			aliases,
+		} => Ok(delete(aliases).await),
 		RoomAliasCommand::List {
 			room_id,
 		} => {
@@ -149,3 +156,40 @@ pub(crate) async fn process(command: RoomAliasCommand, _body: Vec<&str>) -> Resu
 		},
 	}
 }
+
+async fn delete(aliases: Vec<Box<RoomAliasId>>) -> RoomMessageEventContent {
+	let server_user = &services().globals.server_user;
+	let mut plain = String::new();
+	for alias in aliases {
+		let result = if !server_is_ours(alias.server_name()) {
+			"not a local alias".to_owned()
+		} else {
+			match services().rooms.alias.resolve_local_alias(&alias) {
+				Ok(Some(room_id)) => match services()
+					.rooms
+					.alias
+					.remove_alias(&alias, server_user)
+					.await
+				{
+					Ok(()) => match services()
+						.rooms
+						.alias
+						.remove_from_canonical_alias(&alias, &room_id)
+						.await
+					{
+						Ok(true) => format!("removed from {room_id} and its canonical alias"),
+						Ok(false) => format!("removed from {room_id}"),
+						Err(e) => format!("removed from {room_id}, but failed to update its canonical alias: {e}"),
+					},
+					Err(e) => format!("failed to remove: {e}"),
+				},
+				Ok(None) => "not in use".to_owned(),
+				Err(e) => format!("unable to look up: {e}"),
+			}
+		};
+
+		writeln!(plain, "- {alias}: {result}").expect("should be able to write to string buffer");
+	}
+
+	RoomMessageEventContent::text_plain(plain)
+}
@@ -1,6 +1,6 @@
 use std::fmt::Write;
 
-use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId};
+use ruma::{events::room::message::RoomMessageEventContent, OwnedRoomId, RoomId};
 
 use crate::{escape_html, get_room_info, handler::PAGE_SIZE, services, Result};
 
@@ -54,3 +54,47 @@ pub(crate) async fn list(_body: Vec<&str>, page: Option<usize>) -> Result<RoomMe
 	);
 	Ok(RoomMessageEventContent::text_html(output_plain, output_html))
 }
+
+pub(crate) async fn recalculate_room_counts(
+	_body: Vec<&str>, room_id: Option<Box<RoomId>>,
+) -> Result<RoomMessageEventContent> {
+	let room_ids: Vec<OwnedRoomId> = match room_id {
+		Some(room_id) => vec![room_id.into()],
+		None => services()
+			.rooms
+			.metadata
+			.iter_ids()
+			.filter_map(Result::ok)
+			.collect(),
+	};
+
+	let mut fixed = Vec::new();
+	for room_id in &room_ids {
+		let (cached, recounted) = services()
+			.rooms
+			.state_cache
+			.recalculate_room_counts(room_id)
+			.await?;
+
+		if cached != recounted {
+			fixed.push(format!(
+				"{room_id}: joined {} -> {}, invited {} -> {}",
+				cached.0, recounted.0, cached.1, recounted.1
+			));
+		}
+	}
+
+	if fixed.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"Checked {} room(s), all member counts were correct.",
+			room_ids.len()
+		)));
+	}
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"Checked {} room(s), fixed member counts in {}:\n```\n{}\n```",
+		room_ids.len(),
+		fixed.len(),
+		fixed.join("\n")
+	)))
+}
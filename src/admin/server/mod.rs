@@ -5,7 +5,7 @@ use ruma::events::room::message::RoomMessageEventContent;
 
 use self::server_commands::{
 	backup_database, clear_database_caches, clear_service_caches, list_backups, list_database_files, memory_usage,
-	show_config, uptime,
+	prune_backups, show_config, uptime,
 };
 use crate::Result;
 
@@ -35,8 +35,13 @@ pub(crate) enum ServerCommand {
 
 	/// - Performs an online backup of the database (only available for RocksDB
 	///   at the moment)
+	#[command(alias = "create-backup")]
 	BackupDatabase,
 
+	/// - Deletes all but the newest `database_backups_to_keep` database
+	///   backups
+	PruneBackups,
+
 	/// - List database backups
 	ListBackups,
 
@@ -57,6 +62,7 @@ pub(crate) async fn process(command: ServerCommand, body: Vec<&str>) -> Result<R
 		} => clear_service_caches(body, amount).await?,
 		ServerCommand::ListBackups => list_backups(body).await?,
 		ServerCommand::BackupDatabase => backup_database(body).await?,
+		ServerCommand::PruneBackups => prune_backups(body).await?,
 		ServerCommand::ListDatabaseFiles => list_database_files(body).await?,
 	})
 }
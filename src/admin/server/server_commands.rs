@@ -1,5 +1,8 @@
+use conduit::Error;
 use ruma::events::room::message::RoomMessageEventContent;
 
+use service::globals;
+
 use crate::{services, Result};
 
 pub(crate) async fn uptime(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -69,21 +72,51 @@ pub(crate) async fn backup_database(_body: Vec<&str>) -> Result<RoomMessageEvent
 		));
 	}
 
+	let result = match globals::backup::backup().await {
+		Ok(true) => services().globals.db.backup_list()?,
+		Ok(false) => "A database backup is already in progress.".to_owned(),
+		Err(e) => e.to_string(),
+	};
+
+	Ok(RoomMessageEventContent::text_plain(&result))
+}
+
+pub(crate) async fn prune_backups(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
+	if !cfg!(feature = "rocksdb") {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Only RocksDB supports online backups in conduwuit.",
+		));
+	}
+
+	if services().globals.config.database_backups_to_keep < 0 {
+		return Ok(RoomMessageEventContent::text_plain(
+			"database_backups_to_keep is negative, all backups are kept.",
+		));
+	}
+
+	let Ok(_lock) = services().globals.backup_mutex.try_lock() else {
+		return Ok(RoomMessageEventContent::text_plain("A database backup is already in progress."));
+	};
+
 	let mut result = services()
 		.server
 		.runtime()
-		.spawn_blocking(move || match services().globals.db.backup() {
+		.spawn_blocking(move || match services().globals.db.backup_prune() {
 			Ok(()) => String::new(),
 			Err(e) => (*e).to_string(),
 		})
 		.await
-		.unwrap();
+		.map_err(|e| Error::Err(format!("Database backup pruning task failed: {e}")))?;
 
 	if result.is_empty() {
 		result = services().globals.db.backup_list()?;
 	}
 
-	Ok(RoomMessageEventContent::text_plain(&result))
+	if result.is_empty() {
+		Ok(RoomMessageEventContent::text_plain("No backups found."))
+	} else {
+		Ok(RoomMessageEventContent::text_plain(&result))
+	}
 }
 
 pub(crate) async fn list_database_files(_body: Vec<&str>) -> Result<RoomMessageEventContent> {
@@ -2,9 +2,11 @@ pub(crate) mod user_commands;
 
 use clap::Subcommand;
 use ruma::{events::room::message::RoomMessageEventContent, RoomId};
-use user_commands::{delete_room_tag, get_room_tags, put_room_tag};
+use user_commands::{delete_room_tag, get_room_tags, put_room_tag, to_device_backlog};
 
-use self::user_commands::{create, deactivate, deactivate_all, list, list_joined_rooms, reset_password};
+use self::user_commands::{
+	create, deactivate, deactivate_all, list, list_joined_rooms, make_user_admin, reset_password, revoke_admin,
+};
 use crate::Result;
 
 #[cfg_attr(test, derive(Debug))]
@@ -60,12 +62,36 @@ pub(crate) enum UserCommand {
 	/// - List local users in the database
 	List,
 
+	/// - Grant server admin privileges to a local user
+	///
+	/// The user is joined to the admin room with the admin power level.
+	MakeUserAdmin {
+		user_id: String,
+	},
+
+	/// - Revoke server admin privileges from a local user
+	///
+	/// The user is removed from the admin room. The last remaining admin
+	/// cannot be revoked.
+	RevokeAdmin {
+		user_id: String,
+	},
+
 	/// - Lists all the rooms (local and remote) that the specified user is
 	///   joined in
 	ListJoinedRooms {
 		user_id: String,
 	},
 
+	/// - Shows how many to-device messages are waiting to be delivered to each
+	///   of a local user's devices
+	///
+	/// Devices which never sync again keep their messages until
+	/// `to_device_ttl_days` expires them.
+	ToDeviceBacklog {
+		user_id: String,
+	},
+
 	/// - Puts a room tag for the specified user and room ID.
 	///
 	/// This is primarily useful if you'd like to set your admin room
@@ -111,9 +137,18 @@ pub(crate) async fn process(command: UserCommand, body: Vec<&str>) -> Result<Roo
 			no_leave_rooms,
 			force,
 		} => deactivate_all(body, no_leave_rooms, force).await?,
+		UserCommand::MakeUserAdmin {
+			user_id,
+		} => make_user_admin(body, user_id).await?,
+		UserCommand::RevokeAdmin {
+			user_id,
+		} => revoke_admin(body, user_id).await?,
 		UserCommand::ListJoinedRooms {
 			user_id,
 		} => list_joined_rooms(body, user_id).await?,
+		UserCommand::ToDeviceBacklog {
+			user_id,
+		} => to_device_backlog(body, user_id).await?,
 		UserCommand::PutRoomTag {
 			user_id,
 			room_id,
@@ -166,6 +166,63 @@ pub(crate) async fn deactivate(
 	)))
 }
 
+pub(crate) async fn make_user_admin(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_active_local_user_id(&user_id)?;
+
+	if services().users.is_admin(&user_id)? {
+		return Ok(RoomMessageEventContent::text_plain(format!("{user_id} is already an admin.")));
+	}
+
+	let displayname = services()
+		.users
+		.displayname(&user_id)?
+		.unwrap_or_else(|| user_id.localpart().to_owned());
+	service::admin::make_user_admin(&user_id, displayname).await?;
+	info!("Granted admin privileges to {user_id}");
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"{user_id} has been granted admin privileges."
+	)))
+}
+
+pub(crate) async fn revoke_admin(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+
+	if user_id == services().globals.server_user {
+		return Ok(RoomMessageEventContent::text_plain(
+			"Not allowed to revoke admin from the server service account.",
+		));
+	}
+
+	if !services().users.is_admin(&user_id)? {
+		return Ok(RoomMessageEventContent::text_plain(format!("{user_id} is not an admin.")));
+	}
+
+	let Some(admin_room) = service::admin::Service::get_admin_room()? else {
+		return Ok(RoomMessageEventContent::text_plain("There is no admin room."));
+	};
+
+	let other_admins = services()
+		.rooms
+		.state_cache
+		.room_members(&admin_room)
+		.filter_map(Result::ok)
+		.filter(|member| *member != user_id && *member != services().globals.server_user)
+		.count();
+	if other_admins == 0 {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"Not revoking admin from {user_id} as they are the last admin."
+		)));
+	}
+
+	service::admin::revoke_user_admin(&user_id).await?;
+	info!("Revoked admin privileges from {user_id}");
+
+	Ok(RoomMessageEventContent::text_plain(format!(
+		"{user_id} no longer has admin privileges."
+	)))
+}
+
 pub(crate) async fn reset_password(_body: Vec<&str>, username: String) -> Result<RoomMessageEventContent> {
 	let user_id = parse_local_user_id(&username)?;
 
@@ -335,6 +392,31 @@ pub(crate) async fn list_joined_rooms(_body: Vec<&str>, user_id: String) -> Resu
 	Ok(RoomMessageEventContent::text_html(output_plain, output_html))
 }
 
+pub(crate) async fn to_device_backlog(_body: Vec<&str>, user_id: String) -> Result<RoomMessageEventContent> {
+	let user_id = parse_local_user_id(&user_id)?;
+	let backlog = services().users.to_device_backlog(&user_id)?;
+	if backlog.is_empty() {
+		return Ok(RoomMessageEventContent::text_plain(format!(
+			"{user_id} has no undelivered to-device messages."
+		)));
+	}
+
+	let total = backlog.values().sum::<usize>();
+	let mut msg = format!("{user_id} has {total} undelivered to-device message(s):\n```\n");
+	for (device_id, count) in backlog {
+		let last_seen = services()
+			.users
+			.get_device_metadata(&user_id, &device_id)?
+			.and_then(|device| device.last_seen_ts)
+			.map_or_else(|| "never".to_owned(), |ts| ts.get().to_string());
+		writeln!(msg, "{device_id}\t{count}\tlast seen: {last_seen}")
+			.expect("should be able to write to string buffer");
+	}
+	msg += "```";
+
+	Ok(RoomMessageEventContent::text_markdown(msg))
+}
+
 pub(crate) async fn put_room_tag(
 	_body: Vec<&str>, user_id: String, room_id: Box<RoomId>, tag: String,
 ) -> Result<RoomMessageEventContent> {
@@ -1,12 +1,14 @@
 use std::fmt::Write;
 
-use axum_client_ip::InsecureClientIp;
-use conduit::debug_info;
+use axum::{extract::RawQuery, http::StatusCode, response::IntoResponse};
+use axum_client_ip::SecureClientIp;
+use conduit::{debug_info, PduCount};
 use register::RegistrationKind;
 use ruma::{
 	api::client::{
 		account::{
-			change_password, check_registration_token_validity, deactivate, get_3pids, get_username_availability,
+			add_3pid, bind_3pid, change_password, check_registration_token_validity, deactivate, delete_3pid,
+			get_3pids, get_username_availability,
 			register::{self, LoginType},
 			request_3pid_management_token_via_email, request_3pid_management_token_via_msisdn, whoami,
 			ThirdPartyIdRemovalStatus,
@@ -14,14 +16,24 @@ use ruma::{
 		error::ErrorKind,
 		uiaa::{AuthFlow, AuthType, UiaaInfo},
 	},
-	events::{room::message::RoomMessageEventContent, GlobalAccountDataEventType},
-	push, OwnedRoomId, UserId,
+	events::{
+		room::{message::RoomMessageEventContent, redaction::RoomRedactionEventContent},
+		GlobalAccountDataEventType, TimelineEventType,
+	},
+	push,
+	thirdparty::{Medium, ThirdPartyIdentifierInit},
+	EventId, MilliSecondsSinceUnixEpoch, OwnedRoomId, RoomId, UserId,
 };
+use serde::Deserialize;
+use serde_json::value::to_raw_value;
 use tracing::{error, info, warn};
 
 use super::{join_room_by_id_helper, DEVICE_ID_LENGTH, SESSION_ID_LENGTH, TOKEN_LENGTH};
 use crate::{
-	service::user_is_local,
+	service::{
+		pdu::{PduBuilder, PduEvent},
+		user_is_local,
+	},
 	services,
 	utils::{self},
 	Error, Result, Ruma,
@@ -42,7 +54,7 @@ const RANDOM_USER_ID_LENGTH: usize = 10;
 /// invalid when trying to register
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn get_register_available_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<get_username_availability::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<get_username_availability::v3::Request>,
 ) -> Result<get_username_availability::v3::Response> {
 	// Validate user id
 	let user_id = UserId::parse_with_server_name(body.username.to_lowercase(), services().globals.server_name())
@@ -91,7 +103,7 @@ pub(crate) async fn get_register_available_route(
 #[allow(clippy::doc_markdown)]
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn register_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<register::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<register::v3::Request>,
 ) -> Result<register::v3::Response> {
 	if !services().globals.allow_registration() && body.appservice_info.is_none() {
 		info!(
@@ -238,6 +250,9 @@ pub(crate) async fn register_route(
 
 	// Create user
 	services().users.create(&user_id, password)?;
+	if is_guest {
+		services().users.set_guest(&user_id, true)?;
+	}
 
 	// Default to pretty displayname
 	let mut displayname = user_id.localpart().to_owned();
@@ -422,7 +437,7 @@ pub(crate) async fn register_route(
 /// - Triggers device list updates
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn change_password_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<change_password::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<change_password::v3::Request>,
 ) -> Result<change_password::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
@@ -511,11 +526,15 @@ pub(crate) async fn whoami_route(body: Ruma<whoami::v3::Request>) -> Result<whoa
 /// - Removes ability to log in again
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn deactivate_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<deactivate::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<deactivate::v3::Request>,
 ) -> Result<deactivate::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
 
+	if let Some(id_server) = &body.id_server {
+		check_identity_server(id_server)?;
+	}
+
 	let mut uiaainfo = UiaaInfo {
 		flows: vec![AuthFlow {
 			stages: vec![AuthType::Password],
@@ -554,17 +573,27 @@ pub(crate) async fn deactivate_route(
 		.rooms_joined(sender_user)
 		.filter_map(Result::ok)
 		.collect();
-	super::update_displayname(sender_user.clone(), None, all_joined_rooms.clone()).await?;
-	super::update_avatar_url(sender_user.clone(), None, None, all_joined_rooms).await?;
+
+	super::clear_profile(sender_user.clone(), all_joined_rooms.clone()).await?;
+
+	// Redact the user's messages while they can still send into their rooms
+	if body.erase && services().globals.redact_events_on_erase() {
+		redact_user_events(sender_user, &all_joined_rooms).await;
+	}
 
 	// Make the user leave all rooms before deactivation
 	super::leave_all_rooms(sender_user).await;
 
-	info!("User {sender_user} deactivated their account.");
+	let erased = if body.erase {
+		" and requested erasure"
+	} else {
+		""
+	};
+	info!("User {sender_user} deactivated their account{erased}.");
 	services()
 		.admin
 		.send_message(RoomMessageEventContent::notice_plain(format!(
-			"User {sender_user} deactivated their account."
+			"User {sender_user} deactivated their account{erased}."
 		)))
 		.await;
 
@@ -573,15 +602,231 @@ pub(crate) async fn deactivate_route(
 	})
 }
 
+/// Number of timeline events scanned at a time when erasing an account
+const ERASE_BATCH_SIZE: usize = 100;
+
+/// Redacts the messages a user sent in the given rooms, for a deactivation
+/// with `erase` set. Failures are logged and skipped.
+async fn redact_user_events(user_id: &UserId, rooms: &[OwnedRoomId]) {
+	for room_id in rooms {
+		let mut from = PduCount::min();
+		let mut redacted: usize = 0;
+		loop {
+			let pdus: Vec<_> = match services().rooms.timeline.pdus_after(user_id, room_id, from) {
+				Ok(pdus) => pdus.filter_map(Result::ok).take(ERASE_BATCH_SIZE).collect(),
+				Err(e) => {
+					warn!("Failed to list events of {user_id} in {room_id} for erasure: {e}");
+					break;
+				},
+			};
+
+			let Some(&(last, _)) = pdus.last() else {
+				break;
+			};
+			from = last;
+
+			for (_, pdu) in pdus {
+				if is_erasable(&pdu, user_id) {
+					redact_erased_event(user_id, room_id, &pdu.event_id).await;
+					redacted = redacted.saturating_add(1);
+				}
+			}
+		}
+
+		if redacted > 0 {
+			info!("Redacted {redacted} events of {user_id} in {room_id}");
+		}
+	}
+}
+
+async fn redact_erased_event(user_id: &UserId, room_id: &RoomId, event_id: &EventId) {
+	let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
+	if let Err(e) = services()
+		.rooms
+		.timeline
+		.build_and_append_pdu(
+			PduBuilder {
+				event_type: TimelineEventType::RoomRedaction,
+				content: to_raw_value(&RoomRedactionEventContent {
+					redacts: Some(event_id.to_owned()),
+					reason: Some("Account erased".to_owned()),
+				})
+				.expect("event is valid, we just created it"),
+				unsigned: None,
+				state_key: None,
+				redacts: Some(event_id.into()),
+			},
+			user_id,
+			room_id,
+			&state_lock,
+		)
+		.await
+	{
+		warn!("Failed to redact {event_id} in {room_id}: {e}");
+	}
+}
+
+/// Whether an event should be redacted when its sender erases their account.
+/// State events are kept so room state stays intact.
+fn is_erasable(pdu: &PduEvent, user_id: &UserId) -> bool {
+	pdu.sender == user_id
+		&& pdu.state_key.is_none()
+		&& pdu.kind != TimelineEventType::RoomRedaction
+		&& !pdu.is_redacted()
+}
+
 /// # `GET _matrix/client/v3/account/3pid`
 ///
 /// Get a list of third party identifiers associated with this account.
-///
-/// - Currently always returns empty list
 pub(crate) async fn third_party_route(body: Ruma<get_3pids::v3::Request>) -> Result<get_3pids::v3::Response> {
-	let _sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	Ok(get_3pids::v3::Response::new(services().users.threepids(sender_user)?))
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/add`
+///
+/// Adds an email address validated by this homeserver to the account.
+///
+/// - Requires UIAA to verify password
+/// - The session must have been validated through the link emailed by
+///   `request_3pid_management_token_via_email_route`
+pub(crate) async fn add_3pid_route(body: Ruma<add_3pid::v3::Request>) -> Result<add_3pid::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
+
+	let mut uiaainfo = UiaaInfo {
+		flows: vec![AuthFlow {
+			stages: vec![AuthType::Password],
+		}],
+		completed: Vec::new(),
+		params: Box::default(),
+		session: None,
+		auth_error: None,
+	};
+
+	if let Some(auth) = &body.auth {
+		let (worked, uiaainfo) = services()
+			.uiaa
+			.try_auth(sender_user, sender_device, auth, &uiaainfo)?;
+		if !worked {
+			return Err(Error::Uiaa(uiaainfo));
+		}
+	// Success!
+	} else if let Some(json) = body.json_body {
+		uiaainfo.session = Some(utils::random_string(SESSION_ID_LENGTH));
+		services()
+			.uiaa
+			.create(sender_user, sender_device, &uiaainfo, &json)?;
+		return Err(Error::Uiaa(uiaainfo));
+	} else {
+		return Err(Error::BadRequest(ErrorKind::NotJson, "Not json."));
+	}
+
+	let Some(address) = services()
+		.users
+		.threepid_sessions
+		.lock()
+		.expect("locked")
+		.finish(body.sid.as_str(), body.client_secret.as_str())
+	else {
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidAuthFailed,
+			"No validated third party identifier session found for this client secret and session ID.",
+		));
+	};
+
+	let now = MilliSecondsSinceUnixEpoch::now();
+	services().users.add_threepid(
+		sender_user,
+		&ThirdPartyIdentifierInit {
+			address: address.clone(),
+			medium: Medium::Email,
+			validated_at: now,
+			added_at: now,
+		}
+		.into(),
+	)?;
+
+	info!("{sender_user} added email address {address}");
+
+	Ok(add_3pid::v3::Response::new())
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/bind`
+///
+/// Binds a third party identifier validated by an identity server to the
+/// account on that identity server.
+///
+/// - Only identity servers listed in `trusted_identity_servers` are contacted
+pub(crate) async fn bind_3pid_route(body: Ruma<bind_3pid::v3::Request>) -> Result<bind_3pid::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let id_server = &body.identity_server_info.id_server;
 
-	Ok(get_3pids::v3::Response::new(Vec::new()))
+	check_identity_server(id_server)?;
+
+	let request = serde_json::json!({
+		"sid": body.sid,
+		"client_secret": body.client_secret,
+		"mxid": sender_user,
+	});
+	let response = services()
+		.globals
+		.client
+		.default
+		.post(format!("https://{id_server}/_matrix/identity/v2/3pid/bind"))
+		.bearer_auth(&body.identity_server_info.id_access_token)
+		.header(reqwest::header::CONTENT_TYPE, "application/json")
+		.body(serde_json::to_vec(&request).expect("bind request is valid json"))
+		.send()
+		.await?;
+
+	if !response.status().is_success() {
+		warn!(
+			"Identity server {id_server} refused to bind a third party identifier to {sender_user}: {}",
+			response.status()
+		);
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidAuthFailed,
+			"The identity server refused to bind the third party identifier.",
+		));
+	}
+
+	info!("{sender_user} bound a third party identifier on {id_server}");
+
+	Ok(bind_3pid::v3::Response::new())
+}
+
+/// # `POST /_matrix/client/v3/account/3pid/delete`
+///
+/// Removes a third party identifier from the account.
+///
+/// - `id_server` must be listed in `trusted_identity_servers`
+/// - We never ask identity servers to unbind, so the identifier stays bound on
+///   any identity server it was bound to and we report `no-support`
+pub(crate) async fn delete_3pid_route(body: Ruma<delete_3pid::v3::Request>) -> Result<delete_3pid::v3::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+
+	if let Some(id_server) = &body.id_server {
+		check_identity_server(id_server)?;
+	}
+
+	let address = normalize_threepid_address(&body.medium, &body.address);
+	if !services()
+		.users
+		.remove_threepid(sender_user, &body.medium, &address)?
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::NotFound,
+			"Third party identifier is not associated with this account.",
+		));
+	}
+
+	info!("{sender_user} removed third party identifier {address}");
+
+	Ok(delete_3pid::v3::Response {
+		id_server_unbind_result: ThirdPartyIdRemovalStatus::NoSupport,
+	})
 }
 
 /// # `POST /_matrix/client/v3/account/3pid/email/requestToken`
@@ -589,17 +834,89 @@ pub(crate) async fn third_party_route(body: Ruma<get_3pids::v3::Request>) -> Res
 /// "This API should be used to request validation tokens when adding an email
 /// address to an account"
 ///
+/// - Emails a link validating the address, unless the client is retrying a
+///   send attempt it already made
 /// - 403 signals that The homeserver does not allow the third party identifier
-///   as a contact option.
+///   as a contact option, which is the case while SMTP is not configured.
 pub(crate) async fn request_3pid_management_token_via_email_route(
-	_body: Ruma<request_3pid_management_token_via_email::v3::Request>,
+	body: Ruma<request_3pid_management_token_via_email::v3::Request>,
 ) -> Result<request_3pid_management_token_via_email::v3::Response> {
-	Err(Error::BadRequest(
-		ErrorKind::ThreepidDenied,
-		"Third party identifier is not allowed",
+	if !services().pusher.can_email() {
+		return Err(Error::BadRequest(
+			ErrorKind::ThreepidDenied,
+			"Third party identifier is not allowed",
+		));
+	}
+
+	let address = normalize_threepid_address(&Medium::Email, &body.email);
+	let (sid, token) = services()
+		.users
+		.threepid_sessions
+		.lock()
+		.expect("locked")
+		.start(body.client_secret.as_str(), &address, body.send_attempt)?;
+
+	if let Some(token) = token {
+		let base = services().globals.well_known_client().as_ref().map_or_else(
+			|| format!("https://{}", services().globals.server_name()),
+			|client| client.as_str().trim_end_matches('/').to_owned(),
+		);
+		let link = format!(
+			"{base}/_conduwuit/3pid/email/validate?sid={sid}&client_secret={}&token={token}",
+			body.client_secret
+		);
+
+		if let Err(e) = services()
+			.pusher
+			.send_validation_email(&address, &link)
+			.await
+		{
+			warn!("Failed to send validation email to {address}: {e}");
+			return Err(Error::BadRequest(
+				ErrorKind::InvalidParam,
+				"Could not send a validation email to this address.",
+			));
+		}
+	}
+
+	Ok(request_3pid_management_token_via_email::v3::Response::new(
+		sid.try_into().expect("random session ID is valid"),
 	))
 }
 
+#[derive(Deserialize)]
+pub(crate) struct ValidateEmailQuery {
+	sid: String,
+	client_secret: String,
+	token: String,
+}
+
+/// # `GET /_conduwuit/3pid/email/validate`
+///
+/// Validates an email address through the link emailed by
+/// `request_3pid_management_token_via_email_route`, after which the client can
+/// add it to the account.
+pub(crate) async fn validate_3pid_email_route(RawQuery(query): RawQuery) -> impl IntoResponse {
+	let validated =
+		serde_html_form::from_str::<ValidateEmailQuery>(query.as_deref().unwrap_or_default()).is_ok_and(|query| {
+			services()
+				.users
+				.threepid_sessions
+				.lock()
+				.expect("locked")
+				.validate(&query.sid, &query.client_secret, &query.token)
+		});
+
+	if validated {
+		(
+			StatusCode::OK,
+			"Your email address has been validated, you can return to your Matrix client.",
+		)
+	} else {
+		(StatusCode::BAD_REQUEST, "This link is invalid or has expired.")
+	}
+}
+
 /// # `POST /_matrix/client/v3/account/3pid/msisdn/requestToken`
 ///
 /// "This API should be used to request validation tokens when adding an phone
@@ -636,3 +953,78 @@ pub(crate) async fn check_registration_token_validity(
 		valid: reg_token == body.token,
 	})
 }
+
+/// Refuses identity servers not listed in `trusted_identity_servers`.
+fn check_identity_server(id_server: &str) -> Result<()> {
+	if !identity_server_trusted(id_server, &services().globals.config.trusted_identity_servers) {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"This identity server is not trusted by the homeserver.",
+		));
+	}
+
+	Ok(())
+}
+
+/// Whether `id_server` is one of the `trusted` identity servers. Hostnames are
+/// compared case-insensitively.
+fn identity_server_trusted(id_server: &str, trusted: &[String]) -> bool {
+	trusted
+		.iter()
+		.any(|server| server.eq_ignore_ascii_case(id_server))
+}
+
+/// Email addresses are stored lowercased so they match regardless of how the
+/// client cased them; other media are kept as given.
+fn normalize_threepid_address(medium: &Medium, address: &str) -> String {
+	if *medium == Medium::Email {
+		address.trim().to_lowercase()
+	} else {
+		address.trim().to_owned()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{thirdparty::Medium, user_id};
+	use serde_json::json;
+
+	use super::{identity_server_trusted, is_erasable, normalize_threepid_address};
+	use crate::PduEvent;
+
+	fn pdu(sender: &str, kind: &str, state_key: Option<&str>) -> PduEvent {
+		PduEvent::test_event(json!({ "sender": sender, "type": kind, "state_key": state_key }))
+	}
+
+	#[test]
+	fn erase_redacts_only_own_messages() {
+		let user = user_id!("@alice:example.com");
+		assert!(is_erasable(&pdu("@alice:example.com", "m.room.message", None), user));
+		assert!(!is_erasable(&pdu("@bob:example.com", "m.room.message", None), user));
+		assert!(!is_erasable(
+			&pdu("@alice:example.com", "m.room.member", Some("@alice:example.com")),
+			user
+		));
+		assert!(!is_erasable(&pdu("@alice:example.com", "m.room.redaction", None), user));
+	}
+
+	#[test]
+	fn email_addresses_are_lowercased() {
+		assert_eq!(
+			normalize_threepid_address(&Medium::Email, " Alice@Example.COM "),
+			"alice@example.com"
+		);
+		assert_eq!(normalize_threepid_address(&Medium::Msisdn, "447700900000"), "447700900000");
+	}
+
+	#[test]
+	fn only_trusted_identity_servers_are_allowed() {
+		let trusted = ["vector.im".to_owned(), "id.example.com:8090".to_owned()];
+		assert!(identity_server_trusted("vector.im", &trusted));
+		assert!(identity_server_trusted("Vector.IM", &trusted));
+		assert!(identity_server_trusted("id.example.com:8090", &trusted));
+		assert!(!identity_server_trusted("id.example.com", &trusted));
+		assert!(!identity_server_trusted("vector.im.evil.com", &trusted));
+		assert!(!identity_server_trusted("vector.im", &[]));
+	}
+}
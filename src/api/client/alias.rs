@@ -256,13 +256,96 @@ async fn alias_checks(room_alias: &RoomAliasId, appservice_info: &Option<Registr
 		return Err(Error::BadRequest(ErrorKind::InvalidParam, "Alias is from another server."));
 	}
 
-	if let Some(ref info) = appservice_info {
+	let registrations = services().appservice.read().await;
+	alias_namespace_check(room_alias, appservice_info.as_ref(), registrations.values())
+}
+
+/// Appservices may only use aliases in their own namespace, and nobody may use
+/// an alias that another appservice has claimed exclusively.
+fn alias_namespace_check<'a, I>(
+	room_alias: &RoomAliasId, appservice_info: Option<&RegistrationInfo>, registrations: I,
+) -> Result<()>
+where
+	I: Iterator<Item = &'a RegistrationInfo>,
+{
+	if let Some(info) = appservice_info {
 		if !info.aliases.is_match(room_alias.as_str()) {
 			return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias is not in namespace."));
 		}
-	} else if services().appservice.is_exclusive_alias(room_alias).await {
+	}
+
+	let own_id = appservice_info.map(|info| info.registration.id.as_str());
+	if registrations
+		.filter(|other| Some(other.registration.id.as_str()) != own_id)
+		.any(|other| other.aliases.is_exclusive_match(room_alias.as_str()))
+	{
 		return Err(Error::BadRequest(ErrorKind::Exclusive, "Room alias reserved by appservice."));
 	}
 
 	Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+	use ruma::{api::client::error::ErrorKind, room_alias_id};
+	use serde_json::json;
+
+	use super::alias_namespace_check;
+	use crate::{service::appservice::RegistrationInfo, Error};
+
+	fn registration(id: &str, regex: &str, exclusive: bool) -> RegistrationInfo {
+		serde_json::from_value::<ruma::api::appservice::Registration>(json!({
+			"id": id,
+			"url": null,
+			"as_token": "as_token",
+			"hs_token": "hs_token",
+			"sender_localpart": id,
+			"namespaces": {
+				"users": [],
+				"aliases": [{ "exclusive": exclusive, "regex": regex }],
+				"rooms": [],
+			},
+		}))
+		.expect("valid registration")
+		.try_into()
+		.expect("valid regex")
+	}
+
+	fn is_exclusive_error(result: crate::Result<()>) -> bool {
+		matches!(result, Err(Error::BadRequest(ErrorKind::Exclusive, _)))
+	}
+
+	#[test]
+	fn user_cannot_use_exclusive_appservice_alias() {
+		let bridge = registration("bridge", "#bridge_.*:example.com", true);
+		let alias = room_alias_id!("#bridge_room:example.com");
+
+		assert!(is_exclusive_error(alias_namespace_check(alias, None, [&bridge].into_iter())));
+		assert!(alias_namespace_check(room_alias_id!("#room:example.com"), None, [&bridge].into_iter()).is_ok());
+	}
+
+	#[test]
+	fn appservice_limited_to_own_namespace() {
+		let bridge = registration("bridge", "#bridge_.*:example.com", true);
+		let shared = registration("shared", "#.*:example.com", false);
+		let registrations = [&bridge, &shared];
+
+		let own = room_alias_id!("#bridge_room:example.com");
+		assert!(alias_namespace_check(own, Some(&bridge), registrations.into_iter()).is_ok());
+
+		let outside = room_alias_id!("#other:example.com");
+		assert!(is_exclusive_error(alias_namespace_check(
+			outside,
+			Some(&bridge),
+			registrations.into_iter()
+		)));
+
+		// a non-exclusive namespace cannot take aliases claimed by another appservice
+		assert!(is_exclusive_error(alias_namespace_check(
+			own,
+			Some(&shared),
+			registrations.into_iter()
+		)));
+		assert!(alias_namespace_check(outside, Some(&shared), registrations.into_iter()).is_ok());
+	}
+}
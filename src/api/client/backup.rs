@@ -136,6 +136,12 @@ pub(crate) async fn add_backup_keys_route(
 		));
 	}
 
+	for room_id in body.rooms.keys() {
+		services()
+			.key_backups
+			.check_room_encryption(sender_user, room_id)?;
+	}
+
 	for (room_id, room) in &body.rooms {
 		for (session_id, key_data) in &room.sessions {
 			services()
@@ -182,6 +188,10 @@ pub(crate) async fn add_backup_keys_for_room_route(
 		));
 	}
 
+	services()
+		.key_backups
+		.check_room_encryption(sender_user, &body.room_id)?;
+
 	for (session_id, key_data) in &body.sessions {
 		services()
 			.key_backups
@@ -226,6 +236,10 @@ pub(crate) async fn add_backup_keys_for_session_route(
 		));
 	}
 
+	services()
+		.key_backups
+		.check_room_encryption(sender_user, &body.room_id)?;
+
 	services()
 		.key_backups
 		.add_key(sender_user, &body.version, &body.room_id, &body.session_id, &body.session_data)?;
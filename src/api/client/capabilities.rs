@@ -1,7 +1,10 @@
 use std::collections::BTreeMap;
 
-use ruma::api::client::discovery::get_capabilities::{
-	self, Capabilities, RoomVersionStability, RoomVersionsCapability, ThirdPartyIdChangesCapability,
+use ruma::{
+	api::client::discovery::get_capabilities::{
+		self, Capabilities, RoomVersionStability, RoomVersionsCapability, ThirdPartyIdChangesCapability,
+	},
+	RoomVersionId,
 };
 
 use crate::{services, Result, Ruma};
@@ -13,19 +16,13 @@ use crate::{services, Result, Ruma};
 pub(crate) async fn get_capabilities_route(
 	_body: Ruma<get_capabilities::v3::Request>,
 ) -> Result<get_capabilities::v3::Response> {
-	let mut available = BTreeMap::new();
-	for room_version in &services().globals.unstable_room_versions {
-		available.insert(room_version.clone(), RoomVersionStability::Unstable);
-	}
-	for room_version in &services().globals.stable_room_versions {
-		available.insert(room_version.clone(), RoomVersionStability::Stable);
-	}
-
+	let globals = &services().globals;
 	let mut capabilities = Capabilities::default();
-	capabilities.room_versions = RoomVersionsCapability {
-		default: services().globals.default_room_version(),
-		available,
-	};
+	capabilities.room_versions = room_versions_capability(
+		globals.default_room_version(),
+		&globals.supported_room_versions(),
+		&globals.stable_room_versions,
+	);
 
 	// conduit does not implement 3PID stuff
 	capabilities.thirdparty_id_changes = ThirdPartyIdChangesCapability {
@@ -36,3 +33,57 @@ pub(crate) async fn get_capabilities_route(
 		capabilities,
 	})
 }
+
+/// Lists every supported room version, marking those outside the stable set
+/// as unstable.
+fn room_versions_capability(
+	default: RoomVersionId, supported: &[RoomVersionId], stable: &[RoomVersionId],
+) -> RoomVersionsCapability {
+	let available = supported
+		.iter()
+		.map(|room_version| {
+			let stability = if stable.contains(room_version) {
+				RoomVersionStability::Stable
+			} else {
+				RoomVersionStability::Unstable
+			};
+
+			(room_version.clone(), stability)
+		})
+		.collect::<BTreeMap<_, _>>();
+
+	RoomVersionsCapability {
+		default,
+		available,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{api::client::discovery::get_capabilities::RoomVersionStability, RoomVersionId};
+
+	use super::room_versions_capability;
+
+	#[test]
+	fn unstable_room_versions_follow_config() {
+		let stable = vec![RoomVersionId::V6, RoomVersionId::V10];
+		let unstable = vec![RoomVersionId::V2, RoomVersionId::V3];
+
+		let capability = room_versions_capability(RoomVersionId::V10, &stable, &stable);
+		assert_eq!(capability.default, RoomVersionId::V10);
+		assert_eq!(capability.available.len(), 2);
+		assert!(!capability.available.contains_key(&RoomVersionId::V2));
+
+		let supported = [stable.clone(), unstable].concat();
+		let capability = room_versions_capability(RoomVersionId::V10, &supported, &stable);
+		assert_eq!(capability.available.len(), 4);
+		assert_eq!(
+			capability.available.get(&RoomVersionId::V2),
+			Some(&RoomVersionStability::Unstable)
+		);
+		assert_eq!(
+			capability.available.get(&RoomVersionId::V6),
+			Some(&RoomVersionStability::Stable)
+		);
+	}
+}
@@ -3,6 +3,7 @@ use std::collections::HashSet;
 use ruma::{
 	api::client::{context::get_context, error::ErrorKind, filter::LazyLoadOptions},
 	events::StateEventType,
+	RoomId,
 };
 use tracing::error;
 
@@ -12,9 +13,10 @@ use crate::{services, Error, Result, Ruma};
 ///
 /// Allows loading room history around an event.
 ///
-/// - Only works if the user is joined (TODO: always allow, but only show events
-///   if the user was
-/// joined, depending on history_visibility)
+/// - Events the user is not allowed to see, including the requested event, are
+///   treated as not found depending on history_visibility
+/// - With lazy loading enabled, only the member events of senders in the
+///   returned context are included in the state
 pub(crate) async fn get_context_route(body: Ruma<get_context::v3::Request>) -> Result<get_context::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 	let sender_device = body.sender_device.as_ref().expect("user is authenticated");
@@ -42,16 +44,14 @@ pub(crate) async fn get_context_route(body: Ruma<get_context::v3::Request>) -> R
 
 	let room_id = base_event.room_id.clone();
 
-	if !services()
-		.rooms
-		.state_accessor
-		.user_can_see_event(sender_user, &room_id, &body.event_id)?
-	{
-		return Err(Error::BadRequest(
-			ErrorKind::forbidden(),
-			"You don't have permission to view this event.",
-		));
-	}
+	check_context_event(
+		&room_id,
+		&body.room_id,
+		services()
+			.rooms
+			.state_accessor
+			.user_can_see_event(sender_user, &room_id, &body.event_id)?,
+	)?;
 
 	if !services().rooms.lazy_loading.lazy_load_was_sent_before(
 		sender_user,
@@ -171,21 +171,16 @@ pub(crate) async fn get_context_route(body: Ruma<get_context::v3::Request>) -> R
 			.short
 			.get_statekey_from_short(shortstatekey)?;
 
-		if event_type != StateEventType::RoomMember {
-			let Some(pdu) = services().rooms.timeline.get_pdu(&id)? else {
-				error!("Pdu in state not found: {}", id);
-				continue;
-			};
+		if event_type == StateEventType::RoomMember && !include_member(lazy_load_enabled, &lazy_loaded, &state_key) {
+			continue;
+		}
 
-			state.push(pdu.to_state_event());
-		} else if !lazy_load_enabled || lazy_loaded.contains(&state_key) {
-			let Some(pdu) = services().rooms.timeline.get_pdu(&id)? else {
-				error!("Pdu in state not found: {}", id);
-				continue;
-			};
+		let Some(pdu) = services().rooms.timeline.get_pdu(&id)? else {
+			error!("Pdu in state not found: {}", id);
+			continue;
+		};
 
-			state.push(pdu.to_state_event());
-		}
+		state.push(pdu.to_state_event());
 	}
 
 	Ok(get_context::v3::Response {
@@ -197,3 +192,47 @@ pub(crate) async fn get_context_route(body: Ruma<get_context::v3::Request>) -> R
 		state,
 	})
 }
+
+/// Rejects context requests for events outside the requested room or hidden
+/// from the user by history visibility, without revealing that they exist.
+fn check_context_event(event_room_id: &RoomId, requested_room_id: &RoomId, visible: bool) -> Result<()> {
+	if event_room_id != requested_room_id || !visible {
+		return Err(Error::BadRequest(ErrorKind::NotFound, "Base event not found."));
+	}
+
+	Ok(())
+}
+
+/// Whether a member event belongs in the context state: all of them without
+/// lazy loading, otherwise only those of senders in the returned events.
+fn include_member(lazy_load_enabled: bool, lazy_loaded: &HashSet<String>, state_key: &str) -> bool {
+	!lazy_load_enabled || lazy_loaded.contains(state_key)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashSet;
+
+	use ruma::room_id;
+
+	use super::{check_context_event, include_member};
+
+	#[test]
+	fn unreadable_or_foreign_event_is_not_found() {
+		let room = room_id!("!room:example.com");
+		let other = room_id!("!other:example.com");
+
+		assert!(check_context_event(room, room, true).is_ok());
+		assert!(check_context_event(room, room, false).is_err());
+		assert!(check_context_event(other, room, true).is_err());
+	}
+
+	#[test]
+	fn lazy_members_scoped_to_context_senders() {
+		let lazy_loaded: HashSet<String> = ["@alice:example.com".to_owned()].into();
+
+		assert!(include_member(true, &lazy_loaded, "@alice:example.com"));
+		assert!(!include_member(true, &lazy_loaded, "@bob:example.com"));
+		assert!(include_member(false, &lazy_loaded, "@bob:example.com"));
+	}
+}
@@ -1,4 +1,4 @@
-use axum_client_ip::InsecureClientIp;
+use axum_client_ip::SecureClientIp;
 use ruma::{
 	api::{
 		client::{
@@ -30,7 +30,7 @@ use crate::{service::server_is_ours, services, Error, Result, Ruma};
 /// - Rooms are ordered by the number of joined members
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn get_public_rooms_filtered_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<get_public_rooms_filtered::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<get_public_rooms_filtered::v3::Request>,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
 	if let Some(server) = &body.server {
 		if services()
@@ -68,7 +68,7 @@ pub(crate) async fn get_public_rooms_filtered_route(
 /// - Rooms are ordered by the number of joined members
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn get_public_rooms_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<get_public_rooms::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<get_public_rooms::v3::Request>,
 ) -> Result<get_public_rooms::v3::Response> {
 	if let Some(server) = &body.server {
 		if services()
@@ -111,7 +111,7 @@ pub(crate) async fn get_public_rooms_route(
 /// - TODO: Access control checks
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn set_room_visibility_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<set_room_visibility::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<set_room_visibility::v3::Request>,
 ) -> Result<set_room_visibility::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
@@ -120,12 +120,17 @@ pub(crate) async fn set_room_visibility_route(
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Room not found"));
 	}
 
+	let config = &services().globals.config;
 	match &body.visibility {
 		room::Visibility::Public => {
-			if services().globals.config.lockdown_public_room_directory && !services().users.is_admin(sender_user)? {
+			if !may_publish_to_directory(
+				config.allow_public_room_directory_publish,
+				config.lockdown_public_room_directory,
+				services().users.is_admin(sender_user)?,
+			) {
 				info!(
-					"Non-admin user {sender_user} tried to publish {0} to the room directory while \
-					 \"lockdown_public_room_directory\" is enabled",
+					"Non-admin user {sender_user} tried to publish {0} to the room directory while publishing is \
+					 restricted to admins",
 					body.room_id
 				);
 
@@ -170,6 +175,22 @@ pub(crate) async fn get_room_visibility_route(
 	})
 }
 
+/// Whether a user may publish rooms to the room directory. Admins always can;
+/// everyone else needs `allow_public_room_directory_publish` on and
+/// `lockdown_public_room_directory` off.
+pub(crate) fn may_publish_to_directory(allow_publish: bool, lockdown: bool, is_admin: bool) -> bool {
+	is_admin || (allow_publish && !lockdown)
+}
+
+/// Decides the room directory visibility of a new room: the configured
+/// `default_room_directory_visibility` if set, otherwise what the client asked
+/// for.
+pub(crate) fn new_room_directory_visibility(
+	requested: &room::Visibility, default: Option<&room::Visibility>,
+) -> room::Visibility {
+	default.unwrap_or(requested).clone()
+}
+
 pub(crate) async fn get_public_rooms_filtered_helper(
 	server: Option<&ServerName>, limit: Option<UInt>, since: Option<&str>, filter: &Filter, _network: &RoomNetwork,
 ) -> Result<get_public_rooms_filtered::v3::Response> {
@@ -368,3 +389,28 @@ pub(crate) async fn get_public_rooms_filtered_helper(
 		total_room_count_estimate: Some(total_room_count_estimate),
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use ruma::api::client::room::Visibility;
+
+	use super::{may_publish_to_directory, new_room_directory_visibility};
+
+	#[test]
+	fn publishing_restricted_to_admins() {
+		assert!(may_publish_to_directory(true, false, false));
+		assert!(!may_publish_to_directory(false, false, false));
+		assert!(!may_publish_to_directory(true, true, false));
+		assert!(may_publish_to_directory(false, true, true));
+	}
+
+	#[test]
+	fn default_visibility_overrides_client() {
+		assert_eq!(
+			new_room_directory_visibility(&Visibility::Public, Some(&Visibility::Private)),
+			Visibility::Private
+		);
+		assert_eq!(new_room_directory_visibility(&Visibility::Public, None), Visibility::Public);
+		assert_eq!(new_room_directory_visibility(&Visibility::Private, None), Visibility::Private);
+	}
+}
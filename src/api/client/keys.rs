@@ -4,6 +4,7 @@ use std::{
 	time::{Duration, Instant},
 };
 
+use conduit::SyncToken;
 use futures_util::{stream::FuturesUnordered, StreamExt};
 use ruma::{
 	api::{
@@ -33,6 +34,7 @@ use crate::{
 /// Publish end-to-end encryption keys for the sender device.
 ///
 /// - Adds one time keys
+/// - Adds fallback keys, replacing the previous fallback key of each algorithm
 /// - If there are no device keys yet: Adds device keys (TODO: merge with
 ///   existing keys?)
 pub(crate) async fn upload_keys_route(body: Ruma<upload_keys::v3::Request>) -> Result<upload_keys::v3::Response> {
@@ -45,6 +47,12 @@ pub(crate) async fn upload_keys_route(body: Ruma<upload_keys::v3::Request>) -> R
 			.add_one_time_key(sender_user, sender_device, key_key, key_value)?;
 	}
 
+	for (key_key, key_value) in &body.fallback_keys {
+		services()
+			.users
+			.add_fallback_key(sender_user, sender_device, key_key, key_value)?;
+	}
+
 	if let Some(device_keys) = &body.device_keys {
 		// TODO: merge this and the existing event?
 		// This check is needed to assure that signatures are kept
@@ -88,7 +96,8 @@ pub(crate) async fn get_keys_route(body: Ruma<get_keys::v3::Request>) -> Result<
 
 /// # `POST /_matrix/client/r0/keys/claim`
 ///
-/// Claims one-time keys
+/// Claims one-time keys, falling back to the device's fallback key once its
+/// one-time keys are exhausted
 pub(crate) async fn claim_keys_route(body: Ruma<claim_keys::v3::Request>) -> Result<claim_keys::v3::Response> {
 	claim_keys_helper(&body.one_time_keys).await
 }
@@ -207,13 +216,13 @@ pub(crate) async fn get_key_changes_route(
 			.users
 			.keys_changed(
 				sender_user.as_str(),
-				body.from
-					.parse()
-					.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`."))?,
+				SyncToken::decode(&body.from)
+					.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`."))?
+					.count,
 				Some(
-					body.to
-						.parse()
-						.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`."))?,
+					SyncToken::decode(&body.to)
+						.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`."))?
+						.count,
 				),
 			)
 			.filter_map(Result::ok),
@@ -230,13 +239,13 @@ pub(crate) async fn get_key_changes_route(
 				.users
 				.keys_changed(
 					room_id.as_ref(),
-					body.from
-						.parse()
-						.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`."))?,
+					SyncToken::decode(&body.from)
+						.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `from`."))?
+						.count,
 					Some(
-						body.to
-							.parse()
-							.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`."))?,
+						SyncToken::decode(&body.to)
+							.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid `to`."))?
+							.count,
 					),
 				)
 				.filter_map(Result::ok),
@@ -423,31 +432,44 @@ pub(crate) async fn get_keys_helper<F: Fn(&UserId) -> bool + Send>(
 	})
 }
 
-fn add_unsigned_device_display_name(
+/// Sets `unsigned.device_display_name` on the device keys. With display names
+/// hidden the device ID is substituted, overwriting any name the client put in
+/// the uploaded keys, matching what the federation devices endpoint exposes.
+pub(crate) fn add_unsigned_device_display_name(
 	keys: &mut Raw<ruma::encryption::DeviceKeys>, metadata: ruma::api::client::device::Device,
 	include_display_names: bool,
 ) -> serde_json::Result<()> {
-	if let Some(display_name) = metadata.display_name {
-		let mut object = keys.deserialize_as::<serde_json::Map<String, serde_json::Value>>()?;
-
-		let unsigned = object.entry("unsigned").or_insert_with(|| json!({}));
-		if let serde_json::Value::Object(unsigned_object) = unsigned {
-			if include_display_names {
-				unsigned_object.insert("device_display_name".to_owned(), display_name.into());
-			} else {
-				unsigned_object.insert(
-					"device_display_name".to_owned(),
-					Some(metadata.device_id.as_str().to_owned()).into(),
-				);
-			}
-		}
-
-		*keys = Raw::from_json(serde_json::value::to_raw_value(&object)?);
+	if include_display_names && metadata.display_name.is_none() {
+		return Ok(());
 	}
 
+	let mut object = keys.deserialize_as::<serde_json::Map<String, serde_json::Value>>()?;
+	set_unsigned_device_display_name(&mut object, metadata, include_display_names);
+	*keys = Raw::from_json(serde_json::value::to_raw_value(&object)?);
+
 	Ok(())
 }
 
+fn set_unsigned_device_display_name(
+	object: &mut serde_json::Map<String, serde_json::Value>, metadata: ruma::api::client::device::Device,
+	include_display_names: bool,
+) {
+	let display_name = if include_display_names {
+		metadata.display_name
+	} else {
+		Some(metadata.device_id.as_str().to_owned())
+	};
+
+	let Some(display_name) = display_name else {
+		return;
+	};
+
+	let unsigned = object.entry("unsigned").or_insert_with(|| json!({}));
+	if let serde_json::Value::Object(unsigned_object) = unsigned {
+		unsigned_object.insert("device_display_name".to_owned(), display_name.into());
+	}
+}
+
 pub(crate) async fn claim_keys_helper(
 	one_time_keys_input: &BTreeMap<OwnedUserId, BTreeMap<OwnedDeviceId, DeviceKeyAlgorithm>>,
 ) -> Result<claim_keys::v3::Response> {
@@ -467,7 +489,7 @@ pub(crate) async fn claim_keys_helper(
 		for (device_id, key_algorithm) in map {
 			if let Some(one_time_keys) = services()
 				.users
-				.take_one_time_key(user_id, device_id, key_algorithm)?
+				.claim_key(user_id, device_id, key_algorithm)?
 			{
 				let mut c = BTreeMap::new();
 				c.insert(one_time_keys.0, one_time_keys.1);
@@ -517,3 +539,43 @@ pub(crate) async fn claim_keys_helper(
 		one_time_keys,
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use ruma::{api::client::device::Device, device_id, serde::Raw};
+	use serde_json::{json, Value};
+
+	use super::add_unsigned_device_display_name;
+
+	fn device_display_name(keys: &Raw<ruma::encryption::DeviceKeys>) -> Value {
+		keys.deserialize_as::<Value>().unwrap()["unsigned"]["device_display_name"].clone()
+	}
+
+	#[test]
+	fn device_names_hidden_from_keys_when_disabled() {
+		let uploaded = json!({
+			"user_id": "@alice:example.com",
+			"device_id": "ABCDEFG",
+			"algorithms": [],
+			"keys": {},
+			"signatures": {},
+			"unsigned": { "device_display_name": "Alice's phone" },
+		});
+
+		let mut metadata = Device::new(device_id!("ABCDEFG").to_owned());
+		metadata.display_name = Some("Alice's laptop".to_owned());
+
+		let mut keys = Raw::new(&uploaded).unwrap().cast();
+		add_unsigned_device_display_name(&mut keys, metadata.clone(), true).unwrap();
+		assert_eq!(device_display_name(&keys), "Alice's laptop");
+
+		let mut keys = Raw::new(&uploaded).unwrap().cast();
+		add_unsigned_device_display_name(&mut keys, metadata, false).unwrap();
+		assert_eq!(device_display_name(&keys), "ABCDEFG");
+
+		// a name the client put in the uploaded keys doesn't leak either
+		let mut keys = Raw::new(&uploaded).unwrap().cast();
+		add_unsigned_device_display_name(&mut keys, Device::new(device_id!("ABCDEFG").to_owned()), false).unwrap();
+		assert_eq!(device_display_name(&keys), "ABCDEFG");
+	}
+}
@@ -1,14 +1,17 @@
-use std::{io::Cursor, sync::Arc, time::Duration};
+use std::{io::Cursor, mem, sync::Arc, time::Duration};
 
 use image::io::Reader as ImgReader;
 use ipaddress::IPAddress;
 use reqwest::Url;
-use ruma::api::client::{
-	error::{ErrorKind, RetryAfter},
-	media::{
-		create_content, get_content, get_content_as_filename, get_content_thumbnail, get_media_config,
-		get_media_preview,
+use ruma::{
+	api::client::{
+		error::{ErrorKind, RetryAfter},
+		media::{
+			create_content, get_content, get_content_as_filename, get_content_thumbnail, get_media_config,
+			get_media_preview,
+		},
 	},
+	UserId,
 };
 use tracing::{debug, error, warn};
 use webpage::HTML;
@@ -119,10 +122,48 @@ pub(crate) async fn get_media_preview_v1_route(
 /// - Some metadata will be saved in the database
 /// - Media will be saved in the media/ directory
 pub(crate) async fn create_content_route(
-	body: Ruma<create_content::v3::Request>,
+	mut body: Ruma<create_content::v3::Request>,
 ) -> Result<create_content::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	let mut file = mem::take(&mut body.body.file);
+	if services().globals.config.media_strip_exif
+		&& body
+			.content_type
+			.as_deref()
+			.is_some_and(|content_type| content_type.starts_with("image/"))
+	{
+		let (upload, stripped) = services()
+			.server
+			.runtime()
+			.spawn_blocking(move || {
+				let stripped = services().media.strip_image_metadata(&file);
+				(file, stripped)
+			})
+			.await
+			.map_err(|e| Error::Err(format!("Image metadata stripping task failed: {e}")))?;
+
+		file = match stripped {
+			Ok(Some(stripped)) if stripped.len() <= services().globals.max_request_size() as usize => stripped,
+			Ok(_) => upload,
+			Err(e) => {
+				warn!("Failed to strip metadata from image upload by {sender_user}, storing it as is: {e}");
+				upload
+			},
+		};
+	}
+
+	if !services().users.is_admin(sender_user)?
+		&& !services()
+			.media
+			.user_quota_allows(sender_user, file.len() as u64)?
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::TooLarge,
+			"Upload would exceed your media storage quota.",
+		));
+	}
+
 	let mxc = format!(
 		"mxc://{}/{}",
 		services().globals.server_name(),
@@ -145,7 +186,7 @@ pub(crate) async fn create_content_route(
 				})
 				.as_deref(),
 			body.content_type.as_deref(),
-			&body.file,
+			&file,
 		)
 		.await?;
 
@@ -171,6 +212,31 @@ pub(crate) async fn create_content_v1_route(
 	create_content_route(body).await.map(RumaResponse)
 }
 
+/// # `DELETE /_matrix/client/v1/media/{serverName}/{mediaId}`
+///
+/// conduwuit-specific API to delete media the user uploaded themselves.
+///
+/// - Only the user who uploaded the media may delete it
+/// - Removes the metadata, thumbnails and files from the media directory
+pub(crate) async fn delete_content_route(
+	body: Ruma<delete_content::v1::Request>,
+) -> Result<delete_content::v1::Response> {
+	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let mxc = format!("mxc://{}/{}", body.server_name, body.media_id);
+
+	let uploader = services().media.get_media_uploader(&mxc)?;
+	if !server_is_ours(&body.server_name) || !is_media_uploader(uploader.as_deref(), sender_user) {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"You can only delete media you uploaded.",
+		));
+	}
+
+	services().media.delete(mxc).await?;
+
+	Ok(delete_content::v1::Response {})
+}
+
 /// # `GET /_matrix/media/v3/download/{serverName}/{mediaId}`
 ///
 /// Load media from our server or over federation.
@@ -543,15 +609,14 @@ async fn download_image(client: &reqwest::Client, url: &str) -> Result<UrlPrevie
 async fn download_html(client: &reqwest::Client, url: &str) -> Result<UrlPreviewData> {
 	let mut response = client.get(url).send().await?;
 
+	let max_spider_size = services().globals.url_preview_max_spider_size();
 	let mut bytes: Vec<u8> = Vec::new();
 	while let Some(chunk) = response.chunk().await? {
-		bytes.extend_from_slice(&chunk);
-		if bytes.len() > services().globals.url_preview_max_spider_size() {
+		if extend_capped(&mut bytes, &chunk, max_spider_size) {
 			debug!(
 				"Response body from URL {} exceeds url_preview_max_spider_size ({}), not processing the rest of the \
 				 response body and assuming our necessary data is in this range.",
-				url,
-				services().globals.url_preview_max_spider_size()
+				url, max_spider_size
 			);
 			break;
 		}
@@ -643,101 +708,269 @@ fn url_preview_allowed(url_str: &str) -> bool {
 		},
 	};
 
-	if ["http", "https"]
-		.iter()
-		.all(|&scheme| scheme != url.scheme().to_lowercase())
-	{
-		debug!("Ignoring non-HTTP/HTTPS URL to preview: {}", url);
-		return false;
+	UrlPreviewPolicy::from_globals().allows(&url)
+}
+
+/// The URL preview allow/deny lists from the config. The explicit domain
+/// denylist always wins over every allowlist, including a `*` wildcard.
+struct UrlPreviewPolicy<'a> {
+	domain_contains_allowlist: &'a [String],
+	domain_explicit_allowlist: &'a [String],
+	domain_explicit_denylist: &'a [String],
+	url_contains_allowlist: &'a [String],
+	check_root_domain: bool,
+}
+
+impl<'a> UrlPreviewPolicy<'a> {
+	fn from_globals() -> UrlPreviewPolicy<'static> {
+		let globals = &services().globals;
+		UrlPreviewPolicy {
+			domain_contains_allowlist: globals.url_preview_domain_contains_allowlist(),
+			domain_explicit_allowlist: globals.url_preview_domain_explicit_allowlist(),
+			domain_explicit_denylist: globals.url_preview_domain_explicit_denylist(),
+			url_contains_allowlist: globals.url_preview_url_contains_allowlist(),
+			check_root_domain: globals.url_preview_check_root_domain(),
+		}
 	}
 
-	let host = match url.host_str() {
-		None => {
-			debug!("Ignoring URL preview for a URL that does not have a host (?): {}", url);
+	fn allows(&self, url: &Url) -> bool {
+		if ["http", "https"]
+			.iter()
+			.all(|&scheme| scheme != url.scheme().to_lowercase())
+		{
+			debug!("Ignoring non-HTTP/HTTPS URL to preview: {}", url);
 			return false;
-		},
-		Some(h) => h.to_owned(),
-	};
-
-	let allowlist_domain_contains = services().globals.url_preview_domain_contains_allowlist();
-	let allowlist_domain_explicit = services().globals.url_preview_domain_explicit_allowlist();
-	let denylist_domain_explicit = services().globals.url_preview_domain_explicit_denylist();
-	let allowlist_url_contains = services().globals.url_preview_url_contains_allowlist();
+		}
 
-	if allowlist_domain_contains.contains(&"*".to_owned())
-		|| allowlist_domain_explicit.contains(&"*".to_owned())
-		|| allowlist_url_contains.contains(&"*".to_owned())
-	{
-		debug!("Config key contains * which is allowing all URL previews. Allowing URL {}", url);
-		return true;
-	}
+		let host = match url.host_str() {
+			None | Some("") => {
+				debug!("Ignoring URL preview for a URL that does not have a host (?): {}", url);
+				return false;
+			},
+			Some(h) => h,
+		};
 
-	if !host.is_empty() {
-		if denylist_domain_explicit.contains(&host) {
+		if self.domain_explicit_denylist.iter().any(|d| d == host) {
 			debug!(
 				"Host {} is not allowed by url_preview_domain_explicit_denylist (check 1/4)",
-				&host
+				host
 			);
 			return false;
 		}
 
-		if allowlist_domain_explicit.contains(&host) {
-			debug!("Host {} is allowed by url_preview_domain_explicit_allowlist (check 2/4)", &host);
+		if self.check_root_domain {
+			if let Some(root_domain) = root_domains(host).find(|root_domain| {
+				self.domain_explicit_denylist
+					.iter()
+					.any(|d| d == root_domain)
+			}) {
+				debug!(
+					"Root domain {} is not allowed by url_preview_domain_explicit_denylist (check 1/3)",
+					root_domain
+				);
+				return false;
+			}
+		}
+
+		if self.domain_contains_allowlist.iter().any(|d| d == "*")
+			|| self.domain_explicit_allowlist.iter().any(|d| d == "*")
+			|| self.url_contains_allowlist.iter().any(|u| u == "*")
+		{
+			debug!("Config key contains * which is allowing all URL previews. Allowing URL {}", url);
+			return true;
+		}
+
+		if self.domain_explicit_allowlist.iter().any(|d| d == host) {
+			debug!("Host {} is allowed by url_preview_domain_explicit_allowlist (check 2/4)", host);
 			return true;
 		}
 
-		if allowlist_domain_contains
+		if self
+			.domain_contains_allowlist
 			.iter()
-			.any(|domain_s| domain_s.contains(&host.clone()))
+			.any(|domain_s| host.contains(domain_s.as_str()))
 		{
-			debug!("Host {} is allowed by url_preview_domain_contains_allowlist (check 3/4)", &host);
+			debug!("Host {} is allowed by url_preview_domain_contains_allowlist (check 3/4)", host);
 			return true;
 		}
 
-		if allowlist_url_contains
+		if self
+			.url_contains_allowlist
 			.iter()
-			.any(|url_s| url.to_string().contains(&url_s.to_string()))
+			.any(|url_s| url.as_str().contains(url_s.as_str()))
 		{
-			debug!("URL {} is allowed by url_preview_url_contains_allowlist (check 4/4)", &host);
+			debug!("URL {} is allowed by url_preview_url_contains_allowlist (check 4/4)", url);
 			return true;
 		}
 
-		// check root domain if available and if user has root domain checks
-		if services().globals.url_preview_check_root_domain() {
+		// check root domains if the user has root domain checks enabled
+		if self.check_root_domain {
 			debug!("Checking root domain");
-			match host.split_once('.') {
-				None => return false,
-				Some((_, root_domain)) => {
-					if denylist_domain_explicit.contains(&root_domain.to_owned()) {
-						debug!(
-							"Root domain {} is not allowed by url_preview_domain_explicit_denylist (check 1/3)",
-							&root_domain
-						);
-						return true;
-					}
-
-					if allowlist_domain_explicit.contains(&root_domain.to_owned()) {
-						debug!(
-							"Root domain {} is allowed by url_preview_domain_explicit_allowlist (check 2/3)",
-							&root_domain
-						);
-						return true;
-					}
-
-					if allowlist_domain_contains
-						.iter()
-						.any(|domain_s| domain_s.contains(&root_domain.to_owned()))
-					{
-						debug!(
-							"Root domain {} is allowed by url_preview_domain_contains_allowlist (check 3/3)",
-							&root_domain
-						);
-						return true;
-					}
-				},
+			for root_domain in root_domains(host) {
+				if self
+					.domain_explicit_allowlist
+					.iter()
+					.any(|d| d == root_domain)
+				{
+					debug!(
+						"Root domain {} is allowed by url_preview_domain_explicit_allowlist (check 2/3)",
+						root_domain
+					);
+					return true;
+				}
+
+				if self
+					.domain_contains_allowlist
+					.iter()
+					.any(|domain_s| root_domain.contains(domain_s.as_str()))
+				{
+					debug!(
+						"Root domain {} is allowed by url_preview_domain_contains_allowlist (check 3/3)",
+						root_domain
+					);
+					return true;
+				}
 			}
 		}
+
+		false
+	}
+}
+
+/// Iterates over the parent domains of `host`, nearest first, stopping before
+/// the bare top-level domain (e.g. `en.m.wikipedia.org` yields
+/// `m.wikipedia.org` then `wikipedia.org`).
+fn root_domains(host: &str) -> impl Iterator<Item = &str> {
+	host.match_indices('.')
+		.map(move |(i, _)| &host[i + 1..])
+		.filter(|domain| domain.contains('.'))
+}
+
+/// Appends as much of `chunk` to `buf` as fits within `limit` bytes. Returns
+/// true once the limit has been reached and no more should be read.
+fn extend_capped(buf: &mut Vec<u8>, chunk: &[u8], limit: usize) -> bool {
+	let remaining = limit.saturating_sub(buf.len());
+	buf.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+	buf.len() >= limit
+}
+
+/// Whether `user_id` is recorded as the uploader of the media.
+fn is_media_uploader(uploader: Option<&str>, user_id: &UserId) -> bool { uploader == Some(user_id.as_str()) }
+
+/// `DELETE /_matrix/client/v1/media/{serverName}/{mediaId}`
+///
+/// Delete media the authenticated user uploaded.
+pub(crate) mod delete_content {
+	pub(crate) mod v1 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata, OwnedServerName,
+		};
+
+		const METADATA: Metadata = metadata! {
+			method: DELETE,
+			rate_limited: true,
+			authentication: AccessToken,
+			history: {
+				1.0 => "/_matrix/client/v1/media/:server_name/:media_id",
+			}
+		};
+
+		/// Request type for the `delete_content` endpoint.
+		#[request]
+		pub(crate) struct Request {
+			/// The server name from the MXC URI.
+			#[ruma_api(path)]
+			pub(crate) server_name: OwnedServerName,
+
+			/// The media ID from the MXC URI.
+			#[ruma_api(path)]
+			pub(crate) media_id: String,
+		}
+
+		/// Response type for the `delete_content` endpoint.
+		#[response]
+		pub(crate) struct Response {}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use reqwest::Url;
+	use ruma::user_id;
+
+	use super::{extend_capped, is_media_uploader, UrlPreviewPolicy};
+
+	#[test]
+	fn users_can_only_delete_their_own_media() {
+		let alice = user_id!("@alice:example.com");
+		let bob = user_id!("@bob:example.com");
+
+		assert!(is_media_uploader(Some("@alice:example.com"), alice));
+		assert!(!is_media_uploader(Some("@alice:example.com"), bob));
+		assert!(!is_media_uploader(None, alice));
+	}
+
+	fn policy<'a>(
+		explicit_allowlist: &'a [String], contains_allowlist: &'a [String], denylist: &'a [String],
+		check_root_domain: bool,
+	) -> UrlPreviewPolicy<'a> {
+		UrlPreviewPolicy {
+			domain_contains_allowlist: contains_allowlist,
+			domain_explicit_allowlist: explicit_allowlist,
+			domain_explicit_denylist: denylist,
+			url_contains_allowlist: &[],
+			check_root_domain,
+		}
 	}
 
-	false
+	fn url(s: &str) -> Url { Url::parse(s).unwrap() }
+
+	#[test]
+	fn url_preview_denied_domain() {
+		let allow = ["*".to_owned(), "example.com".to_owned()];
+		let deny = ["example.com".to_owned()];
+		let policy = policy(&allow, &allow, &deny, false);
+
+		assert!(!policy.allows(&url("https://example.com/page")));
+		assert!(policy.allows(&url("https://example.org/page")));
+	}
+
+	#[test]
+	fn url_preview_denied_root_domain() {
+		let allow = ["en.wikipedia.org".to_owned()];
+		let deny = ["wikipedia.org".to_owned()];
+
+		assert!(policy(&allow, &[], &deny, false).allows(&url("https://en.wikipedia.org/")));
+		assert!(!policy(&allow, &[], &deny, true).allows(&url("https://en.wikipedia.org/")));
+	}
+
+	#[test]
+	fn url_preview_subdomain_allowed_by_root_domain() {
+		let allow = ["wikipedia.org".to_owned()];
+
+		assert!(!policy(&allow, &[], &[], false).allows(&url("https://en.m.wikipedia.org/wiki/Rust")));
+		assert!(policy(&allow, &[], &[], true).allows(&url("https://en.m.wikipedia.org/wiki/Rust")));
+		assert!(policy(&[], &allow, &[], true).allows(&url("https://en.m.wikipedia.org/wiki/Rust")));
+		assert!(!policy(&allow, &[], &[], true).allows(&url("https://wikipedia.org.evil.com/")));
+	}
+
+	#[test]
+	fn url_preview_non_http_denied() {
+		let allow = ["*".to_owned()];
+
+		assert!(!policy(&allow, &[], &[], false).allows(&url("file:///etc/passwd")));
+	}
+
+	#[test]
+	fn url_preview_oversize_body_truncated() {
+		const LIMIT: usize = 10;
+		let mut buf = Vec::new();
+
+		assert!(!extend_capped(&mut buf, b"12345", LIMIT));
+		assert!(extend_capped(&mut buf, b"6789abcdef", LIMIT));
+		assert_eq!(buf, b"123456789a");
+		assert!(extend_capped(&mut buf, b"more", LIMIT));
+		assert_eq!(buf.len(), LIMIT);
+	}
 }
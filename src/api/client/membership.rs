@@ -6,15 +6,15 @@ use std::{
 	time::{Duration, Instant},
 };
 
-use axum_client_ip::InsecureClientIp;
+use axum_client_ip::SecureClientIp;
 use conduit::utils::mutex_map;
 use ruma::{
 	api::{
 		client::{
-			error::ErrorKind,
+			error::{ErrorKind, RetryAfter},
 			membership::{
 				ban_user, forget_room, get_member_events, invite_user, join_room_by_id, join_room_by_id_or_alias,
-				joined_members, joined_rooms, kick_user, leave_room, unban_user, ThirdPartySigned,
+				joined_members, kick_user, leave_room, unban_user, ThirdPartySigned,
 			},
 		},
 		federation::{self, membership::create_invite},
@@ -29,7 +29,7 @@ use ruma::{
 		StateEventType, TimelineEventType,
 	},
 	serde::Base64,
-	state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId, OwnedServerName,
+	state_res, CanonicalJsonObject, CanonicalJsonValue, EventId, MxcUri, OwnedEventId, OwnedRoomId, OwnedServerName,
 	OwnedUserId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
@@ -167,7 +167,7 @@ async fn banned_room_check(
 ///   federation
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn join_room_by_id_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<join_room_by_id::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<join_room_by_id::v3::Request>,
 ) -> Result<join_room_by_id::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
@@ -220,7 +220,7 @@ pub(crate) async fn join_room_by_id_route(
 ///   via room alias server name and room ID server name
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn join_room_by_id_or_alias_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<join_room_by_id_or_alias::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<join_room_by_id_or_alias::v3::Request>,
 ) -> Result<join_room_by_id_or_alias::v3::Response> {
 	let sender_user = body.sender_user.as_deref().expect("user is authenticated");
 	let body = body.body;
@@ -323,7 +323,7 @@ pub(crate) async fn leave_room_route(body: Ruma<leave_room::v3::Request>) -> Res
 /// Tries to send an invite event into the room.
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn invite_user_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<invite_user::v3::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<invite_user::v3::Request>,
 ) -> Result<invite_user::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
@@ -340,10 +340,26 @@ pub(crate) async fn invite_user_route(
 
 	banned_room_check(sender_user, Some(&body.room_id), body.room_id.server_name(), client_ip).await?;
 
+	invite_rate_limit_check(sender_user, &body.room_id, body.appservice_info.is_some()).await?;
+
 	if let invite_user::v3::InvitationRecipient::UserId {
 		user_id,
 	} = &body.recipient
 	{
+		let membership = services()
+			.rooms
+			.state_accessor
+			.get_member(&body.room_id, user_id)?
+			.map(|member| member.membership);
+		let ignore_joined = services().globals.config.ignore_invites_to_joined_users;
+		if invite_membership_check(membership.as_ref(), ignore_joined)? {
+			info!(
+				"{sender_user} invited {user_id} to {} where they are already joined",
+				&body.room_id
+			);
+			return Ok(invite_user::v3::Response {});
+		}
+
 		invite_helper(sender_user, user_id, &body.room_id, body.reason.clone(), false).await?;
 		Ok(invite_user::v3::Response {})
 	} else {
@@ -351,6 +367,24 @@ pub(crate) async fn invite_user_route(
 	}
 }
 
+/// Checks the invitee's current membership before inviting them: banned users
+/// must be unbanned first, and joined users are refused unless `ignore_joined`
+/// is set. Returns `true` when the invite should silently do nothing.
+pub(crate) fn invite_membership_check(membership: Option<&MembershipState>, ignore_joined: bool) -> Result<bool> {
+	match membership {
+		Some(MembershipState::Ban) => Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"User is banned from this room and must be unbanned before being invited.",
+		)),
+		Some(MembershipState::Join) if ignore_joined => Ok(true),
+		Some(MembershipState::Join) => Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"User is already joined to this room.",
+		)),
+		_ => Ok(false),
+	}
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/kick`
 ///
 /// Tries to send a kick event into the room.
@@ -546,22 +580,47 @@ pub(crate) async fn forget_room_route(body: Ruma<forget_room::v3::Request>) -> R
 	Ok(forget_room::v3::Response::new())
 }
 
-/// # `POST /_matrix/client/r0/joined_rooms`
+/// # `GET /_matrix/client/r0/joined_rooms`
 ///
 /// Lists all rooms the user has joined.
+///
+/// Accounts in thousands of rooms can page through them instead by passing
+/// `im.conduwuit.limit`, then `im.conduwuit.from` set to the returned
+/// `im.conduwuit.next_batch` until it is absent.
 pub(crate) async fn joined_rooms_route(body: Ruma<joined_rooms::v3::Request>) -> Result<joined_rooms::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 
+	let rooms = services()
+		.rooms
+		.state_cache
+		.rooms_joined(sender_user)
+		.filter_map(Result::ok);
+	let (joined_rooms, next_batch) = joined_rooms_page(rooms, body.from.as_deref(), body.limit);
+
 	Ok(joined_rooms::v3::Response {
-		joined_rooms: services()
-			.rooms
-			.state_cache
-			.rooms_joined(sender_user)
-			.filter_map(Result::ok)
-			.collect(),
+		joined_rooms,
+		next_batch,
 	})
 }
 
+/// Takes the page of `limit` rooms following the `from` cursor, along with the
+/// cursor for the page after it. `rooms` must be in ascending order, as the
+/// joined rooms index yields them, so the cursor stays valid while rooms are
+/// joined and left between pages.
+fn joined_rooms_page(
+	rooms: impl Iterator<Item = OwnedRoomId>, from: Option<&RoomId>, limit: Option<usize>,
+) -> (Vec<OwnedRoomId>, Option<OwnedRoomId>) {
+	let mut rooms = rooms.skip_while(|room_id| from.is_some_and(|from| room_id.as_str() <= from.as_str()));
+	let Some(limit) = limit else {
+		return (rooms.collect(), None);
+	};
+
+	let page: Vec<_> = rooms.by_ref().take(limit.max(1)).collect();
+	let next_batch = rooms.next().and(page.last().cloned());
+
+	(page, next_batch)
+}
+
 /// # `POST /_matrix/client/r0/rooms/{roomId}/members`
 ///
 /// Lists all joined users in a room (TODO: at a specific point in time, with a
@@ -650,12 +709,42 @@ pub async fn join_room_by_id_helper(
 	let sender_user = sender_user.expect("user is authenticated");
 
 	if matches!(services().rooms.state_cache.is_joined(sender_user, room_id), Ok(true)) {
-		info!("{sender_user} is already joined in {room_id}");
-		return Ok(join_room_by_id::v3::Response {
-			room_id: room_id.into(),
-		});
+		let displayname = services().users.displayname(sender_user)?;
+		let avatar_url = services().users.avatar_url(sender_user)?;
+		let redundant = services()
+			.rooms
+			.state_accessor
+			.get_member(room_id, sender_user)?
+			.map_or(true, |member| {
+				join_is_redundant(&member, displayname.as_deref(), avatar_url.as_deref())
+			});
+
+		if redundant {
+			info!("{sender_user} is already joined in {room_id}");
+			return Ok(join_room_by_id::v3::Response {
+				room_id: room_id.into(),
+			});
+		}
+
+		// Re-joining refreshes the profile in the member event; we're in the room
+		// so this is always a local join
+		info!("{sender_user} is re-joining {room_id} to update their profile");
+		let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
+		return join_room_by_id_helper_local(sender_user, room_id, reason, servers, third_party_signed, state_lock)
+			.await;
+	}
+
+	// Rooms we aren't in have no local guest access state, so guests are refused
+	// federated joins
+	if services().users.is_guest(sender_user)? && !services().rooms.state_accessor.guest_can_join(room_id)? {
+		return Err(Error::BadRequest(
+			ErrorKind::GuestAccessForbidden,
+			"Guests are not allowed to join this room.",
+		));
 	}
 
+	joined_rooms_limit_check(sender_user)?;
+
 	let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
 
 	// Ask a remote server if we are not participating in this room
@@ -670,6 +759,15 @@ pub async fn join_room_by_id_helper(
 	}
 }
 
+/// Whether joining would only repeat the user's current membership: they're
+/// already joined with the same profile, so another member event would just be
+/// noise in the timeline.
+fn join_is_redundant(current: &RoomMemberEventContent, displayname: Option<&str>, avatar_url: Option<&MxcUri>) -> bool {
+	current.membership == MembershipState::Join
+		&& current.displayname.as_deref() == displayname
+		&& current.avatar_url.as_deref() == avatar_url
+}
+
 async fn join_room_by_id_helper_remote(
 	sender_user: &UserId, room_id: &RoomId, reason: Option<String>, servers: &[OwnedServerName],
 	_third_party_signed: Option<&ThirdPartySigned>, state_lock: mutex_map::Guard<()>,
@@ -680,17 +778,8 @@ async fn join_room_by_id_helper_remote(
 
 	info!("make_join finished");
 
-	let room_version_id = match make_join_response.room_version {
-		Some(room_version)
-			if services()
-				.globals
-				.supported_room_versions()
-				.contains(&room_version) =>
-		{
-			room_version
-		},
-		_ => return Err(Error::BadServerResponse("Room version is not supported")),
-	};
+	let room_version_id =
+		remote_room_version(make_join_response.room_version, &services().globals.supported_room_versions())?;
 
 	let mut join_event_stub: CanonicalJsonObject = serde_json::from_str(make_join_response.event.get())
 		.map_err(|_| Error::BadServerResponse("Invalid make_join event json received from server."))?;
@@ -956,15 +1045,10 @@ async fn join_room_by_id_helper_remote(
 	let (statehash_before_join, new, removed) = services().rooms.state_compressor.save_state(
 		room_id,
 		Arc::new(
-			state
-				.into_iter()
-				.map(|(k, id)| {
-					services()
-						.rooms
-						.state_compressor
-						.compress_state_event(k, &id)
-				})
-				.collect::<Result<_>>()?,
+			services()
+				.rooms
+				.state_compressor
+				.compress_state_events(state.iter().map(|(k, id)| (*k, &**id)))?,
 		),
 	)?;
 
@@ -1113,17 +1197,8 @@ async fn join_room_by_id_helper_local(
 		info!("We couldn't do the join locally, maybe federation can help to satisfy the restricted join requirements");
 		let (make_join_response, remote_server) = make_join_request(sender_user, room_id, servers).await?;
 
-		let room_version_id = match make_join_response.room_version {
-			Some(room_version_id)
-				if services()
-					.globals
-					.supported_room_versions()
-					.contains(&room_version_id) =>
-			{
-				room_version_id
-			},
-			_ => return Err(Error::BadServerResponse("Room version is not supported")),
-		};
+		let room_version_id =
+			remote_room_version(make_join_response.room_version, &services().globals.supported_room_versions())?;
 		let mut join_event_stub: CanonicalJsonObject = serde_json::from_str(make_join_response.event.get())
 			.map_err(|_| Error::BadServerResponse("Invalid make_join event json received from server."))?;
 		let join_authorized_via_users_server = join_event_stub
@@ -1377,6 +1452,70 @@ pub async fn validate_and_add_event_id(
 	Ok((event_id, value))
 }
 
+/// Applies the per-user and per-room invite rate limits to an invite from
+/// `sender_user`. Admins and appservices are exempt.
+pub(crate) async fn invite_rate_limit_check(sender_user: &UserId, room_id: &RoomId, appservice: bool) -> Result<()> {
+	if appservice || services().users.is_admin(sender_user)? {
+		return Ok(());
+	}
+
+	if let Err(wait) = services()
+		.globals
+		.invite_rate_limit(sender_user, room_id)
+		.await
+	{
+		info!("Rate limiting invite from {sender_user} to room {room_id}");
+		return Err(Error::BadRequest(
+			ErrorKind::LimitExceeded {
+				retry_after: Some(RetryAfter::Delay(wait)),
+			},
+			"Too many invites sent recently, try again later.",
+		));
+	}
+
+	Ok(())
+}
+
+/// Checks the room version a remote server gave in a make_join or make_leave
+/// response. Any supported version is accepted, whether or not local users may
+/// create rooms of it.
+fn remote_room_version(room_version: Option<RoomVersionId>, supported: &[RoomVersionId]) -> Result<RoomVersionId> {
+	match room_version {
+		Some(room_version) if supported.contains(&room_version) => Ok(room_version),
+		_ => Err(Error::BadServerResponse("Room version is not supported")),
+	}
+}
+
+/// Rejects a join or room creation by `user_id` once they are joined to
+/// `max_joined_rooms_per_user` rooms. Admins are exempt.
+pub(crate) fn joined_rooms_limit_check(user_id: &UserId) -> Result<()> {
+	let limit = services().globals.config.max_joined_rooms_per_user;
+	if limit == 0 || services().users.is_admin(user_id)? {
+		return Ok(());
+	}
+
+	joined_rooms_below_limit(user_id, services().rooms.state_cache.rooms_joined(user_id), limit)
+}
+
+/// Errors if `rooms_joined` holds at least `limit` rooms, reading no more of
+/// it than that.
+fn joined_rooms_below_limit(
+	user_id: &UserId, rooms_joined: impl Iterator<Item = Result<OwnedRoomId>>, limit: usize,
+) -> Result<()> {
+	let joined = rooms_joined.take(limit).count();
+	if joined >= limit {
+		info!("{user_id} is joined to {joined} rooms and may not join any more");
+		return Err(Error::BadRequest(
+			ErrorKind::LimitExceeded {
+				retry_after: None,
+			},
+			"You are joined to too many rooms, leave a room before joining another.",
+		));
+	}
+
+	Ok(())
+}
+
 pub(crate) async fn invite_helper(
 	sender_user: &UserId, user_id: &UserId, room_id: &RoomId, reason: Option<String>, is_direct: bool,
 ) -> Result<()> {
@@ -1388,6 +1527,18 @@ pub(crate) async fn invite_helper(
 		));
 	}
 
+	if services()
+		.rooms
+		.state_cache
+		.invite_refused_by_recipient(sender_user, user_id)?
+	{
+		info!("Refusing invite from {sender_user} to {user_id}, who only accepts invites from shared rooms");
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"This user only accepts invites from users they share a room with.",
+		));
+	}
+
 	if !user_is_local(user_id) {
 		let (pdu, pdu_json, invite_room_state) = {
 			let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
@@ -1702,17 +1853,8 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 
 	let (make_leave_response, remote_server) = make_leave_response_and_server?;
 
-	let room_version_id = match make_leave_response.room_version {
-		Some(version)
-			if services()
-				.globals
-				.supported_room_versions()
-				.contains(&version) =>
-		{
-			version
-		},
-		_ => return Err(Error::BadServerResponse("Room version is not supported")),
-	};
+	let room_version_id =
+		remote_room_version(make_leave_response.room_version, &services().globals.supported_room_versions())?;
 
 	let mut leave_event_stub = serde_json::from_str::<CanonicalJsonObject>(make_leave_response.event.get())
 		.map_err(|_| Error::BadServerResponse("Invalid make_leave event json received from server."))?;
@@ -1777,3 +1919,151 @@ async fn remote_leave_room(user_id: &UserId, room_id: &RoomId) -> Result<()> {
 
 	Ok(())
 }
+
+/// `joined_rooms` with optional cursor-based paging as a conduwuit extension;
+/// without the extra parameters it behaves exactly like the spec endpoint.
+pub(crate) mod joined_rooms {
+	pub(crate) mod v3 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata, OwnedRoomId,
+		};
+
+		const METADATA: Metadata = metadata! {
+			method: GET,
+			rate_limited: false,
+			authentication: AccessToken,
+			history: {
+				1.0 => "/_matrix/client/r0/joined_rooms",
+				1.1 => "/_matrix/client/v3/joined_rooms",
+			}
+		};
+
+		#[request]
+		pub(crate) struct Request {
+			/// Return the rooms after this room ID, from a previous `next_batch`.
+			#[ruma_api(query)]
+			#[serde(rename = "im.conduwuit.from", skip_serializing_if = "Option::is_none")]
+			pub(crate) from: Option<OwnedRoomId>,
+
+			/// Return at most this many rooms; everything when absent.
+			#[ruma_api(query)]
+			#[serde(rename = "im.conduwuit.limit", skip_serializing_if = "Option::is_none")]
+			pub(crate) limit: Option<usize>,
+		}
+
+		#[response]
+		pub(crate) struct Response {
+			pub(crate) joined_rooms: Vec<OwnedRoomId>,
+
+			#[serde(rename = "im.conduwuit.next_batch", skip_serializing_if = "Option::is_none")]
+			pub(crate) next_batch: Option<OwnedRoomId>,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		api::client::error::ErrorKind,
+		events::room::member::{MembershipState, RoomMemberEventContent},
+		mxc_uri, user_id, OwnedRoomId, RoomId, RoomVersionId,
+	};
+
+	use super::{
+		invite_membership_check, join_is_redundant, joined_rooms_below_limit, joined_rooms_page, remote_room_version,
+	};
+	use crate::{service::globals::room_version_allowed_for_creation, Error};
+
+	#[test]
+	fn joined_rooms_limit() {
+		let user_id = user_id!("@alice:example.com");
+		let mut joined: Vec<OwnedRoomId> = (0..3)
+			.map(|i| RoomId::parse(format!("!room{i}:example.com")).unwrap())
+			.collect();
+		let check = |joined: &[OwnedRoomId]| joined_rooms_below_limit(user_id, joined.iter().cloned().map(Ok), 3);
+
+		assert!(
+			matches!(check(&joined), Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))),
+			"user at the cap may not join or create another room"
+		);
+
+		joined.pop();
+		assert!(check(&joined).is_ok(), "user may join again after leaving a room");
+
+		// only as many rooms as the limit are read
+		let mut read = 0;
+		let rooms = joined
+			.iter()
+			.cycle()
+			.cloned()
+			.map(Ok)
+			.inspect(|_| read += 1);
+		assert!(joined_rooms_below_limit(user_id, rooms, 3).is_err());
+		assert_eq!(read, 3);
+	}
+
+	#[test]
+	fn joined_rooms_pages_cover_every_room_once() {
+		let rooms: Vec<OwnedRoomId> = (0..7)
+			.map(|i| RoomId::parse(format!("!room{i}:example.com")).unwrap())
+			.collect();
+
+		let (all, next) = joined_rooms_page(rooms.iter().cloned(), None, None);
+		assert_eq!((all, next), (rooms.clone(), None));
+
+		let mut paged = Vec::new();
+		let mut from = None;
+		loop {
+			let (page, next) = joined_rooms_page(rooms.iter().cloned(), from.as_deref(), Some(3));
+			assert!(page.len() <= 3);
+			paged.extend(page);
+			match next {
+				Some(next) => from = Some(next),
+				None => break,
+			}
+		}
+		assert_eq!(paged, rooms);
+	}
+
+	#[test]
+	fn rejoin_is_redundant_only_with_unchanged_profile() {
+		let avatar = mxc_uri!("mxc://example.com/avatar");
+		let mut member = RoomMemberEventContent::new(MembershipState::Join);
+		member.displayname = Some("Alice".to_owned());
+		member.avatar_url = Some(avatar.to_owned());
+
+		assert!(join_is_redundant(&member, Some("Alice"), Some(avatar)));
+		assert!(!join_is_redundant(&member, Some("Alice B"), Some(avatar)));
+		assert!(!join_is_redundant(&member, Some("Alice"), None));
+
+		member.membership = MembershipState::Invite;
+		assert!(!join_is_redundant(&member, Some("Alice"), Some(avatar)));
+	}
+
+	#[test]
+	fn inviting_joined_and_banned_users() {
+		assert!(invite_membership_check(Some(&MembershipState::Join), false).is_err());
+		assert!(invite_membership_check(Some(&MembershipState::Join), true).unwrap());
+		assert!(invite_membership_check(Some(&MembershipState::Ban), true).is_err());
+		assert!(!invite_membership_check(Some(&MembershipState::Leave), false).unwrap());
+		assert!(!invite_membership_check(None, false).unwrap());
+	}
+
+	#[test]
+	fn creation_allowlist_does_not_affect_joins() {
+		let supported = [RoomVersionId::V6, RoomVersionId::V10, RoomVersionId::V11];
+		let allowed = [RoomVersionId::V10, RoomVersionId::V11];
+
+		// creating a supported but disallowed version is rejected...
+		assert!(!room_version_allowed_for_creation(&supported, &allowed, &RoomVersionId::V6));
+
+		// ...while joining or leaving a remote room of that version works
+		assert_eq!(
+			remote_room_version(Some(RoomVersionId::V6), &supported).unwrap(),
+			RoomVersionId::V6
+		);
+		assert!(remote_room_version(Some(RoomVersionId::V1), &supported).is_err());
+		assert!(remote_room_version(None, &supported).is_err());
+	}
+}
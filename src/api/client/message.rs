@@ -12,7 +12,7 @@ use ruma::{
 };
 use serde_json::{from_str, Value};
 
-use crate::{service::pdu::PduBuilder, services, utils, Error, PduEvent, Result, Ruma};
+use crate::{service::pdu::PduBuilder, services, Error, PduEvent, Result, Ruma};
 
 /// # `PUT /_matrix/client/v3/rooms/{roomId}/send/{eventType}/{txnId}`
 ///
@@ -50,23 +50,10 @@ pub(crate) async fn send_message_event_route(
 	}
 
 	// Check if this is a new transaction id
-	if let Some(response) = services()
+	if let Some(event_id) = services()
 		.transaction_ids
-		.existing_txnid(sender_user, sender_device, &body.txn_id)?
+		.existing_event_id(sender_user, sender_device, &body.txn_id)?
 	{
-		// The client might have sent a txnid of the /sendToDevice endpoint
-		// This txnid has no response associated with it
-		if response.is_empty() {
-			return Err(Error::BadRequest(
-				ErrorKind::InvalidParam,
-				"Tried to use txn id already used for an incompatible endpoint.",
-			));
-		}
-
-		let event_id = utils::string_from_bytes(&response)
-			.map_err(|_| Error::bad_database("Invalid txnid bytes in database."))?
-			.try_into()
-			.map_err(|_| Error::bad_database("Invalid event id in txnid data."))?;
 		return Ok(send_message_event::v3::Response {
 			event_id,
 		});
@@ -23,6 +23,7 @@ pub(super) mod search;
 pub(super) mod session;
 pub(super) mod space;
 pub(super) mod state;
+pub(super) mod synapse_admin;
 pub(super) mod sync;
 pub(super) mod tag;
 pub(super) mod thirdparty;
@@ -51,7 +52,7 @@ pub use membership::{join_room_by_id_helper, leave_all_rooms, leave_room, valida
 pub(super) use message::*;
 pub(super) use presence::*;
 pub(super) use profile::*;
-pub use profile::{update_all_rooms, update_avatar_url, update_displayname};
+pub use profile::{clear_profile, update_all_rooms, update_avatar_url, update_displayname};
 pub(super) use push::*;
 pub(super) use read_marker::*;
 pub(super) use redact::*;
@@ -62,6 +63,7 @@ pub(super) use search::*;
 pub(super) use session::*;
 pub(super) use space::*;
 pub(super) use state::*;
+pub(super) use synapse_admin::*;
 pub(super) use sync::*;
 pub(super) use tag::*;
 pub(super) use thirdparty::*;
@@ -1,3 +1,5 @@
+use std::{sync::Arc, time::Duration};
+
 use ruma::{
 	api::{
 		client::{
@@ -6,11 +8,15 @@ use ruma::{
 		},
 		federation,
 	},
-	events::{room::member::RoomMemberEventContent, StateEventType, TimelineEventType},
+	events::{
+		room::member::{MembershipState, RoomMemberEventContent},
+		StateEventType, TimelineEventType,
+	},
 	presence::PresenceState,
-	OwnedMxcUri, OwnedRoomId, OwnedUserId,
+	OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomId, UserId,
 };
-use serde_json::value::to_raw_value;
+use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
+use tokio::time::sleep;
 use tracing::warn;
 
 use crate::{
@@ -18,6 +24,15 @@ use crate::{
 	services, Error, Result, Ruma,
 };
 
+/// Rooms updated immediately when a user's profile changes, before pacing
+const PROFILE_UPDATE_BURST: usize = 20;
+
+/// Delay between membership updates once past `PROFILE_UPDATE_BURST` rooms
+const PROFILE_UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Rebuilds a user's current membership content with their changed profile
+pub type ProfileUpdate = Arc<dyn Fn(&RawJsonValue) -> Result<RoomMemberEventContent> + Send + Sync>;
+
 /// # `PUT /_matrix/client/r0/profile/{userId}/displayname`
 ///
 /// Updates the displayname.
@@ -27,12 +42,7 @@ pub(crate) async fn set_displayname_route(
 	body: Ruma<set_display_name::v3::Request>,
 ) -> Result<set_display_name::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-	let all_joined_rooms: Vec<OwnedRoomId> = services()
-		.rooms
-		.state_cache
-		.rooms_joined(sender_user)
-		.filter_map(Result::ok)
-		.collect();
+	let all_joined_rooms = profile_update_rooms(sender_user);
 
 	update_displayname(sender_user.clone(), body.displayname.clone(), all_joined_rooms).await?;
 
@@ -111,12 +121,7 @@ pub(crate) async fn set_avatar_url_route(
 	body: Ruma<set_avatar_url::v3::Request>,
 ) -> Result<set_avatar_url::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
-	let all_joined_rooms: Vec<OwnedRoomId> = services()
-		.rooms
-		.state_cache
-		.rooms_joined(sender_user)
-		.filter_map(Result::ok)
-		.collect();
+	let all_joined_rooms = profile_update_rooms(sender_user);
 
 	update_avatar_url(
 		sender_user.clone(),
@@ -261,40 +266,8 @@ pub async fn update_displayname(
 		.await?;
 
 	// Send a new join membership event into all joined rooms
-	let all_joined_rooms: Vec<_> = all_joined_rooms
-		.iter()
-		.map(|room_id| {
-			Ok::<_, Error>((
-				PduBuilder {
-					event_type: TimelineEventType::RoomMember,
-					content: to_raw_value(&RoomMemberEventContent {
-						displayname: displayname.clone(),
-						join_authorized_via_users_server: None,
-						..serde_json::from_str(
-							services()
-								.rooms
-								.state_accessor
-								.room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?
-								.ok_or_else(|| {
-									Error::bad_database("Tried to send display name update for user not in the room.")
-								})?
-								.content
-								.get(),
-						)
-						.map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-					})
-					.expect("event is valid, we just created it"),
-					unsigned: None,
-					state_key: Some(user_id.to_string()),
-					redacts: None,
-				},
-				room_id,
-			))
-		})
-		.filter_map(Result::ok)
-		.collect();
-
-	update_all_rooms(all_joined_rooms, user_id).await;
+	let update = Arc::new(move |current: &RawJsonValue| with_displayname(current, displayname.clone()));
+	update_all_rooms(all_joined_rooms, user_id, update).await;
 
 	Ok(())
 }
@@ -312,55 +285,219 @@ pub async fn update_avatar_url(
 		.await?;
 
 	// Send a new join membership event into all joined rooms
-	let all_joined_rooms: Vec<_> = all_joined_rooms
-		.iter()
-		.map(|room_id| {
-			Ok::<_, Error>((
-				PduBuilder {
-					event_type: TimelineEventType::RoomMember,
-					content: to_raw_value(&RoomMemberEventContent {
-						avatar_url: avatar_url.clone(),
-						blurhash: blurhash.clone(),
-						join_authorized_via_users_server: None,
-						..serde_json::from_str(
-							services()
-								.rooms
-								.state_accessor
-								.room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?
-								.ok_or_else(|| {
-									Error::bad_database("Tried to send avatar URL update for user not in the room.")
-								})?
-								.content
-								.get(),
-						)
-						.map_err(|_| Error::bad_database("Database contains invalid PDU."))?
-					})
-					.expect("event is valid, we just created it"),
-					unsigned: None,
-					state_key: Some(user_id.to_string()),
-					redacts: None,
-				},
-				room_id,
-			))
-		})
-		.filter_map(Result::ok)
-		.collect();
+	let update = Arc::new(move |current: &RawJsonValue| with_avatar_url(current, avatar_url.clone(), blurhash.clone()));
+	update_all_rooms(all_joined_rooms, user_id, update).await;
 
-	update_all_rooms(all_joined_rooms, user_id).await;
+	Ok(())
+}
+
+/// Clears the user's displayname, avatar and blurhash, sending a single
+/// membership update into each room for all of them.
+pub async fn clear_profile(user_id: OwnedUserId, all_joined_rooms: Vec<OwnedRoomId>) -> Result<()> {
+	services().users.set_displayname(&user_id, None).await?;
+	services().users.set_avatar_url(&user_id, None).await?;
+	services().users.set_blurhash(&user_id, None).await?;
+
+	update_all_rooms(all_joined_rooms, user_id, Arc::new(without_profile)).await;
 
 	Ok(())
 }
 
-pub async fn update_all_rooms(all_joined_rooms: Vec<(PduBuilder, &OwnedRoomId)>, user_id: OwnedUserId) {
-	for (pdu_builder, room_id) in all_joined_rooms {
-		let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
-		if let Err(e) = services()
-			.rooms
-			.timeline
-			.build_and_append_pdu(pdu_builder, &user_id, room_id, &state_lock)
-			.await
-		{
-			warn!(%user_id, %room_id, %e, "Failed to update/send new profile join membership update in room");
+/// Sends the updated membership event into the user's rooms. The first
+/// `PROFILE_UPDATE_BURST` rooms are updated before returning and the rest are
+/// paced out by a background task, so the request isn't held up.
+pub async fn update_all_rooms(all_joined_rooms: Vec<OwnedRoomId>, user_id: OwnedUserId, update: ProfileUpdate) {
+	let (burst, paced) = split_profile_updates(all_joined_rooms);
+	for room_id in &burst {
+		update_room(room_id, &user_id, &update).await;
+	}
+
+	if paced.is_empty() {
+		return;
+	}
+
+	services().server.runtime().spawn(async move {
+		for room_id in &paced {
+			sleep(PROFILE_UPDATE_INTERVAL).await;
+			update_room(room_id, &user_id, &update).await;
 		}
+	});
+}
+
+/// Sends the user's membership content with `update` applied into the room,
+/// building it under the state lock so concurrent profile updates don't
+/// revert each other.
+async fn update_room(room_id: &RoomId, user_id: &UserId, update: &ProfileUpdate) {
+	let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
+	let pdu_builder = match profile_update_pdu(room_id, user_id, update) {
+		Ok(Some(pdu_builder)) => pdu_builder,
+		Ok(None) => return,
+		Err(e) => {
+			warn!(%user_id, %room_id, %e, "Failed to build profile join membership update in room");
+			return;
+		},
+	};
+
+	if let Err(e) = services()
+		.rooms
+		.timeline
+		.build_and_append_pdu(pdu_builder, user_id, room_id, &state_lock)
+		.await
+	{
+		warn!(%user_id, %room_id, %e, "Failed to update/send new profile join membership update in room");
+	}
+}
+
+/// The membership event for a profile change, or none if the user is no
+/// longer joined to the room.
+fn profile_update_pdu(room_id: &RoomId, user_id: &UserId, update: &ProfileUpdate) -> Result<Option<PduBuilder>> {
+	let current =
+		services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?;
+	let Some(current) = current else {
+		return Ok(None);
+	};
+
+	let content = update(&current.content)?;
+	if content.membership != MembershipState::Join {
+		return Ok(None);
+	}
+
+	Ok(Some(PduBuilder {
+		event_type: TimelineEventType::RoomMember,
+		content: to_raw_value(&content).expect("event is valid, we just created it"),
+		unsigned: None,
+		state_key: Some(user_id.to_string()),
+		redacts: None,
+	}))
+}
+
+/// Rooms that get a new membership event for a profile change, or none when
+/// `update_membership_on_profile_change` is disabled. Rooms larger than
+/// `profile_update_membership_max_room_size` are skipped.
+fn profile_update_rooms(user_id: &UserId) -> Vec<OwnedRoomId> {
+	if !services().globals.update_membership_on_profile_change() {
+		return Vec::new();
+	}
+
+	let max_room_size = services().globals.profile_update_membership_max_room_size();
+	services()
+		.rooms
+		.state_cache
+		.rooms_joined(user_id)
+		.filter_map(Result::ok)
+		.filter(|room_id| {
+			let joined = services()
+				.rooms
+				.state_cache
+				.room_joined_count(room_id)
+				.ok()
+				.flatten()
+				.unwrap_or(0);
+
+			room_gets_profile_update(joined, max_room_size)
+		})
+		.collect()
+}
+
+/// Whether a room with `joined` members is small enough for profile updates.
+fn room_gets_profile_update(joined: u64, max_room_size: Option<u64>) -> bool {
+	max_room_size.map_or(true, |max| joined <= max)
+}
+
+/// Splits off the rooms beyond the first `PROFILE_UPDATE_BURST`, whose updates
+/// are paced out so users in many rooms don't flood them and federation at
+/// once.
+fn split_profile_updates<T>(mut rooms: Vec<T>) -> (Vec<T>, Vec<T>) {
+	let paced = rooms.split_off(PROFILE_UPDATE_BURST.min(rooms.len()));
+	(rooms, paced)
+}
+
+/// Rebuilds the user's current membership content with a new displayname.
+fn with_displayname(current: &RawJsonValue, displayname: Option<String>) -> Result<RoomMemberEventContent> {
+	Ok(RoomMemberEventContent {
+		displayname,
+		join_authorized_via_users_server: None,
+		..serde_json::from_str(current.get()).map_err(|_| Error::bad_database("Database contains invalid PDU."))?
+	})
+}
+
+/// Rebuilds the user's current membership content with a new avatar.
+fn with_avatar_url(
+	current: &RawJsonValue, avatar_url: Option<OwnedMxcUri>, blurhash: Option<String>,
+) -> Result<RoomMemberEventContent> {
+	Ok(RoomMemberEventContent {
+		avatar_url,
+		blurhash,
+		join_authorized_via_users_server: None,
+		..serde_json::from_str(current.get()).map_err(|_| Error::bad_database("Database contains invalid PDU."))?
+	})
+}
+
+/// Rebuilds the user's current membership content without any profile.
+fn without_profile(current: &RawJsonValue) -> Result<RoomMemberEventContent> {
+	Ok(RoomMemberEventContent {
+		displayname: None,
+		..with_avatar_url(current, None, None)?
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::events::room::member::MembershipState;
+	use serde_json::value::RawValue as RawJsonValue;
+
+	use super::{
+		room_gets_profile_update, split_profile_updates, with_displayname, without_profile, PROFILE_UPDATE_BURST,
+	};
+
+	#[test]
+	fn deactivation_clears_profile_from_membership() {
+		let current = RawJsonValue::from_string(
+			r#"{"membership":"join","displayname":"Alice","avatar_url":"mxc://example.com/alice","xyz.amorgan.blurhash":"LKO2?U%2Tw=w"}"#
+				.to_owned(),
+		)
+		.unwrap();
+
+		let content = without_profile(&current).unwrap();
+		assert_eq!(content.membership, MembershipState::Join);
+		assert!(content.displayname.is_none());
+		assert!(content.avatar_url.is_none());
+		assert!(content.blurhash.is_none());
+	}
+
+	#[test]
+	fn displayname_change_updates_membership() {
+		let current = RawJsonValue::from_string(
+			r#"{"membership":"join","displayname":"old","join_authorised_via_users_server":"@admin:example.com"}"#
+				.to_owned(),
+		)
+		.unwrap();
+
+		let content = with_displayname(&current, Some("new".to_owned())).unwrap();
+		assert_eq!(content.membership, MembershipState::Join);
+		assert_eq!(content.displayname.as_deref(), Some("new"));
+		assert!(content.join_authorized_via_users_server.is_none());
+	}
+
+	#[test]
+	fn rooms_above_max_size_are_skipped() {
+		assert!(room_gets_profile_update(10, Some(1000)));
+		assert!(room_gets_profile_update(1000, Some(1000)));
+		assert!(!room_gets_profile_update(5000, Some(1000)));
+		assert!(room_gets_profile_update(5000, None));
+	}
+
+	#[test]
+	fn updates_beyond_burst_are_paced() {
+		let (burst, paced) = split_profile_updates((0..PROFILE_UPDATE_BURST + 5).collect());
+		assert_eq!(burst, (0..PROFILE_UPDATE_BURST).collect::<Vec<_>>());
+		assert_eq!(paced, (PROFILE_UPDATE_BURST..PROFILE_UPDATE_BURST + 5).collect::<Vec<_>>());
+
+		let (burst, paced) = split_profile_updates(vec![1, 2]);
+		assert_eq!(burst, vec![1, 2]);
+		assert!(paced.is_empty());
 	}
 }
@@ -367,7 +367,8 @@ pub(crate) async fn set_pushers_route(body: Ruma<set_pusher::v3::Request>) -> Re
 
 	services()
 		.pusher
-		.set_pusher(sender_user, body.action.clone())?;
+		.set_pusher(sender_user, body.sender_device.as_deref(), body.action.clone())
+		.await?;
 
 	Ok(set_pusher::v3::Response::default())
 }
@@ -1,18 +1,23 @@
+use std::collections::BTreeMap;
+
 use ruma::{
-	api::client::redact::redact_event,
+	api::client::{error::ErrorKind, redact::redact_event},
 	events::{room::redaction::RoomRedactionEventContent, TimelineEventType},
 };
 use serde_json::value::to_raw_value;
 
-use crate::{service::pdu::PduBuilder, services, Result, Ruma};
+use crate::{service::pdu::PduBuilder, services, Error, Result, Ruma};
 
 /// # `PUT /_matrix/client/r0/rooms/{roomId}/redact/{eventId}/{txnId}`
 ///
 /// Tries to send a redaction event into the room.
 ///
-/// - TODO: Handle txn id
+/// - Is a NOOP if the txn id was already used before and returns the same event
+///   id again
+/// - Redacting events of other users requires the `redact` power level
 pub(crate) async fn redact_event_route(body: Ruma<redact_event::v3::Request>) -> Result<redact_event::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
+	let sender_device = body.sender_device.as_deref();
 	let body = body.body;
 
 	let state_lock = services()
@@ -21,6 +26,30 @@ pub(crate) async fn redact_event_route(body: Ruma<redact_event::v3::Request>) ->
 		.lock(&body.room_id)
 		.await;
 
+	// Check if this is a new transaction id
+	if let Some(event_id) = services()
+		.transaction_ids
+		.existing_event_id(sender_user, sender_device, &body.txn_id)?
+	{
+		return Ok(redact_event::v3::Response {
+			event_id,
+		});
+	}
+
+	if !services()
+		.rooms
+		.state_accessor
+		.user_can_redact(&body.event_id, sender_user, &body.room_id)?
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"You don't have permission to redact this event.",
+		));
+	}
+
+	let mut unsigned = BTreeMap::new();
+	unsigned.insert("transaction_id".to_owned(), body.txn_id.to_string().into());
+
 	let event_id = services()
 		.rooms
 		.timeline
@@ -32,7 +61,7 @@ pub(crate) async fn redact_event_route(body: Ruma<redact_event::v3::Request>) ->
 					reason: body.reason.clone(),
 				})
 				.expect("event is valid, we just created it"),
-				unsigned: None,
+				unsigned: Some(unsigned),
 				state_key: None,
 				redacts: Some(body.event_id.into()),
 			},
@@ -42,6 +71,10 @@ pub(crate) async fn redact_event_route(body: Ruma<redact_event::v3::Request>) ->
 		)
 		.await?;
 
+	services()
+		.transaction_ids
+		.add_txnid(sender_user, sender_device, &body.txn_id, event_id.as_bytes())?;
+
 	drop(state_lock);
 
 	let event_id = (*event_id).to_owned();
@@ -1,6 +1,9 @@
 use std::{cmp::max, collections::BTreeMap};
 
-use conduit::{debug_info, debug_warn};
+use conduit::{
+	debug_info, debug_warn,
+	utils::{mutex_map, MutexMap},
+};
 use ruma::{
 	api::client::{
 		error::ErrorKind,
@@ -28,7 +31,10 @@ use ruma::{
 use serde_json::{json, value::to_raw_value};
 use tracing::{error, info, warn};
 
-use super::invite_helper;
+use super::{
+	invite_helper, invite_rate_limit_check, joined_rooms_limit_check, may_publish_to_directory,
+	new_room_directory_visibility,
+};
 use crate::{
 	service::{appservice::RegistrationInfo, pdu::PduBuilder},
 	services, Error, Result, Ruma,
@@ -75,22 +81,38 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		return Err(Error::BadRequest(ErrorKind::forbidden(), "Room creation has been disabled."));
 	}
 
+	// the creator joins the new room, so it counts against their cap like any join
+	joined_rooms_limit_check(sender_user)?;
+
+	let config = &services().globals.config;
+	let directory_visibility =
+		new_room_directory_visibility(&body.visibility, config.default_room_directory_visibility.as_ref());
+
+	if directory_visibility == room::Visibility::Public
+		&& !may_publish_to_directory(
+			config.allow_public_room_directory_publish,
+			config.lockdown_public_room_directory,
+			services().users.is_admin(sender_user)?,
+		) {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Publishing rooms to the room directory is not allowed",
+		));
+	}
+
 	let room_id: OwnedRoomId = if let Some(custom_room_id) = &body.room_id {
 		custom_room_id_check(custom_room_id)?
 	} else {
 		RoomId::new(&services().globals.config.server_name)
 	};
 
-	// check if room ID doesn't already exist instead of erroring on auth check
-	if services().rooms.short.get_shortroomid(&room_id)?.is_some() {
-		return Err(Error::BadRequest(
-			ErrorKind::RoomInUse,
-			"Room with that custom room ID already exists",
-		));
-	}
-
-	let _short_id = services().rooms.short.get_or_create_shortroomid(&room_id)?;
-	let state_lock = services().globals.roomid_mutex_state.lock(&room_id).await;
+	let state_lock = claim_room_id(
+		&services().globals.roomid_mutex_state,
+		&room_id,
+		|room_id| Ok(services().rooms.short.get_shortroomid(room_id)?.is_some()),
+		|room_id| services().rooms.short.get_or_create_shortroomid(room_id),
+	)
+	.await?;
 
 	let alias: Option<OwnedRoomAliasId> = if let Some(alias) = &body.room_alias_name {
 		Some(room_alias_check(alias, &body.appservice_info).await?)
@@ -102,14 +124,13 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		Some(room_version) => {
 			if services()
 				.globals
-				.supported_room_versions()
-				.contains(&room_version)
+				.room_version_allowed_for_creation(&room_version)
 			{
 				room_version
 			} else {
 				return Err(Error::BadRequest(
 					ErrorKind::UnsupportedRoomVersion,
-					"This server does not support that room version.",
+					"This server does not support creating rooms of that version.",
 				));
 			}
 		},
@@ -261,8 +282,12 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 		}
 	}
 
-	let power_levels_content =
-		default_power_levels_content(&body.power_level_content_override, &body.visibility, users)?;
+	let power_levels_content = default_power_levels_content(
+		&body.power_level_content_override,
+		&body.visibility,
+		users,
+		services().globals.config.default_call_power_level,
+	)?;
 
 	services()
 		.rooms
@@ -450,6 +475,11 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 	// 8. Events implied by invite (and TODO: invite_3pid)
 	drop(state_lock);
 	for user_id in &body.invite {
+		if let Err(e) = invite_rate_limit_check(sender_user, &room_id, body.appservice_info.is_some()).await {
+			warn!(%e, "Not sending invite to {user_id}");
+			continue;
+		}
+
 		if let Err(e) = invite_helper(sender_user, user_id, &room_id, None, body.is_direct).await {
 			warn!(%e, "Failed to send invite");
 		}
@@ -463,7 +493,7 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 			.set_alias(&alias, &room_id, sender_user)?;
 	}
 
-	if body.visibility == room::Visibility::Public {
+	if directory_visibility == room::Visibility::Public {
 		services().rooms.directory.set_public(&room_id)?;
 	}
 
@@ -478,6 +508,8 @@ pub(crate) async fn create_room_route(body: Ruma<create_room::v3::Request>) -> R
 ///
 /// - You have to currently be joined to the room (TODO: Respect history
 ///   visibility)
+/// - Bundles the latest edit, reaction counts and references into
+///   `unsigned.m.relations` unless `bundled_aggregations` is disabled
 pub(crate) async fn get_room_event_route(
 	body: Ruma<get_room_event::v3::Request>,
 ) -> Result<get_room_event::v3::Response> {
@@ -505,6 +537,12 @@ pub(crate) async fn get_room_event_route(
 
 	let mut event = (*event).clone();
 	event.add_age()?;
+	if services().globals.bundled_aggregations() {
+		services()
+			.rooms
+			.pdu_metadata
+			.add_bundled_relations(sender_user, &mut event)?;
+	}
 
 	Ok(get_room_event::v3::Response {
 		event: event.to_room_event(),
@@ -556,12 +594,11 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 
 	if !services()
 		.globals
-		.supported_room_versions()
-		.contains(&body.new_version)
+		.room_version_allowed_for_creation(&body.new_version)
 	{
 		return Err(Error::BadRequest(
 			ErrorKind::UnsupportedRoomVersion,
-			"This server does not support that room version.",
+			"This server does not support upgrading rooms to that version.",
 		));
 	}
 
@@ -829,7 +866,7 @@ pub(crate) async fn upgrade_room_route(body: Ruma<upgrade_room::v3::Request>) ->
 /// creates the power_levels_content for the PDU builder
 fn default_power_levels_content(
 	power_level_content_override: &Option<Raw<RoomPowerLevelsEventContent>>, visibility: &room::Visibility,
-	users: BTreeMap<OwnedUserId, Int>,
+	users: BTreeMap<OwnedUserId, Int>, call_power_level: Option<i64>,
 ) -> Result<serde_json::Value> {
 	let mut power_levels_content = serde_json::to_value(RoomPowerLevelsEventContent {
 		users,
@@ -847,13 +884,13 @@ fn default_power_levels_content(
 		serde_json::to_value(100).expect("100 is valid Value");
 
 	// synapse does this too. clients do not expose these permissions. it prevents
-	// default users from calling public rooms, for obvious reasons.
-	if *visibility == room::Visibility::Public {
-		power_levels_content["events"]["m.call.invite"] = serde_json::to_value(50).expect("50 is valid Value");
-		power_levels_content["events"]["org.matrix.msc3401.call"] =
-			serde_json::to_value(50).expect("50 is valid Value");
-		power_levels_content["events"]["org.matrix.msc3401.call.member"] =
-			serde_json::to_value(50).expect("50 is valid Value");
+	// default users from calling public rooms, for obvious reasons. a configured
+	// level applies to every room instead.
+	let call_power_level = call_power_level.or((*visibility == room::Visibility::Public).then_some(50));
+	if let Some(call_power_level) = call_power_level {
+		for event_type in ["m.call.invite", "org.matrix.msc3401.call", "org.matrix.msc3401.call.member"] {
+			power_levels_content["events"][event_type] = call_power_level.into();
+		}
 	}
 
 	if let Some(power_level_content_override) = power_level_content_override {
@@ -927,6 +964,28 @@ async fn room_alias_check(
 	Ok(full_room_alias)
 }
 
+/// Takes the new room's state lock and creates its short ID. The lock is taken
+/// before checking the room doesn't already exist, so concurrent requests for
+/// the same custom room ID can't both pass the check.
+async fn claim_room_id(
+	state_mutex: &MutexMap<OwnedRoomId, ()>, room_id: &RoomId, exists: impl FnOnce(&RoomId) -> Result<bool>,
+	create: impl FnOnce(&RoomId) -> Result<u64>,
+) -> Result<mutex_map::Guard<()>> {
+	let state_lock = state_mutex.lock(room_id).await;
+
+	// check if room ID doesn't already exist instead of erroring on auth check
+	if exists(room_id)? {
+		return Err(Error::BadRequest(
+			ErrorKind::RoomInUse,
+			"Room with that custom room ID already exists",
+		));
+	}
+
+	create(room_id)?;
+
+	Ok(state_lock)
+}
+
 /// if a room is being created with a custom room ID, run our checks against it
 fn custom_room_id_check(custom_room_id: &str) -> Result<OwnedRoomId> {
 	// apply forbidden room alias checks to custom room IDs too
@@ -960,3 +1019,81 @@ fn custom_room_id_check(custom_room_id: &str) -> Result<OwnedRoomId> {
 		Error::BadRequest(ErrorKind::InvalidParam, "Custom room ID could not be parsed")
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::{BTreeMap, HashSet},
+		sync::{Arc, Mutex},
+		thread,
+		time::Duration,
+	};
+
+	use conduit::utils::MutexMap;
+	use ruma::{
+		api::client::{error::ErrorKind, room::Visibility},
+		room_id, OwnedRoomId,
+	};
+
+	use super::{claim_room_id, default_power_levels_content};
+	use crate::Error;
+
+	#[test]
+	fn call_power_level_defaults_and_config() {
+		let public = default_power_levels_content(&None, &Visibility::Public, BTreeMap::new(), None).unwrap();
+		assert_eq!(public["events"]["m.call.invite"], 50);
+		let private = default_power_levels_content(&None, &Visibility::Private, BTreeMap::new(), None).unwrap();
+		assert!(private["events"].get("m.call.invite").is_none());
+
+		for visibility in [Visibility::Public, Visibility::Private] {
+			let content = default_power_levels_content(&None, &visibility, BTreeMap::new(), Some(0)).unwrap();
+			assert_eq!(content["events"]["m.call.invite"], 0);
+			assert_eq!(content["events"]["org.matrix.msc3401.call"], 0);
+			assert_eq!(content["events"]["org.matrix.msc3401.call.member"], 0);
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn parallel_creates_of_a_custom_room_id_admit_one() {
+		let state_mutex = Arc::new(MutexMap::<OwnedRoomId, ()>::new());
+		// stands in for the short room IDs
+		let rooms = Arc::new(Mutex::new(HashSet::new()));
+
+		let create = |state_mutex: Arc<MutexMap<OwnedRoomId, ()>>, rooms: Arc<Mutex<HashSet<OwnedRoomId>>>| async move {
+			let claimed = claim_room_id(
+				&state_mutex,
+				room_id!("!custom:example.com"),
+				|room_id| {
+					let exists = rooms.lock().unwrap().contains(room_id);
+					// leave the other request time to check too
+					thread::sleep(Duration::from_millis(50));
+					Ok(exists)
+				},
+				|room_id| {
+					rooms.lock().unwrap().insert(room_id.to_owned());
+					Ok(1)
+				},
+			)
+			.await;
+
+			match claimed {
+				Ok(_state_lock) => true,
+				Err(Error::BadRequest(ErrorKind::RoomInUse, _)) => false,
+				Err(e) => panic!("unexpected error: {e}"),
+			}
+		};
+
+		let (a, b) = tokio::join!(
+			tokio::spawn(create(Arc::clone(&state_mutex), Arc::clone(&rooms))),
+			tokio::spawn(create(Arc::clone(&state_mutex), Arc::clone(&rooms))),
+		);
+
+		assert_eq!(
+			[a.unwrap(), b.unwrap()]
+				.iter()
+				.filter(|created| **created)
+				.count(),
+			1
+		);
+	}
+}
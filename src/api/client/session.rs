@@ -4,7 +4,10 @@ use ruma::{
 		session::{
 			get_login_types::{
 				self,
-				v3::{ApplicationServiceLoginType, PasswordLoginType},
+				v3::{
+					ApplicationServiceLoginType, IdentityProvider, LoginType, PasswordLoginType, SsoLoginType,
+					TokenLoginType,
+				},
 			},
 			login::{
 				self,
@@ -32,13 +35,35 @@ struct Claims {
 ///
 /// Get the supported login types of this server. One of these should be used as
 /// the `type` field when logging in.
+///
+/// - Lists `m.login.sso` with the configured identity providers
+/// - Lists `m.login.token` only when `jwt_secret` is set to verify the tokens
 pub(crate) async fn get_login_types_route(
 	_body: Ruma<get_login_types::v3::Request>,
 ) -> Result<get_login_types::v3::Response> {
-	Ok(get_login_types::v3::Response::new(vec![
-		get_login_types::v3::LoginType::Password(PasswordLoginType::default()),
-		get_login_types::v3::LoginType::ApplicationService(ApplicationServiceLoginType::default()),
-	]))
+	Ok(get_login_types::v3::Response::new(login_types(
+		&services().globals.config.sso_identity_providers,
+		services().globals.jwt_decoding_key().is_some(),
+	)))
+}
+
+fn login_types(identity_providers: &[IdentityProvider], token_login: bool) -> Vec<LoginType> {
+	let mut flows = vec![
+		LoginType::Password(PasswordLoginType::default()),
+		LoginType::ApplicationService(ApplicationServiceLoginType::default()),
+	];
+
+	if !identity_providers.is_empty() {
+		flows.push(LoginType::Sso(SsoLoginType {
+			identity_providers: identity_providers.to_vec(),
+		}));
+	}
+
+	if token_login {
+		flows.push(LoginType::Token(TokenLoginType::default()));
+	}
+
+	flows
 }
 
 /// # `POST /_matrix/client/v3/login`
@@ -90,6 +115,13 @@ pub(crate) async fn login_route(body: Ruma<login::v3::Request>) -> Result<login:
 				return Err(Error::BadRequest(ErrorKind::forbidden(), "Wrong username or password."));
 			}
 
+			if hash::needs_rehash(&hash) {
+				debug!("Upgrading password hash parameters for {user_id}");
+				if let Err(e) = services().users.set_password(&user_id, Some(password)) {
+					warn!("Failed to rehash password for {user_id}: {e}");
+				}
+			}
+
 			user_id
 		},
 		login::v3::LoginInfo::Token(login::v3::Token {
@@ -248,3 +280,38 @@ pub(crate) async fn logout_all_route(body: Ruma<logout_all::v3::Request>) -> Res
 
 	Ok(logout_all::v3::Response::new())
 }
+
+#[cfg(test)]
+mod tests {
+	use ruma::api::client::session::get_login_types::v3::{IdentityProvider, LoginType};
+
+	use super::login_types;
+
+	#[test]
+	fn identity_providers_listed_under_sso() {
+		assert!(!login_types(&[], true)
+			.iter()
+			.any(|flow| matches!(flow, LoginType::Sso(_))));
+
+		let idp = IdentityProvider::new("oidc-github".to_owned(), "GitHub".to_owned());
+		let flows = login_types(&[idp], true);
+		let Some(LoginType::Sso(sso)) = flows.iter().find(|flow| matches!(flow, LoginType::Sso(_))) else {
+			panic!("m.login.sso is listed");
+		};
+		assert_eq!(sso.identity_providers.len(), 1);
+		assert_eq!(sso.identity_providers[0].id, "oidc-github");
+		assert!(flows.iter().any(|flow| matches!(flow, LoginType::Token(_))));
+	}
+
+	#[test]
+	fn token_login_only_listed_with_jwt_secret() {
+		let idp = IdentityProvider::new("oidc-github".to_owned(), "GitHub".to_owned());
+		let flows = login_types(&[idp], false);
+		assert!(flows.iter().any(|flow| matches!(flow, LoginType::Sso(_))));
+		assert!(!flows.iter().any(|flow| matches!(flow, LoginType::Token(_))));
+
+		assert!(login_types(&[], true)
+			.iter()
+			.any(|flow| matches!(flow, LoginType::Token(_))));
+	}
+}
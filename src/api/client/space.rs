@@ -24,7 +24,15 @@ pub(crate) async fn get_hierarchy_route(body: Ruma<get_hierarchy::v1::Request>)
 	let max_depth = body
 		.max_depth
 		.unwrap_or_else(|| UInt::from(3_u32))
-		.min(UInt::from(10_u32));
+		.min(UInt::from(10_u32))
+		.min(
+			services()
+				.globals
+				.config
+				.federation_hierarchy_max_depth
+				.try_into()
+				.unwrap_or(UInt::MAX),
+		);
 
 	let key = body
 		.from
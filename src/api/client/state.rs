@@ -10,6 +10,8 @@ use ruma::{
 			canonical_alias::RoomCanonicalAliasEventContent,
 			history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
 			join_rules::{JoinRule, RoomJoinRulesEventContent},
+			member::{MembershipState, RoomMemberEventContent},
+			power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
 		},
 		AnyStateEventContent, StateEventType,
 	},
@@ -31,6 +33,8 @@ use crate::{
 /// - Tries to send the event into the room, auth rules will determine if it is
 ///   allowed
 /// - If event is new `canonical_alias`: Rejects if alias is incorrect
+/// - If event is `m.room.member`: Rejects membership changes the sender isn't
+///   allowed to make, e.g. joining someone else or banning without power
 pub(crate) async fn send_state_event_for_key_route(
 	body: Ruma<send_state_event::v3::Request>,
 ) -> Result<send_state_event::v3::Response> {
@@ -171,7 +175,7 @@ pub(crate) async fn get_state_events_for_empty_key_route(
 async fn send_state_event_for_key_helper(
 	sender: &UserId, room_id: &RoomId, event_type: &StateEventType, json: &Raw<AnyStateEventContent>, state_key: String,
 ) -> Result<Arc<EventId>> {
-	allowed_to_send_state_event(room_id, event_type, json).await?;
+	allowed_to_send_state_event(sender, room_id, event_type, json, &state_key).await?;
 	let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
 	let event_id = services()
 		.rooms
@@ -194,9 +198,19 @@ async fn send_state_event_for_key_helper(
 }
 
 async fn allowed_to_send_state_event(
-	room_id: &RoomId, event_type: &StateEventType, json: &Raw<AnyStateEventContent>,
+	sender: &UserId, room_id: &RoomId, event_type: &StateEventType, json: &Raw<AnyStateEventContent>, state_key: &str,
 ) -> Result<()> {
 	match event_type {
+		// check membership transitions up front rather than leaving them to auth rules
+		StateEventType::RoomMember => {
+			if let Ok(content) = serde_json::from_str::<RoomMemberEventContent>(json.json().get()) {
+				let target = UserId::parse(state_key).map_err(|_| {
+					Error::BadRequest(ErrorKind::InvalidParam, "State key of a membership event must be a user ID.")
+				})?;
+
+				check_membership_transition(sender, room_id, &target, &content.membership)?;
+			}
+		},
 		// Forbid m.room.encryption if encryption is disabled
 		StateEventType::RoomEncryption => {
 			if !services().globals.allow_encryption() {
@@ -265,3 +279,145 @@ async fn allowed_to_send_state_event(
 	}
 	Ok(())
 }
+
+fn check_membership_transition(
+	sender: &UserId, room_id: &RoomId, target: &UserId, membership: &MembershipState,
+) -> Result<()> {
+	// without power levels the room creator is privileged, which auth rules
+	// handle for us
+	let Some(power_levels) =
+		services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+	else {
+		return Ok(());
+	};
+
+	let power_levels: RoomPowerLevels = serde_json::from_str::<RoomPowerLevelsEventContent>(power_levels.content.get())
+		.map_err(|_| Error::bad_database("Invalid event content for m.room.power_levels"))?
+		.into();
+
+	let state_accessor = &services().rooms.state_accessor;
+	let sender_membership = state_accessor
+		.get_member(room_id, sender)?
+		.map(|member| member.membership);
+	let target_membership = state_accessor
+		.get_member(room_id, target)?
+		.map(|member| member.membership);
+
+	match membership_transition_error(
+		sender,
+		target,
+		sender_membership.as_ref(),
+		target_membership.as_ref(),
+		membership,
+		&power_levels,
+	) {
+		Some(error) => Err(Error::BadRequest(ErrorKind::forbidden(), error)),
+		None => Ok(()),
+	}
+}
+
+/// Why `sender` may not set `target`'s membership to `membership`, following
+/// the membership auth rules. Returns `None` for changes to leave to them.
+fn membership_transition_error(
+	sender: &UserId, target: &UserId, sender_membership: Option<&MembershipState>,
+	target_membership: Option<&MembershipState>, membership: &MembershipState, power_levels: &RoomPowerLevels,
+) -> Option<&'static str> {
+	let sender_joined = sender_membership == Some(&MembershipState::Join);
+	let target_banned = target_membership == Some(&MembershipState::Ban);
+
+	match membership {
+		MembershipState::Join | MembershipState::Knock if sender != target => {
+			Some("You cannot join or knock on behalf of another user.")
+		},
+		MembershipState::Join | MembershipState::Knock if target_banned => Some("You are banned from this room."),
+		MembershipState::Invite if !sender_joined => Some("You must be joined to the room to invite users."),
+		MembershipState::Invite if matches!(target_membership, Some(MembershipState::Join | MembershipState::Ban)) => {
+			Some("The user is already joined to or banned from this room.")
+		},
+		MembershipState::Invite if !power_levels.user_can_invite(sender) => {
+			Some("You don't have permission to invite users.")
+		},
+		MembershipState::Leave if sender == target && target_banned => Some("You cannot unban yourself."),
+		MembershipState::Leave | MembershipState::Ban if sender != target && !sender_joined => {
+			Some("You must be joined to the room to kick or ban users.")
+		},
+		MembershipState::Leave
+			if sender != target && target_banned && !power_levels.user_can_ban_user(sender, target) =>
+		{
+			Some("You don't have permission to unban this user.")
+		},
+		MembershipState::Leave
+			if sender != target && !target_banned && !power_levels.user_can_kick_user(sender, target) =>
+		{
+			Some("You don't have permission to kick this user.")
+		},
+		MembershipState::Ban if !power_levels.user_can_ban_user(sender, target) => {
+			Some("You don't have permission to ban this user.")
+		},
+		_ => None,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		events::room::{member::MembershipState, power_levels::RoomPowerLevelsEventContent},
+		int, user_id,
+	};
+
+	use super::membership_transition_error;
+
+	#[test]
+	fn invalid_membership_transitions_are_forbidden() {
+		let (admin, alice, bob) = (
+			user_id!("@admin:example.com"),
+			user_id!("@alice:example.com"),
+			user_id!("@bob:example.com"),
+		);
+		let mut content = RoomPowerLevelsEventContent::new();
+		content.users.insert(admin.to_owned(), int!(100));
+		let power_levels = content.into();
+		let joined = Some(&MembershipState::Join);
+
+		// joining on someone else's behalf
+		assert!(membership_transition_error(alice, bob, joined, None, &MembershipState::Join, &power_levels).is_some());
+		// banning without power
+		assert!(
+			membership_transition_error(alice, bob, joined, joined, &MembershipState::Ban, &power_levels).is_some()
+		);
+		// kicking someone more powerful
+		assert!(
+			membership_transition_error(alice, admin, joined, joined, &MembershipState::Leave, &power_levels).is_some()
+		);
+		// unbanning yourself
+		let banned = Some(&MembershipState::Ban);
+		assert!(
+			membership_transition_error(bob, bob, banned, banned, &MembershipState::Leave, &power_levels).is_some()
+		);
+		// inviting while not in the room
+		assert!(membership_transition_error(alice, bob, None, None, &MembershipState::Invite, &power_levels).is_some());
+	}
+
+	#[test]
+	fn valid_membership_transitions_are_left_to_auth_rules() {
+		let (admin, alice) = (user_id!("@admin:example.com"), user_id!("@alice:example.com"));
+		let mut content = RoomPowerLevelsEventContent::new();
+		content.users.insert(admin.to_owned(), int!(100));
+		let power_levels = content.into();
+		let joined = Some(&MembershipState::Join);
+
+		assert!(membership_transition_error(alice, alice, None, None, &MembershipState::Join, &power_levels).is_none());
+		assert!(
+			membership_transition_error(alice, alice, joined, joined, &MembershipState::Leave, &power_levels).is_none()
+		);
+		assert!(
+			membership_transition_error(admin, alice, joined, joined, &MembershipState::Ban, &power_levels).is_none()
+		);
+		assert!(
+			membership_transition_error(admin, alice, joined, None, &MembershipState::Invite, &power_levels).is_none()
+		);
+	}
+}
@@ -0,0 +1,737 @@
+//! Subset of the Synapse admin API, for tooling such as synapse-admin that
+//! only speaks it. Only registered when `allow_synapse_admin_api` is enabled,
+//! apart from shared-secret registration which is enabled by setting
+//! `registration_shared_secret`.
+
+use std::{
+	collections::HashMap,
+	time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use ruma::{
+	api::client::error::ErrorKind, events::GlobalAccountDataEventType, push, OwnedDeviceId, OwnedRoomId, OwnedUserId,
+	UserId,
+};
+use sha1::Sha1;
+use tracing::{info, warn};
+
+use super::{clear_profile, leave_all_rooms, leave_room, DEVICE_ID_LENGTH, TOKEN_LENGTH};
+use crate::{service::user_is_local, services, utils, Error, Result, Ruma};
+
+/// Number of entries returned when the client does not send a `limit`
+const DEFAULT_LIMIT: usize = 100;
+
+/// Length of the nonces handed out for shared-secret registration
+const NONCE_LENGTH: usize = 32;
+
+/// How long a shared-secret registration nonce stays valid, as in Synapse
+const NONCE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most shared-secret registration nonces outstanding at once; the oldest is
+/// dropped to make room for a new one
+const MAX_NONCES: usize = 1000;
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// # `GET /_synapse/admin/v2/users`
+///
+/// Lists local user accounts, paginated with `from` and `limit`.
+pub(crate) async fn synapse_admin_list_users_route(
+	body: Ruma<list_users::v2::Request>,
+) -> Result<list_users::v2::Response> {
+	require_admin(body.sender_user.as_deref())?;
+
+	let mut users = Vec::new();
+	for user_id in services().users.iter().filter_map(Result::ok) {
+		let is_guest = services().users.is_guest(&user_id)?;
+		let deactivated = services().users.is_deactivated(&user_id)?;
+		if (is_guest && !body.guests.unwrap_or(true)) || (deactivated && !body.deactivated.unwrap_or(false)) {
+			continue;
+		}
+
+		users.push(list_users::v2::User {
+			admin: services().users.is_admin(&user_id)?,
+			displayname: services().users.displayname(&user_id)?,
+			avatar_url: services()
+				.users
+				.avatar_url(&user_id)?
+				.map(|url| url.to_string()),
+			name: user_id,
+			is_guest,
+			deactivated,
+		});
+	}
+
+	let total = users.len();
+	let (users, next) = paginate(users, body.from.unwrap_or(0), body.limit.unwrap_or(DEFAULT_LIMIT));
+
+	Ok(list_users::v2::Response {
+		users,
+		next_token: next.map(|next| next.to_string()),
+		total,
+	})
+}
+
+/// # `POST /_synapse/admin/v1/deactivate/{userId}`
+///
+/// Deactivates a local user and makes them leave all their rooms.
+pub(crate) async fn synapse_admin_deactivate_user_route(
+	body: Ruma<deactivate_user::v1::Request>,
+) -> Result<deactivate_user::v1::Response> {
+	let sender_user = require_admin(body.sender_user.as_deref())?;
+
+	if !user_is_local(&body.user_id) {
+		return Err(Error::BadRequest(ErrorKind::InvalidParam, "Can only deactivate local users."));
+	}
+
+	if body.user_id == services().globals.server_user {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Not allowed to deactivate the server service account.",
+		));
+	}
+
+	if !services().users.exists(&body.user_id)? {
+		return Err(Error::BadRequest(ErrorKind::NotFound, "User does not exist."));
+	}
+
+	info!("{sender_user} deactivated {} through the Synapse admin API", body.user_id);
+	services().users.deactivate_account(&body.user_id)?;
+
+	let all_joined_rooms: Vec<OwnedRoomId> = services()
+		.rooms
+		.state_cache
+		.rooms_joined(&body.user_id)
+		.filter_map(Result::ok)
+		.collect();
+	clear_profile(body.user_id.clone(), all_joined_rooms).await?;
+	leave_all_rooms(&body.user_id).await;
+
+	Ok(deactivate_user::v1::Response {
+		id_server_unbind_result: "success".to_owned(),
+	})
+}
+
+/// # `GET /_synapse/admin/v1/rooms`
+///
+/// Lists the rooms known to this server, paginated with `from` and `limit`.
+pub(crate) async fn synapse_admin_list_rooms_route(
+	body: Ruma<list_rooms::v1::Request>,
+) -> Result<list_rooms::v1::Response> {
+	require_admin(body.sender_user.as_deref())?;
+
+	let mut rooms = Vec::new();
+	for room_id in services().rooms.metadata.iter_ids().filter_map(Result::ok) {
+		rooms.push(list_rooms::v1::Room {
+			name: services().rooms.state_accessor.get_name(&room_id)?,
+			canonical_alias: services()
+				.rooms
+				.state_accessor
+				.get_canonical_alias(&room_id)?
+				.map(|alias| alias.to_string()),
+			joined_members: services()
+				.rooms
+				.state_cache
+				.room_joined_count(&room_id)?
+				.unwrap_or(0),
+			joined_local_members: services()
+				.rooms
+				.state_cache
+				.room_members(&room_id)
+				.filter_map(Result::ok)
+				.filter(|user_id| user_is_local(user_id))
+				.count(),
+			public: services().rooms.directory.is_public_room(&room_id)?,
+			room_id,
+		});
+	}
+
+	let offset = body.from.unwrap_or(0);
+	let total_rooms = rooms.len();
+	let (rooms, next_batch) = paginate(rooms, offset, body.limit.unwrap_or(DEFAULT_LIMIT));
+
+	Ok(list_rooms::v1::Response {
+		rooms,
+		offset,
+		total_rooms,
+		next_batch,
+	})
+}
+
+/// # `DELETE /_synapse/admin/v1/rooms/{roomId}`
+///
+/// Makes all local users leave the room and removes its local aliases and
+/// directory listing. With `block`, the room is also banned so nobody can join
+/// it again. Room history is not purged.
+pub(crate) async fn synapse_admin_delete_room_route(
+	body: Ruma<delete_room::v1::Request>,
+) -> Result<delete_room::v1::Response> {
+	let sender_user = require_admin(body.sender_user.as_deref())?;
+
+	if service::admin::Service::get_admin_room()?.is_some_and(|admin_room_id| admin_room_id == body.room_id) {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Not allowed to delete the admin room.",
+		));
+	}
+
+	info!("{sender_user} deleted {} through the Synapse admin API", body.room_id);
+	if body.block {
+		services().rooms.metadata.ban_room(&body.room_id, true)?;
+	}
+
+	let local_users: Vec<OwnedUserId> = services()
+		.rooms
+		.state_cache
+		.room_members(&body.room_id)
+		.filter_map(Result::ok)
+		.filter(|user_id| user_is_local(user_id))
+		.collect();
+
+	let mut kicked_users = Vec::new();
+	let mut failed_to_kick_users = Vec::new();
+	for user_id in local_users {
+		match leave_room(&user_id, &body.room_id, None).await {
+			Ok(()) => kicked_users.push(user_id),
+			Err(e) => {
+				warn!(%user_id, room_id = %body.room_id, %e, "Failed to leave room");
+				failed_to_kick_users.push(user_id);
+			},
+		}
+	}
+
+	let local_aliases: Vec<_> = services()
+		.rooms
+		.alias
+		.local_aliases_for_room(&body.room_id)
+		.filter_map(Result::ok)
+		.collect();
+	for alias in &local_aliases {
+		services()
+			.rooms
+			.alias
+			.remove_alias(alias, &services().globals.server_user)
+			.await?;
+	}
+
+	services().rooms.directory.set_not_public(&body.room_id)?;
+
+	Ok(delete_room::v1::Response {
+		kicked_users,
+		failed_to_kick_users,
+		local_aliases: local_aliases.iter().map(ToString::to_string).collect(),
+		new_room_id: None,
+	})
+}
+
+/// # `GET /_synapse/admin/v1/register`
+///
+/// Hands out a single-use nonce for shared-secret registration.
+pub(crate) async fn synapse_admin_register_nonce_route(
+	_body: Ruma<register_nonce::v1::Request>,
+) -> Result<register_nonce::v1::Response> {
+	let nonce = utils::random_string(NONCE_LENGTH);
+	store_nonce(
+		&mut *services().globals.registration_nonces.write().await,
+		nonce.clone(),
+		Instant::now(),
+	);
+
+	Ok(register_nonce::v1::Response {
+		nonce,
+	})
+}
+
+/// Keeps a newly issued nonce, dropping the expired ones and, if there are
+/// still too many outstanding, the oldest.
+fn store_nonce(nonces: &mut HashMap<String, Instant>, nonce: String, now: Instant) {
+	nonces.retain(|_, issued| now.saturating_duration_since(*issued) < NONCE_TIMEOUT);
+	if nonces.len() >= MAX_NONCES {
+		let oldest = nonces
+			.iter()
+			.min_by_key(|(_, issued)| **issued)
+			.map(|(nonce, _)| nonce.clone());
+		if let Some(oldest) = oldest {
+			nonces.remove(&oldest);
+		}
+	}
+
+	nonces.insert(nonce, now);
+}
+
+/// Consumes a nonce, which is accepted only once and before it expires.
+fn take_nonce(nonces: &mut HashMap<String, Instant>, nonce: &str, now: Instant) -> bool {
+	nonces.retain(|_, issued| now.saturating_duration_since(*issued) < NONCE_TIMEOUT);
+	nonces.remove(nonce).is_some()
+}
+
+/// # `POST /_synapse/admin/v1/register`
+///
+/// Creates a local account, optionally as a server admin, when the request is
+/// signed with `registration_shared_secret` as done by Synapse's
+/// `register_new_matrix_user`. Registration does not need to be enabled.
+pub(crate) async fn synapse_admin_register_route(body: Ruma<register::v1::Request>) -> Result<register::v1::Response> {
+	let Some(shared_secret) = services().globals.registration_shared_secret() else {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"Shared secret registration is not enabled.",
+		));
+	};
+
+	if !take_nonce(
+		&mut *services().globals.registration_nonces.write().await,
+		&body.nonce,
+		Instant::now(),
+	) {
+		return Err(Error::BadRequest(ErrorKind::InvalidParam, "Unrecognised nonce."));
+	}
+
+	let expected = registration_mac(
+		shared_secret,
+		&body.nonce,
+		&body.username,
+		&body.password,
+		body.admin,
+		body.user_type.as_deref(),
+	);
+	let mac = decode_hex(&body.mac).ok_or(Error::BadRequest(ErrorKind::InvalidParam, "Invalid MAC."))?;
+	if expected.verify_slice(&mac).is_err() {
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "HMAC incorrect."));
+	}
+
+	let user_id = UserId::parse_with_server_name(body.username.to_lowercase(), services().globals.server_name())
+		.ok()
+		.filter(|user_id| !user_id.is_historical() && user_is_local(user_id))
+		.ok_or(Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid."))?;
+
+	if services().users.exists(&user_id)? {
+		return Err(Error::BadRequest(ErrorKind::UserInUse, "Desired user ID is already taken."));
+	}
+
+	services().users.create(&user_id, Some(&body.password))?;
+
+	let displayname = body
+		.displayname
+		.clone()
+		.unwrap_or_else(|| user_id.localpart().to_owned());
+	services()
+		.users
+		.set_displayname(&user_id, Some(displayname.clone()))
+		.await?;
+
+	services().account_data.update(
+		None,
+		&user_id,
+		GlobalAccountDataEventType::PushRules.to_string().into(),
+		&serde_json::to_value(ruma::events::push_rules::PushRulesEvent {
+			content: ruma::events::push_rules::PushRulesEventContent {
+				global: push::Ruleset::server_default(&user_id),
+			},
+		})
+		.expect("to json always works"),
+	)?;
+
+	let device_id: OwnedDeviceId = utils::random_string(DEVICE_ID_LENGTH).into();
+	let token = utils::random_string(TOKEN_LENGTH);
+	services()
+		.users
+		.create_device(&user_id, &device_id, &token, None)?;
+
+	if body.admin {
+		service::admin::make_user_admin(&user_id, displayname).await?;
+	}
+
+	info!(
+		"Registered {user_id} using the registration shared secret (admin: {})",
+		body.admin
+	);
+
+	Ok(register::v1::Response {
+		access_token: token,
+		home_server: services().globals.server_name().to_owned(),
+		user_id,
+		device_id,
+	})
+}
+
+/// Computes Synapse's shared-secret registration MAC over the NUL-separated
+/// nonce, username, password, admin flag and optional user type.
+fn registration_mac(
+	shared_secret: &str, nonce: &str, username: &str, password: &str, admin: bool, user_type: Option<&str>,
+) -> HmacSha1 {
+	let mut mac = HmacSha1::new_from_slice(shared_secret.as_bytes()).expect("HMAC can take key of any size");
+	mac.update(nonce.as_bytes());
+	mac.update(b"\x00");
+	mac.update(username.as_bytes());
+	mac.update(b"\x00");
+	mac.update(password.as_bytes());
+	mac.update(b"\x00");
+	let admin: &[u8] = if admin {
+		b"admin"
+	} else {
+		b"notadmin"
+	};
+	mac.update(admin);
+	if let Some(user_type) = user_type {
+		mac.update(b"\x00");
+		mac.update(user_type.as_bytes());
+	}
+
+	mac
+}
+
+/// Decodes a hex string, returning `None` if it is not valid hex.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 {
+		return None;
+	}
+
+	hex.as_bytes()
+		.chunks(2)
+		.map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+		.collect()
+}
+
+/// Rejects requests from users who are not server admins.
+fn require_admin(sender_user: Option<&UserId>) -> Result<&UserId> {
+	let sender_user = sender_user.expect("user is authenticated");
+	if !services().users.is_admin(sender_user)? {
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not a server admin."));
+	}
+
+	Ok(sender_user)
+}
+
+/// Returns the `limit` items starting at `from`, and the offset of the next
+/// page if there are more.
+fn paginate<T>(items: Vec<T>, from: usize, limit: usize) -> (Vec<T>, Option<usize>) {
+	let total = items.len();
+	let page: Vec<T> = items.into_iter().skip(from).take(limit).collect();
+	let next = from.saturating_add(limit);
+
+	(page, (next < total).then_some(next))
+}
+
+pub(crate) mod register_nonce {
+	pub(crate) mod v1 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata,
+		};
+
+		const METADATA: Metadata = metadata! {
+			method: GET,
+			rate_limited: true,
+			authentication: None,
+			history: {
+				1.0 => "/_synapse/admin/v1/register",
+			}
+		};
+
+		#[request]
+		pub(crate) struct Request {}
+
+		#[response]
+		pub(crate) struct Response {
+			pub(crate) nonce: String,
+		}
+	}
+}
+
+pub(crate) mod register {
+	pub(crate) mod v1 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata, OwnedDeviceId, OwnedServerName, OwnedUserId,
+		};
+
+		const METADATA: Metadata = metadata! {
+			method: POST,
+			rate_limited: true,
+			authentication: None,
+			history: {
+				1.0 => "/_synapse/admin/v1/register",
+			}
+		};
+
+		#[request]
+		pub(crate) struct Request {
+			pub(crate) nonce: String,
+			pub(crate) username: String,
+			pub(crate) password: String,
+
+			#[serde(default)]
+			pub(crate) admin: bool,
+
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) displayname: Option<String>,
+
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) user_type: Option<String>,
+
+			/// Hex-encoded HMAC-SHA1 of the request, keyed with the shared secret.
+			pub(crate) mac: String,
+		}
+
+		#[response]
+		pub(crate) struct Response {
+			pub(crate) access_token: String,
+			pub(crate) user_id: OwnedUserId,
+			pub(crate) home_server: OwnedServerName,
+			pub(crate) device_id: OwnedDeviceId,
+		}
+	}
+}
+
+pub(crate) mod list_users {
+	pub(crate) mod v2 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata, OwnedUserId,
+		};
+		use serde::{Deserialize, Serialize};
+
+		const METADATA: Metadata = metadata! {
+			method: GET,
+			rate_limited: false,
+			authentication: AccessToken,
+			history: {
+				1.0 => "/_synapse/admin/v2/users",
+			}
+		};
+
+		#[request]
+		pub(crate) struct Request {
+			#[ruma_api(query)]
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) from: Option<usize>,
+
+			#[ruma_api(query)]
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) limit: Option<usize>,
+
+			/// Whether to include guest accounts; defaults to true.
+			#[ruma_api(query)]
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) guests: Option<bool>,
+
+			/// Whether to include deactivated accounts; defaults to false.
+			#[ruma_api(query)]
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) deactivated: Option<bool>,
+		}
+
+		#[response]
+		pub(crate) struct Response {
+			pub(crate) users: Vec<User>,
+
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) next_token: Option<String>,
+
+			pub(crate) total: usize,
+		}
+
+		#[derive(Clone, Debug, Serialize, Deserialize)]
+		pub(crate) struct User {
+			pub(crate) name: OwnedUserId,
+			pub(crate) is_guest: bool,
+			pub(crate) admin: bool,
+			pub(crate) deactivated: bool,
+			pub(crate) displayname: Option<String>,
+			pub(crate) avatar_url: Option<String>,
+		}
+	}
+}
+
+pub(crate) mod deactivate_user {
+	pub(crate) mod v1 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata, OwnedUserId,
+		};
+
+		const METADATA: Metadata = metadata! {
+			method: POST,
+			rate_limited: false,
+			authentication: AccessToken,
+			history: {
+				1.0 => "/_synapse/admin/v1/deactivate/:user_id",
+			}
+		};
+
+		#[request]
+		pub(crate) struct Request {
+			#[ruma_api(path)]
+			pub(crate) user_id: OwnedUserId,
+		}
+
+		#[response]
+		pub(crate) struct Response {
+			pub(crate) id_server_unbind_result: String,
+		}
+	}
+}
+
+pub(crate) mod list_rooms {
+	pub(crate) mod v1 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata, OwnedRoomId,
+		};
+		use serde::{Deserialize, Serialize};
+
+		const METADATA: Metadata = metadata! {
+			method: GET,
+			rate_limited: false,
+			authentication: AccessToken,
+			history: {
+				1.0 => "/_synapse/admin/v1/rooms",
+			}
+		};
+
+		#[request]
+		pub(crate) struct Request {
+			#[ruma_api(query)]
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) from: Option<usize>,
+
+			#[ruma_api(query)]
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) limit: Option<usize>,
+		}
+
+		#[response]
+		pub(crate) struct Response {
+			pub(crate) rooms: Vec<Room>,
+			pub(crate) offset: usize,
+			pub(crate) total_rooms: usize,
+
+			#[serde(skip_serializing_if = "Option::is_none")]
+			pub(crate) next_batch: Option<usize>,
+		}
+
+		#[derive(Clone, Debug, Serialize, Deserialize)]
+		pub(crate) struct Room {
+			pub(crate) room_id: OwnedRoomId,
+			pub(crate) name: Option<String>,
+			pub(crate) canonical_alias: Option<String>,
+			pub(crate) joined_members: u64,
+			pub(crate) joined_local_members: usize,
+			pub(crate) public: bool,
+		}
+	}
+}
+
+pub(crate) mod delete_room {
+	pub(crate) mod v1 {
+		use ruma::{
+			api::{request, response, Metadata},
+			metadata, OwnedRoomId, OwnedUserId,
+		};
+
+		const METADATA: Metadata = metadata! {
+			method: DELETE,
+			rate_limited: false,
+			authentication: AccessToken,
+			history: {
+				1.0 => "/_synapse/admin/v1/rooms/:room_id",
+			}
+		};
+
+		#[request]
+		pub(crate) struct Request {
+			#[ruma_api(path)]
+			pub(crate) room_id: OwnedRoomId,
+
+			/// Also ban the room so it cannot be joined again.
+			#[serde(default)]
+			pub(crate) block: bool,
+		}
+
+		#[response]
+		pub(crate) struct Response {
+			pub(crate) kicked_users: Vec<OwnedUserId>,
+			pub(crate) failed_to_kick_users: Vec<OwnedUserId>,
+			pub(crate) local_aliases: Vec<String>,
+			pub(crate) new_room_id: Option<OwnedRoomId>,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		time::{Duration, Instant},
+	};
+
+	use hmac::Mac;
+
+	use super::{decode_hex, paginate, registration_mac, store_nonce, take_nonce, MAX_NONCES, NONCE_TIMEOUT};
+
+	#[test]
+	fn paginate_returns_next_offset_until_exhausted() {
+		let items: Vec<u32> = (0..5).collect();
+
+		assert_eq!(paginate(items.clone(), 0, 2), (vec![0, 1], Some(2)));
+		assert_eq!(paginate(items.clone(), 2, 2), (vec![2, 3], Some(4)));
+		assert_eq!(paginate(items.clone(), 4, 2), (vec![4], None));
+		assert_eq!(paginate(items, 10, 2), (vec![], None));
+	}
+
+	#[test]
+	fn registration_mac_matches_synapse() {
+		let mac = registration_mac("shared_secret", "thisisanonce", "pepper_roni", "pizza", true, None);
+		let expected = decode_hex("48715842ad67d5dc9a9ee938a3bda4fcfae8d7c7").expect("valid hex");
+		mac.verify_slice(&expected).expect("MAC matches");
+
+		let mac = registration_mac("shared_secret", "thisisanonce", "pepper_roni", "pizza", false, Some("bot"));
+		let expected = decode_hex("b269635cb53e1adc15073ae7ffbd000b836b3105").expect("valid hex");
+		mac.verify_slice(&expected).expect("MAC matches");
+
+		let mac = registration_mac("wrong_secret", "thisisanonce", "pepper_roni", "pizza", true, None);
+		let expected = decode_hex("48715842ad67d5dc9a9ee938a3bda4fcfae8d7c7").expect("valid hex");
+		assert!(mac.verify_slice(&expected).is_err());
+	}
+
+	#[test]
+	fn nonces_are_single_use_and_expire() {
+		let now = Instant::now();
+		let mut nonces = HashMap::new();
+		store_nonce(&mut nonces, "once".to_owned(), now);
+		store_nonce(&mut nonces, "late".to_owned(), now);
+
+		assert!(take_nonce(&mut nonces, "once", now));
+		assert!(!take_nonce(&mut nonces, "once", now));
+		assert!(!take_nonce(&mut nonces, "late", now + NONCE_TIMEOUT));
+		assert!(nonces.is_empty());
+	}
+
+	#[test]
+	fn outstanding_nonces_are_bounded() {
+		let now = Instant::now();
+		let mut nonces = HashMap::new();
+		store_nonce(&mut nonces, "expired".to_owned(), now);
+		let mut issued = now + NONCE_TIMEOUT;
+		for i in 0..MAX_NONCES {
+			store_nonce(&mut nonces, i.to_string(), issued);
+			issued += Duration::from_millis(1);
+		}
+
+		// the expired nonce went first, then the oldest live one
+		assert_eq!(nonces.len(), MAX_NONCES);
+		assert!(!nonces.contains_key("expired"));
+		store_nonce(&mut nonces, "new".to_owned(), issued);
+		assert_eq!(nonces.len(), MAX_NONCES);
+		assert!(!nonces.contains_key("0"));
+		assert!(nonces.contains_key("new"));
+	}
+
+	#[test]
+	fn decode_hex_rejects_invalid_input() {
+		assert_eq!(decode_hex("00ff10"), Some(vec![0x00, 0xFF, 0x10]));
+		assert_eq!(decode_hex("abc"), None);
+		assert_eq!(decode_hex("zz"), None);
+	}
+}
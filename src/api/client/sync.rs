@@ -1,10 +1,10 @@
 use std::{
-	cmp::Ordering,
+	cmp::Reverse,
 	collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
 	time::Duration,
 };
 
-use conduit::PduCount;
+use conduit::{PduCount, SyncToken};
 use ruma::{
 	api::client::{
 		filter::{FilterDefinition, LazyLoadOptions},
@@ -14,23 +14,32 @@ use ruma::{
 				Ephemeral, Filter, GlobalAccountData, InviteState, InvitedRoom, JoinedRoom, LeftRoom, Presence,
 				RoomAccountData, RoomSummary, Rooms, State, Timeline, ToDevice,
 			},
-			v4::SlidingOp,
+			v4::{RoomReceiptConfig, SlidingOp},
 			DeviceLists, UnreadNotificationsCount,
 		},
 		uiaa::UiaaResponse,
 	},
 	events::{
 		presence::PresenceEvent,
-		room::member::{MembershipState, RoomMemberEventContent},
-		StateEventType, TimelineEventType,
+		receipt::{ReceiptEventContent, Receipts, SyncReceiptEvent},
+		room::{
+			avatar::RoomAvatarEventContent,
+			member::{MembershipState, RoomMemberEventContent},
+		},
+		typing::TypingEventContent,
+		AnySyncEphemeralRoomEvent, StateEventType, SyncEphemeralRoomEvent, TimelineEventType,
 	},
 	serde::Raw,
-	uint, DeviceId, EventId, OwnedUserId, RoomId, UInt, UserId,
+	uint, DeviceId, EventId, OwnedEventId, OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomId, UInt, UserId,
 };
 use tracing::{error, Instrument as _, Span};
 
 use crate::{service::pdu::EventHash, services, utils, Error, PduEvent, Result, Ruma, RumaResponse};
 
+/// How many members to consider when naming a room after its members.
+const HEROES_LIMIT: usize = 5;
+
+
 /// # `GET /_matrix/client/r0/sync`
 ///
 /// Synchronize the client's state with the latest state on the server.
@@ -88,7 +97,7 @@ pub(crate) async fn sync_events_route(
 
 	let next_batch = services().globals.current_count()?;
 	let next_batchcount = PduCount::Normal(next_batch);
-	let next_batch_string = next_batch.to_string();
+	let next_batch_string = SyncToken::new(next_batch).encode();
 
 	// Load filter
 	let filter = match body.filter {
@@ -112,9 +121,9 @@ pub(crate) async fn sync_events_route(
 	let mut joined_rooms = BTreeMap::new();
 	let since = body
 		.since
-		.as_ref()
-		.and_then(|string| string.parse().ok())
-		.unwrap_or(0);
+		.as_deref()
+		.and_then(|token| SyncToken::decode(token).ok())
+		.map_or(0, |token| token.count);
 	let sincecount = PduCount::Normal(since);
 
 	let mut presence_updates = HashMap::new();
@@ -287,8 +296,11 @@ pub(crate) async fn sync_events_route(
 				.users
 				.get_to_device_events(&sender_user, &sender_device)?,
 		},
-		// Fallback keys are not yet supported
-		device_unused_fallback_key_types: None,
+		device_unused_fallback_key_types: Some(
+			services()
+				.users
+				.unused_fallback_key_types(&sender_user, &sender_device)?,
+		),
 	};
 
 	// TODO: Retry the endpoint instead of returning
@@ -301,10 +313,7 @@ pub(crate) async fn sync_events_route(
 	{
 		// Hang a few seconds so requests are not spammed
 		// Stop hanging if new info arrives
-		let mut duration = body.timeout.unwrap_or_default();
-		if duration.as_secs() > 30 {
-			duration = Duration::from_secs(30);
-		}
+		let duration = long_poll_timeout(body.timeout.unwrap_or_default());
 
 		#[allow(clippy::let_underscore_must_use)]
 		{
@@ -721,27 +730,29 @@ async fn load_joined_room(
 				let mut delta_state_events = Vec::new();
 
 				if since_shortstatehash != current_shortstatehash {
-					let current_state_ids = services()
-						.rooms
-						.state_accessor
-						.state_full_ids(current_shortstatehash)
-						.await?;
-					let since_state_ids = services()
-						.rooms
-						.state_accessor
-						.state_full_ids(since_shortstatehash)
-						.await?;
+					let state_ids: Vec<_> = if full_state {
+						services()
+							.rooms
+							.state_accessor
+							.state_full_ids(current_shortstatehash)
+							.await?
+							.into_iter()
+							.collect()
+					} else {
+						services()
+							.rooms
+							.state_compressor
+							.state_delta(since_shortstatehash, current_shortstatehash)?
+					};
 
-					for (key, id) in current_state_ids {
-						if full_state || since_state_ids.get(&key) != Some(&id) {
-							let Some(pdu) = services().rooms.timeline.get_pdu(&id)? else {
-								error!("Pdu in state not found: {}", id);
-								continue;
-							};
+					for (_, id) in state_ids {
+						let Some(pdu) = services().rooms.timeline.get_pdu(&id)? else {
+							error!("Pdu in state not found: {}", id);
+							continue;
+						};
 
-							delta_state_events.push(pdu);
-							tokio::task::yield_now().await;
-						}
+						delta_state_events.push(pdu);
+						tokio::task::yield_now().await;
 					}
 				}
 
@@ -929,7 +940,7 @@ async fn load_joined_room(
 					error!("timeline in backfill state?!");
 					"0".to_owned()
 				},
-				PduCount::Normal(c) => c.to_string(),
+				PduCount::Normal(c) => SyncToken::new(*c).encode(),
 			}))
 		})?;
 
@@ -1081,9 +1092,9 @@ pub(crate) async fn sync_events_v4_route(
 
 	let globalsince = body
 		.pos
-		.as_ref()
-		.and_then(|string| string.parse().ok())
-		.unwrap_or(0);
+		.as_deref()
+		.and_then(|token| SyncToken::decode(token).ok())
+		.map_or(0, |token| token.count);
 
 	if globalsince == 0 {
 		if let Some(conn_id) = &body.conn_id {
@@ -1176,50 +1187,41 @@ pub(crate) async fn sync_events_v4_route(
 
 				let new_encrypted_room = encrypted_room && since_encryption.is_none();
 				if encrypted_room {
-					let current_state_ids = services()
+					let delta_state_ids = services()
 						.rooms
-						.state_accessor
-						.state_full_ids(current_shortstatehash)
-						.await?;
-					let since_state_ids = services()
-						.rooms
-						.state_accessor
-						.state_full_ids(since_shortstatehash)
-						.await?;
+						.state_compressor
+						.state_delta(since_shortstatehash, current_shortstatehash)?;
 
-					for (key, id) in current_state_ids {
-						if since_state_ids.get(&key) != Some(&id) {
-							let Some(pdu) = services().rooms.timeline.get_pdu(&id)? else {
-								error!("Pdu in state not found: {}", id);
-								continue;
-							};
-							if pdu.kind == TimelineEventType::RoomMember {
-								if let Some(state_key) = &pdu.state_key {
-									let user_id = UserId::parse(state_key.clone())
-										.map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
-
-									if user_id == sender_user {
-										continue;
-									}
+					for (_, id) in delta_state_ids {
+						let Some(pdu) = services().rooms.timeline.get_pdu(&id)? else {
+							error!("Pdu in state not found: {}", id);
+							continue;
+						};
+						if pdu.kind == TimelineEventType::RoomMember {
+							if let Some(state_key) = &pdu.state_key {
+								let user_id = UserId::parse(state_key.clone())
+									.map_err(|_| Error::bad_database("Invalid UserId in member PDU."))?;
 
-									let new_membership =
-										serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
-											.map_err(|_| Error::bad_database("Invalid PDU in database."))?
-											.membership;
-
-									match new_membership {
-										MembershipState::Join => {
-											// A new user joined an encrypted room
-											if !share_encrypted_room(&sender_user, &user_id, room_id)? {
-												device_list_changes.insert(user_id);
-											}
-										},
-										MembershipState::Leave => {
-											// Write down users that have left encrypted rooms we are in
-											left_encrypted_users.insert(user_id);
-										},
-										_ => {},
-									}
+								if user_id == sender_user {
+									continue;
+								}
+
+								let new_membership = serde_json::from_str::<RoomMemberEventContent>(pdu.content.get())
+									.map_err(|_| Error::bad_database("Invalid PDU in database."))?
+									.membership;
+
+								match new_membership {
+									MembershipState::Join => {
+										// A new user joined an encrypted room
+										if !share_encrypted_room(&sender_user, &user_id, room_id)? {
+											device_list_changes.insert(user_id);
+										}
+									},
+									MembershipState::Leave => {
+										// Write down users that have left encrypted rooms we are in
+										left_encrypted_users.insert(user_id);
+									},
+									_ => {},
 								}
 							}
 						}
@@ -1279,6 +1281,7 @@ pub(crate) async fn sync_events_v4_route(
 	}
 
 	let mut lists = BTreeMap::new();
+	let mut list_rooms = BTreeMap::new();
 	let mut todo_rooms = BTreeMap::new(); // and required state
 
 	for (list_id, list) in body.lists {
@@ -1288,6 +1291,19 @@ pub(crate) async fn sync_events_v4_route(
 
 		let mut new_known_rooms = BTreeSet::new();
 
+		let mut ordered_rooms = all_joined_rooms.clone();
+		// Sorted by the same bump stamp each room reports as its timestamp
+		if list.sort.iter().any(|sort| sort == "by_recency") {
+			sort_by_recency(&mut ordered_rooms, |room_id| {
+				services()
+					.rooms
+					.timeline
+					.bump_stamp(&sender_user, room_id)
+					.ok()
+					.flatten()
+			});
+		}
+
 		lists.insert(
 			list_id.clone(),
 			sync_events::v4::SyncList {
@@ -1303,7 +1319,7 @@ pub(crate) async fn sync_events_v4_route(
 							r.0,
 							UInt::try_from(all_joined_rooms.len().saturating_sub(1)).unwrap_or(UInt::MAX),
 						);
-						let room_ids = all_joined_rooms[(u64::from(r.0) as usize)..=(u64::from(r.1) as usize)].to_vec();
+						let room_ids = ordered_rooms[(u64::from(r.0) as usize)..=(u64::from(r.1) as usize)].to_vec();
 						new_known_rooms.extend(room_ids.iter().cloned());
 						for room_id in &room_ids {
 							let todo_room = todo_rooms
@@ -1340,6 +1356,8 @@ pub(crate) async fn sync_events_v4_route(
 			},
 		);
 
+		list_rooms.insert(list_id.clone(), new_known_rooms.clone());
+
 		if let Some(conn_id) = &body.conn_id {
 			services().users.update_sync_known_rooms(
 				sender_user.clone(),
@@ -1379,6 +1397,7 @@ pub(crate) async fn sync_events_v4_route(
 		body.room_subscriptions.remove(&r);
 	}
 
+	let subscription_rooms = known_subscription_rooms.clone();
 	if let Some(conn_id) = &body.conn_id {
 		services().users.update_sync_known_rooms(
 			sender_user.clone(),
@@ -1417,12 +1436,12 @@ pub(crate) async fn sync_events_v4_route(
 						error!("timeline in backfill state?!");
 						"0".to_owned()
 					},
-					PduCount::Normal(c) => c.to_string(),
+					PduCount::Normal(c) => SyncToken::new(*c).encode(),
 				}))
 			})?
 			.or_else(|| {
 				if roomsince != &0 {
-					Some(roomsince.to_string())
+					Some(SyncToken::new(*roomsince).encode())
 				} else {
 					None
 				}
@@ -1433,6 +1452,14 @@ pub(crate) async fn sync_events_v4_route(
 			.map(|(_, pdu)| pdu.to_sync_room_event())
 			.collect();
 
+		// everything past the room's since token is live; an initial sync has no such
+		// baseline
+		let num_live = if roomsince == &0 {
+			uint!(0)
+		} else {
+			UInt::try_from(room_events.len()).unwrap_or(UInt::MAX)
+		};
+
 		let required_state = required_state_request
 			.iter()
 			.map(|state| {
@@ -1446,65 +1473,45 @@ pub(crate) async fn sync_events_v4_route(
 			.map(|state| state.to_sync_state_event())
 			.collect();
 
-		// Heroes
-		let heroes = services()
+		// Heroes, counting invitees so a DM the other side hasn't joined yet still
+		// gets their name and avatar
+		let members = services()
 			.rooms
 			.state_cache
 			.room_members(room_id)
-			.filter_map(Result::ok)
-			.filter(|member| member != &sender_user)
-			.map(|member| {
-				Ok::<_, Error>(
-					services()
-						.rooms
-						.state_accessor
-						.get_member(room_id, &member)?
-						.map(|memberevent| {
-							(
-								memberevent
-									.displayname
-									.unwrap_or_else(|| member.to_string()),
-								memberevent.avatar_url,
-							)
-						}),
-				)
-			})
-			.filter_map(Result::ok)
-			.flatten()
-			.take(5)
-			.collect::<Vec<_>>();
-		let name = match heroes.len().cmp(&(1_usize)) {
-			Ordering::Greater => {
-				let firsts = heroes[1..]
-					.iter()
-					.map(|h| h.0.clone())
-					.collect::<Vec<_>>()
-					.join(", ");
-				let last = heroes[0].0.clone();
-				Some(format!("{firsts} and {last}"))
-			},
-			Ordering::Equal => Some(heroes[0].0.clone()),
-			Ordering::Less => None,
-		};
-
-		let heroes_avatar = if heroes.len() == 1 {
-			heroes[0].1.clone()
-		} else {
-			None
-		};
+			.chain(services().rooms.state_cache.room_members_invited(room_id))
+			.filter_map(Result::ok);
+		let heroes = collect_heroes(members, &sender_user, |member| {
+			services()
+				.rooms
+				.state_accessor
+				.get_member(room_id, &member)
+				.ok()
+				.flatten()
+				.map(|memberevent| {
+					(
+						memberevent
+							.displayname
+							.unwrap_or_else(|| member.to_string()),
+						memberevent.avatar_url,
+					)
+				})
+		});
+		let (name, heroes_avatar) = hero_name_and_avatar(&heroes);
 
 		rooms.insert(
 			room_id.clone(),
 			sync_events::v4::SlidingSyncRoom {
 				name: services().rooms.state_accessor.get_name(room_id)?.or(name),
-				avatar: if let Some(heroes_avatar) = heroes_avatar {
-					ruma::JsOption::Some(heroes_avatar)
-				} else {
-					match services().rooms.state_accessor.get_avatar(room_id)? {
-						ruma::JsOption::Some(avatar) => ruma::JsOption::from_option(avatar.url),
-						ruma::JsOption::Null => ruma::JsOption::Null,
-						ruma::JsOption::Undefined => ruma::JsOption::Undefined,
-					}
+				// the room's own avatar wins; a DM without one falls back to the other member's
+				avatar: match services().rooms.state_accessor.get_avatar(room_id)? {
+					ruma::JsOption::Some(RoomAvatarEventContent {
+						url: Some(url),
+						..
+					}) => ruma::JsOption::Some(url),
+					_ if heroes_avatar.is_some() => ruma::JsOption::from_option(heroes_avatar),
+					ruma::JsOption::Undefined => ruma::JsOption::Undefined,
+					_ => ruma::JsOption::Null,
 				},
 				initial: Some(roomsince == &0),
 				is_dm: None,
@@ -1547,23 +1554,102 @@ pub(crate) async fn sync_events_v4_route(
 						.unwrap_or(0) as u32)
 						.into(),
 				),
-				num_live: None, // Count events in timeline greater than global sync counter
-				timestamp: None,
+				num_live: Some(num_live),
+				timestamp: services()
+					.rooms
+					.timeline
+					.bump_stamp(&sender_user, room_id)?,
 				heroes: None,
 			},
 		);
 	}
 
+	// extensions only send what changed since the client last saw each room
+	let room_since = |room_id: &RoomId| todo_rooms.get(room_id).map_or(globalsince, |room| room.2);
+
+	let mut receipts = sync_events::v4::Receipts {
+		rooms: BTreeMap::new(),
+	};
+	if body.extensions.receipts.enabled.unwrap_or(false) {
+		let config = &body.extensions.receipts;
+		let rooms = config.rooms.as_ref().and_then(|rooms| {
+			rooms
+				.iter()
+				.map(|room| match room {
+					RoomReceiptConfig::Room(room_id) => Some(room_id.clone()),
+					_ => None,
+				})
+				.collect::<Option<Vec<_>>>()
+		});
+
+		for room_id in extension_rooms(&list_rooms, &subscription_rooms, config.lists.as_deref(), rooms.as_deref()) {
+			let events = services()
+				.rooms
+				.read_receipt
+				.readreceipts_since(&room_id, room_since(&room_id))
+				.filter_map(Result::ok)
+				.map(|(_, _, event)| event);
+
+			if let Some(event) = merge_receipts(events) {
+				receipts.rooms.insert(room_id, event);
+			}
+		}
+	}
+
+	let mut typing = sync_events::v4::Typing {
+		rooms: BTreeMap::new(),
+	};
+	if body.extensions.typing.enabled.unwrap_or(false) {
+		let config = &body.extensions.typing;
+		typing.rooms = typing_updates(
+			&services().rooms.typing,
+			extension_rooms(
+				&list_rooms,
+				&subscription_rooms,
+				config.lists.as_deref(),
+				config.rooms.as_deref(),
+			),
+			room_since,
+		)
+		.await?;
+	}
+
+	let mut room_account_data = BTreeMap::new();
+	if body.extensions.account_data.enabled.unwrap_or(false) {
+		let config = &body.extensions.account_data;
+		for room_id in extension_rooms(
+			&list_rooms,
+			&subscription_rooms,
+			config.lists.as_deref(),
+			config.rooms.as_deref(),
+		) {
+			let events: Vec<_> = services()
+				.account_data
+				.changes_since(Some(&room_id), &sender_user, room_since(&room_id))?
+				.into_iter()
+				.filter_map(|(_, v)| {
+					serde_json::from_str(v.json().get())
+						.map_err(|_| Error::bad_database("Invalid account event in database."))
+						.ok()
+				})
+				.collect();
+
+			if !events.is_empty() {
+				room_account_data.insert(room_id, events);
+			}
+		}
+	}
+
 	if rooms
 		.iter()
 		.all(|(_, r)| r.timeline.is_empty() && r.required_state.is_empty())
+		&& receipts.rooms.is_empty()
+		&& typing.rooms.is_empty()
+		&& room_account_data.is_empty()
 	{
 		// Hang a few seconds so requests are not spammed
 		// Stop hanging if new info arrives
-		let mut duration = body.timeout.unwrap_or(Duration::from_secs(30));
-		if duration.as_secs() > 30 {
-			duration = Duration::from_secs(30);
-		}
+		let duration = long_poll_timeout(body.timeout.unwrap_or(Duration::MAX));
 		#[allow(clippy::let_underscore_must_use)]
 		{
 			_ = tokio::time::timeout(duration, watcher).await;
@@ -1573,7 +1659,7 @@ pub(crate) async fn sync_events_v4_route(
 	Ok(sync_events::v4::Response {
 		initial: globalsince == 0,
 		txn_id: body.txn_id.clone(),
-		pos: next_batch.to_string(),
+		pos: SyncToken::new(next_batch).encode(),
 		lists,
 		rooms,
 		extensions: sync_events::v4::Extensions {
@@ -1582,7 +1668,7 @@ pub(crate) async fn sync_events_v4_route(
 					events: services()
 						.users
 						.get_to_device_events(&sender_user, &sender_device)?,
-					next_batch: next_batch.to_string(),
+					next_batch: SyncToken::new(next_batch).encode(),
 				})
 			} else {
 				None
@@ -1595,8 +1681,11 @@ pub(crate) async fn sync_events_v4_route(
 				device_one_time_keys_count: services()
 					.users
 					.count_one_time_keys(&sender_user, &sender_device)?,
-				// Fallback keys are not yet supported
-				device_unused_fallback_key_types: None,
+				device_unused_fallback_key_types: Some(
+					services()
+						.users
+						.unused_fallback_key_types(&sender_user, &sender_device)?,
+				),
 			},
 			account_data: sync_events::v4::AccountData {
 				global: if body.extensions.account_data.enabled.unwrap_or(false) {
@@ -1613,15 +1702,326 @@ pub(crate) async fn sync_events_v4_route(
 				} else {
 					Vec::new()
 				},
-				rooms: BTreeMap::new(),
-			},
-			receipts: sync_events::v4::Receipts {
-				rooms: BTreeMap::new(),
-			},
-			typing: sync_events::v4::Typing {
-				rooms: BTreeMap::new(),
+				rooms: room_account_data,
 			},
+			receipts,
+			typing,
 		},
 		delta_token: None,
 	})
 }
+
+/// Picks the rooms a sliding sync extension covers: the rooms of the named
+/// lists plus the named room subscriptions, each defaulting to all of them.
+fn extension_rooms(
+	list_rooms: &BTreeMap<String, BTreeSet<OwnedRoomId>>, subscription_rooms: &BTreeSet<OwnedRoomId>,
+	lists: Option<&[String]>, rooms: Option<&[OwnedRoomId]>,
+) -> BTreeSet<OwnedRoomId> {
+	let mut selected = BTreeSet::new();
+	for (list_id, list) in list_rooms {
+		if lists.map_or(true, |lists| lists.iter().any(|name| name == "*" || name == list_id)) {
+			selected.extend(list.iter().cloned());
+		}
+	}
+
+	match rooms {
+		None => selected.extend(subscription_rooms.iter().cloned()),
+		Some(rooms) => selected.extend(
+			rooms
+				.iter()
+				.filter(|room_id| subscription_rooms.contains(*room_id))
+				.cloned(),
+		),
+	}
+
+	selected
+}
+
+/// Collects the typing events of the rooms whose typing users changed after
+/// the client's `since` for that room.
+async fn typing_updates(
+	typing: &crate::service::rooms::typing::Service, room_ids: BTreeSet<OwnedRoomId>,
+	room_since: impl Fn(&RoomId) -> u64,
+) -> Result<BTreeMap<OwnedRoomId, Raw<SyncEphemeralRoomEvent<TypingEventContent>>>> {
+	let mut rooms = BTreeMap::new();
+	for room_id in room_ids {
+		if typing.last_typing_update(&room_id).await? > room_since(&room_id) {
+			let event = typing.typings_all(&room_id).await?;
+			rooms.insert(room_id, Raw::new(&event).expect("event is valid, we just created it"));
+		}
+	}
+
+	Ok(rooms)
+}
+
+/// How long to hold a sync request open waiting for new data, given what the
+/// client asked for and the configured bounds.
+fn long_poll_timeout(requested: Duration) -> Duration {
+	let config = &services().globals.config;
+	clamp_timeout(
+		requested,
+		Duration::from_secs(config.sync_min_timeout_s),
+		Duration::from_secs(config.sync_max_timeout_s),
+	)
+}
+
+fn clamp_timeout(requested: Duration, min: Duration, max: Duration) -> Duration { requested.min(max).max(min.min(max)) }
+
+/// Picks up to [`HEROES_LIMIT`] members other than the sender, resolving each
+/// one lazily so a large room's member list is never collected.
+fn collect_heroes<T>(
+	members: impl Iterator<Item = OwnedUserId>, sender_user: &UserId, resolve: impl FnMut(OwnedUserId) -> Option<T>,
+) -> Vec<T> {
+	members
+		.filter(|member| member != sender_user)
+		.filter_map(resolve)
+		.take(HEROES_LIMIT)
+		.collect()
+}
+
+/// Derives a name and avatar for a room from its heroes, for use when the room
+/// doesn't set its own. A DM with a single other member takes that member's
+/// name and avatar; larger rooms only get a name listing their members.
+fn hero_name_and_avatar(heroes: &[(String, Option<OwnedMxcUri>)]) -> (Option<String>, Option<OwnedMxcUri>) {
+	match heroes {
+		[] => (None, None),
+		[(name, avatar)] => (Some(name.clone()), avatar.clone()),
+		[(last, _), rest @ ..] => {
+			let firsts = rest
+				.iter()
+				.map(|(name, _)| name.as_str())
+				.collect::<Vec<_>>()
+				.join(", ");
+			(Some(format!("{firsts} and {last}")), None)
+		},
+	}
+}
+
+/// Orders rooms most recently active first, leaving rooms without any known
+/// activity at the end in their original order.
+fn sort_by_recency<K: Ord>(rooms: &mut [OwnedRoomId], recency: impl Fn(&RoomId) -> Option<K>) {
+	rooms.sort_by_cached_key(|room_id| Reverse(recency(room_id)));
+}
+
+/// Combines the per-user `m.receipt` events we store into a single event for
+/// the room.
+fn merge_receipts(events: impl Iterator<Item = Raw<AnySyncEphemeralRoomEvent>>) -> Option<Raw<SyncReceiptEvent>> {
+	let mut content = BTreeMap::<OwnedEventId, Receipts>::new();
+	for event in events {
+		let Ok(event) = event.deserialize_as::<SyncReceiptEvent>() else {
+			continue;
+		};
+
+		for (event_id, receipts) in event.content.0 {
+			let merged = content.entry(event_id).or_default();
+			for (receipt_type, users) in receipts {
+				merged.entry(receipt_type).or_default().extend(users);
+			}
+		}
+	}
+
+	if content.is_empty() {
+		return None;
+	}
+
+	Raw::new(&SyncReceiptEvent {
+		content: ReceiptEventContent(content),
+	})
+	.ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::{BTreeMap, BTreeSet},
+		time::Duration,
+	};
+
+	use conduit::PduCount;
+	use ruma::{
+		event_id,
+		events::{receipt::ReceiptType, AnySyncEphemeralRoomEvent},
+		owned_mxc_uri, owned_room_id, room_id,
+		serde::Raw,
+		user_id, OwnedRoomId,
+	};
+	use serde_json::json;
+	use tokio::sync::{broadcast, RwLock};
+
+	use super::{
+		clamp_timeout, collect_heroes, extension_rooms, hero_name_and_avatar, merge_receipts, sort_by_recency,
+		typing_updates,
+	};
+	use crate::{
+		service::rooms::{
+			timeline::{newest_bump_stamp, DEFAULT_BUMP_EVENT_TYPES},
+			typing::Service,
+		},
+		PduEvent,
+	};
+
+	fn receipt(event_id: &str, user_id: &str) -> Raw<AnySyncEphemeralRoomEvent> {
+		Raw::new(&json!({
+			"type": "m.receipt",
+			"content": { event_id: { "m.read": { user_id: { "ts": 1 } } } },
+		}))
+		.unwrap()
+		.cast()
+	}
+
+	#[test]
+	fn receipts_are_merged_into_one_room_event() {
+		let event = merge_receipts(
+			vec![
+				receipt("$a:example.com", "@alice:example.com"),
+				receipt("$a:example.com", "@bob:example.com"),
+			]
+			.into_iter(),
+		)
+		.unwrap()
+		.deserialize()
+		.unwrap();
+
+		let readers = &event.content.0[event_id!("$a:example.com")][&ReceiptType::Read];
+		assert!(readers.contains_key(user_id!("@alice:example.com")));
+		assert!(readers.contains_key(user_id!("@bob:example.com")));
+		assert!(merge_receipts(std::iter::empty()).is_none());
+	}
+
+	#[test]
+	fn extensions_respect_list_and_room_scoping() {
+		let listed = owned_room_id!("!listed:example.com");
+		let other = owned_room_id!("!other:example.com");
+		let subscribed = owned_room_id!("!subscribed:example.com");
+		let list_rooms = BTreeMap::from([
+			("main".to_owned(), BTreeSet::from([listed.clone()])),
+			("other".to_owned(), BTreeSet::from([other.clone()])),
+		]);
+		let subscription_rooms = BTreeSet::from([subscribed.clone()]);
+
+		let all = extension_rooms(&list_rooms, &subscription_rooms, None, None);
+		assert_eq!(all, BTreeSet::from([listed.clone(), other.clone(), subscribed.clone()]));
+
+		let scoped = extension_rooms(&list_rooms, &subscription_rooms, Some(&["main".to_owned()]), Some(&[]));
+		assert_eq!(scoped, BTreeSet::from([listed]));
+
+		let rooms: Vec<OwnedRoomId> = vec![subscribed.clone(), owned_room_id!("!unknown:example.com")];
+		let subscribed_only = extension_rooms(&list_rooms, &subscription_rooms, Some(&[]), Some(&rooms));
+		assert_eq!(subscribed_only, BTreeSet::from([subscribed]));
+	}
+
+	#[test]
+	fn recently_active_rooms_sort_first() {
+		let pdu = |kind: &str, state_key: Option<&str>, ts: u64| {
+			let event = PduEvent::test_event(json!({ "type": kind, "state_key": state_key, "origin_server_ts": ts }));
+			(PduCount::Normal(ts), event)
+		};
+		let chatty = owned_room_id!("!chatty:example.com");
+		let renamed = owned_room_id!("!renamed:example.com");
+		let unknown = owned_room_id!("!unknown:example.com");
+		// newest first, as the timeline is searched
+		let timelines = BTreeMap::from([
+			(chatty.clone(), vec![pdu("m.room.message", None, 2_000)]),
+			(
+				renamed.clone(),
+				vec![pdu("m.room.topic", Some(""), 3_000), pdu("m.room.message", None, 1_000)],
+			),
+		]);
+
+		// a newer topic change doesn't count as activity, so the room sorts by its
+		// last message like the timestamp it reports
+		let mut rooms = vec![unknown.clone(), renamed.clone(), chatty.clone()];
+		sort_by_recency(&mut rooms, |room_id| {
+			let timeline = timelines.get(room_id)?;
+			newest_bump_stamp(timeline.iter().cloned().map(Ok), DEFAULT_BUMP_EVENT_TYPES).unwrap()
+		});
+		assert_eq!(rooms, vec![chatty, renamed, unknown]);
+	}
+
+	#[test]
+	fn dm_takes_the_other_members_name_and_avatar() {
+		let avatar = owned_mxc_uri!("mxc://example.com/alice");
+		let heroes = [("Alice".to_owned(), Some(avatar.clone()))];
+		assert_eq!(hero_name_and_avatar(&heroes), (Some("Alice".to_owned()), Some(avatar.clone())));
+
+		let heroes = [
+			("Alice".to_owned(), Some(avatar)),
+			("Bob".to_owned(), None),
+			("Carol".to_owned(), None),
+		];
+		assert_eq!(hero_name_and_avatar(&heroes), (Some("Bob, Carol and Alice".to_owned()), None));
+		assert_eq!(hero_name_and_avatar(&[]), (None, None));
+	}
+
+	#[test]
+	fn long_poll_is_clamped_to_configured_bounds() {
+		let (min, max) = (Duration::from_secs(5), Duration::from_secs(90));
+		assert_eq!(clamp_timeout(Duration::from_secs(120), min, max), max);
+		assert_eq!(clamp_timeout(Duration::from_secs(60), min, max), Duration::from_secs(60));
+		assert_eq!(clamp_timeout(Duration::ZERO, min, max), min);
+		assert_eq!(
+			clamp_timeout(Duration::MAX, Duration::ZERO, Duration::from_secs(30)),
+			Duration::from_secs(30)
+		);
+	}
+
+	#[test]
+	fn heroes_skip_the_sender_and_unresolved_members() {
+		let sender = user_id!("@user0:example.com");
+		let mut resolved = Vec::new();
+		let members = (0..100_000).map(|i| ruma::UserId::parse(format!("@user{i}:example.com")).unwrap());
+
+		// members without a readable member event don't take a hero's place
+		let heroes = collect_heroes(members, sender, |member| {
+			resolved.push(member.clone());
+			member.as_str().starts_with("@user1").then_some(member)
+		});
+		assert_eq!(
+			heroes,
+			[
+				"@user1:example.com",
+				"@user10:example.com",
+				"@user11:example.com",
+				"@user12:example.com",
+				"@user13:example.com"
+			]
+		);
+
+		// the sender is never resolved, and nothing after the last hero is
+		assert_eq!(resolved.first().unwrap(), "@user1:example.com");
+		assert_eq!(resolved.last().unwrap(), "@user13:example.com");
+		assert_eq!(resolved.len(), 13);
+	}
+
+	#[tokio::test]
+	async fn typing_updates_cover_rooms_changed_since() {
+		let typing = Service {
+			typing: RwLock::new(BTreeMap::from([(
+				owned_room_id!("!typing:example.com"),
+				BTreeMap::from([(user_id!("@alice:example.com").to_owned(), u64::MAX)]),
+			)])),
+			last_typing_update: RwLock::new(BTreeMap::from([
+				(owned_room_id!("!typing:example.com"), 5),
+				(owned_room_id!("!stale:example.com"), 2),
+				(owned_room_id!("!other:example.com"), 9),
+			])),
+			typing_update_sender: broadcast::channel(1).0,
+		};
+		let room_ids: BTreeSet<OwnedRoomId> = [
+			owned_room_id!("!typing:example.com"),
+			owned_room_id!("!stale:example.com"),
+			owned_room_id!("!quiet:example.com"),
+		]
+		.into();
+
+		let rooms = typing_updates(&typing, room_ids, |_| 3).await.unwrap();
+		assert_eq!(rooms.keys().collect::<Vec<_>>(), ["!typing:example.com"]);
+
+		let event = rooms
+			.get(room_id!("!typing:example.com"))
+			.unwrap()
+			.deserialize()
+			.unwrap();
+		assert_eq!(event.content.user_ids, [user_id!("@alice:example.com").to_owned()]);
+	}
+}
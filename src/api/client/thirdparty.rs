@@ -1,17 +1,275 @@
-use std::collections::BTreeMap;
+use std::{fmt::Debug, future::Future};
 
-use ruma::api::client::thirdparty::get_protocols;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use ruma::api::{
+	appservice::{self, Registration},
+	client::{
+		error::ErrorKind,
+		thirdparty::{
+			get_location_for_protocol, get_location_for_room_alias, get_protocol, get_protocols, get_user_for_protocol,
+			get_user_for_user_id,
+		},
+	},
+	OutgoingRequest,
+};
+use tracing::debug;
 
-use crate::{Result, Ruma};
+use crate::{services, Error, Result, Ruma};
 
 /// # `GET /_matrix/client/r0/thirdparty/protocols`
 ///
-/// TODO: Fetches all metadata about protocols supported by the homeserver.
+/// Fetches all metadata about protocols supported by the homeserver, as
+/// advertised by its registered appservices.
 pub(crate) async fn get_protocols_route(
 	_body: Ruma<get_protocols::v3::Request>,
 ) -> Result<get_protocols::v3::Response> {
-	// TODO
 	Ok(get_protocols::v3::Response {
-		protocols: BTreeMap::new(),
+		protocols: services().appservice.thirdparty_protocols().await,
 	})
 }
+
+/// # `GET /_matrix/client/r0/thirdparty/protocol/{protocol}`
+///
+/// Fetches the metadata of a single protocol supported by the homeserver.
+pub(crate) async fn get_protocol_route(body: Ruma<get_protocol::v3::Request>) -> Result<get_protocol::v3::Response> {
+	let protocol = services()
+		.appservice
+		.thirdparty_protocols()
+		.await
+		.remove(&body.protocol)
+		.ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown protocol."))?;
+
+	Ok(get_protocol::v3::Response {
+		protocol,
+	})
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/location/{protocol}`
+///
+/// Asks the appservices bridging the protocol for Matrix portal rooms matching
+/// the given fields.
+pub(crate) async fn get_location_for_protocol_route(
+	body: Ruma<get_location_for_protocol::v3::Request>,
+) -> Result<get_location_for_protocol::v3::Response> {
+	let registrations = services()
+		.appservice
+		.registrations_for_protocol(&body.protocol)
+		.await;
+
+	let request = appservice::thirdparty::get_location_for_protocol::v1::Request {
+		protocol: body.protocol.clone(),
+		fields: body.fields.clone(),
+	};
+
+	Ok(get_location_for_protocol::v3::Response {
+		locations: query_appservices(registrations, request)
+			.await
+			.into_iter()
+			.flat_map(|response| response.locations)
+			.collect(),
+	})
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/location`
+///
+/// Asks the appservices whose namespace covers the room alias for the third
+/// party locations it maps to.
+pub(crate) async fn get_location_for_room_alias_route(
+	body: Ruma<get_location_for_room_alias::v3::Request>,
+) -> Result<get_location_for_room_alias::v3::Response> {
+	let registrations = services()
+		.appservice
+		.registrations_for_alias(&body.alias)
+		.await;
+
+	let request = appservice::thirdparty::get_location_for_room_alias::v1::Request {
+		alias: body.alias.clone(),
+	};
+
+	Ok(get_location_for_room_alias::v3::Response {
+		locations: query_appservices(registrations, request)
+			.await
+			.into_iter()
+			.flat_map(|response| response.locations)
+			.collect(),
+	})
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/user/{protocol}`
+///
+/// Asks the appservices bridging the protocol for Matrix users matching the
+/// given fields.
+pub(crate) async fn get_user_for_protocol_route(
+	body: Ruma<get_user_for_protocol::v3::Request>,
+) -> Result<get_user_for_protocol::v3::Response> {
+	let registrations = services()
+		.appservice
+		.registrations_for_protocol(&body.protocol)
+		.await;
+
+	let request = appservice::thirdparty::get_user_for_protocol::v1::Request {
+		protocol: body.protocol.clone(),
+		fields: body.fields.clone(),
+	};
+
+	Ok(get_user_for_protocol::v3::Response {
+		users: query_appservices(registrations, request)
+			.await
+			.into_iter()
+			.flat_map(|response| response.users)
+			.collect(),
+	})
+}
+
+/// # `GET /_matrix/client/r0/thirdparty/user`
+///
+/// Asks the appservices whose namespace covers the user ID for the third party
+/// users it maps to.
+pub(crate) async fn get_user_for_user_id_route(
+	body: Ruma<get_user_for_user_id::v3::Request>,
+) -> Result<get_user_for_user_id::v3::Response> {
+	let registrations = services()
+		.appservice
+		.registrations_for_user(&body.userid)
+		.await;
+
+	let request = appservice::thirdparty::get_user_for_user_id::v1::Request {
+		userid: body.userid.clone(),
+	};
+
+	Ok(get_user_for_user_id::v3::Response {
+		users: query_appservices(registrations, request)
+			.await
+			.into_iter()
+			.flat_map(|response| response.users)
+			.collect(),
+	})
+}
+
+/// Sends `request` to each appservice, returning the responses of those that
+/// answered. Appservices that fail are skipped rather than failing the lookup.
+async fn query_appservices<T>(registrations: Vec<Registration>, request: T) -> Vec<T::IncomingResponse>
+where
+	T: OutgoingRequest + Clone + Debug + Send,
+{
+	query_appservices_with(registrations, request, |registration, request| {
+		services()
+			.sending
+			.send_appservice_request(registration, request)
+	})
+	.await
+}
+
+async fn query_appservices_with<T, F, Fut>(
+	registrations: Vec<Registration>, request: T, send: F,
+) -> Vec<T::IncomingResponse>
+where
+	T: OutgoingRequest + Clone,
+	F: Fn(Registration, T) -> Fut,
+	Fut: Future<Output = Result<Option<T::IncomingResponse>>>,
+{
+	let mut futures: FuturesUnordered<_> = registrations
+		.into_iter()
+		.map(|registration| {
+			let id = registration.id.clone();
+			let response = send(registration, request.clone());
+			async move { (id, response.await) }
+		})
+		.collect();
+
+	let mut responses = Vec::new();
+	while let Some((id, response)) = futures.next().await {
+		match response {
+			Ok(Some(response)) => responses.push(response),
+			Ok(None) => {},
+			Err(e) => debug!("Third party lookup on appservice {id} failed: {e}"),
+		}
+	}
+
+	responses
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::BTreeMap, sync::Arc};
+
+	use ruma::{
+		api::appservice::{thirdparty::get_location_for_protocol, Registration},
+		owned_room_alias_id,
+		thirdparty::Location,
+	};
+	use serde_json::json;
+
+	use super::query_appservices_with;
+	use crate::{
+		service::appservice::{Data, Service},
+		Error, Result,
+	};
+
+	struct Registrations(Vec<Registration>);
+
+	impl Data for Registrations {
+		fn register_appservice(&self, yaml: Registration) -> Result<String> { Ok(yaml.id) }
+
+		fn unregister_appservice(&self, _service_name: &str) -> Result<()> { Ok(()) }
+
+		fn get_registration(&self, id: &str) -> Result<Option<Registration>> {
+			Ok(self.0.iter().find(|r| r.id == id).cloned())
+		}
+
+		fn iter_ids<'a>(&'a self) -> Result<Box<dyn Iterator<Item = Result<String>> + 'a>> {
+			Ok(Box::new(self.0.iter().map(|r| Ok(r.id.clone()))))
+		}
+
+		fn all(&self) -> Result<Vec<(String, Registration)>> {
+			Ok(self.0.iter().map(|r| (r.id.clone(), r.clone())).collect())
+		}
+	}
+
+	fn registration(id: &str, protocols: &[&str]) -> Registration {
+		serde_json::from_value(json!({
+			"id": id,
+			"url": null,
+			"as_token": id,
+			"hs_token": id,
+			"sender_localpart": id,
+			"namespaces": { "users": [], "aliases": [], "rooms": [] },
+			"protocols": protocols,
+		}))
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn location_lookup_aggregates_the_bridging_appservices() {
+		let appservices = Service::build(Arc::new(Registrations(vec![
+			registration("irc", &["irc"]),
+			registration("irc-broken", &["irc"]),
+			registration("slack", &["slack"]),
+		])))
+		.unwrap();
+
+		let registrations = appservices.registrations_for_protocol("irc").await;
+		let request = get_location_for_protocol::v1::Request::new("irc".to_owned());
+		let locations: Vec<Location> =
+			query_appservices_with(registrations, request, |registration, request| async move {
+				assert_ne!(registration.id, "slack", "only appservices bridging the protocol are asked");
+				assert_eq!(request.protocol, "irc");
+				match registration.id.as_str() {
+					"irc" => Ok(Some(get_location_for_protocol::v1::Response::new(vec![Location::new(
+						owned_room_alias_id!("#irc_#matrix:example.com"),
+						"irc".to_owned(),
+						BTreeMap::new(),
+					)]))),
+					_ => Err(Error::BadServerResponse("appservice is down")),
+				}
+			})
+			.await
+			.into_iter()
+			.flat_map(|response| response.locations)
+			.collect();
+
+		// the failing appservice is skipped instead of failing the lookup
+		assert_eq!(locations.len(), 1);
+		assert_eq!(locations[0].alias, "#irc_#matrix:example.com");
+	}
+}
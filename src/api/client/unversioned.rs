@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 
 use axum::{response::IntoResponse, Json};
+use conduit::config::WellKnownConfig;
 use ruma::api::client::{
 	discovery::{
 		discover_homeserver::{self, HomeserverInfo, SlidingSyncProxyInfo},
@@ -84,42 +85,14 @@ pub(crate) async fn well_known_client(
 /// # `GET /.well-known/matrix/support`
 ///
 /// Server support contact and support page of a homeserver's domain.
+///
+/// - Returns 404 only when neither contacts nor a support page are configured
 pub(crate) async fn well_known_support(_body: Ruma<discover_support::Request>) -> Result<discover_support::Response> {
-	let support_page = services()
-		.globals
-		.well_known_support_page()
-		.as_ref()
-		.map(ToString::to_string);
-
-	let role = services().globals.well_known_support_role().clone();
+	let well_known = &services().globals.config.well_known;
+	let support_page = well_known.support_page.as_ref().map(ToString::to_string);
+	let contacts = support_contacts(well_known);
 
-	// support page or role must be either defined for this to be valid
-	if support_page.is_none() && role.is_none() {
-		return Err(Error::BadRequest(ErrorKind::NotFound, "Not found."));
-	}
-
-	let email_address = services().globals.well_known_support_email().clone();
-	let matrix_id = services().globals.well_known_support_mxid().clone();
-
-	// if a role is specified, an email address or matrix id is required
-	if role.is_some() && (email_address.is_none() && matrix_id.is_none()) {
-		return Err(Error::BadRequest(ErrorKind::NotFound, "Not found."));
-	}
-
-	// TOOD: support defining multiple contacts in the config
-	let mut contacts: Vec<Contact> = vec![];
-
-	if let Some(role) = role {
-		let contact = Contact {
-			role,
-			email_address,
-			matrix_id,
-		};
-
-		contacts.push(contact);
-	}
-
-	// support page or role+contacts must be either defined for this to be valid
+	// support page or contacts must be either defined for this to be valid
 	if contacts.is_empty() && support_page.is_none() {
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Not found."));
 	}
@@ -130,6 +103,26 @@ pub(crate) async fn well_known_support(_body: Ruma<discover_support::Request>) -
 	})
 }
 
+/// Assembles the support contacts from the single `support_role` contact and
+/// the `support_contacts` list, dropping empty fields and any contact left
+/// without a way to reach it.
+fn support_contacts(well_known: &WellKnownConfig) -> Vec<Contact> {
+	let single = well_known.support_role.clone().map(|role| Contact {
+		role,
+		email_address: well_known.support_email.clone(),
+		matrix_id: well_known.support_mxid.clone(),
+	});
+
+	single
+		.into_iter()
+		.chain(well_known.support_contacts.iter().cloned())
+		.filter_map(|mut contact| {
+			contact.email_address = contact.email_address.filter(|email| !email.is_empty());
+			(contact.email_address.is_some() || contact.matrix_id.is_some()).then_some(contact)
+		})
+		.collect()
+}
+
 /// # `GET /client/server.json`
 ///
 /// Endpoint provided by sliding sync proxy used by some clients such as Element
@@ -172,3 +165,48 @@ pub(crate) async fn conduwuit_local_user_count() -> Result<impl IntoResponse> {
 		"count": user_count
 	})))
 }
+
+#[cfg(test)]
+mod tests {
+	use conduit::config::WellKnownConfig;
+	use ruma::{
+		api::client::discovery::discover_support::{Contact, ContactRole},
+		owned_user_id,
+	};
+
+	use super::support_contacts;
+
+	#[test]
+	fn support_contacts_include_all_configured() {
+		let well_known = WellKnownConfig {
+			support_role: Some(ContactRole::Admin),
+			support_email: Some("admin@example.com".to_owned()),
+			support_contacts: vec![
+				Contact {
+					role: ContactRole::Security,
+					email_address: Some(String::new()),
+					matrix_id: Some(owned_user_id!("@security:example.com")),
+				},
+				Contact {
+					role: ContactRole::Admin,
+					email_address: None,
+					matrix_id: None,
+				},
+			],
+			..Default::default()
+		};
+
+		let contacts = support_contacts(&well_known);
+		assert_eq!(contacts.len(), 2);
+		assert_eq!(contacts[0].role, ContactRole::Admin);
+		assert_eq!(contacts[0].email_address.as_deref(), Some("admin@example.com"));
+		assert_eq!(contacts[1].role, ContactRole::Security);
+		assert_eq!(contacts[1].email_address, None);
+		assert_eq!(contacts[1].matrix_id, Some(owned_user_id!("@security:example.com")));
+	}
+
+	#[test]
+	fn no_support_contacts_when_unconfigured() {
+		assert!(support_contacts(&WellKnownConfig::default()).is_empty());
+	}
+}
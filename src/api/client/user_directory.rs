@@ -1,9 +1,11 @@
+use conduit::config::UserDirectorySearchScope;
 use ruma::{
 	api::client::user_directory::search_users,
 	events::{
 		room::join_rules::{JoinRule, RoomJoinRulesEventContent},
 		StateEventType,
 	},
+	UserId,
 };
 
 use crate::{services, Result, Ruma};
@@ -15,11 +17,22 @@ use crate::{services, Result, Ruma};
 /// - Hides any local users that aren't in any public rooms (i.e. those that
 ///   have the join rule set to public)
 /// and don't share a room with the sender
+/// - `user_directory_search_scope` can restrict results to users sharing a
+///   room with the sender, or disable the search entirely
 pub(crate) async fn search_users_route(body: Ruma<search_users::v3::Request>) -> Result<search_users::v3::Response> {
 	let sender_user = body.sender_user.as_ref().expect("user is authenticated");
 	let limit = usize::try_from(body.limit).unwrap_or(10); // default limit is 10
+	let scope = services().globals.config.user_directory_search_scope;
 
-	let mut users = services().users.iter().filter_map(|user_id| {
+	if scope == UserDirectorySearchScope::Disabled {
+		return Ok(search_users::v3::Response {
+			results: Vec::new(),
+			limited: false,
+		});
+	}
+
+	let search_term = body.search_term.to_lowercase();
+	let users = services().users.iter().filter_map(|user_id| {
 		// Filter out buggy users (they should not exist, but you never know...)
 		let user_id = user_id.ok()?;
 
@@ -29,74 +42,124 @@ pub(crate) async fn search_users_route(body: Ruma<search_users::v3::Request>) ->
 			avatar_url: services().users.avatar_url(&user_id).ok()?,
 		};
 
-		let user_id_matches = user
-			.user_id
-			.to_string()
-			.to_lowercase()
-			.contains(&body.search_term.to_lowercase());
-
-		let user_displayname_matches = user
-			.display_name
-			.as_ref()
-			.filter(|name| {
-				name.to_lowercase()
-					.contains(&body.search_term.to_lowercase())
-			})
-			.is_some();
-
-		if !user_id_matches && !user_displayname_matches {
+		if !search_term_matches(&user.user_id, user.display_name.as_deref(), &search_term) {
 			return None;
 		}
 
 		// It's a matching user, but is the sender allowed to see them?
-		let mut user_visible = false;
-
-		let user_is_in_public_rooms = services()
-			.rooms
-			.state_cache
-			.rooms_joined(&user_id)
-			.filter_map(Result::ok)
-			.any(|room| {
-				services()
-					.rooms
-					.state_accessor
-					.room_state_get(&room, &StateEventType::RoomJoinRules, "")
-					.map_or(false, |event| {
-						event.map_or(false, |event| {
-							serde_json::from_str(event.content.get())
-								.map_or(false, |r: RoomJoinRulesEventContent| r.join_rule == JoinRule::Public)
+		let user_is_in_public_rooms = || {
+			services()
+				.rooms
+				.state_cache
+				.rooms_joined(&user_id)
+				.filter_map(Result::ok)
+				.any(|room| {
+					services()
+						.rooms
+						.state_accessor
+						.room_state_get(&room, &StateEventType::RoomJoinRules, "")
+						.map_or(false, |event| {
+							event.map_or(false, |event| {
+								serde_json::from_str(event.content.get())
+									.map_or(false, |r: RoomJoinRulesEventContent| r.join_rule == JoinRule::Public)
+							})
 						})
-					})
-			});
+				})
+		};
 
-		if user_is_in_public_rooms {
-			user_visible = true;
-		} else {
-			let user_is_in_shared_rooms = services()
+		let user_is_in_shared_rooms = || {
+			services()
 				.rooms
 				.user
-				.get_shared_rooms(vec![sender_user.clone(), user_id])
-				.ok()?
-				.next()
-				.is_some();
-
-			if user_is_in_shared_rooms {
-				user_visible = true;
-			}
-		}
+				.get_shared_rooms(vec![sender_user.clone(), user_id.clone()])
+				.map_or(false, |mut rooms| rooms.next().is_some())
+		};
 
-		if !user_visible {
+		if !user_visible(scope, user_is_in_public_rooms, user_is_in_shared_rooms) {
 			return None;
 		}
 
 		Some(user)
 	});
 
-	let results = users.by_ref().take(limit).collect();
-	let limited = users.next().is_some();
+	let (results, limited) = limit_results(users, limit);
 
 	Ok(search_users::v3::Response {
 		results,
 		limited,
 	})
 }
+
+/// Whether the lowercased `search_term` appears in the user ID (and so its
+/// localpart) or the display name, ignoring case.
+fn search_term_matches(user_id: &UserId, display_name: Option<&str>, search_term: &str) -> bool {
+	user_id.as_str().to_lowercase().contains(search_term)
+		|| display_name.is_some_and(|name| name.to_lowercase().contains(search_term))
+}
+
+/// Takes up to `limit` results, and whether there were more that didn't fit.
+fn limit_results<T>(mut results: impl Iterator<Item = T>, limit: usize) -> (Vec<T>, bool) {
+	let taken = results.by_ref().take(limit).collect();
+	(taken, results.next().is_some())
+}
+
+/// Whether a matching user is shown to the searcher under `scope`. The checks
+/// are only run when the scope needs them.
+fn user_visible(
+	scope: UserDirectorySearchScope, in_public_room: impl FnOnce() -> bool, shares_room: impl FnOnce() -> bool,
+) -> bool {
+	match scope {
+		UserDirectorySearchScope::All => in_public_room() || shares_room(),
+		UserDirectorySearchScope::SharedRooms => shares_room(),
+		UserDirectorySearchScope::Disabled => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use conduit::config::UserDirectorySearchScope;
+	use ruma::user_id;
+
+	use super::{limit_results, search_term_matches, user_visible};
+
+	#[test]
+	fn more_matches_than_limit_are_limited() {
+		let (results, limited) = limit_results(1..=15, 10);
+		assert_eq!(results, (1..=10).collect::<Vec<_>>());
+		assert!(limited);
+
+		let (results, limited) = limit_results(1..=10, 10);
+		assert_eq!(results.len(), 10);
+		assert!(!limited);
+	}
+
+	#[test]
+	fn search_matches_localpart_and_display_name() {
+		let alice = user_id!("@alice:example.com");
+		assert!(search_term_matches(alice, None, "ali"));
+		assert!(search_term_matches(alice, Some("Wonderland Girl"), "wonder"));
+		assert!(!search_term_matches(alice, Some("Wonderland Girl"), "bob"));
+	}
+
+	#[test]
+	fn all_scope_shows_public_room_members_and_room_mates() {
+		let scope = UserDirectorySearchScope::All;
+		assert!(user_visible(scope, || true, || false));
+		assert!(user_visible(scope, || false, || true));
+		assert!(!user_visible(scope, || false, || false));
+	}
+
+	#[test]
+	fn shared_rooms_scope_only_shows_room_mates() {
+		let scope = UserDirectorySearchScope::SharedRooms;
+		assert!(!user_visible(scope, || true, || false));
+		assert!(user_visible(scope, || false, || true));
+		assert!(user_visible(scope, || true, || true));
+	}
+
+	#[test]
+	fn disabled_scope_shows_nobody() {
+		let scope = UserDirectorySearchScope::Disabled;
+		assert!(!user_visible(scope, || true, || true));
+	}
+}
@@ -23,6 +23,9 @@ pub fn build(router: Router, server: &Server) -> Router {
 		.ruma_route(client::change_password_route)
 		.ruma_route(client::deactivate_route)
 		.ruma_route(client::third_party_route)
+		.ruma_route(client::add_3pid_route)
+		.ruma_route(client::bind_3pid_route)
+		.ruma_route(client::delete_3pid_route)
 		.ruma_route(client::request_3pid_management_token_via_email_route)
 		.ruma_route(client::request_3pid_management_token_via_msisdn_route)
 		.ruma_route(client::check_registration_token_validity)
@@ -93,6 +96,11 @@ pub fn build(router: Router, server: &Server) -> Router {
 		.ruma_route(client::search_users_route)
 		.ruma_route(client::get_member_events_route)
 		.ruma_route(client::get_protocols_route)
+		.ruma_route(client::get_protocol_route)
+		.ruma_route(client::get_location_for_protocol_route)
+		.ruma_route(client::get_location_for_room_alias_route)
+		.ruma_route(client::get_user_for_protocol_route)
+		.ruma_route(client::get_user_for_user_id_route)
 		.ruma_route(client::send_message_event_route)
 		.ruma_route(client::send_state_event_for_key_route)
 		.ruma_route(client::get_state_events_route)
@@ -158,6 +166,7 @@ pub fn build(router: Router, server: &Server) -> Router {
 		.ruma_route(client::get_content_route)
 		.ruma_route(client::get_content_as_filename_route)
 		.ruma_route(client::get_content_thumbnail_route)
+		.ruma_route(client::delete_content_route)
 		.ruma_route(client::get_devices_route)
 		.ruma_route(client::get_device_route)
 		.ruma_route(client::update_device_route)
@@ -182,15 +191,35 @@ pub fn build(router: Router, server: &Server) -> Router {
         .ruma_route(client::well_known_support)
         .ruma_route(client::well_known_client)
         .route("/_conduwuit/server_version", get(client::conduwuit_server_version))
+		.route("/_conduwuit/3pid/email/validate", get(client::validate_3pid_email_route))
 		.route("/_matrix/client/r0/rooms/:room_id/initialSync", get(initial_sync))
 		.route("/_matrix/client/v3/rooms/:room_id/initialSync", get(initial_sync))
 		.route("/client/server.json", get(client::syncv3_client_server_json));
 
+	let router = if config.registration_shared_secret.is_some() {
+		router
+			.ruma_route(client::synapse_admin_register_nonce_route)
+			.ruma_route(client::synapse_admin_register_route)
+	} else {
+		router
+	};
+
+	let router = if config.allow_synapse_admin_api {
+		router
+			.ruma_route(client::synapse_admin_list_users_route)
+			.ruma_route(client::synapse_admin_deactivate_user_route)
+			.ruma_route(client::synapse_admin_list_rooms_route)
+			.ruma_route(client::synapse_admin_delete_room_route)
+	} else {
+		router
+	};
+
 	if config.allow_federation {
 		router
 			.ruma_route(server::get_server_version_route)
 			.route("/_matrix/key/v2/server", get(server::get_server_keys_route))
 			.route("/_matrix/key/v2/server/:key_id", get(server::get_server_keys_deprecated_route))
+			.ruma_route(server::get_remote_server_keys_batch_route)
 			.ruma_route(server::get_public_rooms_route)
 			.ruma_route(server::get_public_rooms_filtered_route)
 			.ruma_route(server::send_transaction_message_route)
@@ -1,21 +1,35 @@
-use std::collections::BTreeMap;
+use std::{
+	collections::BTreeMap,
+	future::Future,
+	sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
 
 use axum::RequestPartsExt;
 use axum_extra::{
-	headers::{authorization::Bearer, Authorization},
-	typed_header::TypedHeaderRejectionReason,
+	headers::{
+		authorization::{Bearer, Credentials},
+		Authorization,
+	},
 	TypedHeader,
 };
-use http::uri::PathAndQuery;
+use http::{header, uri::PathAndQuery, HeaderMap};
 use ruma::{
 	api::{client::error::ErrorKind, AuthScheme, Metadata},
-	CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, UserId,
+	serde::Base64,
+	CanonicalJsonObject, CanonicalJsonValue, OwnedDeviceId, OwnedServerName, OwnedUserId, ServerName, UserId,
 };
 use tracing::warn;
 
 use super::{request::Request, xmatrix::XMatrix};
 use crate::{service::appservice::RegistrationInfo, services, Error, Result};
 
+/// Number of requests for another destination after which we warn that the
+/// reverse proxy or server_name is likely misconfigured.
+const DESTINATION_MISMATCH_WARN_THRESHOLD: u32 = 10;
+
+static DESTINATION_MISMATCHES: AtomicU32 = AtomicU32::new(0);
+static DESTINATION_MISMATCH_WARNED: AtomicBool = AtomicBool::new(false);
+
 enum Token {
 	Appservice(Box<RegistrationInfo>),
 	User((OwnedUserId, OwnedDeviceId)),
@@ -171,29 +185,16 @@ async fn auth_server(request: &mut Request, json_body: &Option<CanonicalJsonValu
 		return Err(Error::bad_config("Federation is disabled."));
 	}
 
-	let TypedHeader(Authorization(x_matrix)) = request
-		.parts
-		.extract::<TypedHeader<Authorization<XMatrix>>>()
-		.await
-		.map_err(|e| {
-			warn!("Missing or invalid Authorization header: {e}");
-
-			let msg = match e.reason() {
-				TypedHeaderRejectionReason::Missing => "Missing Authorization header.",
-				TypedHeaderRejectionReason::Error(_) => "Invalid X-Matrix signatures.",
-				_ => "Unknown header-related error",
-			};
-
-			Error::BadRequest(ErrorKind::forbidden(), msg)
-		})?;
-
-	let origin = &x_matrix.origin;
-	let signatures = BTreeMap::from_iter([(x_matrix.key.clone(), CanonicalJsonValue::String(x_matrix.sig))]);
-	let signatures = BTreeMap::from_iter([(origin.as_str().to_owned(), CanonicalJsonValue::Object(signatures))]);
+	let x_matrix = x_matrix_headers(&request.parts.headers)?;
+	let origin = &x_matrix[0].origin;
 
 	let server_destination = services().globals.server_name().as_str().to_owned();
-	if let Some(destination) = x_matrix.destination.as_ref() {
+	for destination in x_matrix
+		.iter()
+		.filter_map(|x_matrix| x_matrix.destination.as_ref())
+	{
 		if destination != &server_destination {
+			destination_mismatch(request, origin, destination, &server_destination);
 			return Err(Error::BadRequest(ErrorKind::forbidden(), "Invalid authorization."));
 		}
 	}
@@ -215,27 +216,21 @@ async fn auth_server(request: &mut Request, json_body: &Option<CanonicalJsonValu
 		("uri".to_owned(), signature_uri),
 		("origin".to_owned(), CanonicalJsonValue::String(origin.as_str().to_owned())),
 		("destination".to_owned(), CanonicalJsonValue::String(server_destination)),
-		("signatures".to_owned(), CanonicalJsonValue::Object(signatures)),
 	]);
 
 	if let Some(json_body) = json_body {
 		request_map.insert("content".to_owned(), json_body.clone());
 	};
 
-	let keys_result = services()
-		.rooms
-		.event_handler
-		.fetch_signing_keys_for_server(origin, vec![x_matrix.key.clone()])
-		.await;
-
-	let keys = keys_result.map_err(|e| {
-		warn!("Failed to fetch signing keys: {e}");
-		Error::BadRequest(ErrorKind::forbidden(), "Failed to fetch signing keys.")
-	})?;
-
-	let pub_key_map = BTreeMap::from_iter([(origin.as_str().to_owned(), keys)]);
+	let verified = verify_x_matrix_request(&request_map, &x_matrix, |origin, key_ids| {
+		services()
+			.rooms
+			.event_handler
+			.fetch_signing_keys_for_server(origin, key_ids)
+	})
+	.await?;
 
-	match ruma::signatures::verify_json(&pub_key_map, &request_map) {
+	match verified {
 		Ok(()) => Ok(Auth {
 			origin: Some(origin.clone()),
 			sender_user: None,
@@ -259,3 +254,219 @@ async fn auth_server(request: &mut Request, json_body: &Option<CanonicalJsonValu
 		},
 	}
 }
+
+/// Logs a request signed for a destination other than us. The client only
+/// gets a generic error, so this is where admins find out; if it keeps
+/// happening we also point at the usual causes, once.
+fn destination_mismatch(request: &Request, origin: &ServerName, received: &str, expected: &str) {
+	let host = request
+		.parts
+		.headers
+		.get(header::HOST)
+		.and_then(|host| host.to_str().ok())
+		.unwrap_or("<none>");
+
+	warn!(
+		%origin,
+		%host,
+		"X-Matrix destination {received:?} does not match our server_name {expected:?}"
+	);
+
+	let mismatches = DESTINATION_MISMATCHES
+		.fetch_add(1, Ordering::Relaxed)
+		.saturating_add(1);
+	if mismatches >= DESTINATION_MISMATCH_WARN_THRESHOLD && !DESTINATION_MISMATCH_WARNED.swap(true, Ordering::Relaxed) {
+		warn!(
+			"Received {mismatches} federation requests signed for a destination other than our server_name \
+			 {expected:?}. Check that server_name is correct and that your reverse proxy and .well-known delegation \
+			 send federation traffic for this server here."
+		);
+	}
+}
+
+/// Parses every `Authorization: X-Matrix` header of the request. Servers may
+/// send one per signing key, but they must all be from the same origin.
+fn x_matrix_headers(headers: &HeaderMap) -> Result<Vec<XMatrix>> {
+	let x_matrix = headers
+		.get_all(header::AUTHORIZATION)
+		.iter()
+		.filter(|value| value.as_bytes().starts_with(b"X-Matrix "))
+		.map(|value| {
+			XMatrix::decode(value).ok_or_else(|| {
+				warn!("Invalid X-Matrix Authorization header: {value:?}");
+				Error::BadRequest(ErrorKind::forbidden(), "Invalid X-Matrix signatures.")
+			})
+		})
+		.collect::<Result<Vec<_>>>()?;
+
+	let Some(first) = x_matrix.first() else {
+		warn!("Missing X-Matrix Authorization header");
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "Missing Authorization header."));
+	};
+
+	if x_matrix
+		.iter()
+		.any(|x_matrix| x_matrix.origin != first.origin)
+	{
+		warn!("X-Matrix Authorization headers have differing origins");
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "Invalid authorization."));
+	}
+
+	Ok(x_matrix)
+}
+
+/// Fetches the origin's keys for all the X-Matrix headers, including any we
+/// don't have yet, e.g. after the origin rotated its signing key. The request
+/// is authentic if any of the signatures verifies.
+async fn verify_x_matrix_request<'a, F, Fut>(
+	request_map: &CanonicalJsonObject, x_matrix: &'a [XMatrix], fetch_keys: F,
+) -> Result<Result<(), ruma::signatures::Error>>
+where
+	F: FnOnce(&'a ServerName, Vec<String>) -> Fut,
+	Fut: Future<Output = Result<BTreeMap<String, Base64>>>,
+{
+	let key_ids = x_matrix
+		.iter()
+		.map(|x_matrix| x_matrix.key.clone())
+		.collect();
+	let keys = fetch_keys(&x_matrix[0].origin, key_ids)
+		.await
+		.map_err(|e| {
+			warn!("Failed to fetch signing keys: {e}");
+			Error::BadRequest(ErrorKind::forbidden(), "Failed to fetch signing keys.")
+		})?;
+
+	Ok(x_matrix
+		.iter()
+		.map(|x_matrix| verify_x_matrix(request_map, x_matrix, &keys))
+		.reduce(Result::or)
+		.expect("at least one X-Matrix header"))
+}
+
+/// Verifies a single X-Matrix signature over the request against the origin's
+/// keys.
+fn verify_x_matrix(
+	request_map: &CanonicalJsonObject, x_matrix: &XMatrix, keys: &BTreeMap<String, Base64>,
+) -> Result<(), ruma::signatures::Error> {
+	let origin = x_matrix.origin.as_str().to_owned();
+	let signatures = BTreeMap::from_iter([(x_matrix.key.clone(), CanonicalJsonValue::String(x_matrix.sig.clone()))]);
+	let signatures = BTreeMap::from_iter([(origin.clone(), CanonicalJsonValue::Object(signatures))]);
+
+	let mut request_map = request_map.clone();
+	request_map.insert("signatures".to_owned(), CanonicalJsonValue::Object(signatures));
+
+	let pub_key_map = BTreeMap::from_iter([(origin, keys.clone())]);
+	ruma::signatures::verify_json(&pub_key_map, &request_map)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::BTreeMap, sync::Mutex};
+
+	use http::{header, HeaderMap, HeaderValue};
+	use ruma::{
+		serde::Base64,
+		server_name,
+		signatures::{sign_json, Ed25519KeyPair},
+		CanonicalJsonObject, CanonicalJsonValue,
+	};
+
+	use super::{verify_x_matrix, verify_x_matrix_request, x_matrix_headers, XMatrix};
+
+	fn signed_request(keypair: &Ed25519KeyPair) -> (CanonicalJsonObject, XMatrix) {
+		let request_map = BTreeMap::from_iter([
+			("method".to_owned(), CanonicalJsonValue::String("GET".to_owned())),
+			(
+				"uri".to_owned(),
+				CanonicalJsonValue::String("/_matrix/federation/v1/version".to_owned()),
+			),
+			("origin".to_owned(), CanonicalJsonValue::String("remote.org".to_owned())),
+			("destination".to_owned(), CanonicalJsonValue::String("example.com".to_owned())),
+		]);
+
+		let mut signed = request_map.clone();
+		sign_json("remote.org", keypair, &mut signed).unwrap();
+		let key = format!("ed25519:{}", keypair.version());
+		let CanonicalJsonValue::Object(signatures) = &signed["signatures"] else {
+			panic!("signatures is an object");
+		};
+		let CanonicalJsonValue::Object(ours) = &signatures["remote.org"] else {
+			panic!("signature set is an object");
+		};
+		let CanonicalJsonValue::String(sig) = &ours[&key] else {
+			panic!("signature is a string");
+		};
+
+		let x_matrix = XMatrix {
+			origin: server_name!("remote.org").to_owned(),
+			destination: Some("example.com".to_owned()),
+			key,
+			sig: sig.clone(),
+		};
+
+		(request_map, x_matrix)
+	}
+
+	fn keypair(version: &str) -> Ed25519KeyPair {
+		Ed25519KeyPair::from_der(&Ed25519KeyPair::generate().unwrap(), version.to_owned()).unwrap()
+	}
+
+	#[test]
+	fn uncached_signing_key_verifies_once_fetched() {
+		let old = keypair("old");
+		let rotated = keypair("new");
+		let (request_map, x_matrix) = signed_request(&rotated);
+
+		// only the key from before the rotation is cached
+		let mut keys = BTreeMap::from_iter([("ed25519:old".to_owned(), Base64::new(old.public_key().to_vec()))]);
+		assert!(verify_x_matrix(&request_map, &x_matrix, &keys).is_err());
+
+		// fetching the origin's current keys brings in the key it signed with
+		keys.insert("ed25519:new".to_owned(), Base64::new(rotated.public_key().to_vec()));
+		assert!(verify_x_matrix(&request_map, &x_matrix, &keys).is_ok());
+	}
+
+	#[tokio::test]
+	async fn uncached_signing_key_is_fetched_to_verify() {
+		let rotated = keypair("new");
+		let (request_map, signed) = signed_request(&rotated);
+
+		// one header per key; the first key is unknown to the origin now
+		let mut headers = HeaderMap::new();
+		for (key, sig) in [
+			("ed25519:gone", "bm90IGEgc2lnbmF0dXJl"),
+			(signed.key.as_str(), signed.sig.as_str()),
+		] {
+			let value = format!(r#"X-Matrix origin="remote.org",destination="example.com",key="{key}",sig="{sig}""#);
+			headers.append(header::AUTHORIZATION, HeaderValue::from_str(&value).unwrap());
+		}
+		let x_matrix = x_matrix_headers(&headers).unwrap();
+
+		let requested = Mutex::new(Vec::new());
+		let verified = verify_x_matrix_request(&request_map, &x_matrix, |origin, key_ids| {
+			requested.lock().unwrap().push((origin.to_owned(), key_ids));
+			async {
+				Ok(BTreeMap::from_iter([(
+					"ed25519:new".to_owned(),
+					Base64::new(rotated.public_key().to_vec()),
+				)]))
+			}
+		})
+		.await
+		.unwrap();
+		assert!(verified.is_ok());
+		assert_eq!(
+			requested.into_inner().unwrap(),
+			[(
+				server_name!("remote.org").to_owned(),
+				vec!["ed25519:gone".to_owned(), "ed25519:new".to_owned()]
+			)]
+		);
+
+		// without the rotated key no signature verifies
+		let verified = verify_x_matrix_request(&request_map, &x_matrix, |_, _| async { Ok(BTreeMap::new()) })
+			.await
+			.unwrap();
+		assert!(verified.is_err());
+	}
+}
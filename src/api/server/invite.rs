@@ -1,4 +1,4 @@
-use axum_client_ip::InsecureClientIp;
+use axum_client_ip::SecureClientIp;
 use ruma::{
 	api::{client::error::ErrorKind, federation::membership::create_invite},
 	events::room::member::{MembershipState, RoomMemberEventContent},
@@ -8,6 +8,7 @@ use ruma::{
 use tracing::warn;
 
 use crate::{
+	client::invite_membership_check,
 	service::server_is_ours,
 	services,
 	utils::{self},
@@ -19,7 +20,7 @@ use crate::{
 /// Invites a remote user to a room.
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn create_invite_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<create_invite::v2::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<create_invite::v2::Request>,
 ) -> Result<create_invite::v2::Response> {
 	let origin = body.origin.as_ref().expect("server is authenticated");
 
@@ -145,6 +146,26 @@ pub(crate) async fn create_invite_route(
 		));
 	}
 
+	// The inviting server needs a signed event back, so there's no way to accept
+	// an invite for a joined user as a no-op here
+	let membership = services()
+		.rooms
+		.state_accessor
+		.get_member(&body.room_id, &invited_user)?
+		.map(|member| member.membership);
+	invite_membership_check(membership.as_ref(), false)?;
+
+	if services()
+		.rooms
+		.state_cache
+		.invite_refused_by_recipient(&sender, &invited_user)?
+	{
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"This user only accepts invites from users they share a room with.",
+		));
+	}
+
 	let mut invite_state = body.invite_room_state.clone();
 
 	let mut event: JsonObject = serde_json::from_str(body.event.get())
@@ -1,67 +1,49 @@
-use std::{
-	collections::BTreeMap,
-	time::{Duration, SystemTime},
-};
+use std::collections::BTreeMap;
 
 use axum::{response::IntoResponse, Json};
+use conduit::debug_warn;
 use ruma::{
 	api::{
-		federation::discovery::{get_server_keys, ServerSigningKeys, VerifyKey},
+		client::error::ErrorKind,
+		federation::discovery::{get_remote_server_keys_batch, get_server_keys, ServerSigningKeys},
 		OutgoingResponse,
 	},
-	serde::{Base64, Raw},
-	MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId,
+	serde::Raw,
+	signatures::{Ed25519KeyPair, PublicKeyMap, PublicKeySet},
+	CanonicalJsonObject, MilliSecondsSinceUnixEpoch, ServerName,
 };
+use serde_json::value::to_raw_value;
 
-use crate::{services, Result};
+use crate::{service::server_is_ours, services, Error, Result, Ruma};
 
 /// # `GET /_matrix/key/v2/server`
 ///
 /// Gets the public signing keys of this server.
 ///
-/// - Matrix does not support invalidating public keys, so the key returned by
-///   this will be valid
-/// forever.
+/// - Keys this server signed with before a key rotation are listed in
+///   `old_verify_keys`
+/// - `valid_until_ts` is set from `server_key_validity_period_s`
 // Response type for this endpoint is Json because we need to calculate a
 // signature for the response
 pub(crate) async fn get_server_keys_route() -> Result<impl IntoResponse> {
-	let verify_keys: BTreeMap<OwnedServerSigningKeyId, VerifyKey> = BTreeMap::from([(
-		format!("ed25519:{}", services().globals.keypair().version())
-			.try_into()
-			.expect("found invalid server signing keys in DB"),
-		VerifyKey {
-			key: Base64::new(services().globals.keypair().public_key().to_vec()),
-		},
-	)]);
-
-	let mut response = serde_json::from_slice(
-		get_server_keys::v2::Response {
-			server_key: Raw::new(&ServerSigningKeys {
-				server_name: services().globals.server_name().to_owned(),
-				verify_keys,
-				old_verify_keys: BTreeMap::new(),
-				signatures: BTreeMap::new(),
-				valid_until_ts: MilliSecondsSinceUnixEpoch::from_system_time(
-					SystemTime::now()
-						.checked_add(Duration::from_secs(86400 * 7))
-						.expect("valid_until_ts should not get this high"),
-				)
-				.expect("time is valid"),
-			})
-			.expect("static conversion, no errors"),
-		}
-		.try_into_http_response::<Vec<u8>>()
-		.unwrap()
-		.body(),
-	)
-	.unwrap();
+	let server_key = Raw::new(&services().globals.own_server_signing_keys()?)
+		.map_err(|e| Error::Err(format!("Failed to serialize server keys: {e}")))?;
+
+	let http_response = get_server_keys::v2::Response {
+		server_key,
+	}
+	.try_into_http_response::<Vec<u8>>()
+	.map_err(|e| Error::Err(format!("Failed to build server keys response: {e}")))?;
+
+	let mut response = serde_json::from_slice(http_response.body())
+		.map_err(|e| Error::Err(format!("Failed to parse server keys response: {e}")))?;
 
 	ruma::signatures::sign_json(
 		services().globals.server_name().as_str(),
 		services().globals.keypair(),
 		&mut response,
 	)
-	.unwrap();
+	.map_err(|e| Error::Err(format!("Failed to sign server keys: {e}")))?;
 
 	Ok(Json(response))
 }
@@ -74,3 +56,208 @@ pub(crate) async fn get_server_keys_route() -> Result<impl IntoResponse> {
 ///   this will be valid
 /// forever.
 pub(crate) async fn get_server_keys_deprecated_route() -> impl IntoResponse { get_server_keys_route().await }
+
+/// # `POST /_matrix/key/v2/query`
+///
+/// Returns the signing keys of the requested servers, acting as a notary.
+///
+/// - Only available when `allow_notary_server` is enabled
+/// - Keys are served as the origin signed them, with our signature added
+/// - Keys are fetched from the origin again when those we have expire before
+///   the requested `minimum_valid_until_ts`
+pub(crate) async fn get_remote_server_keys_batch_route(
+	body: Ruma<get_remote_server_keys_batch::v2::Request>,
+) -> Result<get_remote_server_keys_batch::v2::Response> {
+	if !services().globals.allow_notary_server() {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"This server does not act as a notary server.",
+		));
+	}
+
+	let mut server_keys = Vec::with_capacity(body.server_keys.len());
+	for (server, criteria) in &body.server_keys {
+		let keys = if server_is_ours(server) {
+			Raw::new(&services().globals.own_server_signing_keys()?)
+				.map_err(|e| Error::Err(format!("Failed to serialize server keys: {e}")))?
+		} else {
+			// the keys must stay valid until the latest time any requested key is needed
+			let minimum_valid_until_ts = criteria
+				.values()
+				.filter_map(|criteria| criteria.minimum_valid_until_ts)
+				.max()
+				.unwrap_or_else(MilliSecondsSinceUnixEpoch::now);
+
+			match notary_keys_for(server, minimum_valid_until_ts).await {
+				Ok(Some(keys)) => keys,
+				Ok(None) => continue,
+				Err(e) => {
+					debug_warn!("Failed to get signing keys of {server} for notary query: {e}");
+					continue;
+				},
+			}
+		};
+
+		server_keys.push(sign_server_keys(
+			&keys,
+			services().globals.server_name(),
+			services().globals.keypair(),
+		)?);
+	}
+
+	Ok(get_remote_server_keys_batch::v2::Response {
+		server_keys,
+	})
+}
+
+/// Returns the keys response `server` signed, fetching it again if the one we
+/// have expires before `minimum_valid_until_ts`. The stored response is still
+/// returned if the origin can't be reached.
+async fn notary_keys_for(
+	server: &ServerName, minimum_valid_until_ts: MilliSecondsSinceUnixEpoch,
+) -> Result<Option<Raw<ServerSigningKeys>>> {
+	let stored = services().globals.signed_server_keys(server)?;
+	if stored
+		.as_ref()
+		.and_then(|keys| {
+			keys.get_field::<MilliSecondsSinceUnixEpoch>("valid_until_ts")
+				.ok()
+				.flatten()
+		})
+		.is_some_and(|valid_until_ts| valid_until_ts >= minimum_valid_until_ts)
+	{
+		return Ok(stored);
+	}
+
+	match fetch_server_keys(server).await {
+		Ok(keys) => Ok(Some(keys)),
+		Err(e) if stored.is_some() => {
+			debug_warn!("Failed to refresh signing keys of {server}, serving the ones we have: {e}");
+			Ok(stored)
+		},
+		Err(e) => Err(e),
+	}
+}
+
+/// Fetches the keys of `server` from the server itself, and stores them once
+/// their signature checks out.
+async fn fetch_server_keys(server: &ServerName) -> Result<Raw<ServerSigningKeys>> {
+	let server_key = services()
+		.sending
+		.send_federation_request(server, get_server_keys::v2::Request::new())
+		.await?
+		.server_key;
+
+	let keys = verify_server_keys(server, &server_key)?;
+	services().globals.add_signing_key(server, keys)?;
+	services()
+		.globals
+		.set_signed_server_keys(server, &server_key)?;
+
+	Ok(server_key)
+}
+
+/// Checks that a keys response is for `server` and signed by it with the keys
+/// it lists, so we never vouch for keys the origin did not publish.
+fn verify_server_keys(server: &ServerName, raw: &Raw<ServerSigningKeys>) -> Result<ServerSigningKeys> {
+	let keys = raw
+		.deserialize()
+		.map_err(|_| Error::BadServerResponse("Invalid server keys response."))?;
+	if keys.server_name != server {
+		return Err(Error::BadServerResponse("Server keys response is for another server."));
+	}
+
+	if keys.signatures.get(server).map_or(true, BTreeMap::is_empty) {
+		return Err(Error::BadServerResponse("Server keys response is not signed by the server."));
+	}
+
+	let object: CanonicalJsonObject = serde_json::from_str(raw.json().get())
+		.map_err(|_| Error::BadServerResponse("Invalid server keys response."))?;
+	let public_keys: PublicKeySet = keys
+		.verify_keys
+		.iter()
+		.map(|(key_id, key)| (key_id.to_string(), key.key.clone()))
+		.collect();
+
+	ruma::signatures::verify_json(&PublicKeyMap::from([(server.to_string(), public_keys)]), &object)
+		.map_err(|_| Error::BadServerResponse("Server keys response has an invalid signature."))?;
+
+	Ok(keys)
+}
+
+/// Adds our signature as `server_name` to `keys`, keeping the signatures
+/// already on them, so they can be returned to another server.
+fn sign_server_keys(
+	keys: &Raw<ServerSigningKeys>, server_name: &ServerName, keypair: &Ed25519KeyPair,
+) -> Result<Raw<ServerSigningKeys>> {
+	let mut object: CanonicalJsonObject =
+		serde_json::from_str(keys.json().get()).map_err(|e| Error::Err(format!("Failed to parse server keys: {e}")))?;
+
+	ruma::signatures::sign_json(server_name.as_str(), keypair, &mut object)
+		.map_err(|e| Error::Err(format!("Failed to sign server keys: {e}")))?;
+
+	let raw = to_raw_value(&object).map_err(|e| Error::Err(format!("Failed to serialize server keys: {e}")))?;
+	Ok(Raw::from_json(raw))
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		api::federation::discovery::{ServerSigningKeys, VerifyKey},
+		serde::{Base64, Raw},
+		server_name,
+		signatures::Ed25519KeyPair,
+		CanonicalJsonObject, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId,
+	};
+
+	use super::{sign_server_keys, verify_server_keys};
+
+	fn keypair(version: &str) -> Ed25519KeyPair {
+		let der = Ed25519KeyPair::generate().unwrap();
+		Ed25519KeyPair::from_der(&der, version.to_owned()).unwrap()
+	}
+
+	/// The keys response `remote.org` serves, signed with `keypair`
+	fn origin_keys(keypair: &Ed25519KeyPair) -> Raw<ServerSigningKeys> {
+		let key_id: OwnedServerSigningKeyId = format!("ed25519:{}", keypair.version()).try_into().unwrap();
+		let mut keys = ServerSigningKeys::new(server_name!("remote.org").to_owned(), MilliSecondsSinceUnixEpoch::now());
+		keys.verify_keys
+			.insert(key_id, VerifyKey::new(Base64::new(keypair.public_key().to_vec())));
+
+		let mut object: CanonicalJsonObject = serde_json::from_value(serde_json::to_value(&keys).unwrap()).unwrap();
+		ruma::signatures::sign_json("remote.org", keypair, &mut object).unwrap();
+		Raw::from_json(serde_json::value::to_raw_value(&object).unwrap())
+	}
+
+	#[test]
+	fn notary_keeps_origin_signature() {
+		let origin = keypair("remote");
+		let keys = origin_keys(&origin);
+		assert!(verify_server_keys(server_name!("remote.org"), &keys).is_ok());
+
+		let signed = sign_server_keys(&keys, server_name!("notary.org"), &keypair("notary"))
+			.unwrap()
+			.deserialize()
+			.unwrap();
+
+		let origin_key_id: OwnedServerSigningKeyId = "ed25519:remote".try_into().unwrap();
+		let notary_key_id: OwnedServerSigningKeyId = "ed25519:notary".try_into().unwrap();
+		assert_eq!(signed.server_name.as_str(), "remote.org");
+		assert!(signed.signatures[server_name!("remote.org")].contains_key(&origin_key_id));
+		assert!(signed.signatures[server_name!("notary.org")].contains_key(&notary_key_id));
+	}
+
+	#[test]
+	fn forged_server_keys_are_not_served() {
+		let keys = origin_keys(&keypair("remote"));
+		assert!(verify_server_keys(server_name!("other.org"), &keys).is_err());
+
+		// signed by a key the response doesn't list
+		let forged = keys
+			.json()
+			.get()
+			.replace(r#""ed25519:remote":{"key""#, r#""ed25519:forged":{"key""#);
+		let forged = Raw::from_json(serde_json::value::RawValue::from_string(forged).unwrap());
+		assert!(verify_server_keys(server_name!("remote.org"), &forged).is_err());
+	}
+}
@@ -7,14 +7,14 @@ use ruma::{
 		},
 		StateEventType, TimelineEventType,
 	},
-	RoomVersionId,
+	RoomId, RoomVersionId, UserId,
 };
 use serde_json::value::to_raw_value;
 use tracing::warn;
 
 use crate::{
 	service::{pdu::PduBuilder, user_is_local},
-	services, Error, Result, Ruma,
+	services, Error, PduEvent, Result, Ruma,
 };
 
 /// # `GET /_matrix/federation/v1/make_join/{roomId}/{userId}`
@@ -27,6 +27,8 @@ pub(crate) async fn create_join_event_template_route(
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Room is unknown to this server."));
 	}
 
+	check_room_federated(&body.room_id)?;
+
 	let origin = body.origin.as_ref().expect("server is authenticated");
 	if body.user_id.server_name() != origin {
 		return Err(Error::BadRequest(
@@ -91,6 +93,11 @@ pub(crate) async fn create_join_event_template_route(
 		})
 		.transpose()?;
 
+	let join_rule = join_rules_event_content
+		.as_ref()
+		.map_or(&JoinRule::Invite, |content| &content.join_rule);
+	check_invite_only(join_rule, &body.room_id, &body.user_id)?;
+
 	let join_authorized_via_users_server = if let Some(join_rules_event_content) = join_rules_event_content {
 		if let JoinRule::Restricted(r) | JoinRule::KnockRestricted(r) = join_rules_event_content.join_rule {
 			if r.allow
@@ -231,3 +238,128 @@ pub(crate) async fn create_join_event_template_route(
 		event: to_raw_value(&pdu_json).expect("CanonicalJson can be serialized to JSON"),
 	})
 }
+
+/// Refuses remote joins to rooms created with `m.federate` set to false or made
+/// local-only on this server.
+pub(super) fn check_room_federated(room_id: &RoomId) -> Result<()> {
+	refuse_unfederated_room(services().sending.is_room_federated(room_id)?)
+}
+
+fn refuse_unfederated_room(federated: bool) -> Result<()> {
+	if !federated {
+		return Err(Error::BadRequest(
+			ErrorKind::forbidden(),
+			"This room does not federate with other servers.",
+		));
+	}
+
+	Ok(())
+}
+
+/// Returns the room's current join rule; rooms without one are invite-only.
+pub(super) fn room_join_rule(room_id: &RoomId) -> Result<JoinRule> {
+	join_rule_of(
+		services()
+			.rooms
+			.state_accessor
+			.room_state_get(room_id, &StateEventType::RoomJoinRules, "")?
+			.as_deref(),
+	)
+}
+
+fn join_rule_of(join_rules_event: Option<&PduEvent>) -> Result<JoinRule> {
+	join_rules_event.map_or(Ok(JoinRule::Invite), |event| {
+		serde_json::from_str(event.content.get())
+			.map(|content: RoomJoinRulesEventContent| content.join_rule)
+			.map_err(|_| Error::bad_database("Invalid join rules event in db."))
+	})
+}
+
+/// Refuses a remote join to an invite-only or knock room up front when the
+/// user holds no invite, rather than leaving it to the auth rules.
+pub(super) fn check_invite_only(join_rule: &JoinRule, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+	refuse_uninvited_join(join_rule, || {
+		Ok(services().rooms.state_cache.is_invited(user_id, room_id)?
+			|| services().rooms.state_cache.is_joined(user_id, room_id)?)
+	})
+}
+
+/// The membership is only looked up for rooms that need an invite.
+fn refuse_uninvited_join(join_rule: &JoinRule, invited_or_joined: impl FnOnce() -> Result<bool>) -> Result<()> {
+	if join_rule_requires_invite(join_rule) && !invited_or_joined()? {
+		return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not invited to this room."));
+	}
+
+	Ok(())
+}
+
+/// Restricted rooms are checked separately against their allow rules.
+fn join_rule_requires_invite(join_rule: &JoinRule) -> bool {
+	matches!(join_rule, JoinRule::Invite | JoinRule::Knock | JoinRule::Private)
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		api::client::error::ErrorKind,
+		events::room::{
+			create::RoomCreateEventContent,
+			join_rules::{JoinRule, Restricted},
+		},
+	};
+	use serde_json::json;
+
+	use super::{join_rule_of, join_rule_requires_invite, refuse_unfederated_room, refuse_uninvited_join};
+	use crate::{service::rooms::state_accessor::create_event_federates, Error, PduEvent};
+
+	fn state_event(kind: &str, content: serde_json::Value) -> PduEvent {
+		PduEvent::test_event(json!({ "type": kind, "state_key": "", "content": content }))
+	}
+
+	fn join_rules_event(join_rule: &str) -> PduEvent { state_event("m.room.join_rules", json!({ "join_rule": join_rule })) }
+
+	#[test]
+	fn invite_only_room_requires_invite() {
+		assert!(join_rule_requires_invite(&JoinRule::Invite));
+		assert!(join_rule_requires_invite(&JoinRule::Knock));
+	}
+
+	#[test]
+	fn public_and_restricted_rooms_do_not_require_invite() {
+		assert!(!join_rule_requires_invite(&JoinRule::Public));
+		assert!(!join_rule_requires_invite(&JoinRule::Restricted(Restricted::new(Vec::new()))));
+	}
+
+	#[test]
+	fn uninvited_join_to_invite_only_room_is_refused() {
+		let invite = join_rule_of(Some(&join_rules_event("invite"))).unwrap();
+		assert!(matches!(
+			refuse_uninvited_join(&invite, || Ok(false)),
+			Err(Error::BadRequest(ErrorKind::Forbidden { .. }, _))
+		));
+		assert!(refuse_uninvited_join(&invite, || Ok(true)).is_ok());
+
+		// a room without join rules is invite-only
+		let missing = join_rule_of(None).unwrap();
+		assert!(refuse_uninvited_join(&missing, || Ok(false)).is_err());
+
+		// public rooms don't look up the membership at all
+		let public = join_rule_of(Some(&join_rules_event("public"))).unwrap();
+		assert!(refuse_uninvited_join(&public, || panic!("membership looked up")).is_ok());
+	}
+
+	#[test]
+	fn remote_join_to_non_federated_room_is_refused() {
+		// the create event content as createRoom writes it for `"m.federate": false`
+		let mut content = RoomCreateEventContent::new_v11();
+		content.federate = false;
+		let create = state_event("m.room.create", serde_json::to_value(content).unwrap());
+		assert!(matches!(
+			refuse_unfederated_room(create_event_federates(Some(&create)).unwrap()),
+			Err(Error::BadRequest(ErrorKind::Forbidden { .. }, _))
+		));
+
+		let create = state_event("m.room.create", json!({ "room_version": "11" }));
+		assert!(refuse_unfederated_room(create_event_federates(Some(&create)).unwrap()).is_ok());
+	}
+}
@@ -1,4 +1,4 @@
-use axum_client_ip::InsecureClientIp;
+use axum_client_ip::SecureClientIp;
 use ruma::{
 	api::{
 		client::error::ErrorKind,
@@ -14,7 +14,7 @@ use crate::{services, Error, Result, Ruma};
 /// Lists the public rooms on this server.
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn get_public_rooms_filtered_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<get_public_rooms_filtered::v1::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<get_public_rooms_filtered::v1::Request>,
 ) -> Result<get_public_rooms_filtered::v1::Response> {
 	if !services()
 		.globals
@@ -46,7 +46,7 @@ pub(crate) async fn get_public_rooms_filtered_route(
 /// Lists the public rooms on this server.
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn get_public_rooms_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<get_public_rooms::v1::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<get_public_rooms::v1::Request>,
 ) -> Result<get_public_rooms::v1::Response> {
 	if !services()
 		.globals
@@ -1,14 +1,20 @@
+use std::{cmp::Reverse, time::Instant};
+
+use conduit::debug_info;
 use get_profile_information::v1::ProfileField;
 use rand::seq::SliceRandom;
 use ruma::{
 	api::{
-		client::error::ErrorKind,
+		client::error::{ErrorKind, RetryAfter},
 		federation::query::{get_profile_information, get_room_information},
 	},
-	OwnedServerName,
+	OwnedServerName, ServerName,
 };
 
-use crate::{service::server_is_ours, services, Error, Result, Ruma};
+use crate::{
+	service::{server_is_ours, users::Profile},
+	services, Error, Result, Ruma,
+};
 
 /// # `GET /_matrix/federation/v1/query/directory`
 ///
@@ -33,15 +39,9 @@ pub(crate) async fn get_room_information_route(
 	servers.dedup();
 
 	servers.shuffle(&mut rand::thread_rng());
-
-	// insert our server as the very first choice if in list
-	if let Some(server_index) = servers
-		.iter()
-		.position(|server| server == services().globals.server_name())
-	{
-		servers.swap_remove(server_index);
-		servers.insert(0, services().globals.server_name().to_owned());
-	}
+	order_servers(&mut servers, services().globals.server_name(), |server| {
+		services().sending.last_success(server)
+	});
 
 	Ok(get_room_information::v1::Response {
 		room_id,
@@ -49,6 +49,15 @@ pub(crate) async fn get_room_information_route(
 	})
 }
 
+/// Puts our server first, then servers we most recently sent a transaction to
+/// successfully, then the rest in their existing (shuffled) order.
+fn order_servers<F>(servers: &mut [OwnedServerName], ours: &ServerName, last_success: F)
+where
+	F: Fn(&ServerName) -> Option<Instant>,
+{
+	servers.sort_by_cached_key(|server| (server != ours, Reverse(last_success(server))));
+}
+
 /// # `GET /_matrix/federation/v1/query/profile`
 ///
 ///
@@ -66,6 +75,9 @@ pub(crate) async fn get_profile_information_route(
 		));
 	}
 
+	let origin = body.origin.as_ref().expect("server is authenticated");
+	federation_query_rate_limit_check(origin).await?;
+
 	if !server_is_ours(body.user_id.server_name()) {
 		return Err(Error::BadRequest(
 			ErrorKind::InvalidParam,
@@ -73,30 +85,73 @@ pub(crate) async fn get_profile_information_route(
 		));
 	}
 
-	let mut displayname = None;
-	let mut avatar_url = None;
-	let mut blurhash = None;
-
+	let mut profile = services().users.federation_profile(&body.user_id)?;
 	match &body.field {
 		Some(ProfileField::DisplayName) => {
-			displayname = services().users.displayname(&body.user_id)?;
-		},
-		Some(ProfileField::AvatarUrl) => {
-			avatar_url = services().users.avatar_url(&body.user_id)?;
-			blurhash = services().users.blurhash(&body.user_id)?;
+			profile.avatar_url = None;
+			profile.blurhash = None;
 		},
+		Some(ProfileField::AvatarUrl) => profile.displayname = None,
 		// TODO: what to do with custom
-		Some(_) => {},
-		None => {
-			displayname = services().users.displayname(&body.user_id)?;
-			avatar_url = services().users.avatar_url(&body.user_id)?;
-			blurhash = services().users.blurhash(&body.user_id)?;
-		},
+		Some(_) => profile = Profile::default(),
+		None => {},
 	}
 
 	Ok(get_profile_information::v1::Response {
-		displayname,
-		avatar_url,
-		blurhash,
+		displayname: profile.displayname,
+		avatar_url: profile.avatar_url,
+		blurhash: profile.blurhash,
 	})
 }
+
+/// Applies the per-origin rate limit for profile, device list and key queries,
+/// which a remote server could otherwise use to scan our users.
+pub(crate) async fn federation_query_rate_limit_check(origin: &ServerName) -> Result<()> {
+	if let Err(wait) = services().globals.federation_query_rate_limit(origin).await {
+		debug_info!("Rate limiting federation queries from {origin}");
+		return Err(Error::BadRequest(
+			ErrorKind::LimitExceeded {
+				retry_after: Some(RetryAfter::Delay(wait)),
+			},
+			"Too many queries sent recently, try again later.",
+		));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::{Duration, Instant};
+
+	use ruma::{owned_server_name, server_name};
+
+	use super::order_servers;
+
+	#[test]
+	fn recently_reachable_servers_rank_above_unknown_ones() {
+		let now = Instant::now();
+		let mut servers = vec![
+			owned_server_name!("unknown.example"),
+			owned_server_name!("stale.example"),
+			owned_server_name!("ours.example"),
+			owned_server_name!("good.example"),
+		];
+
+		order_servers(&mut servers, server_name!("ours.example"), |server| match server.as_str() {
+			"good.example" => Some(now + Duration::from_secs(3600)),
+			"stale.example" => Some(now),
+			_ => None,
+		});
+
+		assert_eq!(
+			servers,
+			[
+				owned_server_name!("ours.example"),
+				owned_server_name!("good.example"),
+				owned_server_name!("stale.example"),
+				owned_server_name!("unknown.example"),
+			]
+		);
+	}
+}
@@ -1,6 +1,6 @@
 use std::{collections::BTreeMap, time::Instant};
 
-use axum_client_ip::InsecureClientIp;
+use axum_client_ip::SecureClientIp;
 use conduit::debug_warn;
 use ruma::{
 	api::{
@@ -28,7 +28,7 @@ use crate::{
 /// Push EDUs and PDUs to this server.
 #[tracing::instrument(skip_all, fields(%client_ip))]
 pub(crate) async fn send_transaction_message_route(
-	InsecureClientIp(client_ip): InsecureClientIp, body: Ruma<send_transaction_message::v1::Request>,
+	SecureClientIp(client_ip): SecureClientIp, body: Ruma<send_transaction_message::v1::Request>,
 ) -> Result<send_transaction_message::v1::Response> {
 	let origin = body.origin.as_ref().expect("server is authenticated");
 
@@ -11,7 +11,10 @@ use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tokio::sync::RwLock;
 use tracing::warn;
 
-use crate::{service::pdu::gen_event_id_canonical_json, services, Error, PduEvent, Result, Ruma};
+use crate::{
+	service::{pdu::gen_event_id_canonical_json, user_is_local},
+	services, Error, PduEvent, Result, Ruma,
+};
 
 /// helper method for /send_join v1 and v2
 async fn create_join_event(
@@ -21,6 +24,8 @@ async fn create_join_event(
 		return Err(Error::BadRequest(ErrorKind::NotFound, "Room is unknown to this server."));
 	}
 
+	super::make_join::check_room_federated(room_id)?;
+
 	// ACL check origin server
 	services().rooms.event_handler.acl_check(origin, room_id)?;
 
@@ -124,6 +129,28 @@ async fn create_join_event(
 		));
 	}
 
+	super::make_join::check_invite_only(&super::make_join::room_join_rule(room_id)?, room_id, &sender)?;
+
+	// a restricted join naming one of our users as the authoriser is vouched for by
+	// our signature, so make sure that user could actually have invited them
+	if let Some(authorising_user) = content.get("join_authorised_via_users_server") {
+		let authorising_user: OwnedUserId = serde_json::from_value(authorising_user.clone().into())
+			.map_err(|_| Error::BadRequest(ErrorKind::BadJson, "join_authorised_via_users_server is not a user ID."))?;
+
+		if user_is_local(&authorising_user)
+			&& !services()
+				.rooms
+				.state_accessor
+				.user_can_authorise_join(room_id, &authorising_user)?
+		{
+			warn!("Rejecting join of {sender} to {room_id} authorised via {authorising_user} who cannot invite");
+			return Err(Error::BadRequest(
+				ErrorKind::forbidden(),
+				"The user authorising this join cannot invite users to the room.",
+			));
+		}
+	}
+
 	ruma::signatures::hash_and_sign_event(
 		services().globals.server_name().as_str(),
 		services().globals.keypair(),
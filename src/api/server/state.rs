@@ -1,6 +1,11 @@
 use std::sync::Arc;
 
-use ruma::api::{client::error::ErrorKind, federation::event::get_room_state};
+use ruma::{
+	api::{client::error::ErrorKind, federation::event::get_room_state},
+	CanonicalJsonObject, EventId,
+};
+use serde_json::value::RawValue as RawJsonValue;
+use tracing::warn;
 
 use crate::{services, Error, PduEvent, Result, Ruma};
 
@@ -35,41 +40,112 @@ pub(crate) async fn get_room_state_route(
 		.pdu_shortstatehash(&body.event_id)?
 		.ok_or_else(|| Error::BadRequest(ErrorKind::NotFound, "Pdu state not found."))?;
 
-	let pdus = services()
+	let max_size = services().globals.config.max_state_response_size;
+	let mut size = 0_usize;
+
+	let state_ids = services()
 		.rooms
 		.state_accessor
 		.state_full_ids(shortstatehash)
 		.await?
-		.into_values()
-		.map(|id| {
-			PduEvent::convert_to_outgoing_federation_event(
-				services()
-					.rooms
-					.timeline
-					.get_pdu_json(&id)
-					.unwrap()
-					.unwrap(),
-			)
-		})
-		.collect();
+		.into_values();
+	let pdus = outgoing_pdus(state_ids, get_pdu_json, max_size, &mut size)?;
 
 	let auth_chain_ids = services()
 		.rooms
 		.auth_chain
 		.event_ids_iter(&body.room_id, vec![Arc::from(&*body.event_id)])
 		.await?;
+	let auth_chain = outgoing_pdus(auth_chain_ids, get_pdu_json, max_size, &mut size)?;
 
 	Ok(get_room_state::v1::Response {
-		auth_chain: auth_chain_ids
-			.filter_map(|id| {
-				services()
-					.rooms
-					.timeline
-					.get_pdu_json(&id)
-					.ok()?
-					.map(PduEvent::convert_to_outgoing_federation_event)
-			})
-			.collect(),
+		auth_chain,
 		pdus,
 	})
 }
+
+fn get_pdu_json(event_id: &EventId) -> Result<Option<CanonicalJsonObject>> {
+	services().rooms.timeline.get_pdu_json(event_id)
+}
+
+/// Converts the PDUs for `ids` to their outgoing federation format. PDUs we
+/// don't have are skipped, and the response is refused once the running
+/// `size` exceeds `max_size` so huge rooms can't exhaust our memory.
+fn outgoing_pdus<I, F>(ids: I, get_pdu_json: F, max_size: usize, size: &mut usize) -> Result<Vec<Box<RawJsonValue>>>
+where
+	I: Iterator<Item = Arc<EventId>>,
+	F: Fn(&EventId) -> Result<Option<CanonicalJsonObject>>,
+{
+	let mut pdus = Vec::new();
+	for id in ids {
+		let pdu_json = match get_pdu_json(&id) {
+			Ok(Some(pdu_json)) => pdu_json,
+			Ok(None) => {
+				warn!("Could not find PDU {id} referenced by room state, skipping");
+				continue;
+			},
+			Err(e) => {
+				warn!("Failed to fetch PDU {id} referenced by room state, skipping: {e}");
+				continue;
+			},
+		};
+
+		let pdu = PduEvent::convert_to_outgoing_federation_event(pdu_json);
+		*size = size.saturating_add(pdu.get().len());
+		if *size > max_size {
+			return Err(Error::BadRequest(
+				ErrorKind::TooLarge,
+				"Room state is too large to send, use /state_ids instead.",
+			));
+		}
+
+		pdus.push(pdu);
+	}
+
+	Ok(pdus)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use ruma::{api::client::error::ErrorKind, event_id, CanonicalJsonObject, CanonicalJsonValue, EventId};
+
+	use super::outgoing_pdus;
+	use crate::{Error, Result};
+
+	fn get_pdu_json(event_id: &EventId) -> Result<Option<CanonicalJsonObject>> {
+		if event_id == event_id!("$missing:example.com") {
+			return Ok(None);
+		}
+
+		let mut pdu = CanonicalJsonObject::new();
+		pdu.insert("event_id".to_owned(), CanonicalJsonValue::String(event_id.to_string()));
+		Ok(Some(pdu))
+	}
+
+	fn state_ids() -> impl Iterator<Item = Arc<EventId>> {
+		[
+			event_id!("$create:example.com"),
+			event_id!("$missing:example.com"),
+			event_id!("$member:example.com"),
+		]
+		.into_iter()
+		.map(Arc::from)
+	}
+
+	#[test]
+	fn missing_pdus_are_skipped() {
+		let mut size = 0;
+		let pdus = outgoing_pdus(state_ids(), get_pdu_json, usize::MAX, &mut size).unwrap();
+		assert_eq!(pdus.len(), 2);
+		assert!(pdus.iter().all(|pdu| !pdu.get().contains("$missing")));
+	}
+
+	#[test]
+	fn oversized_state_is_refused() {
+		let mut size = 0;
+		let result = outgoing_pdus(state_ids(), get_pdu_json, 16, &mut size);
+		assert!(matches!(result, Err(Error::BadRequest(ErrorKind::TooLarge, _))));
+	}
+}
@@ -6,8 +6,9 @@ use ruma::api::{
 	},
 };
 
+use super::federation_query_rate_limit_check;
 use crate::{
-	client::{claim_keys_helper, get_keys_helper},
+	client::{add_unsigned_device_display_name, claim_keys_helper, get_keys_helper},
 	service::user_is_local,
 	services, Error, Result, Ruma,
 };
@@ -24,6 +25,7 @@ pub(crate) async fn get_devices_route(body: Ruma<get_devices::v1::Request>) -> R
 	}
 
 	let origin = body.origin.as_ref().expect("server is authenticated");
+	federation_query_rate_limit_check(origin).await?;
 
 	Ok(get_devices::v1::Response {
 		user_id: body.user_id.clone(),
@@ -38,17 +40,19 @@ pub(crate) async fn get_devices_route(body: Ruma<get_devices::v1::Request>) -> R
 			.all_devices_metadata(&body.user_id)
 			.filter_map(Result::ok)
 			.filter_map(|metadata| {
-				let device_id_string = metadata.device_id.as_str().to_owned();
-				let device_display_name = if services().globals.allow_device_name_federation() {
-					metadata.display_name
+				let allow_device_name_federation = services().globals.allow_device_name_federation();
+				let device_display_name = if allow_device_name_federation {
+					metadata.display_name.clone()
 				} else {
-					Some(device_id_string)
+					Some(metadata.device_id.as_str().to_owned())
 				};
+				let mut keys = services()
+					.users
+					.get_device_keys(&body.user_id, &metadata.device_id)
+					.ok()??;
+				add_unsigned_device_display_name(&mut keys, metadata.clone(), allow_device_name_federation).ok()?;
 				Some(UserDevice {
-					keys: services()
-						.users
-						.get_device_keys(&body.user_id, &metadata.device_id)
-						.ok()??,
+					keys,
 					device_id: metadata.device_id,
 					device_display_name,
 				})
@@ -67,6 +71,9 @@ pub(crate) async fn get_devices_route(body: Ruma<get_devices::v1::Request>) -> R
 ///
 /// Gets devices and identity keys for the given users.
 pub(crate) async fn get_keys_route(body: Ruma<get_keys::v1::Request>) -> Result<get_keys::v1::Response> {
+	let origin = body.origin.as_ref().expect("server is authenticated");
+	federation_query_rate_limit_check(origin).await?;
+
 	if body.device_keys.iter().any(|(u, _)| !user_is_local(u)) {
 		return Err(Error::BadRequest(
 			ErrorKind::InvalidParam,
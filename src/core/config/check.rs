@@ -1,9 +1,10 @@
 #[cfg(unix)]
 use std::path::Path; // not unix specific, just only for UNIX sockets stuff and *nix container checks
 
+use ruma::api::client::{discovery::discover_support::ContactRole, room::Visibility};
 use tracing::{debug, error, info, warn};
 
-use crate::{error::Error, Config};
+use crate::{error::Error, utils, Config};
 
 pub fn check(config: &Config) -> Result<(), Error> {
 	config.warn_deprecated();
@@ -27,6 +28,12 @@ pub fn check(config: &Config) -> Result<(), Error> {
 		));
 	}
 
+	if config.proxy_protocol && config.tls.is_some() {
+		return Err(Error::bad_config(
+			"proxy_protocol cannot be combined with direct TLS; terminate TLS at the load balancer instead.",
+		));
+	}
+
 	config.get_bind_addrs().iter().for_each(|addr| {
 		if addr.ip().is_loopback() && cfg!(unix) {
 			debug!("Found loopback listening address {addr}, running checks if we're in a container.",);
@@ -72,6 +79,25 @@ pub fn check(config: &Config) -> Result<(), Error> {
 		));
 	}
 
+	if config.database_backup_interval_hours > 0
+		&& config
+			.database_backup_path
+			.as_ref()
+			.map_or(true, |path| path.as_os_str().is_empty())
+	{
+		warn!("database_backup_interval_hours is set but database_backup_path is not, no backups will be made.");
+	}
+
+	if !config.sso_identity_providers.is_empty() && config.jwt_secret.is_none() {
+		warn!(
+			"sso_identity_providers is set but jwt_secret is not, SSO logins cannot be completed with a login token."
+		);
+	}
+
+	if config.database_backup_interval_hours > 0 && config.database_backend != "rocksdb" {
+		warn!("database_backup_interval_hours is set but only RocksDB supports online backups, ignoring.");
+	}
+
 	// yeah, unless the user built a debug build hopefully for local testing only
 	if config.server_name == "your.server.name" && !cfg!(debug_assertions) {
 		return Err(Error::bad_config(
@@ -88,10 +114,106 @@ pub fn check(config: &Config) -> Result<(), Error> {
 		return Err(Error::bad_config("Registration token was specified but is empty (\"\")"));
 	}
 
+	if config.registration_shared_secret == Some(String::new()) {
+		return Err(Error::bad_config(
+			"Registration shared secret was specified but is empty (\"\")",
+		));
+	}
+
+	if let Err(e) = utils::hash::params(
+		config.password_hash_memory_kib,
+		config.password_hash_iterations,
+		config.password_hash_parallelism,
+	) {
+		return Err(Error::bad_config(&format!("Invalid password hashing parameters: {e}")));
+	}
+
+	if !(1..=20).contains(&config.max_prev_events) {
+		return Err(Error::bad_config("max_prev_events must be between 1 and 20."));
+	}
+
+	if config.max_auth_chain_fetch == 0 {
+		return Err(Error::bad_config("max_auth_chain_fetch must be greater than 0."));
+	}
+
+	if config.federation_hierarchy_max_depth == 0 {
+		return Err(Error::bad_config("federation_hierarchy_max_depth must be greater than 0."));
+	}
+
+	if config.client_max_connections == 0 {
+		return Err(Error::bad_config("client_max_connections must be greater than 0."));
+	}
+
+	if config.sync_min_timeout_s > config.sync_max_timeout_s {
+		return Err(Error::bad_config(
+			"sync_min_timeout_s must not be greater than sync_max_timeout_s.",
+		));
+	}
+
+	// a request timeout at or below the sync long-poll would cut idle syncs off
+	if config.client_request_timeout_s <= config.sync_max_timeout_s {
+		return Err(Error::bad_config(
+			"client_request_timeout_s must be greater than sync_max_timeout_s.",
+		));
+	}
+
+	if !(1..=65_536).contains(&config.max_event_size) {
+		return Err(Error::bad_config("max_event_size must be between 1 and 65536."));
+	}
+
 	if config.max_request_size < 5_120_000 {
 		return Err(Error::bad_config("Max request size is less than 5MB. Please increase it."));
 	}
 
+	if let Some(role) = &config.well_known.support_role {
+		if !valid_support_role(role) {
+			return Err(Error::bad_config(
+				"well_known support_role must be one of m.role.admin, m.role.security or m.role.moderator.",
+			));
+		}
+	}
+
+	for contact in &config.well_known.support_contacts {
+		if !valid_support_role(&contact.role) {
+			return Err(Error::bad_config(
+				"Every well_known support_contacts role must be one of m.role.admin, m.role.security or \
+				 m.role.moderator.",
+			));
+		}
+
+		if contact
+			.email_address
+			.as_ref()
+			.map_or(true, String::is_empty)
+			&& contact.matrix_id.is_none()
+		{
+			return Err(Error::bad_config(
+				"Every well_known support_contacts entry needs an email_address or matrix_id.",
+			));
+		}
+	}
+
+	if config.default_room_directory_visibility == Some(Visibility::Public)
+		&& (config.lockdown_public_room_directory || !config.allow_public_room_directory_publish)
+	{
+		warn!(
+			"default_room_directory_visibility is \"public\" but non-admins may not publish rooms to the room \
+			 directory, so they will not be able to create rooms."
+		);
+	}
+
+	if config.server_key_validity_period_s == 0 {
+		return Err(Error::bad_config("server_key_validity_period_s must be greater than 0."));
+	}
+
+	if let Some(contact) = &config.federation_user_agent {
+		if http::HeaderValue::from_str(contact).is_err() {
+			return Err(Error::bad_config(
+				"federation_user_agent contains characters that are not allowed in an HTTP header.",
+			));
+		}
+	}
+
 	// check if user specified valid IP CIDR ranges on startup
 	for cidr in &config.ip_range_denylist {
 		if let Err(e) = ipaddress::IPAddress::parse(cidr) {
@@ -100,6 +222,23 @@ pub fn check(config: &Config) -> Result<(), Error> {
 		}
 	}
 
+	if !config.allowed_room_versions_for_creation.is_empty()
+		&& !config
+			.allowed_room_versions_for_creation
+			.contains(&config.default_room_version)
+	{
+		return Err(Error::bad_config(
+			"default_room_version must be one of allowed_room_versions_for_creation.",
+		));
+	}
+
+	for cidr in &config.trusted_proxies {
+		if let Err(e) = ipaddress::IPAddress::parse(cidr) {
+			error!("Error parsing trusted proxy CIDR range from string: {e}");
+			return Err(Error::bad_config("Error parsing trusted_proxies CIDR ranges from strings"));
+		}
+	}
+
 	if config.allow_registration
 		&& !config.yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse
 		&& config.registration_token.is_none()
@@ -165,3 +304,9 @@ For security and safety reasons, conduwuit will shut down. If you are extra sure
 
 	Ok(())
 }
+
+/// Checks a support contact role is one defined by the spec rather than a
+/// custom string, which clients wouldn't understand.
+fn valid_support_role(role: &ContactRole) -> bool {
+	matches!(role, ContactRole::Admin | ContactRole::Security | ContactRole::Moderator)
+}
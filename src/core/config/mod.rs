@@ -16,10 +16,15 @@ use figment::{
 use itertools::Itertools;
 use regex::RegexSet;
 use ruma::{
-	api::client::discovery::discover_support::ContactRole, OwnedRoomId, OwnedServerName, OwnedUserId, RoomVersionId,
+	api::client::{
+		discovery::discover_support::{Contact, ContactRole},
+		room::Visibility,
+		session::get_login_types::v3::IdentityProvider,
+	},
+	OwnedRoomId, OwnedServerName, OwnedUserId, RoomVersionId,
 };
 use serde::{de::IgnoredAny, Deserialize};
-use tracing::{debug, error, warn};
+use tracing::{debug, warn};
 use url::Url;
 
 pub use self::check::check;
@@ -54,9 +59,15 @@ pub struct Config {
 	#[serde(default = "default_port")]
 	port: ListeningPort,
 	pub tls: Option<TlsConfig>,
+	#[serde(default)]
+	pub proxy_protocol: bool,
 	pub unix_socket_path: Option<PathBuf>,
 	#[serde(default = "default_unix_socket_perms")]
 	pub unix_socket_perms: u32,
+	/// Set when both `address` and `unix_socket_path` were given, in which
+	/// case clients are served on the UNIX socket and over TCP at once.
+	#[serde(skip)]
+	pub dual_listening: bool,
 	pub server_name: OwnedServerName,
 	#[serde(default = "default_database_backend")]
 	pub database_backend: String,
@@ -64,6 +75,8 @@ pub struct Config {
 	pub database_backup_path: Option<PathBuf>,
 	#[serde(default = "default_database_backups_to_keep")]
 	pub database_backups_to_keep: i16,
+	#[serde(default)]
+	pub database_backup_interval_hours: u64,
 	#[serde(default = "default_db_cache_capacity_mb")]
 	pub db_cache_capacity_mb: f64,
 	#[serde(default = "default_new_user_displayname_suffix")]
@@ -115,8 +128,40 @@ pub struct Config {
 
 	#[serde(default = "default_max_request_size")]
 	pub max_request_size: u32,
+	pub media_user_quota_bytes: Option<u64>,
+	#[serde(default)]
+	pub media_strip_exif: bool,
+	#[serde(default = "default_client_max_connections")]
+	pub client_max_connections: usize,
+	#[serde(default = "default_client_request_timeout_s")]
+	pub client_request_timeout_s: u64,
+	#[serde(default = "default_client_keepalive_s")]
+	pub client_keepalive_s: u64,
+	#[serde(default)]
+	pub sync_min_timeout_s: u64,
+	#[serde(default = "default_sync_max_timeout_s")]
+	pub sync_max_timeout_s: u64,
+
+	#[serde(default = "default_password_hash_memory_kib")]
+	pub password_hash_memory_kib: u32,
+	#[serde(default = "default_password_hash_iterations")]
+	pub password_hash_iterations: u32,
+	#[serde(default = "default_password_hash_parallelism")]
+	pub password_hash_parallelism: u32,
 	#[serde(default = "default_max_fetch_prev_events")]
 	pub max_fetch_prev_events: u16,
+	#[serde(default = "default_max_state_response_size")]
+	pub max_state_response_size: usize,
+	#[serde(default = "default_max_prev_events")]
+	pub max_prev_events: usize,
+	#[serde(default = "default_max_auth_chain_fetch")]
+	pub max_auth_chain_fetch: usize,
+	#[serde(default = "default_federation_hierarchy_max_depth")]
+	pub federation_hierarchy_max_depth: usize,
+	#[serde(default = "default_max_event_size")]
+	pub max_event_size: usize,
+	#[serde(default = "default_server_key_validity_period_s")]
+	pub server_key_validity_period_s: u64,
 
 	#[serde(default = "default_request_conn_timeout")]
 	pub request_conn_timeout: u64,
@@ -138,6 +183,7 @@ pub struct Config {
 	pub federation_idle_timeout: u64,
 	#[serde(default = "default_federation_idle_per_host")]
 	pub federation_idle_per_host: u16,
+	pub federation_user_agent: Option<String>,
 	#[serde(default = "default_sender_timeout")]
 	pub sender_timeout: u64,
 	#[serde(default = "default_sender_idle_timeout")]
@@ -150,14 +196,29 @@ pub struct Config {
 	pub appservice_idle_timeout: u64,
 	#[serde(default = "default_pusher_idle_timeout")]
 	pub pusher_idle_timeout: u64,
+	#[serde(default = "default_pusher_max_failures")]
+	pub pusher_max_failures: u32,
+	#[serde(default = "default_pusher_gateway_backoff_base_s")]
+	pub pusher_gateway_backoff_base_s: u64,
+	#[serde(default = "default_pusher_gateway_backoff_max_s")]
+	pub pusher_gateway_backoff_max_s: u64,
+	pub smtp_server: Option<String>,
+	pub smtp_username: Option<String>,
+	pub smtp_password: Option<String>,
+	pub smtp_from: Option<String>,
+	#[serde(default = "Vec::new")]
+	pub trusted_identity_servers: Vec<String>,
 
 	#[serde(default)]
 	pub allow_registration: bool,
 	#[serde(default)]
 	pub yes_i_am_very_very_sure_i_want_an_open_registration_server_prone_to_abuse: bool,
 	pub registration_token: Option<String>,
+	pub registration_shared_secret: Option<String>,
 	#[serde(default = "true_fn")]
 	pub allow_encryption: bool,
+	#[serde(default)]
+	pub validate_backup_room_encryption: bool,
 	#[serde(default = "true_fn")]
 	pub allow_federation: bool,
 	#[serde(default)]
@@ -168,10 +229,17 @@ pub struct Config {
 	pub turn_allow_guests: bool,
 	#[serde(default)]
 	pub lockdown_public_room_directory: bool,
+	#[serde(default = "true_fn")]
+	pub allow_public_room_directory_publish: bool,
+	pub default_room_directory_visibility: Option<Visibility>,
+	#[serde(default)]
+	pub user_directory_search_scope: UserDirectorySearchScope,
 	#[serde(default)]
 	pub allow_device_name_federation: bool,
 	#[serde(default = "true_fn")]
 	pub allow_profile_lookup_federation_requests: bool,
+	#[serde(default = "default_federation_profile_cache_ttl_s")]
+	pub federation_profile_cache_ttl_s: u64,
 	#[serde(default = "true_fn")]
 	pub allow_room_creation: bool,
 	#[serde(default = "true_fn")]
@@ -179,6 +247,9 @@ pub struct Config {
 	#[serde(default = "default_default_room_version")]
 	pub default_room_version: RoomVersionId,
 	#[serde(default)]
+	pub allowed_room_versions_for_creation: Vec<RoomVersionId>,
+	pub default_call_power_level: Option<i64>,
+	#[serde(default)]
 	pub well_known: WellKnownConfig,
 	#[serde(default)]
 	#[cfg(feature = "perf_measurements")]
@@ -195,10 +266,16 @@ pub struct Config {
 	#[serde(default)]
 	pub proxy: ProxyConfig,
 	pub jwt_secret: Option<String>,
+	#[serde(default)]
+	pub sso_identity_providers: Vec<IdentityProvider>,
 	#[serde(default = "default_trusted_servers")]
 	pub trusted_servers: Vec<OwnedServerName>,
 	#[serde(default = "true_fn")]
 	pub query_trusted_key_servers_first: bool,
+	#[serde(default)]
+	pub allow_notary_server: bool,
+	#[serde(default)]
+	pub allow_synapse_admin_api: bool,
 	#[serde(default = "default_log")]
 	pub log: String,
 	#[serde(default)]
@@ -257,6 +334,16 @@ pub struct Config {
 	#[serde(default = "default_notification_push_path")]
 	pub notification_push_path: String,
 
+	#[serde(default = "true_fn")]
+	pub update_membership_on_profile_change: bool,
+	pub profile_update_membership_max_room_size: Option<u64>,
+
+	#[serde(default = "true_fn")]
+	pub redact_events_on_erase: bool,
+
+	#[serde(default = "true_fn")]
+	pub bundled_aggregations: bool,
+
 	#[serde(default = "true_fn")]
 	pub allow_local_presence: bool,
 	#[serde(default = "true_fn")]
@@ -292,6 +379,8 @@ pub struct Config {
 	pub gzip_compression: bool,
 	#[serde(default)]
 	pub brotli_compression: bool,
+	#[serde(default = "default_compression_min_size")]
+	pub compression_min_size: u16,
 
 	#[serde(default)]
 	pub allow_guest_registration: bool,
@@ -309,6 +398,8 @@ pub struct Config {
 
 	#[serde(default = "default_ip_range_denylist")]
 	pub ip_range_denylist: Vec<String>,
+	#[serde(default = "default_trusted_proxies")]
+	pub trusted_proxies: Vec<String>,
 
 	#[serde(default = "Vec::new")]
 	pub url_preview_domain_contains_allowlist: Vec<String>,
@@ -335,9 +426,25 @@ pub struct Config {
 	pub startup_netburst: bool,
 	#[serde(default = "default_startup_netburst_keep")]
 	pub startup_netburst_keep: i64,
+	#[serde(default)]
+	pub to_device_ttl_days: u64,
+	#[serde(default)]
+	pub federation_transaction_batch_ms: u64,
 
 	#[serde(default)]
 	pub block_non_admin_invites: bool,
+	#[serde(default = "default_invite_rate_limit_per_user")]
+	pub invite_rate_limit_per_user: u32,
+	#[serde(default = "default_invite_rate_limit_per_room")]
+	pub invite_rate_limit_per_room: u32,
+	#[serde(default = "default_federation_query_rate_limit_per_origin")]
+	pub federation_query_rate_limit_per_origin: u32,
+	#[serde(default)]
+	pub max_joined_rooms_per_user: usize,
+	#[serde(default)]
+	pub ignore_invites_to_joined_users: bool,
+	#[serde(default)]
+	pub mark_accepted_direct_invites: bool,
 	#[serde(default = "true_fn")]
 	pub admin_escape_commands: bool,
 
@@ -366,6 +473,19 @@ pub struct TlsConfig {
 	pub dual_protocol: bool,
 }
 
+/// Which users `/user_directory/search` may return to the searcher.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UserDirectorySearchScope {
+	/// Users in a public room or sharing a room with the searcher
+	#[default]
+	All,
+	/// Only users sharing a room with the searcher
+	SharedRooms,
+	/// Nobody
+	Disabled,
+}
+
 #[derive(Clone, Debug, Deserialize, Default)]
 pub struct WellKnownConfig {
 	pub client: Option<Url>,
@@ -374,6 +494,8 @@ pub struct WellKnownConfig {
 	pub support_role: Option<ContactRole>,
 	pub support_email: Option<String>,
 	pub support_mxid: Option<OwnedUserId>,
+	#[serde(default)]
+	pub support_contacts: Vec<Contact>,
 }
 
 const DEPRECATED_KEYS: &[&str] = &[
@@ -411,15 +533,12 @@ impl Config {
 				.merge(Env::prefixed("CONDUWUIT_").global().split("__"))
 		};
 
-		let config = match raw_config.extract::<Self>() {
+		let mut config = match raw_config.extract::<Self>() {
 			Err(e) => return Err(Error::BadConfig(format!("{e}"))),
 			Ok(config) => config,
 		};
 
-		// don't start if we're listening on both UNIX sockets and TCP at same time
-		if Self::is_dual_listening(&raw_config) {
-			return Err(Error::bad_config("dual listening on UNIX and TCP sockets not allowed."));
-		};
+		config.dual_listening = Self::is_dual_listening(&raw_config);
 
 		Ok(config)
 	}
@@ -460,19 +579,13 @@ impl Config {
 	}
 
 	/// Checks the presence of the `address` and `unix_socket_path` keys in the
-	/// raw_config, exiting the process if both keys were detected.
+	/// raw_config. Only when both were given do we listen on TCP alongside the
+	/// UNIX socket; otherwise the default `address` is ignored for sockets.
 	fn is_dual_listening(raw_config: &Figment) -> bool {
 		let check_address = raw_config.find_value("address");
 		let check_unix_socket = raw_config.find_value("unix_socket_path");
 
-		// are the check_address and check_unix_socket keys both Ok (specified) at the
-		// same time?
-		if check_address.is_ok() && check_unix_socket.is_ok() {
-			error!("TOML keys \"address\" and \"unix_socket_path\" were both defined. Please specify only one option.");
-			return true;
-		}
-
-		false
+		check_address.is_ok() && check_unix_socket.is_ok()
 	}
 
 	#[must_use]
@@ -509,6 +622,7 @@ impl fmt::Display for Config {
 		// Prepare a list of config values to show
 		let lines = [
 			("Server name", self.server_name.host()),
+			("PROXY protocol on TCP listeners", &self.proxy_protocol.to_string()),
 			("Database backend", &self.database_backend),
 			("Database path", &self.database_path.to_string_lossy()),
 			(
@@ -519,6 +633,10 @@ impl fmt::Display for Config {
 				},
 			),
 			("Database backups to keep", &self.database_backups_to_keep.to_string()),
+			(
+				"Database backup interval (hours)",
+				&self.database_backup_interval_hours.to_string(),
+			),
 			("Database cache capacity (MB)", &self.db_cache_capacity_mb.to_string()),
 			("Cache capacity modifier", &self.conduit_cache_capacity_modifier.to_string()),
 			("PDU cache capacity", &self.pdu_cache_capacity.to_string()),
@@ -549,6 +667,35 @@ impl fmt::Display for Config {
 			("DNS query over TCP only", &self.query_over_tcp_only.to_string()),
 			("Query all nameservers", &self.query_all_nameservers.to_string()),
 			("Maximum request size (bytes)", &self.max_request_size.to_string()),
+			("Maximum concurrent client requests", &self.client_max_connections.to_string()),
+			("Client request timeout (seconds)", &self.client_request_timeout_s.to_string()),
+			("Client keep-alive interval (seconds)", &self.client_keepalive_s.to_string()),
+			("Minimum sync long-poll (seconds)", &self.sync_min_timeout_s.to_string()),
+			("Maximum sync long-poll (seconds)", &self.sync_max_timeout_s.to_string()),
+			("Password hash memory (KiB)", &self.password_hash_memory_kib.to_string()),
+			("Password hash iterations", &self.password_hash_iterations.to_string()),
+			("Password hash parallelism", &self.password_hash_parallelism.to_string()),
+			("Per-user media storage quota (bytes)", {
+				&self
+					.media_user_quota_bytes
+					.map_or_else(|| "unlimited".to_owned(), |quota| quota.to_string())
+			}),
+			("Strip metadata from uploaded images", &self.media_strip_exif.to_string()),
+			(
+				"Maximum federation state response size (bytes)",
+				&self.max_state_response_size.to_string(),
+			),
+			("Maximum prev_events of local events", &self.max_prev_events.to_string()),
+			("Maximum auth chain events per fetch", &self.max_auth_chain_fetch.to_string()),
+			(
+				"Maximum space hierarchy restricted room depth",
+				&self.federation_hierarchy_max_depth.to_string(),
+			),
+			("Maximum event size (bytes)", &self.max_event_size.to_string()),
+			(
+				"Server key validity period (seconds)",
+				&self.server_key_validity_period_s.to_string(),
+			),
 			("Sender retry backoff limit", &self.sender_retry_backoff_limit.to_string()),
 			("Request connect timeout", &self.request_conn_timeout.to_string()),
 			("Request timeout", &self.request_timeout.to_string()),
@@ -560,11 +707,35 @@ impl fmt::Display for Config {
 			("Federation timeout", &self.federation_timeout.to_string()),
 			("Federation pool idle per host", &self.federation_idle_per_host.to_string()),
 			("Federation pool idle timeout", &self.federation_idle_timeout.to_string()),
+			(
+				"Federation User-Agent contact",
+				self.federation_user_agent.as_deref().unwrap_or("not set"),
+			),
 			("Sender timeout", &self.sender_timeout.to_string()),
 			("Sender pool idle timeout", &self.sender_idle_timeout.to_string()),
 			("Appservice timeout", &self.appservice_timeout.to_string()),
 			("Appservice pool idle timeout", &self.appservice_idle_timeout.to_string()),
 			("Pusher pool idle timeout", &self.pusher_idle_timeout.to_string()),
+			(
+				"Pusher consecutive failures before removal",
+				&self.pusher_max_failures.to_string(),
+			),
+			(
+				"Push gateway backoff after first failure",
+				&self.pusher_gateway_backoff_base_s.to_string(),
+			),
+			("Push gateway maximum backoff", &self.pusher_gateway_backoff_max_s.to_string()),
+			("SMTP server for email", self.smtp_server.as_deref().unwrap_or("not set")),
+			("SMTP username", self.smtp_username.as_deref().unwrap_or("not set")),
+			(
+				"SMTP password",
+				match self.smtp_password {
+					Some(_) => "set",
+					None => "not set",
+				},
+			),
+			("SMTP sender address", self.smtp_from.as_deref().unwrap_or("not set")),
+			("Trusted identity servers", &self.trusted_identity_servers.join(", ")),
 			("Allow registration", &self.allow_registration.to_string()),
 			(
 				"Registration token",
@@ -573,6 +744,13 @@ impl fmt::Display for Config {
 					None => "not set (open registration!)",
 				},
 			),
+			(
+				"Registration shared secret",
+				match self.registration_shared_secret {
+					Some(_) => "set",
+					None => "not set",
+				},
+			),
 			(
 				"Allow guest registration (inherently false if allow registration is false)",
 				&self.allow_guest_registration.to_string(),
@@ -587,6 +765,10 @@ impl fmt::Display for Config {
 			),
 			("New user display name suffix", &self.new_user_displayname_suffix),
 			("Allow encryption", &self.allow_encryption.to_string()),
+			(
+				"Validate key backup room encryption",
+				&self.validate_backup_room_encryption.to_string(),
+			),
 			("Allow federation", &self.allow_federation.to_string()),
 			(
 				"Allow incoming federated presence requests (updates)",
@@ -596,6 +778,17 @@ impl fmt::Display for Config {
 				"Allow outgoing federated presence requests (updates)",
 				&self.allow_outgoing_presence.to_string(),
 			),
+			(
+				"Update room memberships on profile change",
+				&self.update_membership_on_profile_change.to_string(),
+			),
+			("Profile update membership maximum room size", {
+				&self
+					.profile_update_membership_max_room_size
+					.map_or_else(|| "unlimited".to_owned(), |size| size.to_string())
+			}),
+			("Redact events of erased accounts", &self.redact_events_on_erase.to_string()),
+			("Bundle aggregations of fetched events", &self.bundled_aggregations.to_string()),
 			(
 				"Allow local presence requests (updates)",
 				&self.allow_local_presence.to_string(),
@@ -612,6 +805,21 @@ impl fmt::Display for Config {
 				"Block non-admin room invites (local and remote, admins can still send and receive invites)",
 				&self.block_non_admin_invites.to_string(),
 			),
+			("Invites per minute per user", &self.invite_rate_limit_per_user.to_string()),
+			("Invites per minute per room", &self.invite_rate_limit_per_room.to_string()),
+			(
+				"Federation profile/key queries per minute per server",
+				&self.federation_query_rate_limit_per_origin.to_string(),
+			),
+			("Maximum joined rooms per user", &self.max_joined_rooms_per_user.to_string()),
+			(
+				"Ignore invites to already joined users",
+				&self.ignore_invites_to_joined_users.to_string(),
+			),
+			(
+				"Add accepted direct invites to m.direct",
+				&self.mark_accepted_direct_invites.to_string(),
+			),
 			("Enable admin escape commands", &self.admin_escape_commands.to_string()),
 			("Allow outgoing federated typing", &self.allow_outgoing_typing.to_string()),
 			("Allow incoming federated typing", &self.allow_incoming_typing.to_string()),
@@ -626,12 +834,28 @@ impl fmt::Display for Config {
 				"Allow incoming profile lookup federation requests",
 				&self.allow_profile_lookup_federation_requests.to_string(),
 			),
+			(
+				"Federation profile lookup cache TTL (seconds)",
+				&self.federation_profile_cache_ttl_s.to_string(),
+			),
 			(
 				"Auto deactivate banned room join attempts",
 				&self.auto_deactivate_banned_room_attempts.to_string(),
 			),
 			("Notification push path", &self.notification_push_path),
 			("Allow room creation", &self.allow_room_creation.to_string()),
+			("Room versions allowed for creation", {
+				&if self.allowed_room_versions_for_creation.is_empty() {
+					"all supported".to_owned()
+				} else {
+					self.allowed_room_versions_for_creation.iter().join(", ")
+				}
+			}),
+			("Default call power level", {
+				&self
+					.default_call_power_level
+					.map_or_else(|| "50 in public rooms".to_owned(), |level| level.to_string())
+			}),
 			(
 				"Allow public room directory over federation",
 				&self.allow_public_room_directory_over_federation.to_string(),
@@ -644,6 +868,26 @@ impl fmt::Display for Config {
 				"Lockdown public room directory (only allow admins to publish)",
 				&self.lockdown_public_room_directory.to_string(),
 			),
+			(
+				"Allow non-admins to publish rooms to the room directory",
+				&self.allow_public_room_directory_publish.to_string(),
+			),
+			(
+				"Default room directory visibility of new rooms",
+				&if let Some(visibility) = &self.default_room_directory_visibility {
+					visibility.to_string()
+				} else {
+					"as requested by the client".to_owned()
+				},
+			),
+			(
+				"User directory search scope",
+				match self.user_directory_search_scope {
+					UserDirectorySearchScope::All => "all",
+					UserDirectorySearchScope::SharedRooms => "shared_rooms",
+					UserDirectorySearchScope::Disabled => "disabled",
+				},
+			),
 			(
 				"JWT secret",
 				match self.jwt_secret {
@@ -651,6 +895,14 @@ impl fmt::Display for Config {
 					None => "not set",
 				},
 			),
+			("SSO identity providers", {
+				&self
+					.sso_identity_providers
+					.iter()
+					.map(|idp| idp.id.as_str())
+					.collect::<Vec<_>>()
+					.join(", ")
+			}),
 			("Trusted key servers", {
 				let mut lst = vec![];
 				for server in &self.trusted_servers {
@@ -662,6 +914,8 @@ impl fmt::Display for Config {
 				"Query Trusted Key Servers First",
 				&self.query_trusted_key_servers_first.to_string(),
 			),
+			("Allow notary server", &self.allow_notary_server.to_string()),
+			("Allow Synapse admin API", &self.allow_synapse_admin_api.to_string()),
 			(
 				"TURN username",
 				if self.turn_username.is_empty() {
@@ -706,6 +960,8 @@ impl fmt::Display for Config {
 			("Gzip HTTP Compression", &self.gzip_compression.to_string()),
 			#[cfg(feature = "brotli_compression")]
 			("Brotli HTTP Compression", &self.brotli_compression.to_string()),
+			#[cfg(any(feature = "zstd_compression", feature = "gzip_compression", feature = "brotli_compression"))]
+			("HTTP Compression minimum size (bytes)", &self.compression_min_size.to_string()),
 			#[cfg(feature = "rocksdb")]
 			("RocksDB database LOG level", &self.rocksdb_log_level),
 			#[cfg(feature = "rocksdb")]
@@ -779,6 +1035,7 @@ impl fmt::Display for Config {
 				}
 				&lst.join(", ")
 			}),
+			("Trusted Proxies", &self.trusted_proxies.join(", ")),
 			("Outbound Request IP Range Denylist", {
 				let mut lst = vec![];
 				for item in self.ip_range_denylist.iter().cloned().enumerate() {
@@ -816,6 +1073,11 @@ impl fmt::Display for Config {
 				&self.allow_check_for_updates.to_string(),
 			),
 			("Enable netburst on startup", &self.startup_netburst.to_string()),
+			(
+				"Federation transaction batching window (ms)",
+				&self.federation_transaction_batch_ms.to_string(),
+			),
+			("Undelivered to-device message TTL (days)", &self.to_device_ttl_days.to_string()),
 			#[cfg(feature = "sentry_telemetry")]
 			("Sentry.io reporting and tracing", &self.sentry.to_string()),
 			#[cfg(feature = "sentry_telemetry")]
@@ -862,6 +1124,10 @@ impl fmt::Display for Config {
 					String::new()
 				},
 			),
+			(
+				"Well-known support contacts",
+				&self.well_known.support_contacts.len().to_string(),
+			),
 			(
 				"Well-known support page/URL",
 				&if let Some(support_page) = &self.well_known.support_page {
@@ -942,6 +1208,20 @@ fn default_max_request_size() -> u32 {
 	20 * 1024 * 1024 // Default to 20 MB
 }
 
+fn default_client_max_connections() -> usize { 4096 }
+
+fn default_client_request_timeout_s() -> u64 { 180 }
+
+fn default_client_keepalive_s() -> u64 { 60 }
+
+fn default_sync_max_timeout_s() -> u64 { 30 }
+
+fn default_password_hash_memory_kib() -> u32 { 19_456 }
+
+fn default_password_hash_iterations() -> u32 { 2 }
+
+fn default_password_hash_parallelism() -> u32 { 1 }
+
 fn default_request_conn_timeout() -> u64 { 10 }
 
 fn default_request_timeout() -> u64 { 35 }
@@ -974,8 +1254,34 @@ fn default_appservice_idle_timeout() -> u64 { 300 }
 
 fn default_pusher_idle_timeout() -> u64 { 15 }
 
+fn default_federation_profile_cache_ttl_s() -> u64 { 30 }
+
+fn default_pusher_max_failures() -> u32 { 10 }
+
+fn default_pusher_gateway_backoff_base_s() -> u64 { 30 }
+
+fn default_pusher_gateway_backoff_max_s() -> u64 { 60 * 60 }
+
 fn default_max_fetch_prev_events() -> u16 { 100_u16 }
 
+fn default_max_prev_events() -> usize { 20 }
+
+fn default_max_auth_chain_fetch() -> usize { 100_000 }
+
+fn default_federation_hierarchy_max_depth() -> usize { 10 }
+
+fn default_compression_min_size() -> u16 { 1024 }
+
+fn default_max_event_size() -> usize { 65_536 }
+
+fn default_max_state_response_size() -> usize {
+	128 * 1024 * 1024 // Default to 128 MiB
+}
+
+fn default_server_key_validity_period_s() -> u64 {
+	86400 * 7 // Default to 7 days
+}
+
 #[cfg(feature = "perf_measurements")]
 fn default_tracing_flame_filter() -> String { "trace,h2=off".to_owned() }
 
@@ -1041,6 +1347,8 @@ fn default_rocksdb_bottommost_compression_level() -> i32 { 32767 }
 #[must_use]
 pub fn default_default_room_version() -> RoomVersionId { RoomVersionId::V10 }
 
+fn default_trusted_proxies() -> Vec<String> { vec!["127.0.0.1/32".to_owned(), "::1/128".to_owned()] }
+
 fn default_ip_range_denylist() -> Vec<String> {
 	vec![
 		"127.0.0.0/8".to_owned(),
@@ -1080,3 +1388,9 @@ fn default_sentry_endpoint() -> Option<Url> {
 fn default_sentry_traces_sample_rate() -> f32 { 0.15 }
 
 fn default_startup_netburst_keep() -> i64 { 50 }
+
+fn default_invite_rate_limit_per_user() -> u32 { 30 }
+
+fn default_invite_rate_limit_per_room() -> u32 { 100 }
+
+fn default_federation_query_rate_limit_per_origin() -> u32 { 600 }
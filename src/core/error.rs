@@ -54,6 +54,14 @@ pub enum Error {
 	},
 	#[error("Remote server {0} responded with: {1}")]
 	Federation(OwnedServerName, RumaError),
+	#[error("{method} {path} to {dest} failed ({}): {message}", .status.map_or_else(|| "no response".to_owned(), |s| s.to_string()))]
+	FederationRequest {
+		dest: OwnedServerName,
+		method: http::Method,
+		path: String,
+		status: Option<StatusCode>,
+		message: String,
+	},
 	#[error("Could not do this io: {source}")]
 	Io {
 		#[from]
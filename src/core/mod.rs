@@ -6,6 +6,7 @@ pub mod log;
 pub mod mods;
 pub mod pducount;
 pub mod server;
+pub mod synctoken;
 pub mod utils;
 pub mod version;
 
@@ -13,6 +14,7 @@ pub use config::Config;
 pub use error::{Error, Result, RumaResponse};
 pub use pducount::PduCount;
 pub use server::Server;
+pub use synctoken::SyncToken;
 
 #[cfg(not(conduit_mods))]
 pub mod mods {
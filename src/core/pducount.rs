@@ -2,7 +2,7 @@ use std::cmp::Ordering;
 
 use ruma::api::client::error::ErrorKind;
 
-use crate::{Error, Result};
+use crate::{Error, Result, SyncToken};
 
 #[derive(Hash, PartialEq, Eq, Clone, Copy, Debug)]
 pub enum PduCount {
@@ -17,20 +17,29 @@ impl PduCount {
 	#[must_use]
 	pub fn max() -> Self { Self::Normal(u64::MAX) }
 
+	/// Parses a pagination token. Sync tokens are accepted too, as clients may
+	/// paginate from a sync `prev_batch`.
 	pub fn try_from_string(token: &str) -> Result<Self> {
+		let invalid = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid pagination token.");
 		if let Some(stripped_token) = token.strip_prefix('-') {
-			stripped_token.parse().map(PduCount::Backfilled)
-		} else {
-			token.parse().map(PduCount::Normal)
+			return stripped_token
+				.parse()
+				.map(PduCount::Backfilled)
+				.map_err(|_| invalid());
 		}
-		.map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid pagination token."))
+
+		SyncToken::decode(token)
+			.map(|token| PduCount::Normal(token.count))
+			.map_err(|_| invalid())
 	}
 
+	/// Encodes the count as a pagination token, which normal counts share
+	/// with sync tokens.
 	#[must_use]
 	pub fn stringify(&self) -> String {
 		match self {
 			Self::Backfilled(x) => format!("-{x}"),
-			Self::Normal(x) => x.to_string(),
+			Self::Normal(x) => SyncToken::new(*x).encode(),
 		}
 	}
 }
@@ -49,3 +58,18 @@ impl Ord for PduCount {
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::PduCount;
+	use crate::SyncToken;
+
+	#[test]
+	fn pagination_tokens_round_trip() {
+		for count in [PduCount::Normal(42), PduCount::Backfilled(7), PduCount::max()] {
+			assert_eq!(PduCount::try_from_string(&count.stringify()).unwrap(), count);
+		}
+
+		assert_eq!(PduCount::Normal(42).stringify(), SyncToken::new(42).encode());
+	}
+}
@@ -11,7 +11,6 @@ const P_COST: u32 = Params::DEFAULT_P_COST; // parallelism
 
 static STATE: Mutex<Option<Argon2<'static>>> = Mutex::new(None);
 
-#[allow(clippy::let_underscore_must_use)]
 pub fn init() {
 	// 19456 Kib blocks, iterations = 2, parallelism = 1
 	// * <https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#argon2id>
@@ -19,12 +18,25 @@ pub fn init() {
 	debug_assert!(T_COST == 2, "T_COST default changed");
 	debug_assert!(P_COST == 1, "P_COST default changed");
 
+	init_with(M_COST, T_COST, P_COST).expect("valid parameters");
+}
+
+/// Sets the Argon2id parameters used for new password hashes. Existing hashes
+/// embed the parameters they were created with and verify regardless.
+#[allow(clippy::let_underscore_must_use)]
+pub fn init_with(m_cost: u32, t_cost: u32, p_cost: u32) -> Result<(), argon2::Error> {
 	let algorithm = Algorithm::Argon2id;
 	let version = Version::default();
-	let out_len: Option<usize> = None;
-	let params = Params::new(M_COST, T_COST, P_COST, out_len).expect("valid parameters");
-	let state = Argon2::new(algorithm, version, params);
+	let state = Argon2::new(algorithm, version, params(m_cost, t_cost, p_cost)?);
 	_ = STATE.lock().expect("hashing state locked").insert(state);
+
+	Ok(())
+}
+
+/// Checks Argon2 memory (KiB), iteration and parallelism parameters.
+pub fn params(m_cost: u32, t_cost: u32, p_cost: u32) -> Result<Params, argon2::Error> {
+	let out_len: Option<usize> = None;
+	Params::new(m_cost, t_cost, p_cost, out_len)
 }
 
 pub fn password(password: &str) -> Result<String, password_hash::Error> {
@@ -48,6 +60,29 @@ pub fn verify_password(password: &str, password_hash: &str) -> Result<(), passwo
 		.verify_password(password.as_bytes(), &password_hash)
 }
 
+/// Whether a stored hash was made with a different algorithm or weaker
+/// parameters than those currently configured, and should be replaced.
+pub fn needs_rehash(password_hash: &str) -> bool {
+	let Ok(password_hash) = PasswordHash::new(password_hash) else {
+		return false;
+	};
+
+	if password_hash.algorithm != Algorithm::Argon2id.ident() {
+		return true;
+	}
+
+	let Ok(stored) = Params::try_from(&password_hash) else {
+		return false;
+	};
+
+	let state = STATE.lock().expect("hashing state locked");
+	weaker_params(&stored, state.as_ref().expect("hashing state initialized").params())
+}
+
+fn weaker_params(stored: &Params, current: &Params) -> bool {
+	stored.m_cost() < current.m_cost() || stored.t_cost() < current.t_cost() || stored.p_cost() < current.p_cost()
+}
+
 #[cfg(test)]
 mod tests {
 	#[test]
@@ -59,6 +94,47 @@ mod tests {
 		hash::verify_password(preimage, &digest).expect("verified");
 	}
 
+	#[test]
+	fn password_hash_verifies_after_params_change() {
+		use argon2::{password_hash::SaltString, Algorithm, Argon2, PasswordHasher, Version};
+
+		use crate::utils::hash;
+		hash::init();
+		let preimage = "temp123";
+		let old = hash::params(1024, 1, 1).expect("valid parameters");
+		let salt = SaltString::generate(rand::thread_rng());
+		let digest = Argon2::new(Algorithm::Argon2id, Version::default(), old)
+			.hash_password(preimage.as_bytes(), &salt)
+			.expect("digest");
+		hash::verify_password(preimage, &digest.to_string()).expect("verified");
+	}
+
+	#[test]
+	fn password_rehash_after_params_raised() {
+		use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHash, PasswordHasher, Version};
+
+		use crate::utils::hash;
+		let old = hash::params(1024, 1, 1).expect("valid parameters");
+		let new = hash::params(19_456, 2, 1).expect("valid parameters");
+		let salt = SaltString::generate(rand::thread_rng());
+		let digest = Argon2::new(Algorithm::Argon2id, Version::default(), old.clone())
+			.hash_password(b"temp123", &salt)
+			.expect("digest");
+		let stored = Params::try_from(&PasswordHash::new(&digest.to_string()).expect("parsed")).expect("params");
+
+		assert!(hash::weaker_params(&stored, &new));
+		assert!(!hash::weaker_params(&stored, &old));
+		assert!(!hash::weaker_params(&new, &new));
+	}
+
+	#[test]
+	fn invalid_params_are_rejected() {
+		use crate::utils::hash;
+		assert!(hash::params(0, 2, 1).is_err());
+		assert!(hash::params(19_456, 0, 1).is_err());
+		assert!(hash::params(19_456, 2, 0).is_err());
+	}
+
 	#[test]
 	#[should_panic(expected = "unverified")]
 	fn password_hash_and_verify_fail() {
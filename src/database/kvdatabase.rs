@@ -17,11 +17,14 @@ pub struct KeyValueDatabase {
 	//pub globals: globals::Globals,
 	pub global: Arc<dyn KvTree>,
 	pub server_signingkeys: Arc<dyn KvTree>,
+	pub server_signedkeys: Arc<dyn KvTree>, // ServerName => keys response as signed by the server, served as a notary
 
 	pub roomid_inviteviaservers: Arc<dyn KvTree>,
 
 	//pub users: users::Users,
 	pub userid_password: Arc<dyn KvTree>,
+	pub guestuserids: Arc<dyn KvTree>,         // Local users registered as guest accounts
+	pub userthreepid_addedat: Arc<dyn KvTree>, // UserThreepid = UserId + Medium + Address
 	pub userid_displayname: Arc<dyn KvTree>,
 	pub userid_avatarurl: Arc<dyn KvTree>,
 	pub userid_blurhash: Arc<dyn KvTree>,
@@ -32,16 +35,20 @@ pub struct KeyValueDatabase {
 
 	pub onetimekeyid_onetimekeys: Arc<dyn KvTree>, // OneTimeKeyId = UserId + DeviceKeyId
 	pub userid_lastonetimekeyupdate: Arc<dyn KvTree>, // LastOneTimeKeyUpdate = Count
+	pub fallbackkeyid_fallbackkey: Arc<dyn KvTree>, // FallbackKeyId = UserId + DeviceId + Algorithm
 	pub keychangeid_userid: Arc<dyn KvTree>,       // KeyChangeId = UserId/RoomId + Count
 	pub keyid_key: Arc<dyn KvTree>,                // KeyId = UserId + KeyId (depends on key type)
 	pub userid_masterkeyid: Arc<dyn KvTree>,
 	pub userid_selfsigningkeyid: Arc<dyn KvTree>,
 	pub userid_usersigningkeyid: Arc<dyn KvTree>,
 
-	pub userfilterid_filter: Arc<dyn KvTree>, // UserFilterId = UserId + FilterId
-	pub todeviceid_events: Arc<dyn KvTree>,   // ToDeviceId = UserId + DeviceId + Count
-	pub userid_presenceid: Arc<dyn KvTree>,   // UserId => Count
-	pub presenceid_presence: Arc<dyn KvTree>, // Count + UserId => Presence
+	pub userfilterid_filter: Arc<dyn KvTree>,  // UserFilterId = UserId + FilterId
+	pub todeviceid_events: Arc<dyn KvTree>,    // ToDeviceId = UserId + DeviceId + Count
+	pub todeviceid_timestamp: Arc<dyn KvTree>, // ToDeviceId => millis when queued
+	pub userid_presenceid: Arc<dyn KvTree>,    // UserId => Count
+	pub presenceid_presence: Arc<dyn KvTree>,  // Count + UserId => Presence
+
+	pub userdeviceconnid_slidingsync: Arc<dyn KvTree>, // UserId + DeviceId + ConnId => sliding sync connection state
 
 	//pub uiaa: uiaa::Uiaa,
 	pub userdevicesessionid_uiaainfo: Arc<dyn KvTree>, // User-interactive authentication
@@ -83,6 +90,8 @@ pub struct KeyValueDatabase {
 
 	pub bannedroomids: Arc<dyn KvTree>, // Rooms where local users are not allowed to join
 
+	pub localonlyroomids: Arc<dyn KvTree>, // Rooms that are not federated in either direction
+
 	pub lazyloadedids: Arc<dyn KvTree>, // LazyLoadedIds = UserId + DeviceId + RoomId + LazyLoadedUserId
 
 	pub userroomid_notificationcount: Arc<dyn KvTree>, // NotifyCount = u64
@@ -129,6 +138,7 @@ pub struct KeyValueDatabase {
 	pub mediaid_file: Arc<dyn KvTree>, // MediaId = MXC + WidthHeight + ContentDisposition + ContentType
 	pub url_previews: Arc<dyn KvTree>,
 	pub mediaid_user: Arc<dyn KvTree>,
+	pub userid_mediasize: Arc<dyn KvTree>, // UserMediaId = UserId + MXC, value = size in bytes
 	//pub key_backups: key_backups::KeyBackups,
 	pub backupid_algorithm: Arc<dyn KvTree>, // BackupId = UserId + Version(Count)
 	pub backupid_etag: Arc<dyn KvTree>,      // BackupId = UserId + Version(Count)
@@ -149,6 +159,7 @@ pub struct KeyValueDatabase {
 
 	//pub pusher: pusher::PushData,
 	pub senderkey_pusher: Arc<dyn KvTree>,
+	pub senderkey_deviceid: Arc<dyn KvTree>, // SenderKey = UserId + PushKey, the device which set the pusher
 
 	pub auth_chain_cache: Mutex<LruCache<Vec<u64>, Arc<[u64]>>>,
 	pub appservice_in_room_cache: RwLock<HashMap<OwnedRoomId, HashMap<String, bool>>>,
@@ -165,6 +176,8 @@ impl KeyValueDatabase {
 		Ok(Self {
 			db: builder.clone(),
 			userid_password: builder.open_tree("userid_password")?,
+			guestuserids: builder.open_tree("guestuserids")?,
+			userthreepid_addedat: builder.open_tree("userthreepid_addedat")?,
 			userid_displayname: builder.open_tree("userid_displayname")?,
 			userid_avatarurl: builder.open_tree("userid_avatarurl")?,
 			userid_blurhash: builder.open_tree("userid_blurhash")?,
@@ -174,6 +187,7 @@ impl KeyValueDatabase {
 			token_userdeviceid: builder.open_tree("token_userdeviceid")?,
 			onetimekeyid_onetimekeys: builder.open_tree("onetimekeyid_onetimekeys")?,
 			userid_lastonetimekeyupdate: builder.open_tree("userid_lastonetimekeyupdate")?,
+			fallbackkeyid_fallbackkey: builder.open_tree("fallbackkeyid_fallbackkey")?,
 			keychangeid_userid: builder.open_tree("keychangeid_userid")?,
 			keyid_key: builder.open_tree("keyid_key")?,
 			userid_masterkeyid: builder.open_tree("userid_masterkeyid")?,
@@ -181,6 +195,8 @@ impl KeyValueDatabase {
 			userid_usersigningkeyid: builder.open_tree("userid_usersigningkeyid")?,
 			userfilterid_filter: builder.open_tree("userfilterid_filter")?,
 			todeviceid_events: builder.open_tree("todeviceid_events")?,
+			todeviceid_timestamp: builder.open_tree("todeviceid_timestamp")?,
+			userdeviceconnid_slidingsync: builder.open_tree("userdeviceconnid_slidingsync")?,
 			userid_presenceid: builder.open_tree("userid_presenceid")?,
 			presenceid_presence: builder.open_tree("presenceid_presence")?,
 
@@ -218,6 +234,8 @@ impl KeyValueDatabase {
 
 			bannedroomids: builder.open_tree("bannedroomids")?,
 
+			localonlyroomids: builder.open_tree("localonlyroomids")?,
+
 			lazyloadedids: builder.open_tree("lazyloadedids")?,
 
 			userroomid_notificationcount: builder.open_tree("userroomid_notificationcount")?,
@@ -249,6 +267,7 @@ impl KeyValueDatabase {
 			mediaid_file: builder.open_tree("mediaid_file")?,
 			url_previews: builder.open_tree("url_previews")?,
 			mediaid_user: builder.open_tree("mediaid_user")?,
+			userid_mediasize: builder.open_tree("userid_mediasize")?,
 			backupid_algorithm: builder.open_tree("backupid_algorithm")?,
 			backupid_etag: builder.open_tree("backupid_etag")?,
 			backupkeyid_backup: builder.open_tree("backupkeyid_backup")?,
@@ -258,8 +277,10 @@ impl KeyValueDatabase {
 			servercurrentevent_data: builder.open_tree("servercurrentevent_data")?,
 			id_appserviceregistrations: builder.open_tree("id_appserviceregistrations")?,
 			senderkey_pusher: builder.open_tree("senderkey_pusher")?,
+			senderkey_deviceid: builder.open_tree("senderkey_deviceid")?,
 			global: builder.open_tree("global")?,
 			server_signingkeys: builder.open_tree("server_signingkeys")?,
+			server_signedkeys: builder.open_tree("server_signedkeys")?,
 
 			roomid_inviteviaservers: builder.open_tree("roomid_inviteviaservers")?,
 
@@ -32,6 +32,8 @@ pub trait KeyValueDatabaseEngine: Send + Sync {
 
 	fn backup(&self) -> Result<(), Box<dyn Error>> { unimplemented!() }
 
+	fn backup_prune(&self) -> Result<(), Box<dyn Error>> { Ok(()) }
+
 	fn backup_list(&self) -> Result<String> { Ok(String::new()) }
 
 	fn file_list(&self) -> Result<String> { Ok(String::new()) }
@@ -195,16 +195,24 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 			);
 		}
 
-		if self.config.database_backups_to_keep >= 0 {
-			let keep = u32::try_from(self.config.database_backups_to_keep)?;
-			if let Err(e) = engine.purge_old_backups(keep.try_into()?) {
-				error!("Failed to purge old backup: {:?}", e.to_string());
-			}
+		if let Err(e) = self.purge_backups(&mut engine) {
+			error!("Failed to purge old backup: {:?}", e.to_string());
 		}
 
 		Ok(())
 	}
 
+	fn backup_prune(&self) -> Result<(), Box<dyn std::error::Error>> {
+		let path = self.config.database_backup_path.as_ref();
+		if path.is_none() || path.is_some_and(|path| path.as_os_str().is_empty()) {
+			return Ok(());
+		}
+
+		let options = BackupEngineOptions::new(path.unwrap())?;
+		let mut engine = BackupEngine::open(&options, &self.env)?;
+		self.purge_backups(&mut engine)
+	}
+
 	fn backup_list(&self) -> Result<String> {
 		let path = self.config.database_backup_path.as_ref();
 		if path.is_none() || path.is_some_and(|path| path.as_os_str().is_empty()) {
@@ -256,6 +264,25 @@ impl KeyValueDatabaseEngine for Arc<Engine> {
 	fn clear_caches(&self) {}
 }
 
+impl Engine {
+	/// Deletes all but the newest `database_backups_to_keep` backups. A negative
+	/// value keeps every backup.
+	fn purge_backups(&self, engine: &mut BackupEngine) -> Result<(), Box<dyn std::error::Error>> {
+		if self.config.database_backups_to_keep < 0 {
+			return Ok(());
+		}
+
+		let keep = usize::try_from(self.config.database_backups_to_keep)?;
+		let count = engine.get_backup_info().len();
+		engine.purge_old_backups(keep)?;
+		if count > keep {
+			info!("Purged {} old database backup(s), keeping the latest {keep}", count - keep);
+		}
+
+		Ok(())
+	}
+}
+
 impl Drop for Engine {
 	fn drop(&mut self) {
 		const BLOCKING: bool = true;
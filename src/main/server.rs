@@ -39,7 +39,12 @@ impl Server {
 		config.check()?;
 		#[cfg(unix)]
 		sys::maximize_fd_limit().expect("Unable to increase maximum soft and hard file descriptor limit");
-		hash::init();
+		hash::init_with(
+			config.password_hash_memory_kib,
+			config.password_hash_iterations,
+			config.password_hash_parallelism,
+		)
+		.expect("password hashing parameters were checked with the config");
 
 		info!(
 			server_name = %config.server_name,
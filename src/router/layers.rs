@@ -2,19 +2,20 @@ use std::{any::Any, io, sync::Arc, time::Duration};
 
 use axum::{
 	extract::{DefaultBodyLimit, MatchedPath},
+	response::Response,
 	Router,
 };
 use axum_client_ip::SecureClientIpSource;
 use conduit::Server;
 use http::{
 	header::{self, HeaderName},
-	HeaderValue, Method, StatusCode,
+	HeaderMap, HeaderValue, Method, StatusCode,
 };
-use tower::ServiceBuilder;
+use tower::{limit::GlobalConcurrencyLimitLayer, ServiceBuilder};
 use tower_http::{
 	catch_panic::CatchPanicLayer,
 	cors::{self, CorsLayer},
-	set_header::SetResponseHeaderLayer,
+	timeout::TimeoutLayer,
 	trace::{DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer},
 	ServiceBuilderExt as _,
 };
@@ -26,6 +27,19 @@ const CONDUWUIT_CSP: &str = "sandbox; default-src 'none'; font-src 'none'; scrip
                              form-action 'none'; base-uri 'none';";
 const CONDUWUIT_PERMISSIONS_POLICY: &str = "interest-cohort=(),browsing-topics=()";
 
+/// Hardening headers set on every response that doesn't set them itself. The
+/// sandboxing CSP and `nosniff` matter most for media downloads, which serve
+/// user-controlled content from our origin.
+const SECURITY_HEADERS: [(HeaderName, &str); 6] = [
+	// https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Origin-Agent-Cluster
+	(HeaderName::from_static("origin-agent-cluster"), "?1"),
+	(header::X_CONTENT_TYPE_OPTIONS, "nosniff"),
+	(header::X_XSS_PROTECTION, "0"),
+	(header::X_FRAME_OPTIONS, "DENY"),
+	(HeaderName::from_static("permissions-policy"), CONDUWUIT_PERMISSIONS_POLICY),
+	(header::CONTENT_SECURITY_POLICY, CONDUWUIT_CSP),
+];
+
 pub(crate) fn build(server: &Arc<Server>) -> io::Result<Router> {
 	let layers = ServiceBuilder::new();
 
@@ -47,39 +61,34 @@ pub(crate) fn build(server: &Arc<Server>) -> io::Result<Router> {
 		)
 		.layer(axum::middleware::from_fn_with_state(Arc::clone(server), request::handle))
 		.layer(SecureClientIpSource::ConnectInfo.into_extension())
-		.layer(SetResponseHeaderLayer::if_not_present(
-			HeaderName::from_static("origin-agent-cluster"), // https://developer.mozilla.org/en-US/docs/Web/HTTP/Headers/Origin-Agent-Cluster
-			HeaderValue::from_static("?1"),
-		))
-		.layer(SetResponseHeaderLayer::if_not_present(
-			header::X_CONTENT_TYPE_OPTIONS,
-			HeaderValue::from_static("nosniff"),
-		))
-		.layer(SetResponseHeaderLayer::if_not_present(
-			header::X_XSS_PROTECTION,
-			HeaderValue::from_static("0"),
-		))
-		.layer(SetResponseHeaderLayer::if_not_present(
-			header::X_FRAME_OPTIONS,
-			HeaderValue::from_static("DENY"),
-		))
-		.layer(SetResponseHeaderLayer::if_not_present(
-			HeaderName::from_static("permissions-policy"),
-			HeaderValue::from_static(CONDUWUIT_PERMISSIONS_POLICY),
-		))
-		.layer(SetResponseHeaderLayer::if_not_present(
-			header::CONTENT_SECURITY_POLICY,
-			HeaderValue::from_static(CONDUWUIT_CSP),
-		))
+		.layer(axum::middleware::from_fn(request::client_ip))
+		.layer(axum::middleware::map_response(security_headers))
 		.layer(cors_layer(server))
 		.layer(body_limit_layer(server))
+		.layer(TimeoutLayer::new(Duration::from_secs(server.config.client_request_timeout_s)))
+		.layer(GlobalConcurrencyLimitLayer::new(server.config.client_max_connections))
 		.layer(CatchPanicLayer::custom(catch_panic));
 
 	Ok(router::build(server).layer(layers))
 }
 
+async fn security_headers(mut response: Response) -> Response {
+	set_security_headers(response.headers_mut());
+	response
+}
+
+fn set_security_headers(headers: &mut HeaderMap) {
+	for (name, value) in SECURITY_HEADERS {
+		headers
+			.entry(name)
+			.or_insert_with(|| HeaderValue::from_static(value));
+	}
+}
+
 #[cfg(any(feature = "zstd_compression", feature = "gzip_compression", feature = "brotli_compression"))]
-fn compression_layer(server: &Server) -> tower_http::compression::CompressionLayer {
+fn compression_layer(
+	server: &Server,
+) -> tower_http::compression::CompressionLayer<impl tower_http::compression::Predicate> {
 	let mut compression_layer = tower_http::compression::CompressionLayer::new();
 
 	#[cfg(feature = "zstd_compression")]
@@ -109,7 +118,22 @@ fn compression_layer(server: &Server) -> tower_http::compression::CompressionLay
 		};
 	};
 
-	compression_layer
+	compression_layer.compress_when(compression_predicate(server.config.compression_min_size))
+}
+
+/// Compresses responses larger than `min_size` bytes, except for content that
+/// is already compressed or streamed, as tower-http does by default.
+#[cfg(any(feature = "zstd_compression", feature = "gzip_compression", feature = "brotli_compression"))]
+fn compression_predicate(min_size: u16) -> impl tower_http::compression::Predicate {
+	use tower_http::compression::{
+		predicate::{NotForContentType, SizeAbove},
+		Predicate,
+	};
+
+	SizeAbove::new(min_size)
+		.and(NotForContentType::GRPC)
+		.and(NotForContentType::IMAGES)
+		.and(NotForContentType::SSE)
 }
 
 fn cors_layer(_server: &Server) -> CorsLayer {
@@ -187,3 +211,118 @@ fn tracing_span<T>(request: &http::Request<T>) -> tracing::Span {
 
 	tracing::info_span!("router:", %path)
 }
+
+#[cfg(test)]
+mod tests {
+	#[cfg(feature = "gzip_compression")]
+	use std::convert::Infallible;
+
+	use axum::{body::Body, routing::get, Router};
+	#[cfg(feature = "gzip_compression")]
+	use bytes::Bytes;
+	#[cfg(feature = "gzip_compression")]
+	use http::Response;
+	use http::{header, HeaderMap, Request};
+	#[cfg(feature = "gzip_compression")]
+	use http_body_util::Full;
+	use tower::ServiceExt;
+	#[cfg(feature = "gzip_compression")]
+	use tower::{service_fn, ServiceBuilder};
+	#[cfg(feature = "gzip_compression")]
+	use tower_http::compression::CompressionLayer;
+
+	#[cfg(feature = "gzip_compression")]
+	use super::compression_predicate;
+	use super::{security_headers, CONDUWUIT_CSP};
+
+	#[cfg(feature = "gzip_compression")]
+	async fn content_encoding(body_len: usize, accept_encoding: &str) -> Option<String> {
+		let service = ServiceBuilder::new()
+			.layer(
+				CompressionLayer::new()
+					.gzip(true)
+					.compress_when(compression_predicate(1024)),
+			)
+			.service(service_fn(move |_req: Request<Full<Bytes>>| async move {
+				Ok::<_, Infallible>(
+					Response::builder()
+						.header(header::CONTENT_TYPE, "application/json")
+						.header(header::CONTENT_LENGTH, body_len)
+						.body(Full::from(vec![b'a'; body_len]))
+						.unwrap(),
+				)
+			}));
+
+		let request = Request::builder()
+			.header(header::ACCEPT_ENCODING, accept_encoding)
+			.body(Full::default())
+			.unwrap();
+
+		service
+			.oneshot(request)
+			.await
+			.unwrap()
+			.headers()
+			.get(header::CONTENT_ENCODING)
+			.map(|encoding| encoding.to_str().unwrap().to_owned())
+	}
+
+	#[cfg(feature = "gzip_compression")]
+	#[tokio::test]
+	async fn large_response_is_compressed_when_supported() {
+		assert_eq!(content_encoding(64 * 1024, "gzip").await.as_deref(), Some("gzip"));
+		assert_eq!(content_encoding(64 * 1024, "identity").await, None);
+	}
+
+	#[cfg(feature = "gzip_compression")]
+	#[tokio::test]
+	async fn small_response_is_not_compressed() {
+		assert_eq!(content_encoding(100, "gzip").await, None);
+	}
+
+	/// Response headers of a media download through the security headers
+	/// layer, with the handler setting `handler_headers` as well as the ones
+	/// the media download handlers send.
+	async fn media_download_headers(handler_headers: &'static [(&'static str, &'static str)]) -> HeaderMap {
+		let router = Router::new()
+			.route(
+				"/_matrix/media/v3/download/:server_name/:media_id",
+				get(move || async move {
+					let mut headers = HeaderMap::new();
+					headers.insert(header::CONTENT_TYPE, "text/html".parse().unwrap());
+					headers.insert(header::CONTENT_DISPOSITION, "attachment".parse().unwrap());
+					headers.insert(header::CROSS_ORIGIN_RESOURCE_POLICY, "cross-origin".parse().unwrap());
+					for (name, value) in handler_headers {
+						headers.insert(*name, value.parse().unwrap());
+					}
+
+					(headers, "<script>alert(1)</script>")
+				}),
+			)
+			.layer(axum::middleware::map_response(security_headers));
+
+		let request = Request::builder()
+			.uri("/_matrix/media/v3/download/example.com/abc")
+			.body(Body::empty())
+			.unwrap();
+
+		router.oneshot(request).await.unwrap().headers().clone()
+	}
+
+	#[tokio::test]
+	async fn media_download_gets_hardening_headers() {
+		let headers = media_download_headers(&[]).await;
+		assert_eq!(headers[header::CONTENT_SECURITY_POLICY], CONDUWUIT_CSP);
+		assert!(CONDUWUIT_CSP.starts_with("sandbox; default-src 'none';"));
+		assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+		assert_eq!(headers[header::CROSS_ORIGIN_RESOURCE_POLICY], "cross-origin");
+		assert_eq!(headers[header::CONTENT_DISPOSITION], "attachment");
+	}
+
+	#[tokio::test]
+	async fn headers_set_by_handlers_are_kept() {
+		let headers = media_download_headers(&[("x-frame-options", "SAMEORIGIN")]).await;
+		assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+		assert_eq!(headers.get_all(header::X_FRAME_OPTIONS).iter().count(), 1);
+	}
+}
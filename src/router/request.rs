@@ -1,8 +1,14 @@
-use std::sync::{atomic::Ordering, Arc};
+use std::{
+	net::{IpAddr, SocketAddr},
+	sync::{atomic::Ordering, Arc},
+};
 
-use axum::{extract::State, response::IntoResponse};
+use axum::{
+	extract::{ConnectInfo, State},
+	response::IntoResponse,
+};
 use conduit::{debug_error, debug_warn, defer, Result, RumaResponse, Server};
-use http::{Method, StatusCode, Uri};
+use http::{header, HeaderMap, Method, StatusCode, Uri};
 use ruma::api::client::{
 	error::{Error as RumaError, ErrorBody, ErrorKind},
 	uiaa::UiaaResponse,
@@ -61,6 +67,98 @@ pub(crate) async fn handle(
 	handle_result(&method, &uri, result)
 }
 
+/// Replaces the connection's peer address with the real client address when
+/// the peer is a trusted reverse proxy, so client IP extraction downstream
+/// never has to trust forwarding headers itself.
+#[tracing::instrument(skip_all)]
+pub(crate) async fn client_ip(
+	mut req: http::Request<axum::body::Body>, next: axum::middleware::Next,
+) -> axum::response::Response {
+	if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() {
+		let globals = &conduit_service::services().globals;
+		// UNIX socket peers have no address and can only be a local proxy
+		let trusted = |ip: &IpAddr| ip.is_unspecified() || globals.is_trusted_proxy(ip);
+		let client = forwarded_client_ip(peer.ip(), req.headers(), trusted);
+		if client != peer.ip() {
+			trace!(?peer, ?client, "client address from forwarding headers");
+			req.extensions_mut()
+				.insert(ConnectInfo(SocketAddr::new(client, 0)));
+		}
+	}
+
+	next.run(req).await
+}
+
+/// Resolves the client address of a request received from `peer`. Forwarding
+/// headers are only consulted when `peer` is trusted, and are then walked from
+/// the nearest hop outwards until the first untrusted address, which is the
+/// client. `X-Forwarded-For` is preferred over `Forwarded`.
+fn forwarded_client_ip<F>(peer: IpAddr, headers: &HeaderMap, trusted: F) -> IpAddr
+where
+	F: Fn(&IpAddr) -> bool,
+{
+	if !trusted(&peer) {
+		return peer;
+	}
+
+	let mut hops = forwarded_hops(headers, &header::HeaderName::from_static("x-forwarded-for"), |hop| hop);
+	if hops.is_empty() {
+		hops = forwarded_hops(headers, &header::FORWARDED, forwarded_for);
+	}
+
+	let mut client = peer;
+	for hop in hops.into_iter().rev() {
+		let Some(ip) = hop else {
+			break;
+		};
+
+		client = ip;
+		if !trusted(&ip) {
+			break;
+		}
+	}
+
+	client
+}
+
+/// Splits every instance of a list-valued forwarding header into its hops, in
+/// order, with `None` for any hop that isn't a usable address.
+fn forwarded_hops<'a, F>(headers: &'a HeaderMap, name: &header::HeaderName, extract: F) -> Vec<Option<IpAddr>>
+where
+	F: Fn(&'a str) -> &'a str,
+{
+	headers
+		.get_all(name)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.map(|hop| parse_hop(extract(hop.trim())))
+		.collect()
+}
+
+/// Picks the `for=` parameter out of one `Forwarded` element.
+fn forwarded_for(element: &str) -> &str {
+	element
+		.split(';')
+		.filter_map(|pair| pair.trim().split_once('='))
+		.find(|(key, _)| key.eq_ignore_ascii_case("for"))
+		.map_or("", |(_, value)| value.trim_matches('"'))
+}
+
+/// Parses a hop that may carry a port, e.g. `192.0.2.1:4711` or
+/// `[2001:db8::1]:4711`.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+	hop.parse::<IpAddr>()
+		.ok()
+		.or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+		.or_else(|| {
+			hop.trim_start_matches('[')
+				.trim_end_matches(']')
+				.parse()
+				.ok()
+		})
+}
+
 fn handle_result(
 	method: &Method, uri: &Uri, result: axum::response::Response,
 ) -> Result<axum::response::Response, StatusCode> {
@@ -100,3 +198,54 @@ fn handle_result_log(method: &Method, uri: &Uri, result: &axum::response::Respon
 		trace!(method = ?method, uri = ?uri, "{code} {reason}");
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use std::net::IpAddr;
+
+	use http::{HeaderMap, HeaderValue};
+
+	use super::forwarded_client_ip;
+
+	fn ip(s: &str) -> IpAddr { s.parse().unwrap() }
+
+	fn trusted(ip: &IpAddr) -> bool { ip.is_loopback() || *ip == "10.0.0.2".parse::<IpAddr>().unwrap() }
+
+	fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+		let mut headers = HeaderMap::new();
+		headers.insert(name, HeaderValue::from_static(value));
+		headers
+	}
+
+	#[test]
+	fn untrusted_peer_cannot_spoof_client_ip() {
+		let headers = headers("x-forwarded-for", "203.0.113.7");
+		assert_eq!(forwarded_client_ip(ip("198.51.100.9"), &headers, trusted), ip("198.51.100.9"));
+	}
+
+	#[test]
+	fn trusted_peer_forwards_client_ip() {
+		let headers = headers("x-forwarded-for", "203.0.113.7");
+		assert_eq!(forwarded_client_ip(ip("127.0.0.1"), &headers, trusted), ip("203.0.113.7"));
+	}
+
+	#[test]
+	fn spoofed_hops_before_the_client_are_ignored() {
+		let headers = headers("x-forwarded-for", "192.0.2.66, 203.0.113.7, 10.0.0.2");
+		assert_eq!(forwarded_client_ip(ip("127.0.0.1"), &headers, trusted), ip("203.0.113.7"));
+	}
+
+	#[test]
+	fn forwarded_header_is_used_as_fallback() {
+		let headers = headers("forwarded", r#"for="[2001:db8::17]:4711";proto=https"#);
+		assert_eq!(forwarded_client_ip(ip("::1"), &headers, trusted), ip("2001:db8::17"));
+	}
+
+	#[test]
+	fn trusted_peer_without_headers_is_the_client() {
+		assert_eq!(
+			forwarded_client_ip(ip("127.0.0.1"), &HeaderMap::new(), trusted),
+			ip("127.0.0.1")
+		);
+	}
+}
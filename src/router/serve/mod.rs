@@ -1,26 +1,65 @@
 mod plain;
+mod proxy;
 mod tls;
 mod unix;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::Router;
 use axum_server::Handle as ServerHandle;
-use conduit::{Error, Result, Server};
+use conduit::{Config, Error, Result, Server};
+use hyper_util::{
+	rt::{TokioExecutor, TokioTimer},
+	server::conn::auto::Builder,
+};
 use tokio::sync::broadcast;
 
+/// How long a client has to send the complete request headers.
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Serve clients
 pub(super) async fn serve(
 	server: &Arc<Server>, app: Router, handle: ServerHandle, shutdown: broadcast::Receiver<()>,
 ) -> Result<(), Error> {
 	let config = &server.config;
-	let addrs = config.get_bind_addrs();
+	let unix = cfg!(unix) && config.unix_socket_path.is_some();
 
-	if cfg!(unix) && config.unix_socket_path.is_some() {
+	if unix && config.dual_listening {
+		let tcp = serve_tcp(server, app.clone(), handle, shutdown.resubscribe());
+		tokio::try_join!(unix::serve(server, app, shutdown), tcp).map(|_| ())
+	} else if unix {
 		unix::serve(server, app, shutdown).await
-	} else if config.tls.is_some() {
+	} else {
+		serve_tcp(server, app, handle, shutdown).await
+	}
+}
+
+/// Serve clients on the configured TCP addresses, with or without TLS.
+async fn serve_tcp(
+	server: &Arc<Server>, app: Router, handle: ServerHandle, shutdown: broadcast::Receiver<()>,
+) -> Result<(), Error> {
+	let addrs = server.config.get_bind_addrs();
+
+	if server.config.proxy_protocol {
+		proxy::serve(server, app, addrs, shutdown).await
+	} else if server.config.tls.is_some() {
 		tls::serve(server, app, handle, addrs).await
 	} else {
 		plain::serve(server, app, handle, addrs).await
 	}
 }
+
+/// Applies the client connection limits to the HTTP/1 and HTTP/2 server
+/// builder.
+fn configure_builder(config: &Config, builder: &mut Builder<TokioExecutor>) {
+	let keepalive = config.client_keepalive_s;
+	builder
+		.http1()
+		.timer(TokioTimer::new())
+		.header_read_timeout(HEADER_READ_TIMEOUT)
+		.keep_alive(keepalive > 0);
+	builder
+		.http2()
+		.timer(TokioTimer::new())
+		.keep_alive_interval((keepalive > 0).then(|| Duration::from_secs(keepalive)));
+}
@@ -15,7 +15,9 @@ pub(super) async fn serve(
 	let app = app.into_make_service_with_connect_info::<SocketAddr>();
 	let mut join_set = JoinSet::new();
 	for addr in &addrs {
-		join_set.spawn_on(bind(*addr).handle(handle.clone()).serve(app.clone()), server.runtime());
+		let mut listener = bind(*addr).handle(handle.clone());
+		super::configure_builder(&server.config, listener.http_builder());
+		join_set.spawn_on(listener.serve(app.clone()), server.runtime());
 	}
 
 	info!("Listening on {addrs:?}");
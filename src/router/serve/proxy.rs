@@ -0,0 +1,247 @@
+//! PROXY protocol (v1 and v2) listener, recovering the client address when
+//! conduwuit sits behind a layer 4 load balancer.
+
+use std::{
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	str,
+	sync::Arc,
+};
+
+use axum::{
+	extract::{connect_info::IntoMakeServiceWithConnectInfo, Request},
+	Router,
+};
+use conduit::{debug_error, debug_warn, trace, utils, Error, Result, Server};
+use hyper::{body::Incoming, service::service_fn};
+use hyper_util::{
+	rt::{TokioExecutor, TokioIo},
+	server::conn::auto::Builder,
+};
+use tokio::{
+	io::AsyncReadExt,
+	net::{TcpListener, TcpStream},
+	sync::broadcast,
+	task::JoinSet,
+	time::timeout,
+};
+use tower::{Service, ServiceExt};
+use tracing::info;
+use utils::unwrap_infallible;
+
+use super::HEADER_READ_TIMEOUT;
+
+type MakeService = IntoMakeServiceWithConnectInfo<Router, SocketAddr>;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+#[tracing::instrument(skip_all)]
+pub(super) async fn serve(
+	server: &Arc<Server>, app: Router, addrs: Vec<SocketAddr>, shutdown: broadcast::Receiver<()>,
+) -> Result<()> {
+	let app = app.into_make_service_with_connect_info::<SocketAddr>();
+	let mut builder = Builder::new(TokioExecutor::new());
+	super::configure_builder(&server.config, &mut builder);
+
+	let mut listeners = JoinSet::new();
+	for addr in &addrs {
+		let listener = TcpListener::bind(*addr).await?;
+		listeners.spawn_on(
+			listen(
+				Arc::clone(server),
+				listener,
+				app.clone(),
+				builder.clone(),
+				shutdown.resubscribe(),
+			),
+			server.runtime(),
+		);
+	}
+
+	info!("Listening on {addrs:?} expecting PROXY protocol headers");
+	while listeners.join_next().await.is_some() {}
+
+	Ok(())
+}
+
+#[allow(clippy::let_underscore_must_use)]
+async fn listen(
+	server: Arc<Server>, listener: TcpListener, app: MakeService, builder: Builder<TokioExecutor>,
+	mut shutdown: broadcast::Receiver<()>,
+) {
+	let mut tasks = JoinSet::<()>::new();
+	loop {
+		tokio::select! {
+			_sig = shutdown.recv() => break,
+			conn = listener.accept() => match conn {
+				Ok((socket, peer)) => {
+					_ = tasks.spawn_on(accept(app.clone(), builder.clone(), socket, peer), server.runtime());
+					while tasks.try_join_next().is_some() {}
+				},
+				Err(err) => debug_error!(?listener, "accept error: {err}"),
+			},
+		}
+	}
+
+	drop(listener);
+	tasks.shutdown().await;
+}
+
+#[allow(clippy::let_underscore_must_use)]
+async fn accept(mut app: MakeService, builder: Builder<TokioExecutor>, mut socket: TcpStream, peer: SocketAddr) {
+	let remote = match timeout(HEADER_READ_TIMEOUT, read_header(&mut socket)).await {
+		Ok(Ok(remote)) => remote.unwrap_or(peer),
+		Ok(Err(e)) => {
+			debug_warn!(?peer, "Rejecting connection without a valid PROXY header: {e}");
+			return;
+		},
+		Err(_) => {
+			debug_warn!(?peer, "Timed out waiting for PROXY header");
+			return;
+		},
+	};
+
+	trace!(?peer, ?remote, "accepted");
+	let called = unwrap_infallible(app.call(remote).await);
+	let handler = service_fn(move |req: Request<Incoming>| called.clone().oneshot(req));
+	_ = builder
+		.serve_connection(TokioIo::new(socket), handler)
+		.await;
+}
+
+/// Reads the PROXY header off the front of the stream, consuming exactly the
+/// header so the HTTP connection starts right after it. Returns the client
+/// address, or `None` when the proxy reports a local or unknown connection.
+async fn read_header(socket: &mut TcpStream) -> Result<Option<SocketAddr>> {
+	let mut header = vec![0_u8; V2_SIGNATURE.len()];
+	socket.read_exact(&mut header).await?;
+
+	if header == V2_SIGNATURE {
+		let mut fixed = [0_u8; 4];
+		socket.read_exact(&mut fixed).await?;
+		let mut payload = vec![0_u8; u16::from_be_bytes([fixed[2], fixed[3]]).into()];
+		socket.read_exact(&mut payload).await?;
+		return parse_v2(fixed[0], fixed[1], &payload);
+	}
+
+	if !header.starts_with(V1_PREFIX) {
+		return Err(Error::Err("missing PROXY protocol signature".to_owned()));
+	}
+
+	while !header.ends_with(b"\r\n") {
+		if header.len() >= V1_MAX_LEN {
+			return Err(Error::Err("PROXY protocol v1 header too long".to_owned()));
+		}
+		header.push(socket.read_u8().await?);
+	}
+
+	parse_v1(&header)
+}
+
+/// Parses a complete v1 (text) header line, e.g.
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+	let invalid = || Error::Err("invalid PROXY protocol v1 header".to_owned());
+	let line = str::from_utf8(line).map_err(|_| invalid())?;
+	let mut fields = line.trim_end_matches("\r\n").split(' ');
+	if fields.next() != Some("PROXY") {
+		return Err(invalid());
+	}
+
+	match fields.next() {
+		Some("UNKNOWN") => Ok(None),
+		Some("TCP4" | "TCP6") => {
+			let src: IpAddr = fields
+				.next()
+				.and_then(|s| s.parse().ok())
+				.ok_or_else(invalid)?;
+			let _dst = fields.next().ok_or_else(invalid)?;
+			let port: u16 = fields
+				.next()
+				.and_then(|s| s.parse().ok())
+				.ok_or_else(invalid)?;
+			Ok(Some(SocketAddr::new(src, port)))
+		},
+		_ => Err(invalid()),
+	}
+}
+
+/// Parses a v2 (binary) header from its version/command byte, family byte and
+/// address payload.
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> Result<Option<SocketAddr>> {
+	let invalid = || Error::Err("invalid PROXY protocol v2 header".to_owned());
+	if version_command >> 4 != 2 {
+		return Err(invalid());
+	}
+
+	match version_command & 0x0F {
+		// LOCAL: the proxy's own connection, e.g. a health check
+		0x0 => return Ok(None),
+		0x1 => {},
+		_ => return Err(invalid()),
+	}
+
+	match (family >> 4, payload) {
+		(0x1, [a, b, c, d, _, _, _, _, p1, p2, ..]) => Ok(Some(SocketAddr::new(
+			Ipv4Addr::new(*a, *b, *c, *d).into(),
+			u16::from_be_bytes([*p1, *p2]),
+		))),
+		(0x2, payload) if payload.len() >= 36 => {
+			let (src, rest) = payload.split_at(16);
+			let src: [u8; 16] = src.try_into().expect("16 byte source address");
+			let port = u16::from_be_bytes([rest[16], rest[17]]);
+			Ok(Some(SocketAddr::new(Ipv6Addr::from(src).into(), port)))
+		},
+		// UNSPEC or UNIX: nothing we can use as a client address
+		(0x0 | 0x3, _) => Ok(None),
+		_ => Err(invalid()),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::SocketAddr;
+
+	use super::{parse_v1, parse_v2};
+
+	#[test]
+	fn v1_tcp4_header_yields_client_address() {
+		let addr = parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\n").unwrap();
+		assert_eq!(addr, Some("192.0.2.1:56324".parse::<SocketAddr>().unwrap()));
+	}
+
+	#[test]
+	fn v1_tcp6_and_unknown_headers() {
+		let addr = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").unwrap();
+		assert_eq!(addr, Some("[2001:db8::1]:4000".parse::<SocketAddr>().unwrap()));
+		assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+	}
+
+	#[test]
+	fn v1_malformed_header_is_rejected() {
+		assert!(parse_v1(b"PROXY TCP4 not-an-ip 198.51.100.1 56324 443\r\n").is_err());
+		assert!(parse_v1(b"GET / HTTP/1.1\r\n").is_err());
+	}
+
+	#[test]
+	fn v2_headers_yield_client_address() {
+		let v4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xDC, 0x04, 0x01, 0xBB];
+		let addr = parse_v2(0x21, 0x11, &v4).unwrap();
+		assert_eq!(addr, Some("192.0.2.1:56324".parse::<SocketAddr>().unwrap()));
+
+		let mut v6 = vec![0_u8; 36];
+		v6[..2].copy_from_slice(&[0x20, 0x01]);
+		v6[15] = 1;
+		v6[32..34].copy_from_slice(&4000_u16.to_be_bytes());
+		let addr = parse_v2(0x21, 0x21, &v6).unwrap();
+		assert_eq!(addr, Some("[2001::1]:4000".parse::<SocketAddr>().unwrap()));
+	}
+
+	#[test]
+	fn v2_local_and_bad_version() {
+		assert_eq!(parse_v2(0x20, 0x00, &[]).unwrap(), None);
+		assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+		assert!(parse_v2(0x21, 0x11, &[0; 4]).is_err());
+	}
+}
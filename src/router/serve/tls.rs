@@ -35,22 +35,17 @@ pub(super) async fn serve(
 	if cfg!(feature = "axum_dual_protocol") && tls.dual_protocol {
 		#[cfg(feature = "axum_dual_protocol")]
 		for addr in &addrs {
-			join_set.spawn_on(
-				axum_server_dual_protocol::bind_dual_protocol(*addr, conf.clone())
-					.set_upgrade(false)
-					.handle(handle.clone())
-					.serve(app.clone()),
-				server.runtime(),
-			);
+			let mut listener = axum_server_dual_protocol::bind_dual_protocol(*addr, conf.clone())
+				.set_upgrade(false)
+				.handle(handle.clone());
+			super::configure_builder(config, listener.http_builder());
+			join_set.spawn_on(listener.serve(app.clone()), server.runtime());
 		}
 	} else {
 		for addr in &addrs {
-			join_set.spawn_on(
-				bind_rustls(*addr, conf.clone())
-					.handle(handle.clone())
-					.serve(app.clone()),
-				server.runtime(),
-			);
+			let mut listener = bind_rustls(*addr, conf.clone()).handle(handle.clone());
+			super::configure_builder(config, listener.http_builder());
+			join_set.spawn_on(listener.serve(app.clone()), server.runtime());
 		}
 	}
 
@@ -28,6 +28,8 @@ use utils::unwrap_infallible;
 
 type MakeService = IntoMakeServiceWithConnectInfo<Router, net::SocketAddr>;
 
+/// Peers on a UNIX socket have no IP address; handlers extracting the client IP
+/// see this unless a reverse proxy forwards the real one in a header.
 static NULL_ADDR: net::SocketAddr = net::SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0);
 
 #[tracing::instrument(skip_all)]
@@ -35,7 +37,8 @@ pub(super) async fn serve(server: &Arc<Server>, app: Router, mut shutdown: broad
 	let mut tasks = JoinSet::<()>::new();
 	let executor = TokioExecutor::new();
 	let app = app.into_make_service_with_connect_info::<net::SocketAddr>();
-	let builder = server::conn::auto::Builder::new(executor);
+	let mut builder = server::conn::auto::Builder::new(executor);
+	super::configure_builder(&server.config, &mut builder);
 	let listener = init(server).await?;
 	loop {
 		let app = app.clone();
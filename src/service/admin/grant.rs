@@ -1,6 +1,4 @@
-use std::collections::BTreeMap;
-
-use conduit::Result;
+use conduit::{utils::mutex_map, Error, Result};
 use ruma::{
 	events::{
 		room::{
@@ -8,9 +6,9 @@ use ruma::{
 			message::RoomMessageEventContent,
 			power_levels::RoomPowerLevelsEventContent,
 		},
-		TimelineEventType,
+		StateEventType, TimelineEventType,
 	},
-	UserId,
+	Int, RoomId, UserId,
 };
 use serde_json::value::to_raw_value;
 
@@ -82,30 +80,7 @@ pub async fn make_user_admin(user_id: &UserId, displayname: String) -> Result<()
 			.await?;
 
 		// Set power level
-		let mut users = BTreeMap::new();
-		users.insert(server_user.clone(), 100.into());
-		users.insert(user_id.to_owned(), 100.into());
-
-		services()
-			.rooms
-			.timeline
-			.build_and_append_pdu(
-				PduBuilder {
-					event_type: TimelineEventType::RoomPowerLevels,
-					content: to_raw_value(&RoomPowerLevelsEventContent {
-						users,
-						..Default::default()
-					})
-					.expect("event is valid, we just created it"),
-					unsigned: None,
-					state_key: Some(String::new()),
-					redacts: None,
-				},
-				server_user,
-				&room_id,
-				&state_lock,
-			)
-			.await?;
+		set_admin_power_level(&room_id, user_id, Some(100.into()), &state_lock).await?;
 
 		// Send welcome message
 		services().rooms.timeline.build_and_append_pdu(
@@ -128,3 +103,85 @@ pub async fn make_user_admin(user_id: &UserId, displayname: String) -> Result<()
 
 	Ok(())
 }
+
+/// Kick the user from the conduit admin room and drop their power level.
+///
+/// In conduit, this is equivalent to revoking admin privileges.
+pub async fn revoke_user_admin(user_id: &UserId) -> Result<()> {
+	if let Some(room_id) = Service::get_admin_room()? {
+		let state_lock = services().globals.roomid_mutex_state.lock(&room_id).await;
+
+		services()
+			.rooms
+			.timeline
+			.build_and_append_pdu(
+				PduBuilder {
+					event_type: TimelineEventType::RoomMember,
+					content: to_raw_value(&RoomMemberEventContent {
+						membership: MembershipState::Leave,
+						displayname: None,
+						avatar_url: None,
+						is_direct: None,
+						third_party_invite: None,
+						blurhash: None,
+						reason: Some("Admin privileges revoked".to_owned()),
+						join_authorized_via_users_server: None,
+					})
+					.expect("event is valid, we just created it"),
+					unsigned: None,
+					state_key: Some(user_id.to_string()),
+					redacts: None,
+				},
+				&services().globals.server_user,
+				&room_id,
+				&state_lock,
+			)
+			.await?;
+
+		set_admin_power_level(&room_id, user_id, None, &state_lock).await?;
+	}
+
+	Ok(())
+}
+
+/// Sets or removes the user's power level in the admin room, keeping the
+/// power levels of the other admins.
+async fn set_admin_power_level(
+	room_id: &RoomId, user_id: &UserId, level: Option<Int>, state_lock: &mutex_map::Guard<()>,
+) -> Result<()> {
+	let server_user = &services().globals.server_user;
+	let mut power_levels: RoomPowerLevelsEventContent = services()
+		.rooms
+		.state_accessor
+		.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+		.map(|ev| {
+			serde_json::from_str(ev.content.get()).map_err(|_| Error::bad_database("invalid m.room.power_levels event"))
+		})
+		.transpose()?
+		.unwrap_or_default();
+
+	power_levels.users.insert(server_user.clone(), 100.into());
+	match level {
+		Some(level) => power_levels.users.insert(user_id.to_owned(), level),
+		None => power_levels.users.remove(user_id),
+	};
+
+	services()
+		.rooms
+		.timeline
+		.build_and_append_pdu(
+			PduBuilder {
+				event_type: TimelineEventType::RoomPowerLevels,
+				content: to_raw_value(&power_levels).expect("event is valid, we just created it"),
+				unsigned: None,
+				state_key: Some(String::new()),
+				redacts: None,
+			},
+			server_user,
+			room_id,
+			state_lock,
+		)
+		.await?;
+
+	Ok(())
+}
@@ -6,7 +6,7 @@ use std::{future::Future, pin::Pin, sync::Arc};
 
 use conduit::{utils::mutex_map, Error, Result};
 pub use create::create_admin_room;
-pub use grant::make_user_admin;
+pub use grant::{make_user_admin, revoke_user_admin};
 use ruma::{
 	events::{
 		room::message::{Relation, RoomMessageEventContent},
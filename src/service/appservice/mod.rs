@@ -1,18 +1,27 @@
 mod data;
 
-use std::{collections::BTreeMap, sync::Arc};
+use std::{
+	collections::BTreeMap,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
 pub use data::Data;
-use futures_util::Future;
+use futures_util::{stream::FuturesUnordered, Future, StreamExt};
 use regex::RegexSet;
 use ruma::{
-	api::appservice::{Namespace, Registration},
+	api::appservice::{thirdparty::get_protocol, Namespace, Registration},
+	thirdparty::Protocol,
 	RoomAliasId, RoomId, UserId,
 };
 use tokio::sync::RwLock;
+use tracing::debug;
 
 use crate::{services, Result};
 
+/// How long aggregated third party protocol metadata is served from cache.
+const PROTOCOLS_CACHE_TTL: Duration = Duration::from_secs(60);
+
 /// Compiled regular expressions for a namespace
 #[derive(Clone, Debug)]
 pub struct NamespaceRegex {
@@ -115,6 +124,7 @@ impl TryFrom<Registration> for RegistrationInfo {
 pub struct Service {
 	pub db: Arc<dyn Data>,
 	registration_info: RwLock<BTreeMap<String, RegistrationInfo>>,
+	protocols_cache: RwLock<Option<(Instant, BTreeMap<String, Protocol>)>>,
 }
 
 impl Service {
@@ -134,6 +144,7 @@ impl Service {
 		Ok(Self {
 			db,
 			registration_info: RwLock::new(registration_info),
+			protocols_cache: RwLock::new(None),
 		})
 	}
 
@@ -145,6 +156,13 @@ impl Service {
 			.await
 			.insert(yaml.id.clone(), yaml.clone().try_into()?);
 
+		// cached answers were computed against the old namespaces, if any
+		services()
+			.rooms
+			.state_cache
+			.clear_appservice_in_room_cache();
+		self.protocols_cache.write().await.take();
+
 		self.db.register_appservice(yaml)
 	}
 
@@ -164,6 +182,12 @@ impl Service {
 		// remove the appservice from the database
 		self.db.unregister_appservice(service_name)?;
 
+		services()
+			.rooms
+			.state_cache
+			.clear_appservice_in_room_cache();
+		self.protocols_cache.write().await.take();
+
 		// deletes all active requests for the appservice if there are any so we stop
 		// sending to the URL
 		services().sending.cleanup_events(service_name.to_owned())?;
@@ -224,7 +248,173 @@ impl Service {
 			.any(|info| info.rooms.is_exclusive_match(room_id.as_str()))
 	}
 
+	/// Metadata for every third party protocol advertised in appservice
+	/// registrations, fetched from the appservices themselves. Protocols whose
+	/// appservice can't be queried are left out. Results are cached briefly.
+	pub async fn thirdparty_protocols(&self) -> BTreeMap<String, Protocol> {
+		if let Some((fetched, protocols)) = &*self.protocols_cache.read().await {
+			if fetched.elapsed() < PROTOCOLS_CACHE_TTL {
+				return protocols.clone();
+			}
+		}
+
+		let registrations: Vec<Registration> = self
+			.read()
+			.await
+			.values()
+			.map(|info| info.registration.clone())
+			.collect();
+
+		let mut futures: FuturesUnordered<_> = advertised_protocols(&registrations)
+			.into_iter()
+			.map(|(registration, protocol)| async move {
+				let response = services()
+					.sending
+					.send_appservice_request(registration.clone(), get_protocol::v1::Request::new(protocol.clone()))
+					.await;
+				(registration, protocol, response)
+			})
+			.collect();
+
+		let mut protocols = BTreeMap::new();
+		while let Some((registration, protocol, response)) = futures.next().await {
+			match response {
+				Ok(Some(response)) => merge_protocol(&mut protocols, protocol, response.protocol),
+				Ok(None) => {},
+				Err(e) => debug!("Appservice {} did not describe protocol {protocol}: {e}", registration.id),
+			}
+		}
+
+		*self.protocols_cache.write().await = Some((Instant::now(), protocols.clone()));
+
+		protocols
+	}
+
+	/// Registrations of the appservices bridging `protocol`.
+	pub async fn registrations_for_protocol(&self, protocol: &str) -> Vec<Registration> {
+		self.read()
+			.await
+			.values()
+			.filter(|info| bridges_protocol(&info.registration, protocol))
+			.map(|info| info.registration.clone())
+			.collect()
+	}
+
+	/// Registrations of the appservices whose user namespace covers `user_id`.
+	pub async fn registrations_for_user(&self, user_id: &UserId) -> Vec<Registration> {
+		self.read()
+			.await
+			.values()
+			.filter(|info| info.is_user_match(user_id))
+			.map(|info| info.registration.clone())
+			.collect()
+	}
+
+	/// Registrations of the appservices whose alias namespace covers `alias`.
+	pub async fn registrations_for_alias(&self, alias: &RoomAliasId) -> Vec<Registration> {
+		self.read()
+			.await
+			.values()
+			.filter(|info| info.aliases.is_match(alias.as_str()))
+			.map(|info| info.registration.clone())
+			.collect()
+	}
+
 	pub fn read(&self) -> impl Future<Output = tokio::sync::RwLockReadGuard<'_, BTreeMap<String, RegistrationInfo>>> {
 		self.registration_info.read()
 	}
 }
+
+/// Pairs each registration with the protocols it advertises.
+fn advertised_protocols(registrations: &[Registration]) -> Vec<(&Registration, String)> {
+	registrations
+		.iter()
+		.flat_map(|registration| {
+			registration
+				.protocols
+				.iter()
+				.flatten()
+				.map(move |protocol| (registration, protocol.clone()))
+		})
+		.collect()
+}
+
+fn bridges_protocol(registration: &Registration, protocol: &str) -> bool {
+	registration
+		.protocols
+		.iter()
+		.flatten()
+		.any(|p| p == protocol)
+}
+
+/// Adds a protocol to the aggregate. When several appservices bridge the same
+/// protocol, the first description is kept and their instances are combined.
+fn merge_protocol(protocols: &mut BTreeMap<String, Protocol>, name: String, protocol: Protocol) {
+	match protocols.get_mut(&name) {
+		Some(existing) => existing.instances.extend(protocol.instances),
+		None => {
+			protocols.insert(name, protocol);
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use ruma::{api::appservice::Registration, thirdparty::Protocol, user_id};
+
+	use super::{advertised_protocols, bridges_protocol, merge_protocol, RegistrationInfo};
+
+	fn registration(id: &str, protocols: &str) -> Registration {
+		serde_yaml::from_str(&format!(
+			"id: {id}\nurl: http://localhost:9000\nas_token: as\nhs_token: hs\nsender_localpart: {id}\nnamespaces: \
+			 {{}}\nprotocols: {protocols}\n"
+		))
+		.unwrap()
+	}
+
+	fn protocol(network_id: &str) -> Protocol {
+		serde_json::from_str(&format!(
+			r#"{{"user_fields":["nick"],"location_fields":["channel"],"icon":"","field_types":{{}},"instances":[{{"desc":"{network_id}","fields":{{}},"network_id":"{network_id}","instance_id":"{network_id}"}}]}}"#
+		))
+		.unwrap()
+	}
+
+	#[test]
+	fn registered_appservice_protocols_are_advertised() {
+		let registrations = [registration("irc", "[irc]"), registration("other", "[]")];
+		let advertised = advertised_protocols(&registrations);
+
+		assert_eq!(advertised.len(), 1);
+		assert_eq!(advertised[0].0.id, "irc");
+		assert_eq!(advertised[0].1, "irc");
+	}
+
+	#[test]
+	fn instances_of_the_same_protocol_are_combined() {
+		let mut protocols = BTreeMap::new();
+		merge_protocol(&mut protocols, "irc".to_owned(), protocol("libera"));
+		merge_protocol(&mut protocols, "irc".to_owned(), protocol("oftc"));
+
+		let networks: Vec<_> = protocols["irc"]
+			.instances
+			.iter()
+			.map(|instance| instance.network_id.as_str())
+			.collect();
+		assert_eq!(networks, ["libera", "oftc"]);
+	}
+
+	#[test]
+	fn lookups_go_to_the_matching_appservice() {
+		let irc = registration("irc", "[irc]");
+		assert!(bridges_protocol(&irc, "irc"));
+		assert!(!bridges_protocol(&irc, "slack"));
+
+		let mut bridge = irc;
+		bridge.namespaces.users = serde_yaml::from_str("[{exclusive: true, regex: '@irc_.*:example\\.com'}]").unwrap();
+		let info = RegistrationInfo::try_from(bridge).unwrap();
+		assert!(info.is_user_match(user_id!("@irc_alice:example.com")));
+		assert!(!info.is_user_match(user_id!("@alice:example.com")));
+	}
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use tokio::{
+	task::JoinHandle,
+	time::{interval_at, Instant, MissedTickBehavior},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{services, Error, Result};
+
+#[tracing::instrument]
+pub fn start_backup_task(interval_hours: u64) -> JoinHandle<()> {
+	let timer_interval = Duration::from_secs(interval_hours.saturating_mul(60 * 60));
+	info!("Scheduling database backups every {interval_hours} hour(s)");
+
+	services().server.runtime().spawn(async move {
+		let mut i = interval_at(Instant::now() + timer_interval, timer_interval);
+		i.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+		loop {
+			i.tick().await;
+
+			match backup().await {
+				Ok(true) => info!("Scheduled database backup completed"),
+				Ok(false) => warn!("Skipping scheduled database backup, another backup is already in progress"),
+				Err(e) => error!("Scheduled database backup failed: {e}"),
+			}
+		}
+	})
+}
+
+/// Runs an online backup followed by the `database_backups_to_keep`
+/// rotation. Returns `Ok(false)` without doing anything if another backup is
+/// already in progress.
+pub async fn backup() -> Result<bool> {
+	let Ok(_lock) = services().globals.backup_mutex.try_lock() else {
+		return Ok(false);
+	};
+
+	debug!("Starting database backup");
+	let result = services()
+		.server
+		.runtime()
+		.spawn_blocking(move || match services().globals.db.backup() {
+			Ok(()) => String::new(),
+			Err(e) => (*e).to_string(),
+		})
+		.await
+		.map_err(|e| Error::Err(format!("Database backup task failed: {e}")))?;
+
+	if !result.is_empty() {
+		return Err(Error::Err(result));
+	}
+
+	Ok(true)
+}
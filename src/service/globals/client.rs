@@ -32,6 +32,7 @@ impl Client {
 
 			well_known: Self::base(config)
 				.unwrap()
+				.user_agent(federation_user_agent(config.federation_user_agent.as_deref()))
 				.dns_resolver(resolver.hooked.clone())
 				.connect_timeout(Duration::from_secs(config.well_known_conn_timeout))
 				.read_timeout(Duration::from_secs(config.well_known_timeout))
@@ -43,6 +44,7 @@ impl Client {
 
 			federation: Self::base(config)
 				.unwrap()
+				.user_agent(federation_user_agent(config.federation_user_agent.as_deref()))
 				.dns_resolver(resolver.hooked.clone())
 				.read_timeout(Duration::from_secs(config.federation_timeout))
 				.timeout(Duration::from_secs(config.federation_timeout))
@@ -54,6 +56,7 @@ impl Client {
 
 			sender: Self::base(config)
 				.unwrap()
+				.user_agent(federation_user_agent(config.federation_user_agent.as_deref()))
 				.dns_resolver(resolver.hooked.clone())
 				.read_timeout(Duration::from_secs(config.sender_timeout))
 				.timeout(Duration::from_secs(config.sender_timeout))
@@ -87,9 +90,7 @@ impl Client {
 	}
 
 	fn base(config: &Config) -> Result<reqwest::ClientBuilder> {
-		let version = conduit::version::conduwuit();
-
-		let user_agent = format!("Conduwuit/{version}");
+		let user_agent = federation_user_agent(None);
 
 		let mut builder = reqwest::Client::builder()
 			.hickory_dns(true)
@@ -137,3 +138,32 @@ impl Client {
 		}
 	}
 }
+
+/// User-Agent sent on outgoing requests, with the admin-configured
+/// `federation_user_agent` contact details appended as a comment for requests
+/// to other homeservers.
+fn federation_user_agent(contact: Option<&str>) -> String {
+	let version = conduit::version::conduwuit();
+
+	match contact {
+		Some(contact) if !contact.is_empty() => format!("Conduwuit/{version} ({contact})"),
+		_ => format!("Conduwuit/{version}"),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::federation_user_agent;
+
+	#[test]
+	fn federation_user_agent_contact() {
+		let version = conduit::version::conduwuit();
+
+		assert_eq!(federation_user_agent(None), format!("Conduwuit/{version}"));
+		assert_eq!(federation_user_agent(Some("")), format!("Conduwuit/{version}"));
+		assert_eq!(
+			federation_user_agent(Some("+https://example.com/contact")),
+			format!("Conduwuit/{version} (+https://example.com/contact)")
+		);
+	}
+}
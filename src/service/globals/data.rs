@@ -5,6 +5,7 @@ use futures_util::{stream::FuturesUnordered, StreamExt};
 use lru_cache::LruCache;
 use ruma::{
 	api::federation::discovery::{ServerSigningKeys, VerifyKey},
+	serde::Raw,
 	signatures::Ed25519KeyPair,
 	DeviceId, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId, ServerName, UserId,
 };
@@ -39,9 +40,14 @@ pub trait Data: Send + Sync {
 	/// This returns an empty `Ok(BTreeMap<..>)` when there are no keys found
 	/// for the server.
 	fn signing_keys_for(&self, origin: &ServerName) -> Result<BTreeMap<OwnedServerSigningKeyId, VerifyKey>>;
+	fn server_signing_keys(&self, origin: &ServerName) -> Result<Option<ServerSigningKeys>>;
+	fn set_server_signing_keys(&self, origin: &ServerName, keys: &ServerSigningKeys) -> Result<()>;
+	fn signed_server_keys(&self, origin: &ServerName) -> Result<Option<Raw<ServerSigningKeys>>>;
+	fn set_signed_server_keys(&self, origin: &ServerName, keys: &Raw<ServerSigningKeys>) -> Result<()>;
 	fn database_version(&self) -> Result<u64>;
 	fn bump_database_version(&self, new_version: u64) -> Result<()>;
 	fn backup(&self) -> Result<(), Box<dyn std::error::Error>> { unimplemented!() }
+	fn backup_prune(&self) -> Result<(), Box<dyn std::error::Error>> { Ok(()) }
 	fn backup_list(&self) -> Result<String> { Ok(String::new()) }
 	fn file_list(&self) -> Result<String> { Ok(String::new()) }
 }
@@ -308,6 +314,38 @@ lasttimelinecount_cache: {lasttimelinecount_cache} / {max_lasttimelinecount_cach
 		Ok(signingkeys)
 	}
 
+	fn server_signing_keys(&self, origin: &ServerName) -> Result<Option<ServerSigningKeys>> {
+		self.server_signingkeys
+			.get(origin.as_bytes())?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes)
+					.map_err(|_| Error::bad_database("Invalid server signing keys in database."))
+			})
+			.transpose()
+	}
+
+	fn set_server_signing_keys(&self, origin: &ServerName, keys: &ServerSigningKeys) -> Result<()> {
+		self.server_signingkeys.insert(
+			origin.as_bytes(),
+			&serde_json::to_vec(keys).expect("serversigningkeys can be serialized"),
+		)
+	}
+
+	fn signed_server_keys(&self, origin: &ServerName) -> Result<Option<Raw<ServerSigningKeys>>> {
+		self.server_signedkeys
+			.get(origin.as_bytes())?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes)
+					.map_err(|_| Error::bad_database("Invalid signed server keys in database."))
+			})
+			.transpose()
+	}
+
+	fn set_signed_server_keys(&self, origin: &ServerName, keys: &Raw<ServerSigningKeys>) -> Result<()> {
+		self.server_signedkeys
+			.insert(origin.as_bytes(), keys.json().get().as_bytes())
+	}
+
 	fn database_version(&self) -> Result<u64> {
 		self.global.get(b"version")?.map_or(Ok(0), |version| {
 			utils::u64_from_bytes(&version).map_err(|_| Error::bad_database("Database version id is invalid."))
@@ -321,6 +359,8 @@ lasttimelinecount_cache: {lasttimelinecount_cache} / {max_lasttimelinecount_cach
 
 	fn backup(&self) -> Result<(), Box<dyn std::error::Error>> { self.db.backup() }
 
+	fn backup_prune(&self) -> Result<(), Box<dyn std::error::Error>> { self.db.backup_prune() }
+
 	fn backup_list(&self) -> Result<String> { self.db.backup_list() }
 
 	fn file_list(&self) -> Result<String> { self.db.file_list() }
@@ -117,6 +117,10 @@ pub(crate) async fn migrations(db: &KeyValueDatabase, config: &Config) -> Result
 			retroactively_fix_bad_data_from_roomuserid_joined(db, config).await?;
 		}
 
+		if db.global.get(b"backfill_user_media_sizes")?.is_none() {
+			backfill_user_media_sizes(db, config).await?;
+		}
+
 		assert_eq!(
 			services().globals.database_version().unwrap(),
 			latest_database_version,
@@ -188,6 +192,7 @@ pub(crate) async fn migrations(db: &KeyValueDatabase, config: &Config) -> Result
 			.insert(b"fix_bad_double_separator_in_state_cache", &[])?;
 		db.global
 			.insert(b"retroactively_fix_bad_data_from_roomuserid_joined", &[])?;
+		db.global.insert(b"backfill_user_media_sizes", &[])?;
 
 		// Create the admin room and server user on first run
 		crate::admin::create_admin_room().await?;
@@ -684,27 +689,11 @@ async fn db_lt_13(_db: &KeyValueDatabase, config: &Config) -> Result<()> {
 }
 
 #[cfg(feature = "sha256_media")]
-async fn feat_sha256_media(db: &KeyValueDatabase, _config: &Config) -> Result<()> {
-	use std::path::PathBuf;
+async fn feat_sha256_media(_db: &KeyValueDatabase, _config: &Config) -> Result<()> {
 	warn!("sha256_media feature flag is enabled, migrating legacy base64 file names to sha256 file names");
-	// Move old media files to new names
-	let mut changes = Vec::<(PathBuf, PathBuf)>::new();
-	for (key, _) in db.mediaid_file.iter() {
-		let old_path = services().globals.get_media_file(&key);
-		debug!("Old file path: {old_path:?}");
-		let path = services().globals.get_media_file_new(&key);
-		debug!("New file path: {path:?}");
-		changes.push((old_path, path));
-	}
-	// move the file to the new location
-	for (old_path, path) in changes {
-		if old_path.exists() {
-			tokio::fs::rename(&old_path, &path).await?;
-		}
-	}
+	let moved = services().media.migrate_to_sha256(false).await?;
 
-	services().globals.bump_database_version(14)?;
-	info!("Migration: 13 -> 14 finished");
+	info!("Migration: 13 -> 14 finished, moved {moved} media file(s)");
 	Ok(())
 }
 
@@ -823,3 +812,14 @@ async fn retroactively_fix_bad_data_from_roomuserid_joined(db: &KeyValueDatabase
 	info!("Finished fixing");
 	Ok(())
 }
+
+async fn backfill_user_media_sizes(db: &KeyValueDatabase, _config: &Config) -> Result<()> {
+	warn!("Recording the sizes of existing uploads towards their uploaders' media quotas");
+
+	let recorded = services().media.backfill_media_sizes().await?;
+
+	db.global.insert(b"backfill_user_media_sizes", &[])?;
+
+	info!("Finished recording the sizes of {recorded} upload(s)");
+	Ok(())
+}
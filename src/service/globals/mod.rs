@@ -1,16 +1,20 @@
+pub mod backup;
 mod client;
 mod data;
 pub(super) mod emerg_access;
 pub(super) mod migrations;
 mod resolver;
+pub(super) mod to_device;
 pub(super) mod updates;
 
 use std::{
 	collections::{BTreeMap, HashMap},
 	fs,
+	hash::Hash,
+	net::IpAddr,
 	path::PathBuf,
 	sync::Arc,
-	time::Instant,
+	time::{Duration, Instant, SystemTime},
 };
 
 use base64::{engine::general_purpose, Engine as _};
@@ -22,29 +26,33 @@ use regex::RegexSet;
 use ruma::{
 	api::{
 		client::discovery::discover_support::ContactRole,
-		federation::discovery::{ServerSigningKeys, VerifyKey},
+		federation::discovery::{OldVerifyKey, ServerSigningKeys, VerifyKey},
 	},
-	serde::Base64,
-	DeviceId, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedServerName, OwnedServerSigningKeyId, OwnedUserId,
-	RoomAliasId, RoomVersionId, ServerName, UserId,
+	serde::{Base64, Raw},
+	DeviceId, MilliSecondsSinceUnixEpoch, OwnedEventId, OwnedRoomAliasId, OwnedRoomId, OwnedServerName,
+	OwnedServerSigningKeyId, OwnedUserId, RoomAliasId, RoomId, RoomVersionId, ServerName, UserId,
 };
 use tokio::{
 	sync::{Mutex, RwLock},
 	task::JoinHandle,
 };
-use tracing::{error, trace};
+use tracing::{error, trace, warn};
 use url::Url;
 use utils::MutexMap;
 
-use crate::{services, Config, Result};
+use crate::{services, Config, Error, Result};
 
-type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
+pub(crate) type RateLimitState = (Instant, u32); // Time if last failed try, number of failed tries
+
+const INVITE_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const FEDERATION_QUERY_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
 
 pub struct Service {
 	pub db: Arc<dyn Data>,
 
 	pub config: Config,
 	pub cidr_range_denylist: Vec<IPAddress>,
+	pub trusted_proxies: Vec<IPAddress>,
 	keypair: Arc<ruma::signatures::Ed25519KeyPair>,
 	jwt_decoding_key: Option<jsonwebtoken::DecodingKey>,
 	pub resolver: Arc<resolver::Resolver>,
@@ -54,11 +62,18 @@ pub struct Service {
 	pub bad_event_ratelimiter: Arc<RwLock<HashMap<OwnedEventId, RateLimitState>>>,
 	pub bad_signature_ratelimiter: Arc<RwLock<HashMap<Vec<String>, RateLimitState>>>,
 	pub bad_query_ratelimiter: Arc<RwLock<HashMap<OwnedServerName, RateLimitState>>>,
+	pub invite_user_ratelimiter: RwLock<HashMap<OwnedUserId, RateLimitState>>,
+	pub invite_room_ratelimiter: RwLock<HashMap<OwnedRoomId, RateLimitState>>,
+	pub federation_query_ratelimiter: RwLock<HashMap<OwnedServerName, RateLimitState>>,
 	pub roomid_mutex_insert: MutexMap<OwnedRoomId, ()>,
 	pub roomid_mutex_state: MutexMap<OwnedRoomId, ()>,
 	pub roomid_mutex_federation: MutexMap<OwnedRoomId, ()>,
 	pub roomid_federationhandletime: RwLock<HashMap<OwnedRoomId, (OwnedEventId, Instant)>>,
+	pub registration_nonces: RwLock<HashMap<String, Instant>>,
 	pub updates_handle: Mutex<Option<JoinHandle<()>>>,
+	pub backup_handle: Mutex<Option<JoinHandle<()>>>,
+	pub to_device_cleanup_handle: Mutex<Option<JoinHandle<()>>>,
+	pub backup_mutex: Mutex<()>,
 	pub stateres_mutex: Arc<Mutex<()>>,
 	pub server_user: OwnedUserId,
 	pub admin_alias: OwnedRoomAliasId,
@@ -103,10 +118,17 @@ impl Service {
 			cidr_range_denylist.push(cidr);
 		}
 
+		let trusted_proxies = config
+			.trusted_proxies
+			.iter()
+			.map(|cidr| IPAddress::parse(cidr.as_str()).expect("valid cidr range"))
+			.collect();
+
 		let mut s = Self {
 			db,
 			config: config.clone(),
 			cidr_range_denylist,
+			trusted_proxies,
 			keypair: Arc::new(keypair),
 			resolver: resolver.clone(),
 			client: client::Client::new(config, &resolver),
@@ -116,11 +138,18 @@ impl Service {
 			bad_event_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
 			bad_signature_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
 			bad_query_ratelimiter: Arc::new(RwLock::new(HashMap::new())),
+			invite_user_ratelimiter: RwLock::new(HashMap::new()),
+			invite_room_ratelimiter: RwLock::new(HashMap::new()),
+			federation_query_ratelimiter: RwLock::new(HashMap::new()),
 			roomid_mutex_state: MutexMap::<OwnedRoomId, ()>::new(),
 			roomid_mutex_insert: MutexMap::<OwnedRoomId, ()>::new(),
 			roomid_mutex_federation: MutexMap::<OwnedRoomId, ()>::new(),
 			roomid_federationhandletime: RwLock::new(HashMap::new()),
+			registration_nonces: RwLock::new(HashMap::new()),
 			updates_handle: Mutex::new(None),
+			backup_handle: Mutex::new(None),
+			to_device_cleanup_handle: Mutex::new(None),
+			backup_mutex: Mutex::new(()),
 			stateres_mutex: Arc::new(Mutex::new(())),
 			admin_alias: RoomAliasId::parse(format!("#admins:{}", &config.server_name))
 				.expect("#admins:server_name is valid alias name"),
@@ -128,14 +157,18 @@ impl Service {
 				.expect("@conduit:server_name is valid"),
 		};
 
+		s.record_verify_key()?;
+
 		fs::create_dir_all(s.get_media_folder())?;
 
-		if !s
-			.supported_room_versions()
-			.contains(&s.config.default_room_version)
-		{
-			error!(config=?s.config.default_room_version, fallback=?crate::config::default_default_room_version(), "Room version in config isn't supported, falling back to default version");
-			s.config.default_room_version = crate::config::default_default_room_version();
+		let default_room_version = effective_default_room_version(
+			&s.config.default_room_version,
+			&s.supported_room_versions(),
+			&s.unstable_room_versions,
+		)?;
+		if default_room_version != s.config.default_room_version {
+			warn!(config=?s.config.default_room_version, fallback=?default_room_version, "Room version in config is disabled, falling back to default version");
+			s.config.default_room_version = default_room_version;
 		};
 
 		Ok(s)
@@ -164,10 +197,18 @@ impl Service {
 
 	pub fn max_request_size(&self) -> u32 { self.config.max_request_size }
 
+	pub fn media_user_quota_bytes(&self) -> Option<u64> { self.config.media_user_quota_bytes }
+
 	pub fn max_fetch_prev_events(&self) -> u16 { self.config.max_fetch_prev_events }
 
+	pub fn max_prev_events(&self) -> usize { self.config.max_prev_events }
+
+	pub fn max_event_size(&self) -> usize { self.config.max_event_size }
+
 	pub fn allow_registration(&self) -> bool { self.config.allow_registration }
 
+	pub fn registration_shared_secret(&self) -> Option<&String> { self.config.registration_shared_secret.as_ref() }
+
 	pub fn allow_guest_registration(&self) -> bool { self.config.allow_guest_registration }
 
 	pub fn allow_guests_auto_join_rooms(&self) -> bool { self.config.allow_guests_auto_join_rooms }
@@ -198,6 +239,8 @@ impl Service {
 
 	pub fn query_trusted_key_servers_first(&self) -> bool { self.config.query_trusted_key_servers_first }
 
+	pub fn allow_notary_server(&self) -> bool { self.config.allow_notary_server }
+
 	pub fn dns_resolver(&self) -> &TokioAsyncResolver { &self.resolver.resolver }
 
 	pub fn actual_destinations(&self) -> &Arc<RwLock<resolver::WellKnownMap>> { &self.resolver.destinations }
@@ -244,6 +287,16 @@ impl Service {
 
 	pub fn forbidden_usernames(&self) -> &RegexSet { &self.config.forbidden_usernames }
 
+	pub fn update_membership_on_profile_change(&self) -> bool { self.config.update_membership_on_profile_change }
+
+	pub fn profile_update_membership_max_room_size(&self) -> Option<u64> {
+		self.config.profile_update_membership_max_room_size
+	}
+
+	pub fn redact_events_on_erase(&self) -> bool { self.config.redact_events_on_erase }
+
+	pub fn bundled_aggregations(&self) -> bool { self.config.bundled_aggregations }
+
 	pub fn allow_local_presence(&self) -> bool { self.config.allow_local_presence }
 
 	pub fn allow_incoming_presence(&self) -> bool { self.config.allow_incoming_presence }
@@ -270,6 +323,47 @@ impl Service {
 
 	pub fn block_non_admin_invites(&self) -> bool { self.config.block_non_admin_invites }
 
+	/// Counts an invite from `sender` into `room_id` against the per-user and
+	/// per-room invite rate limits, returning how long to wait when either is
+	/// exhausted. Callers exempt admins and appservices.
+	pub async fn invite_rate_limit(&self, sender: &UserId, room_id: &RoomId) -> Result<(), Duration> {
+		let now = Instant::now();
+
+		let mut users = self.invite_user_ratelimiter.write().await;
+		let mut rooms = self.invite_room_ratelimiter.write().await;
+		users.retain(|_, (start, _)| now.duration_since(*start) < INVITE_RATE_LIMIT_WINDOW);
+		rooms.retain(|_, (start, _)| now.duration_since(*start) < INVITE_RATE_LIMIT_WINDOW);
+
+		let user = users.entry(sender.to_owned()).or_insert((now, 0));
+		let room = rooms.entry(room_id.to_owned()).or_insert((now, 0));
+		let user_limit = self.config.invite_rate_limit_per_user;
+		let room_limit = self.config.invite_rate_limit_per_room;
+		rate_limit_wait(user, now, user_limit, INVITE_RATE_LIMIT_WINDOW)?;
+		rate_limit_wait(room, now, room_limit, INVITE_RATE_LIMIT_WINDOW)?;
+
+		user.1 = user.1.saturating_add(1);
+		room.1 = room.1.saturating_add(1);
+
+		Ok(())
+	}
+
+	/// Counts a profile, key or device query from `origin` against the
+	/// per-origin federation query rate limit, returning how long to wait when
+	/// it is exhausted. Trusted servers are exempt.
+	pub async fn federation_query_rate_limit(&self, origin: &ServerName) -> Result<(), Duration> {
+		if self.trusted_servers().iter().any(|server| server == origin) {
+			return Ok(());
+		}
+
+		count_rate_limited(
+			&mut *self.federation_query_ratelimiter.write().await,
+			origin.to_owned(),
+			Instant::now(),
+			self.config.federation_query_rate_limit_per_origin,
+			FEDERATION_QUERY_RATE_LIMIT_WINDOW,
+		)
+	}
+
 	pub fn supported_room_versions(&self) -> Vec<RoomVersionId> {
 		let mut room_versions: Vec<RoomVersionId> = Vec::with_capacity(self.stable_room_versions.len());
 		room_versions.extend(self.stable_room_versions.clone());
@@ -279,6 +373,15 @@ impl Service {
 		room_versions
 	}
 
+	/// Whether local users may create (or upgrade to) rooms of this version.
+	pub fn room_version_allowed_for_creation(&self, room_version: &RoomVersionId) -> bool {
+		room_version_allowed_for_creation(
+			&self.supported_room_versions(),
+			&self.config.allowed_room_versions_for_creation,
+			room_version,
+		)
+	}
+
 	/// TODO: the key valid until timestamp (`valid_until_ts`) is only honored
 	/// in room version > 4
 	///
@@ -310,6 +413,78 @@ impl Service {
 		Ok(keys)
 	}
 
+	/// Returns the ID and public key of this server's current signing key.
+	pub fn verify_key(&self) -> Result<(OwnedServerSigningKeyId, VerifyKey)> {
+		let key_id = format!("ed25519:{}", self.keypair.version())
+			.try_into()
+			.map_err(|_| Error::bad_database("Found invalid server signing key ID."))?;
+
+		Ok((
+			key_id,
+			VerifyKey {
+				key: Base64::new(self.keypair.public_key().to_vec()),
+			},
+		))
+	}
+
+	/// Returns the keys this server signed with before rotating to its current
+	/// key, along with when each one expired.
+	pub fn old_verify_keys(&self) -> Result<BTreeMap<OwnedServerSigningKeyId, OldVerifyKey>> {
+		Ok(self
+			.db
+			.server_signing_keys(self.server_name())?
+			.map(|keys| keys.old_verify_keys)
+			.unwrap_or_default())
+	}
+
+	/// Returns this server's current and old verify keys, valid for
+	/// `server_key_validity_period_s` from now. The result is unsigned.
+	pub fn own_server_signing_keys(&self) -> Result<ServerSigningKeys> {
+		let (key_id, verify_key) = self.verify_key()?;
+		let valid_until_ts = SystemTime::now()
+			.checked_add(Duration::from_secs(self.config.server_key_validity_period_s))
+			.and_then(MilliSecondsSinceUnixEpoch::from_system_time)
+			.ok_or_else(|| Error::Err("Server key valid_until_ts is out of range.".to_owned()))?;
+
+		let mut keys = ServerSigningKeys::new(self.server_name().to_owned(), valid_until_ts);
+		keys.verify_keys.insert(key_id, verify_key);
+		keys.old_verify_keys = self.old_verify_keys()?;
+
+		Ok(keys)
+	}
+
+	/// Returns the signing keys we have stored for `origin`, as last fetched.
+	pub fn server_signing_keys(&self, origin: &ServerName) -> Result<Option<ServerSigningKeys>> {
+		self.db.server_signing_keys(origin)
+	}
+
+	/// Returns the last keys response of `origin`, with its own signatures,
+	/// which we serve as a notary.
+	pub fn signed_server_keys(&self, origin: &ServerName) -> Result<Option<Raw<ServerSigningKeys>>> {
+		self.db.signed_server_keys(origin)
+	}
+
+	pub fn set_signed_server_keys(&self, origin: &ServerName, keys: &Raw<ServerSigningKeys>) -> Result<()> {
+		self.db.set_signed_server_keys(origin, keys)
+	}
+
+	/// Stores our current verify key, retiring any previous one into
+	/// `old_verify_keys` so it keeps being published after a key rotation.
+	fn record_verify_key(&self) -> Result<()> {
+		let (key_id, verify_key) = self.verify_key()?;
+		let now = MilliSecondsSinceUnixEpoch::now();
+		let mut keys = self
+			.db
+			.server_signing_keys(self.server_name())?
+			.unwrap_or_else(|| ServerSigningKeys::new(self.server_name().to_owned(), now));
+
+		if retire_verify_keys(&mut keys, key_id, verify_key, now) {
+			self.db.set_server_signing_keys(self.server_name(), &keys)?;
+		}
+
+		Ok(())
+	}
+
 	pub fn database_version(&self) -> Result<u64> { self.db.database_version() }
 
 	pub fn bump_database_version(&self, new_version: u64) -> Result<()> { self.db.bump_database_version(new_version) }
@@ -351,6 +526,12 @@ impl Service {
 
 	pub fn well_known_server(&self) -> &Option<OwnedServerName> { &self.config.well_known.server }
 
+	/// Whether a connecting peer is a reverse proxy we take forwarded client
+	/// addresses from.
+	pub fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+		IPAddress::parse(ip.to_string()).is_ok_and(|ip| self.trusted_proxies.iter().any(|cidr| cidr.includes(&ip)))
+	}
+
 	pub fn valid_cidr_range(&self, ip: &IPAddress) -> bool {
 		for cidr in &self.cidr_range_denylist {
 			if cidr.includes(ip) {
@@ -370,3 +551,198 @@ pub fn server_is_ours(server_name: &ServerName) -> bool { server_name == service
 #[inline]
 #[must_use]
 pub fn user_is_local(user_id: &UserId) -> bool { server_is_ours(user_id.server_name()) }
+
+/// Makes `key_id` the only current verify key in `keys`, moving any others
+/// into `old_verify_keys` as expired at `now`. Returns whether `keys` changed.
+fn retire_verify_keys(
+	keys: &mut ServerSigningKeys, key_id: OwnedServerSigningKeyId, verify_key: VerifyKey,
+	now: MilliSecondsSinceUnixEpoch,
+) -> bool {
+	if keys.verify_keys.len() == 1 && keys.verify_keys.contains_key(&key_id) {
+		return false;
+	}
+
+	for (old_id, old_key) in std::mem::take(&mut keys.verify_keys) {
+		if old_id != key_id {
+			keys.old_verify_keys
+				.insert(old_id, OldVerifyKey::new(now, old_key.key));
+		}
+	}
+
+	keys.old_verify_keys.remove(&key_id);
+	keys.verify_keys.insert(key_id, verify_key);
+
+	true
+}
+
+/// Resolves the configured default room version against what is currently
+/// supported. Versions we don't implement at all are a configuration error;
+/// unstable ones disabled by `allow_unstable_room_versions` fall back to the
+/// built-in default.
+fn effective_default_room_version(
+	configured: &RoomVersionId, supported: &[RoomVersionId], unstable: &[RoomVersionId],
+) -> Result<RoomVersionId> {
+	if supported.contains(configured) {
+		Ok(configured.clone())
+	} else if unstable.contains(configured) {
+		Ok(crate::config::default_default_room_version())
+	} else {
+		Err(Error::bad_config(&format!(
+			"default_room_version {configured} is not a room version conduwuit supports."
+		)))
+	}
+}
+
+/// Fixed window rate limit over a `(window start, count)` state. Returns the
+/// time left in the window once `limit` actions have been counted in it; a
+/// limit of zero never throttles.
+fn rate_limit_wait(state: &(Instant, u32), now: Instant, limit: u32, window: Duration) -> Result<(), Duration> {
+	let (start, count) = *state;
+	if limit == 0 || count < limit {
+		return Ok(());
+	}
+
+	Err(window.saturating_sub(now.saturating_duration_since(start)))
+}
+
+/// Counts an action by `key` in a map of fixed window rate limits, forgetting
+/// windows that have ended. Actions refused while throttled aren't counted.
+fn count_rate_limited<K: Eq + Hash>(
+	states: &mut HashMap<K, RateLimitState>, key: K, now: Instant, limit: u32, window: Duration,
+) -> Result<(), Duration> {
+	states.retain(|_, (start, _)| now.saturating_duration_since(*start) < window);
+	let state = states.entry(key).or_insert((now, 0));
+	rate_limit_wait(state, now, limit, window)?;
+	state.1 = state.1.saturating_add(1);
+
+	Ok(())
+}
+
+/// Whether a room version is in `allowed_room_versions_for_creation` as well
+/// as supported. An empty allowlist allows every supported room version.
+pub fn room_version_allowed_for_creation(
+	supported: &[RoomVersionId], allowed: &[RoomVersionId], room_version: &RoomVersionId,
+) -> bool {
+	supported.contains(room_version) && (allowed.is_empty() || allowed.contains(room_version))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashMap,
+		time::{Duration, Instant},
+	};
+
+	use ruma::{
+		api::federation::discovery::{ServerSigningKeys, VerifyKey},
+		serde::Base64,
+		server_name, MilliSecondsSinceUnixEpoch, OwnedServerSigningKeyId, RoomVersionId,
+	};
+
+	use super::{
+		count_rate_limited, effective_default_room_version, rate_limit_wait, retire_verify_keys,
+		room_version_allowed_for_creation,
+	};
+
+	fn key(id: &str, bytes: &[u8]) -> (OwnedServerSigningKeyId, VerifyKey) {
+		(id.try_into().unwrap(), VerifyKey::new(Base64::new(bytes.to_vec())))
+	}
+
+	#[test]
+	fn rotated_key_moves_to_old_verify_keys() {
+		let now = MilliSecondsSinceUnixEpoch::now();
+		let mut keys = ServerSigningKeys::new(server_name!("example.com").to_owned(), now);
+
+		let (old_id, old_key) = key("ed25519:old", b"old");
+		assert!(retire_verify_keys(&mut keys, old_id.clone(), old_key, now));
+		assert!(keys.old_verify_keys.is_empty());
+
+		let (new_id, new_key) = key("ed25519:new", b"new");
+		assert!(retire_verify_keys(&mut keys, new_id.clone(), new_key.clone(), now));
+		assert_eq!(keys.verify_keys.keys().collect::<Vec<_>>(), [&new_id]);
+		assert_eq!(keys.old_verify_keys[&old_id].key, Base64::new(b"old".to_vec()));
+		assert_eq!(keys.old_verify_keys[&old_id].expired_ts, now);
+
+		assert!(!retire_verify_keys(&mut keys, new_id, new_key, now));
+	}
+
+	#[test]
+	fn creation_allowlist_limits_supported_versions() {
+		let supported = [RoomVersionId::V6, RoomVersionId::V10, RoomVersionId::V11];
+		let allowed = [RoomVersionId::V10, RoomVersionId::V11, RoomVersionId::V1];
+
+		assert!(!room_version_allowed_for_creation(&supported, &allowed, &RoomVersionId::V6));
+		assert!(room_version_allowed_for_creation(&supported, &allowed, &RoomVersionId::V11));
+		// allowing an unsupported version doesn't make it supported
+		assert!(!room_version_allowed_for_creation(&supported, &allowed, &RoomVersionId::V1));
+
+		assert!(room_version_allowed_for_creation(&supported, &[], &RoomVersionId::V6));
+		assert!(!room_version_allowed_for_creation(&supported, &[], &RoomVersionId::V1));
+	}
+
+	#[test]
+	fn disabled_default_room_version_falls_back() {
+		let stable = [RoomVersionId::V10, RoomVersionId::V11];
+		let unstable = [RoomVersionId::V5];
+
+		let effective = effective_default_room_version(&RoomVersionId::V5, &stable, &unstable).unwrap();
+		assert_eq!(effective, crate::config::default_default_room_version());
+		assert!(stable.contains(&effective));
+
+		let effective = effective_default_room_version(&RoomVersionId::V11, &stable, &unstable).unwrap();
+		assert_eq!(effective, RoomVersionId::V11);
+	}
+
+	#[test]
+	fn unknown_default_room_version_is_a_config_error() {
+		let stable = [RoomVersionId::V10, RoomVersionId::V11];
+		let unknown = RoomVersionId::try_from("org.example.custom").unwrap();
+		assert!(effective_default_room_version(&unknown, &stable, &[]).is_err());
+	}
+
+	#[test]
+	fn invite_after_limit_is_throttled() {
+		let window = Duration::from_secs(60);
+		let start = Instant::now();
+		let mut user = (start, 0_u32);
+		let mut room = (start, 0_u32);
+
+		// three invites allowed per user, five per room
+		for _ in 0..3 {
+			assert!(rate_limit_wait(&user, start, 3, window).is_ok());
+			user.1 += 1;
+		}
+		let wait = rate_limit_wait(&user, start + Duration::from_secs(20), 3, window).unwrap_err();
+		assert_eq!(wait, Duration::from_secs(40));
+
+		for _ in 0..5 {
+			assert!(rate_limit_wait(&room, start, 5, window).is_ok());
+			room.1 += 1;
+		}
+		assert!(rate_limit_wait(&room, start, 5, window).is_err());
+	}
+
+	#[test]
+	fn zero_invite_limit_is_disabled() {
+		let start = Instant::now();
+		assert!(rate_limit_wait(&(start, 1_000), start, 0, Duration::from_secs(60)).is_ok());
+	}
+
+	#[test]
+	fn federation_query_flood_only_throttles_its_origin() {
+		let window = Duration::from_secs(60);
+		let now = Instant::now();
+		let mut origins = HashMap::new();
+
+		for _ in 0..10 {
+			assert!(count_rate_limited(&mut origins, server_name!("flood.example"), now, 10, window).is_ok());
+		}
+		let wait = count_rate_limited(&mut origins, server_name!("flood.example"), now, 10, window).unwrap_err();
+		assert_eq!(wait, window);
+		assert!(count_rate_limited(&mut origins, server_name!("quiet.example"), now, 10, window).is_ok());
+
+		// the flooding origin may query again once its window is over
+		let later = now + window;
+		assert!(count_rate_limited(&mut origins, server_name!("flood.example"), later, 10, window).is_ok());
+	}
+}
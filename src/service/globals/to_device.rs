@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use tokio::{
+	task::JoinHandle,
+	time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, error, info};
+
+use crate::services;
+
+const TO_DEVICE_CLEANUP_INTERVAL: u64 = 3600; // 1 hour
+
+#[tracing::instrument]
+pub fn start_to_device_cleanup_task(ttl_days: u64) -> JoinHandle<()> {
+	let timer_interval = Duration::from_secs(TO_DEVICE_CLEANUP_INTERVAL);
+	let ttl = Duration::from_secs(ttl_days.saturating_mul(24 * 60 * 60));
+	info!("Purging undelivered to-device messages older than {ttl_days} day(s)");
+
+	services().server.runtime().spawn(async move {
+		let mut i = interval(timer_interval);
+		i.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+		loop {
+			i.tick().await;
+
+			let result = services()
+				.server
+				.runtime()
+				.spawn_blocking(move || services().users.remove_expired_to_device_events(ttl))
+				.await;
+
+			match result {
+				Ok(Ok(0)) => debug!("No expired to-device messages"),
+				Ok(Ok(removed)) => info!("Purged {removed} expired to-device message(s)"),
+				Ok(Err(e)) => error!("Failed to purge expired to-device messages: {e}"),
+				Err(e) => error!("To-device cleanup task failed: {e}"),
+			}
+		}
+	})
+}
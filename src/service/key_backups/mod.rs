@@ -3,12 +3,17 @@ use std::{collections::BTreeMap, sync::Arc};
 
 use data::Data;
 use ruma::{
-	api::client::backup::{BackupAlgorithm, KeyBackupData, RoomKeyBackup},
+	api::client::{
+		backup::{BackupAlgorithm, KeyBackupData, RoomKeyBackup},
+		error::ErrorKind,
+	},
+	events::StateEventType,
 	serde::Raw,
 	OwnedRoomId, RoomId, UserId,
 };
+use tracing::warn;
 
-use crate::Result;
+use crate::{services, Error, Result};
 
 pub struct Service {
 	pub(super) db: Arc<dyn Data>,
@@ -41,6 +46,30 @@ impl Service {
 		self.db.get_backup(user_id, version)
 	}
 
+	/// Rejects backing up sessions for a room known to this server that has no
+	/// `m.room.encryption` state, when `validate_backup_room_encryption` is
+	/// enabled.
+	pub fn check_room_encryption(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+		check_backup_room(
+			services().globals.config.validate_backup_room_encryption,
+			user_id,
+			room_id,
+			|| {
+				if !services().rooms.metadata.exists(room_id)? {
+					return Ok(None);
+				}
+
+				Ok(Some(
+					services()
+						.rooms
+						.state_accessor
+						.room_state_get(room_id, &StateEventType::RoomEncryption, "")?
+						.is_some(),
+				))
+			},
+		)
+	}
+
 	pub fn add_key(
 		&self, user_id: &UserId, version: &str, room_id: &RoomId, session_id: &str, key_data: &Raw<KeyBackupData>,
 	) -> Result<()> {
@@ -81,3 +110,48 @@ impl Service {
 			.delete_room_key(user_id, version, room_id, session_id)
 	}
 }
+
+/// With validation on, rooms we have never seen cannot be checked and are let
+/// through, while known rooms must be encrypted. `room_encrypted` gives `None`
+/// for an unknown room and is only asked when validating.
+fn check_backup_room(
+	validate: bool, user_id: &UserId, room_id: &RoomId, room_encrypted: impl FnOnce() -> Result<Option<bool>>,
+) -> Result<()> {
+	if validate && room_encrypted()? == Some(false) {
+		warn!("Rejecting key backup from {user_id} for unencrypted room {room_id}");
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"Cannot back up keys for a room that is not encrypted.",
+		));
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{api::client::error::ErrorKind, room_id, user_id};
+
+	use super::check_backup_room;
+	use crate::Error;
+
+	#[test]
+	fn unencrypted_room_is_rejected_when_validating() {
+		let user_id = user_id!("@alice:example.com");
+		let room_id = room_id!("!room:example.com");
+		assert!(matches!(
+			check_backup_room(true, user_id, room_id, || Ok(Some(false))),
+			Err(Error::BadRequest(ErrorKind::InvalidParam, _))
+		));
+		assert!(check_backup_room(true, user_id, room_id, || Ok(Some(true))).is_ok());
+
+		// the room state isn't read at all when validation is off
+		assert!(check_backup_room(false, user_id, room_id, || panic!("room state read")).is_ok());
+	}
+
+	#[test]
+	fn unknown_room_is_allowed() {
+		let user_id = user_id!("@alice:example.com");
+		assert!(check_backup_room(true, user_id, room_id!("!unknown:example.com"), || Ok(None)).is_ok());
+	}
+}
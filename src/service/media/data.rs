@@ -2,7 +2,7 @@ use conduit::debug_info;
 use ruma::api::client::error::ErrorKind;
 use tracing::debug;
 
-use crate::{media::UrlPreviewData, utils::string_from_bytes, Error, KeyValueDatabase, Result};
+use crate::{database::KvTree, media::UrlPreviewData, utils::string_from_bytes, Error, KeyValueDatabase, Result};
 
 pub(crate) trait Data: Send + Sync {
 	fn create_file_metadata(
@@ -12,6 +12,15 @@ pub(crate) trait Data: Send + Sync {
 
 	fn delete_file_mxc(&self, mxc: String) -> Result<()>;
 
+	/// Records the size of an uploaded file against the uploading user's quota.
+	fn set_media_size(&self, sender_user: &str, mxc: &str, size: u64) -> Result<()>;
+
+	/// Returns the total size of all media uploaded by the user.
+	fn user_media_usage(&self, sender_user: &str) -> Result<u64>;
+
+	/// Returns the local user who uploaded the MXC, if known.
+	fn get_media_uploader(&self, mxc: &str) -> Result<Option<String>>;
+
 	/// Returns content_disposition, content_type and the metadata key.
 	fn search_file_metadata(
 		&self, mxc: String, width: u32, height: u32,
@@ -66,28 +75,28 @@ impl Data for KeyValueDatabase {
 	}
 
 	fn delete_file_mxc(&self, mxc: String) -> Result<()> {
-		debug!("MXC URI: {:?}", mxc);
-
-		let mut prefix = mxc.as_bytes().to_vec();
-		prefix.push(0xFF);
-
-		debug!("MXC db prefix: {prefix:?}");
-
-		for (key, _) in self.mediaid_file.scan_prefix(prefix) {
-			debug!("Deleting key: {:?}", key);
-			self.mediaid_file.remove(&key)?;
-		}
+		delete_mxc(&*self.mediaid_file, &*self.mediaid_user, &*self.userid_mediasize, &mxc)
+	}
 
-		for (key, value) in self.mediaid_user.scan_prefix(mxc.as_bytes().to_vec()) {
-			if key == mxc.as_bytes().to_vec() {
-				let user = string_from_bytes(&value).unwrap_or_default();
+	fn set_media_size(&self, sender_user: &str, mxc: &str, size: u64) -> Result<()> {
+		self.userid_mediasize
+			.insert(&usermediasize_key(sender_user.as_bytes(), mxc.as_bytes()), &size.to_be_bytes())
+	}
 
-				debug_info!("Deleting key \"{key:?}\" which was uploaded by user {user}");
-				self.mediaid_user.remove(&key)?;
-			}
-		}
+	fn user_media_usage(&self, sender_user: &str) -> Result<u64> {
+		total_media_size(
+			self.userid_mediasize
+				.scan_prefix(usermediasize_key(sender_user.as_bytes(), &[])),
+		)
+	}
 
-		Ok(())
+	fn get_media_uploader(&self, mxc: &str) -> Result<Option<String>> {
+		self.mediaid_user
+			.get(mxc.as_bytes())?
+			.map(|user| {
+				string_from_bytes(&user).map_err(|_| Error::bad_database("User ID in mediaid_user is invalid unicode."))
+			})
+			.transpose()
 	}
 
 	/// Searches for all files with the given MXC
@@ -259,3 +268,55 @@ impl Data for KeyValueDatabase {
 		})
 	}
 }
+
+/// Removes an MXC's metadata, its uploader and the upload's size from the
+/// uploader's quota.
+pub(super) fn delete_mxc(
+	mediaid_file: &dyn KvTree, mediaid_user: &dyn KvTree, userid_mediasize: &dyn KvTree, mxc: &str,
+) -> Result<()> {
+	debug!("MXC URI: {:?}", mxc);
+
+	let mut prefix = mxc.as_bytes().to_vec();
+	prefix.push(0xFF);
+
+	debug!("MXC db prefix: {prefix:?}");
+
+	for (key, _) in mediaid_file.scan_prefix(prefix) {
+		debug!("Deleting key: {:?}", key);
+		mediaid_file.remove(&key)?;
+	}
+
+	for (key, value) in mediaid_user.scan_prefix(mxc.as_bytes().to_vec()) {
+		if key == mxc.as_bytes() {
+			let user = string_from_bytes(&value).unwrap_or_default();
+
+			debug_info!("Deleting key \"{key:?}\" which was uploaded by user {user}");
+			mediaid_user.remove(&key)?;
+
+			userid_mediasize.remove(&usermediasize_key(&value, &key))?;
+		}
+	}
+
+	Ok(())
+}
+
+/// Key of an upload's size in userid_mediasize. With an empty `mxc` this is
+/// the prefix of all of the user's uploads.
+pub(super) fn usermediasize_key(sender_user: &[u8], mxc: &[u8]) -> Vec<u8> {
+	let mut key = sender_user.to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(mxc);
+	key
+}
+
+/// Adds up the sizes of userid_mediasize entries.
+pub(super) fn total_media_size(entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>) -> Result<u64> {
+	entries.try_fold(0_u64, |total, (_, size)| {
+		let size = size
+			.try_into()
+			.map(u64::from_be_bytes)
+			.map_err(|_| Error::bad_database("Media size in userid_mediasize is invalid."))?;
+
+		Ok(total.saturating_add(size))
+	})
+}
@@ -0,0 +1,269 @@
+//! Stripping EXIF, XMP and other metadata from uploaded images at the
+//! container level, leaving the image data, colour profile and any animation
+//! untouched.
+
+use crate::{Error, Result};
+
+const JPEG_SIGNATURE: &[u8] = &[0xFF, 0xD8];
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// JPEG APP1 (EXIF, XMP) and APP13 (Photoshop, IPTC) segment markers
+const JPEG_METADATA_MARKERS: &[u8] = &[0xE1, 0xED];
+
+/// JPEG start of scan marker, after which there is only image data
+const JPEG_START_OF_SCAN: u8 = 0xDA;
+
+/// PNG chunks holding EXIF or free-form text
+const PNG_METADATA_CHUNKS: &[&[u8]] = &[b"eXIf", b"tEXt", b"iTXt", b"zTXt"];
+
+/// EXIF tag holding the orientation of the image
+const EXIF_ORIENTATION: u16 = 0x0112;
+
+/// Copies a JPEG or PNG without its metadata segments or chunks, keeping the
+/// ICC colour profile (APP2/iCCP) and APNG animation chunks. A non-default EXIF
+/// orientation is kept in an EXIF block of its own so the image still displays
+/// upright. Returns `None` for anything else, which is stored as uploaded.
+pub(super) fn strip_image_metadata(file: &[u8]) -> Result<Option<Vec<u8>>> {
+	if file.starts_with(JPEG_SIGNATURE) {
+		strip_jpeg(file)
+			.map(Some)
+			.ok_or_else(|| Error::Err("Malformed JPEG segments".to_owned()))
+	} else if file.starts_with(PNG_SIGNATURE) {
+		strip_png(file)
+			.map(Some)
+			.ok_or_else(|| Error::Err("Malformed PNG chunks".to_owned()))
+	} else {
+		Ok(None)
+	}
+}
+
+fn strip_jpeg(file: &[u8]) -> Option<Vec<u8>> {
+	let mut stripped = JPEG_SIGNATURE.to_vec();
+	let mut pos = JPEG_SIGNATURE.len();
+
+	// segments follow the start of image marker until the start of scan
+	while let [0xFF, marker, len_hi, len_lo, ..] = *file.get(pos..)? {
+		if marker == JPEG_START_OF_SCAN {
+			stripped.extend_from_slice(file.get(pos..)?);
+			return Some(stripped);
+		}
+
+		let end = pos
+			.checked_add(2)?
+			.checked_add(u16::from_be_bytes([len_hi, len_lo]).into())?;
+		let segment = file.get(pos..end)?;
+		if !JPEG_METADATA_MARKERS.contains(&marker) {
+			stripped.extend_from_slice(segment);
+		} else if let Some(orientation) = segment
+			.get(4..)?
+			.strip_prefix(b"Exif\0\0")
+			.and_then(orientation)
+			.filter(|&orientation| orientation != 1)
+		{
+			let tiff = orientation_exif(orientation);
+			let len = u16::try_from(tiff.len().checked_add(8)?).ok()?;
+			stripped.extend_from_slice(&[0xFF, 0xE1]);
+			stripped.extend_from_slice(&len.to_be_bytes());
+			stripped.extend_from_slice(b"Exif\0\0");
+			stripped.extend_from_slice(&tiff);
+		}
+
+		pos = end;
+	}
+
+	None
+}
+
+fn strip_png(file: &[u8]) -> Option<Vec<u8>> {
+	let mut stripped = PNG_SIGNATURE.to_vec();
+	let mut pos = PNG_SIGNATURE.len();
+
+	// chunks follow the signature: length, type, data, crc
+	while pos < file.len() {
+		let [a, b, c, d, ..] = *file.get(pos..)? else {
+			return None;
+		};
+		let len: usize = u32::from_be_bytes([a, b, c, d]).try_into().ok()?;
+		let end = pos.checked_add(12)?.checked_add(len)?;
+		let chunk = file.get(pos..end)?;
+		let kind = chunk.get(4..8)?;
+		if !PNG_METADATA_CHUNKS.contains(&kind) {
+			stripped.extend_from_slice(chunk);
+		} else if let Some(orientation) = (kind == b"eXIf")
+			.then(|| chunk.get(8..len.checked_add(8)?))
+			.flatten()
+			.and_then(orientation)
+			.filter(|&orientation| orientation != 1)
+		{
+			stripped.extend_from_slice(&png_chunk(b"eXIf", &orientation_exif(orientation))?);
+		}
+
+		pos = end;
+	}
+
+	Some(stripped)
+}
+
+/// Reads the orientation tag from the first IFD of an EXIF (TIFF) block.
+fn orientation(tiff: &[u8]) -> Option<u16> {
+	let big_endian = match tiff.get(..2)? {
+		b"MM" => true,
+		b"II" => false,
+		_ => return None,
+	};
+
+	let u16_at = |pos: usize| -> Option<u16> {
+		let bytes = [*tiff.get(pos)?, *tiff.get(pos.checked_add(1)?)?];
+		Some(if big_endian {
+			u16::from_be_bytes(bytes)
+		} else {
+			u16::from_le_bytes(bytes)
+		})
+	};
+	let u32_at = |pos: usize| -> Option<u32> {
+		let bytes: [u8; 4] = tiff.get(pos..pos.checked_add(4)?)?.try_into().ok()?;
+		Some(if big_endian {
+			u32::from_be_bytes(bytes)
+		} else {
+			u32::from_le_bytes(bytes)
+		})
+	};
+
+	let ifd: usize = u32_at(4)?.try_into().ok()?;
+	let entries = u16_at(ifd)?;
+	(0..entries)
+		.map(|i| {
+			ifd.checked_add(2)?
+				.checked_add(usize::from(i).checked_mul(12)?)
+		})
+		.find_map(|entry| {
+			let entry = entry?;
+			if u16_at(entry)? != EXIF_ORIENTATION {
+				return None;
+			}
+
+			u16_at(entry.checked_add(8)?)
+		})
+}
+
+/// A big endian EXIF (TIFF) block holding nothing but the orientation.
+fn orientation_exif(orientation: u16) -> Vec<u8> {
+	let mut tiff = b"MM\0\x2A\0\0\0\x08".to_vec();
+	tiff.extend_from_slice(&1_u16.to_be_bytes());
+	tiff.extend_from_slice(&EXIF_ORIENTATION.to_be_bytes());
+	// SHORT, one value, padded to four bytes
+	tiff.extend_from_slice(&[0, 3, 0, 0, 0, 1]);
+	tiff.extend_from_slice(&orientation.to_be_bytes());
+	tiff.extend_from_slice(&[0, 0]);
+	// no next IFD
+	tiff.extend_from_slice(&[0, 0, 0, 0]);
+	tiff
+}
+
+fn png_chunk(kind: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+	let mut chunk = u32::try_from(data.len()).ok()?.to_be_bytes().to_vec();
+	chunk.extend_from_slice(kind);
+	chunk.extend_from_slice(data);
+	let crc = crc32(chunk.get(4..)?);
+	chunk.extend_from_slice(&crc.to_be_bytes());
+	Some(chunk)
+}
+
+/// CRC-32 as used by PNG, over a chunk's type and data.
+fn crc32(data: &[u8]) -> u32 {
+	!data.iter().fold(!0_u32, |crc, &byte| {
+		(0..8).fold(crc ^ u32::from(byte), |crc, _| {
+			if crc & 1 == 1 {
+				(crc >> 1) ^ 0xEDB8_8320
+			} else {
+				crc >> 1
+			}
+		})
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use image::{DynamicImage, GenericImageView, ImageFormat};
+
+	use super::{crc32, orientation, orientation_exif, png_chunk, strip_image_metadata, PNG_SIGNATURE};
+
+	/// A big endian EXIF block with the orientation set to "rotate 90° clockwise"
+	/// and a GPS IFD pointer
+	fn exif_segment() -> Vec<u8> {
+		let mut tiff = b"MM\0\x2A\0\0\0\x08".to_vec();
+		tiff.extend_from_slice(&2_u16.to_be_bytes());
+		tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+		tiff.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0]);
+		tiff.extend_from_slice(&[0, 0, 0, 0]);
+
+		let mut segment = vec![0xFF, 0xE1];
+		let len = u16::try_from(tiff.len() + 8).unwrap();
+		segment.extend_from_slice(&len.to_be_bytes());
+		segment.extend_from_slice(b"Exif\0\0");
+		segment.extend_from_slice(&tiff);
+		segment
+	}
+
+	/// An APP2 segment as an ICC profile would be stored in
+	fn icc_segment() -> Vec<u8> {
+		let mut segment = vec![0xFF, 0xE2, 0, 16];
+		segment.extend_from_slice(b"ICC_PROFILE\0\x01\x01");
+		segment
+	}
+
+	#[test]
+	fn exif_is_stripped_from_jpeg_upload() {
+		let mut jpeg = Vec::new();
+		DynamicImage::new_rgb8(4, 2)
+			.write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+			.unwrap();
+		// right after the start of image marker
+		let upload = [&jpeg[..2], &icc_segment()[..], &exif_segment()[..], &jpeg[2..]].concat();
+
+		let stripped = strip_image_metadata(&upload).unwrap().unwrap();
+		assert!(!stripped.windows(2).any(|w| w == [0x88, 0x25]));
+
+		// the ICC profile and image data are untouched, and only the orientation of
+		// the EXIF block is left
+		let mut orientation_segment = vec![0xFF, 0xE1, 0, 34];
+		orientation_segment.extend_from_slice(b"Exif\0\0");
+		orientation_segment.extend_from_slice(&orientation_exif(6));
+		assert_eq!(
+			stripped,
+			[&jpeg[..2], &icc_segment()[..], &orientation_segment[..], &jpeg[2..]].concat()
+		);
+		assert_eq!(orientation(&orientation_exif(6)), Some(6));
+
+		let image = image::load_from_memory(&stripped).unwrap();
+		assert_eq!(image.dimensions(), (4, 2));
+	}
+
+	#[test]
+	fn text_is_stripped_from_png_upload() {
+		let mut png = Vec::new();
+		DynamicImage::new_rgb8(4, 2)
+			.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+			.unwrap();
+		let header = PNG_SIGNATURE.len() + 25;
+		let icc = png_chunk(b"iCCP", b"sRGB\0\0profile").unwrap();
+		let text = png_chunk(b"tEXt", b"Comment\0taken at home").unwrap();
+		let upload = [&png[..header], &icc[..], &text[..], &png[header..]].concat();
+
+		let stripped = strip_image_metadata(&upload).unwrap().unwrap();
+		assert_eq!(stripped, [&png[..header], &icc[..], &png[header..]].concat());
+	}
+
+	#[test]
+	fn png_chunk_crc() {
+		assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+	}
+
+	#[test]
+	fn non_images_are_left_alone() {
+		assert!(strip_image_metadata(b"just some text").unwrap().is_none());
+	}
+}
@@ -1,9 +1,10 @@
 mod data;
-use std::{collections::HashMap, io::Cursor, sync::Arc, time::SystemTime};
+mod metadata;
+use std::{collections::HashMap, io::Cursor, path::PathBuf, sync::Arc, time::SystemTime};
 
 use data::Data;
 use image::imageops::FilterType;
-use ruma::{OwnedMxcUri, OwnedUserId};
+use ruma::{OwnedMxcUri, OwnedUserId, UserId};
 use serde::Serialize;
 use tokio::{
 	fs::{self, File},
@@ -38,6 +39,13 @@ pub struct UrlPreviewData {
 	pub image_height: Option<u32>,
 }
 
+/// Number of uploads and bytes on disk (thumbnails included) for one user.
+#[derive(Default)]
+pub struct MediaUsage {
+	pub count: usize,
+	pub bytes: u64,
+}
+
 pub struct Service {
 	pub(super) db: Arc<dyn Data>,
 	pub url_preview_mutex: RwLock<HashMap<String, Arc<Mutex<()>>>>,
@@ -51,6 +59,8 @@ impl Service {
 	) -> Result<()> {
 		// Width, Height = 0 if it's not a thumbnail
 		let key = if let Some(user) = sender_user {
+			self.db
+				.set_media_size(user.as_str(), &mxc, file.len() as u64)?;
 			self.db
 				.create_file_metadata(Some(user.as_str()), mxc, 0, 0, content_disposition, content_type)?
 		} else {
@@ -78,6 +88,155 @@ impl Service {
 		Ok(())
 	}
 
+	/// Returns the total size in bytes of the media the user has uploaded.
+	pub fn user_media_usage(&self, user_id: &UserId) -> Result<u64> { self.db.user_media_usage(user_id.as_str()) }
+
+	/// Whether an upload of `size` bytes fits within the user's media quota.
+	pub fn user_quota_allows(&self, user_id: &UserId, size: u64) -> Result<bool> {
+		let Some(quota) = services().globals.media_user_quota_bytes() else {
+			return Ok(true);
+		};
+
+		Ok(within_quota(self.user_media_usage(user_id)?, size, quota))
+	}
+
+	/// Copies a JPEG or PNG upload without its EXIF, XMP and other metadata.
+	/// Returns `None` for other files.
+	pub fn strip_image_metadata(&self, file: &[u8]) -> Result<Option<Vec<u8>>> { metadata::strip_image_metadata(file) }
+
+	/// Returns the local user who uploaded the MXC, if known.
+	pub fn get_media_uploader(&self, mxc: &str) -> Result<Option<String>> { self.db.get_media_uploader(mxc) }
+
+	/// Totals the media uploaded by each local user, keyed by user ID. Sizes are
+	/// read from the filesystem, so files missing on disk count as zero bytes.
+	pub async fn media_usage_by_user(&self) -> Result<HashMap<String, MediaUsage>> {
+		let mut usage: HashMap<String, MediaUsage> = HashMap::new();
+
+		for key in self.db.get_all_media_keys() {
+			let Some(mxc) = key
+				.split(|&b| b == 0xFF)
+				.next()
+				.and_then(|bytes| utils::string_from_bytes(bytes).ok())
+			else {
+				continue;
+			};
+
+			let Some(user) = self.db.get_media_uploader(&mxc)? else {
+				continue;
+			};
+
+			let path;
+
+			#[allow(clippy::unnecessary_operation)] // error[E0658]: attributes on expressions are experimental
+			#[cfg(feature = "sha256_media")]
+			{
+				path = services().globals.get_media_file_new(&key);
+			};
+
+			#[allow(clippy::unnecessary_operation)] // error[E0658]: attributes on expressions are experimental
+			#[cfg(not(feature = "sha256_media"))]
+			{
+				path = services().globals.get_media_file(&key);
+			};
+
+			let bytes = fs::metadata(path)
+				.await
+				.map_or(0, |metadata| metadata.len());
+
+			let entry = usage.entry(user).or_default();
+			if is_original_media_key(&key, mxc.len()) {
+				entry.count = entry.count.saturating_add(1);
+			}
+			entry.bytes = entry.bytes.saturating_add(bytes);
+		}
+
+		Ok(usage)
+	}
+
+	/// Records the size of every upload with a known uploader against that
+	/// user's quota, reading sizes from the filesystem. Files missing on disk
+	/// are skipped. Returns how many uploads were recorded.
+	pub async fn backfill_media_sizes(&self) -> Result<usize> {
+		let mut recorded: usize = 0;
+
+		for key in self.db.get_all_media_keys() {
+			let Some(mxc) = key
+				.split(|&b| b == 0xFF)
+				.next()
+				.and_then(|bytes| utils::string_from_bytes(bytes).ok())
+			else {
+				continue;
+			};
+
+			if !is_original_media_key(&key, mxc.len()) {
+				continue;
+			}
+
+			let Some(user) = self.db.get_media_uploader(&mxc)? else {
+				continue;
+			};
+
+			let path;
+
+			#[allow(clippy::unnecessary_operation)] // error[E0658]: attributes on expressions are experimental
+			#[cfg(feature = "sha256_media")]
+			{
+				path = services().globals.get_media_file_new(&key);
+			};
+
+			#[allow(clippy::unnecessary_operation)] // error[E0658]: attributes on expressions are experimental
+			#[cfg(not(feature = "sha256_media"))]
+			{
+				path = services().globals.get_media_file(&key);
+			};
+
+			let Ok(metadata) = fs::metadata(path).await else {
+				debug!("Media file for {mxc} is missing, not counting it towards {user}'s quota");
+				continue;
+			};
+
+			self.db.set_media_size(&user, &mxc, metadata.len())?;
+			recorded = recorded.saturating_add(1);
+		}
+
+		Ok(recorded)
+	}
+
+	/// Moves media still stored under the legacy base64 file names to the
+	/// SHA256 file names, then bumps the database version so the layout is
+	/// considered migrated. With `dry_run` nothing is moved or bumped. Returns
+	/// how many files were (or would be) moved.
+	///
+	/// This runs once at startup as the 13 -> 14 migration. The admin command
+	/// reruns it for media stored under legacy names after that, such as
+	/// uploads made while temporarily running a build without `sha256_media`.
+	#[cfg(feature = "sha256_media")]
+	pub async fn migrate_to_sha256(&self, dry_run: bool) -> Result<usize> {
+		let moves = media_moves(
+			self.db.get_all_media_keys(),
+			|key| services().globals.get_media_file(key),
+			|key| services().globals.get_media_file_new(key),
+		);
+
+		if dry_run {
+			return Ok(moves.len());
+		}
+
+		let moved = move_media(&moves).await?;
+		if services().globals.database_version()? < 14 {
+			services().globals.bump_database_version(14)?;
+		}
+
+		Ok(moved)
+	}
+
+	#[cfg(not(feature = "sha256_media"))]
+	pub async fn migrate_to_sha256(&self, _dry_run: bool) -> Result<usize> {
+		Err(Error::Err(
+			"conduwuit was built without the sha256_media feature, media cannot be migrated".to_owned(),
+		))
+	}
+
 	/// Deletes a file in the database and from the media directory via an MXC
 	pub async fn delete(&self, mxc: String) -> Result<()> {
 		if let Ok(keys) = self.db.search_mxc_metadata_prefix(mxc.clone()) {
@@ -485,8 +644,143 @@ impl Service {
 	}
 }
 
+/// Whether a media key is for the uploaded file rather than a thumbnail; the
+/// width and height following the MXC are zero for originals.
+fn is_original_media_key(key: &[u8], mxc_len: usize) -> bool {
+	key.get(mxc_len.saturating_add(1)..mxc_len.saturating_add(9))
+		.is_some_and(|dimensions| dimensions.iter().all(|&b| b == 0))
+}
+
+/// Pairs up the old and new path of every media key whose file exists at the
+/// old path and not yet at the new one.
+#[cfg_attr(not(feature = "sha256_media"), allow(dead_code))]
+fn media_moves<O, N>(keys: Vec<Vec<u8>>, old_path: O, new_path: N) -> Vec<(PathBuf, PathBuf)>
+where
+	O: Fn(&[u8]) -> PathBuf,
+	N: Fn(&[u8]) -> PathBuf,
+{
+	keys.iter()
+		.map(|key| (old_path(key), new_path(key)))
+		.filter(|(old, new)| old != new && old.exists() && !new.exists())
+		.collect()
+}
+
+#[cfg_attr(not(feature = "sha256_media"), allow(dead_code))]
+async fn move_media(moves: &[(PathBuf, PathBuf)]) -> Result<usize> {
+	for (old, new) in moves {
+		debug!("Moving media file {old:?} to {new:?}");
+		fs::rename(old, new).await?;
+	}
+
+	Ok(moves.len())
+}
+
+/// Whether a user who has already stored `used` bytes may upload `size` more.
+fn within_quota(used: u64, size: u64, quota: u64) -> bool { used.saturating_add(size) <= quota }
+
 #[cfg(test)]
 mod tests {
+	use super::{
+		data::{delete_mxc, total_media_size, usermediasize_key},
+		is_original_media_key, media_moves, move_media, within_quota,
+	};
+	use crate::{database::KvTree, test_utils::MemoryTree};
+
+	#[test]
+	fn uploads_beyond_quota_are_rejected() {
+		assert!(within_quota(0, 1000, 1000));
+		assert!(within_quota(400, 600, 1000));
+		assert!(!within_quota(400, 601, 1000));
+		assert!(!within_quota(u64::MAX, 1, 1000));
+	}
+
+	#[test]
+	fn thumbnails_are_not_original_media() {
+		let mxc = b"mxc://example.com/abc";
+		let key = |width: u32, height: u32| {
+			let mut key = mxc.to_vec();
+			key.push(0xFF);
+			key.extend_from_slice(&width.to_be_bytes());
+			key.extend_from_slice(&height.to_be_bytes());
+			key.push(0xFF);
+			key
+		};
+
+		assert!(is_original_media_key(&key(0, 0), mxc.len()));
+		assert!(!is_original_media_key(&key(96, 96), mxc.len()));
+	}
+
+	#[test]
+	fn deleting_media_frees_quota() {
+		let (files, uploaders, sizes) = (MemoryTree::default(), MemoryTree::default(), MemoryTree::default());
+		let alice = b"@alice:example.com";
+		let usage = |sizes: &MemoryTree| total_media_size(sizes.scan_prefix(usermediasize_key(alice, &[]))).unwrap();
+		let metadata_key = |mxc: &str| {
+			let mut key = mxc.as_bytes().to_vec();
+			key.push(0xFF);
+			key.extend_from_slice(&[0; 8]);
+			key.push(0xFF);
+			key
+		};
+
+		// as recorded on upload
+		for (uploader, mxc, size) in [
+			(&alice[..], "mxc://example.com/a", 600_u64),
+			(&alice[..], "mxc://example.com/ab", 300),
+			(&b"@alice:example.com.evil"[..], "mxc://example.com/c", 1000),
+		] {
+			files.insert(&metadata_key(mxc), &[]).unwrap();
+			uploaders.insert(mxc.as_bytes(), uploader).unwrap();
+			sizes
+				.insert(&usermediasize_key(uploader, mxc.as_bytes()), &size.to_be_bytes())
+				.unwrap();
+		}
+		assert_eq!(usage(&sizes), 900);
+		assert!(!within_quota(usage(&sizes), 200, 1000));
+
+		delete_mxc(&files, &uploaders, &sizes, "mxc://example.com/a").unwrap();
+
+		assert_eq!(usage(&sizes), 300);
+		assert!(within_quota(usage(&sizes), 200, 1000));
+		assert!(files
+			.get(&metadata_key("mxc://example.com/a"))
+			.unwrap()
+			.is_none());
+		assert!(uploaders.get(b"mxc://example.com/a").unwrap().is_none());
+		assert!(files
+			.get(&metadata_key("mxc://example.com/ab"))
+			.unwrap()
+			.is_some());
+		assert!(uploaders.get(b"mxc://example.com/ab").unwrap().is_some());
+	}
+
+	#[tokio::test]
+	async fn legacy_media_is_moved_to_new_names() {
+		let dir = std::env::temp_dir().join(format!("conduwuit-media-migration-{}", std::process::id()));
+		std::fs::create_dir_all(&dir).unwrap();
+
+		let old_path = |key: &[u8]| dir.join(format!("old-{}", String::from_utf8_lossy(key)));
+		let new_path = |key: &[u8]| dir.join(format!("new-{}", String::from_utf8_lossy(key)));
+		for key in ["a", "b"] {
+			std::fs::write(old_path(key.as_bytes()), key).unwrap();
+		}
+		// already migrated, and missing on disk
+		std::fs::write(new_path(b"c"), "c").unwrap();
+		let keys = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec()];
+
+		// a dry run only counts
+		assert_eq!(media_moves(keys.clone(), old_path, new_path).len(), 2);
+		assert!(old_path(b"a").exists());
+
+		let moves = media_moves(keys.clone(), old_path, new_path);
+		assert_eq!(move_media(&moves).await.unwrap(), 2);
+		assert!(!old_path(b"a").exists());
+		assert_eq!(std::fs::read_to_string(new_path(b"b")).unwrap(), "b");
+		assert!(media_moves(keys, old_path, new_path).is_empty());
+
+		std::fs::remove_dir_all(&dir).unwrap();
+	}
+
 	#[cfg(feature = "sha256_media")]
 	#[tokio::test]
 	async fn long_file_names_works() {
@@ -528,6 +822,12 @@ mod tests {
 
 			fn delete_file_mxc(&self, _mxc: String) -> Result<()> { todo!() }
 
+			fn set_media_size(&self, _sender_user: &str, _mxc: &str, _size: u64) -> Result<()> { todo!() }
+
+			fn user_media_usage(&self, _sender_user: &str) -> Result<u64> { todo!() }
+
+			fn get_media_uploader(&self, _mxc: &str) -> Result<Option<String>> { todo!() }
+
 			fn search_mxc_metadata_prefix(&self, _mxc: String) -> Result<Vec<Vec<u8>>> { todo!() }
 
 			fn get_all_media_keys(&self) -> Vec<Vec<u8>> { todo!() }
@@ -15,6 +15,9 @@ pub mod transaction_ids;
 pub mod uiaa;
 pub mod users;
 
+#[cfg(test)]
+mod test_utils;
+
 extern crate conduit_core as conduit;
 extern crate conduit_database as database;
 use std::sync::{Arc, RwLock};
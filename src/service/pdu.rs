@@ -1,6 +1,7 @@
 use std::{cmp::Ordering, collections::BTreeMap, sync::Arc};
 
 use ruma::{
+	api::client::error::ErrorKind,
 	canonical_json::redact_content_in_place,
 	events::{
 		room::{member::RoomMemberEventContent, redaction::RoomRedactionEventContent},
@@ -416,6 +417,18 @@ impl Ord for PduEvent {
 	fn cmp(&self, other: &Self) -> Ordering { self.event_id.cmp(&other.event_id) }
 }
 
+/// Rejects a PDU whose canonical JSON is larger than `max_size` bytes.
+pub fn check_pdu_size(pdu_json: &CanonicalJsonObject, max_size: usize) -> crate::Result<()> {
+	let size = serde_json::to_vec(pdu_json)
+		.expect("canonical JSON can be serialized")
+		.len();
+	if size > max_size {
+		return Err(Error::BadRequest(ErrorKind::TooLarge, "Event is too large."));
+	}
+
+	Ok(())
+}
+
 /// Generates a correct eventId for the incoming pdu.
 ///
 /// Returns a tuple of the new `EventId` and the PDU as a `BTreeMap<String,
@@ -428,13 +441,15 @@ pub fn gen_event_id_canonical_json(
 		Error::BadServerResponse("Invalid PDU in server response")
 	})?;
 
-	let event_id = format!(
-		"${}",
-		// Anything higher than version3 behaves the same
-		ruma::signatures::reference_hash(&value, room_version_id).expect("ruma can calculate reference hashes")
-	)
-	.try_into()
-	.expect("ruma's reference hashes are valid event ids");
+	// Anything higher than version3 behaves the same
+	let reference_hash = ruma::signatures::reference_hash(&value, room_version_id).map_err(|e| {
+		warn!("Failed to calculate reference hash of incoming event: {e}");
+		Error::BadRequest(ErrorKind::TooLarge, "Event is too large or could not be redacted.")
+	})?;
+
+	let event_id = format!("${reference_hash}")
+		.try_into()
+		.expect("ruma's reference hashes are valid event ids");
 
 	Ok((event_id, value))
 }
@@ -449,3 +464,52 @@ pub struct PduBuilder {
 	pub state_key: Option<String>,
 	pub redacts: Option<Arc<EventId>>,
 }
+
+#[cfg(any(test, feature = "test_utils"))]
+impl PduEvent {
+	/// An event for tests, with the given fields set over an otherwise empty
+	/// `m.room.message` from `@alice:example.com` in `!room:example.com`.
+	#[must_use]
+	pub fn test_event(fields: serde_json::Value) -> Self {
+		let mut event = serde_json::json!({
+			"event_id": "$event:example.com",
+			"room_id": "!room:example.com",
+			"sender": "@alice:example.com",
+			"origin_server_ts": 1,
+			"type": "m.room.message",
+			"content": {},
+			"prev_events": [],
+			"depth": 1,
+			"auth_events": [],
+			"hashes": { "sha256": "" },
+		});
+		if let (Some(event), serde_json::Value::Object(fields)) = (event.as_object_mut(), fields) {
+			event.extend(fields);
+		}
+
+		serde_json::from_value(event).expect("valid test event")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{CanonicalJsonObject, CanonicalJsonValue};
+
+	use super::check_pdu_size;
+
+	fn pdu_of_size(size: usize) -> CanonicalJsonObject {
+		let mut pdu = CanonicalJsonObject::new();
+		pdu.insert("type".to_owned(), CanonicalJsonValue::String("m.room.message".to_owned()));
+		pdu.insert("body".to_owned(), CanonicalJsonValue::String(String::new()));
+
+		let base = serde_json::to_vec(&pdu).expect("serializes").len();
+		pdu.insert("body".to_owned(), CanonicalJsonValue::String("a".repeat(size - base)));
+		pdu
+	}
+
+	#[test]
+	fn pdu_just_over_limit_is_rejected() {
+		assert!(check_pdu_size(&pdu_of_size(65_536), 65_536).is_ok());
+		assert!(check_pdu_size(&pdu_of_size(65_537), 65_536).is_err());
+	}
+}
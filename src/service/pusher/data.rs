@@ -1,22 +1,31 @@
 use ruma::{
 	api::client::push::{set_pusher, Pusher},
-	UserId,
+	DeviceId, UserId,
 };
 
 use crate::{utils, Error, KeyValueDatabase, Result};
 
 pub(crate) trait Data: Send + Sync {
-	fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()>;
+	/// Creates, updates or deletes a pusher. `device` is the device setting
+	/// it, so its pushers can be removed along with the device.
+	fn set_pusher(
+		&self, sender: &UserId, device: Option<&DeviceId>, pusher: set_pusher::v3::PusherAction,
+	) -> Result<()>;
 
 	fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>>;
 
 	fn get_pushers(&self, sender: &UserId) -> Result<Vec<Pusher>>;
 
 	fn get_pushkeys<'a>(&'a self, sender: &UserId) -> Box<dyn Iterator<Item = Result<String>> + 'a>;
+
+	/// Returns the pushkeys of the pushers set by `device`.
+	fn get_device_pushkeys(&self, sender: &UserId, device: &DeviceId) -> Result<Vec<String>>;
 }
 
 impl Data for KeyValueDatabase {
-	fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
+	fn set_pusher(
+		&self, sender: &UserId, device: Option<&DeviceId>, pusher: set_pusher::v3::PusherAction,
+	) -> Result<()> {
 		match &pusher {
 			set_pusher::v3::PusherAction::Post(data) => {
 				let mut key = sender.as_bytes().to_vec();
@@ -24,12 +33,17 @@ impl Data for KeyValueDatabase {
 				key.extend_from_slice(data.pusher.ids.pushkey.as_bytes());
 				self.senderkey_pusher
 					.insert(&key, &serde_json::to_vec(&pusher).expect("Pusher is valid JSON value"))?;
+				match device {
+					Some(device) => self.senderkey_deviceid.insert(&key, device.as_bytes())?,
+					None => self.senderkey_deviceid.remove(&key)?,
+				}
 				Ok(())
 			},
 			set_pusher::v3::PusherAction::Delete(ids) => {
 				let mut key = sender.as_bytes().to_vec();
 				key.push(0xFF);
 				key.extend_from_slice(ids.pushkey.as_bytes());
+				self.senderkey_deviceid.remove(&key)?;
 				self.senderkey_pusher.remove(&key).map_err(Into::into)
 			},
 		}
@@ -72,4 +86,50 @@ impl Data for KeyValueDatabase {
 			Ok(push_key_string)
 		}))
 	}
+
+	fn get_device_pushkeys(&self, sender: &UserId, device: &DeviceId) -> Result<Vec<String>> {
+		let mut prefix = sender.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		device_pushkeys(self.senderkey_deviceid.scan_prefix(prefix), device)
+	}
+
+}
+
+/// Picks the pushkeys set by `device` out of `senderkey_deviceid` entries.
+fn device_pushkeys(entries: impl Iterator<Item = (Vec<u8>, Vec<u8>)>, device: &DeviceId) -> Result<Vec<String>> {
+	entries
+		.filter(|(_, device_id)| device_id == device.as_bytes())
+		.map(|(key, _)| {
+			let push_key = key
+				.splitn(2, |&b| b == 0xFF)
+				.nth(1)
+				.ok_or_else(|| Error::bad_database("Invalid senderkey_deviceid in db"))?;
+
+			utils::string_from_bytes(push_key).map_err(|_| Error::bad_database("Invalid pushkey in senderkey_deviceid"))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::device_id;
+
+	use super::device_pushkeys;
+
+	fn entry(pushkey: &str, device: &str) -> (Vec<u8>, Vec<u8>) {
+		let mut key = b"@alice:example.com".to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(pushkey.as_bytes());
+
+		(key, device.as_bytes().to_vec())
+	}
+
+	#[test]
+	fn deleted_device_pushers_are_found() {
+		let entries = vec![entry("phone-key", "PHONE"), entry("laptop-key", "LAPTOP")];
+
+		let pushkeys = device_pushkeys(entries.into_iter(), device_id!("PHONE")).unwrap();
+		assert_eq!(pushkeys, vec!["phone-key".to_owned()]);
+	}
 }
@@ -1,12 +1,30 @@
 mod data;
-use std::{fmt::Debug, mem, sync::Arc};
+use std::{
+	cmp,
+	collections::{hash_map, HashMap},
+	fmt::Debug,
+	future::Future,
+	mem,
+	net::IpAddr,
+	sync::Arc,
+	time::{Duration, Instant},
+};
 
 use bytes::BytesMut;
+use conduit::{utils::HtmlEscape, Config};
 use data::Data;
 use ipaddress::IPAddress;
+use lettre::{
+	message::{Mailbox, MultiPart},
+	transport::smtp::authentication::Credentials,
+	AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
 use ruma::{
 	api::{
-		client::push::{set_pusher, Pusher, PusherKind},
+		client::{
+			error::ErrorKind,
+			push::{set_pusher, Pusher, PusherKind},
+		},
 		push_gateway::send_event_notification::{
 			self,
 			v1::{Device, Notification, NotificationCounts, NotificationPriority},
@@ -14,23 +32,124 @@ use ruma::{
 		IncomingResponse, MatrixVersion, OutgoingRequest, SendAccessToken,
 	},
 	events::{
-		room::power_levels::RoomPowerLevelsEventContent, AnySyncTimelineEvent, StateEventType, TimelineEventType,
+		room::{
+			member::{MembershipState, RoomMemberEventContent},
+			power_levels::RoomPowerLevelsEventContent,
+		},
+		AnySyncTimelineEvent, StateEventType, TimelineEventType,
 	},
 	push::{Action, PushConditionPowerLevelsCtx, PushConditionRoomCtx, PushFormat, Ruleset, Tweak},
 	serde::Raw,
-	uint, RoomId, UInt, UserId,
+	uint, DeviceId, OwnedUserId, RoomId, UInt, UserId,
 };
-use tracing::{info, trace, warn};
+use serde::Deserialize;
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, trace, warn};
+use url::{Host, Url};
 
-use crate::{debug_info, services, Error, PduEvent, Result};
+use crate::{debug_info, globals::RateLimitState, services, Error, PduEvent, Result};
 
 pub struct Service {
 	pub(super) db: Arc<dyn Data>,
+	/// SMTP transport and sender address for email pushers and validating
+	/// email addresses, if configured
+	pub(super) mailer: Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)>,
+	/// Consecutive failed pushes to each pusher by user and pushkey, so it
+	/// can be removed after `pusher_max_failures`
+	pub(super) pusher_failures: RwLock<HashMap<(OwnedUserId, String), u32>>,
+	/// Last failure and consecutive failures of each push gateway by URL, to
+	/// back off pushes to it
+	pub(super) gateway_ratelimiter: RwLock<HashMap<String, RateLimitState>>,
+}
+
+/// Subject and plaintext and HTML bodies of a notification email.
+#[derive(Debug, PartialEq, Eq)]
+struct EmailNotice {
+	subject: String,
+	plain: String,
+	html: String,
+}
+
+/// How a push gateway answered a request.
+#[derive(Debug)]
+pub enum GatewayResponse<T> {
+	Accepted(T),
+	/// 410 Gone; the gateway no longer knows the pushkey
+	Gone,
+}
+
+/// What a push attempt says about the pusher it was sent to.
+#[derive(Debug, PartialEq, Eq)]
+enum PushOutcome {
+	Delivered,
+	Failed,
+	/// The gateway rejected the pushkey; the pusher should be removed
+	Rejected,
 }
 
 impl Service {
-	pub fn set_pusher(&self, sender: &UserId, pusher: set_pusher::v3::PusherAction) -> Result<()> {
-		self.db.set_pusher(sender, pusher)
+	pub async fn set_pusher(
+		&self, sender: &UserId, device: Option<&DeviceId>, pusher: set_pusher::v3::PusherAction,
+	) -> Result<()> {
+		if let set_pusher::v3::PusherAction::Post(data) = &pusher {
+			if let PusherKind::Http(http) = &data.pusher.kind {
+				let globals = &services().globals;
+				check_pusher_url(
+					&http.url,
+					globals.notification_push_path(),
+					|host| async move {
+						globals
+							.dns_resolver()
+							.lookup_ip(host)
+							.await
+							.map(|lookup| lookup.iter().collect())
+							.unwrap_or_default()
+					},
+					|ip| globals.valid_cidr_range(ip),
+				)
+				.await?;
+			}
+
+			// the pushkey of an email pusher is the address to notify, which must be one
+			// the user has proven they own or this could be used to send mail anywhere
+			if matches!(data.pusher.kind, PusherKind::Email(_))
+				&& !services()
+					.users
+					.has_email(sender, &data.pusher.ids.pushkey)?
+			{
+				return Err(Error::BadRequest(
+					ErrorKind::InvalidParam,
+					"Email pushers can only notify an email address bound to your account.",
+				));
+			}
+		}
+
+		let pushkey = match &pusher {
+			set_pusher::v3::PusherAction::Post(data) => data.pusher.ids.pushkey.clone(),
+			set_pusher::v3::PusherAction::Delete(ids) => ids.pushkey.clone(),
+		};
+		self.pusher_failures
+			.write()
+			.await
+			.remove(&(sender.to_owned(), pushkey));
+
+		self.db.set_pusher(sender, device, pusher)
+	}
+
+	/// Deletes the pushers set by `device`, so a removed session stops
+	/// receiving notifications.
+	pub fn remove_device_pushers(&self, sender: &UserId, device: &DeviceId) -> Result<()> {
+		for pushkey in self.db.get_device_pushkeys(sender, device)? {
+			let Some(pusher) = self.db.get_pusher(sender, &pushkey)? else {
+				continue;
+			};
+
+			debug_info!("Removing pusher {pushkey} of deleted device {device} for {sender}");
+			self.db
+				.set_pusher(sender, None, set_pusher::v3::PusherAction::Delete(pusher.ids))?;
+		}
+
+		Ok(())
 	}
 
 	pub fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>> {
@@ -39,13 +158,27 @@ impl Service {
 
 	pub fn get_pushers(&self, sender: &UserId) -> Result<Vec<Pusher>> { self.db.get_pushers(sender) }
 
+	/// Whether SMTP is configured, so email addresses can be validated and
+	/// email pushers notified
+	pub fn can_email(&self) -> bool { self.mailer.is_some() }
+
+	/// Emails `link` to `to`, for validating the address before it is added
+	/// to an account.
+	pub async fn send_validation_email(&self, to: &str, link: &str) -> Result<()> {
+		let Some((transport, from)) = &self.mailer else {
+			return Err(Error::Err("SMTP is not configured".to_owned()));
+		};
+
+		send_email(transport, from, to, render_validation_email(link)).await
+	}
+
 	#[must_use]
 	pub fn get_pushkeys(&self, sender: &UserId) -> Box<dyn Iterator<Item = Result<String>> + '_> {
 		self.db.get_pushkeys(sender)
 	}
 
 	#[tracing::instrument(skip(self, dest, request))]
-	pub async fn send_request<T>(&self, dest: &str, request: T) -> Result<T::IncomingResponse>
+	pub async fn send_request<T>(&self, dest: &str, request: T) -> Result<GatewayResponse<T::IncomingResponse>>
 	where
 		T: OutgoingRequest + Debug + Send,
 	{
@@ -106,6 +239,11 @@ impl Service {
 
 				let body = response.bytes().await?; // TODO: handle timeout
 
+				if status == http::StatusCode::GONE {
+					info!("Push gateway {dest} reports the pushkey is gone");
+					return Ok(GatewayResponse::Gone);
+				}
+
 				if !status.is_success() {
 					info!("Push gateway {dest} returned unsuccessful HTTP response ({status})");
 					debug_info!("Push gateway response body: {:?}", crate::utils::string_from_bytes(&body));
@@ -118,7 +256,7 @@ impl Service {
 						.body(body)
 						.expect("reqwest body is valid http body"),
 				);
-				response.map_err(|e| {
+				response.map(GatewayResponse::Accepted).map_err(|e| {
 					warn!("Push gateway {dest} returned invalid response bytes: {e}");
 					Error::BadServerResponse("Push gateway returned bad/invalid response")
 				})
@@ -168,7 +306,14 @@ impl Service {
 		}
 
 		if notify == Some(true) {
-			self.send_notice(unread, pusher, tweaks, pdu).await?;
+			let missed_calls = services()
+				.rooms
+				.user
+				.missed_call_count(user, &pdu.room_id, unread.into())?
+				.try_into()
+				.expect("missed calls are capped at the unread count");
+			self.send_notice(user, unread, missed_calls, pusher, tweaks, pdu)
+				.await?;
 		}
 		// Else the event triggered no actions
 
@@ -180,12 +325,6 @@ impl Service {
 		&self, user: &UserId, ruleset: &'a Ruleset, power_levels: &RoomPowerLevelsEventContent,
 		pdu: &Raw<AnySyncTimelineEvent>, room_id: &RoomId,
 	) -> Result<&'a [Action]> {
-		let power_levels = PushConditionPowerLevelsCtx {
-			users: power_levels.users.clone(),
-			users_default: power_levels.users_default,
-			notifications: power_levels.notifications.clone(),
-		};
-
 		let ctx = PushConditionRoomCtx {
 			room_id: room_id.to_owned(),
 			member_count: UInt::try_from(
@@ -201,17 +340,23 @@ impl Service {
 				.users
 				.displayname(user)?
 				.unwrap_or_else(|| user.localpart().to_owned()),
-			power_levels: Some(power_levels),
+			power_levels: Some(power_levels_ctx(power_levels)),
 		};
 
 		Ok(ruleset.get_actions(pdu, &ctx))
 	}
 
-	#[tracing::instrument(skip(self, unread, pusher, tweaks, event))]
-	async fn send_notice(&self, unread: UInt, pusher: &Pusher, tweaks: Vec<Tweak>, event: &PduEvent) -> Result<()> {
-		// TODO: email
+	#[tracing::instrument(skip(self, user, unread, missed_calls, pusher, tweaks, event))]
+	async fn send_notice(
+		&self, user: &UserId, unread: UInt, missed_calls: UInt, pusher: &Pusher, tweaks: Vec<Tweak>, event: &PduEvent,
+	) -> Result<()> {
 		match &pusher.kind {
 			PusherKind::Http(http) => {
+				if let Some(tries) = self.gateway_backed_off(&http.url).await {
+					debug!("Backing off push to {} for {user} after {tries} consecutive failures", http.url);
+					return Ok(());
+				}
+
 				// TODO:
 				// Two problems with this
 				// 1. if "event_id_only" is the only format kind it seems we should never add
@@ -234,8 +379,7 @@ impl Service {
 				notifi.prio = NotificationPriority::Low;
 				notifi.event_id = Some((*event.event_id).to_owned());
 				notifi.room_id = Some((*event.room_id).to_owned());
-				// TODO: missed calls
-				notifi.counts = NotificationCounts::new(unread, uint!(0));
+				notifi.counts = NotificationCounts::new(unread, missed_calls);
 
 				if event.kind == TimelineEventType::RoomEncrypted
 					|| tweaks
@@ -245,9 +389,9 @@ impl Service {
 					notifi.prio = NotificationPriority::High;
 				}
 
-				if event_id_only {
+				let response = if event_id_only {
 					self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-						.await?;
+						.await
 				} else {
 					notifi.sender = Some(event.sender.clone());
 					notifi.event_type = Some(event.kind.clone());
@@ -262,14 +406,632 @@ impl Service {
 					notifi.room_name = services().rooms.state_accessor.get_name(&event.room_id)?;
 
 					self.send_request(&http.url, send_event_notification::v1::Request::new(notifi))
-						.await?;
+						.await
+				};
+
+				let outcome = push_outcome(&pusher.ids.pushkey, &response);
+				let max_failures = services().globals.config.pusher_max_failures;
+				self.record_push_outcome(user, pusher, &http.url, &outcome, max_failures)
+					.await?;
+
+				response.map(|_| ())
+			},
+			PusherKind::Email(_) => {
+				let Some((transport, from)) = &self.mailer else {
+					debug!("Not emailing {user} about {}, SMTP is not configured", event.event_id);
+					return Ok(());
+				};
+
+				// the address may have been unbound since the pusher was set
+				if !services().users.has_email(user, &pusher.ids.pushkey)? {
+					debug!(
+						"Not emailing {user} about {}, {} is no longer bound to the account",
+						event.event_id, pusher.ids.pushkey
+					);
+					return Ok(());
+				}
+
+				let sender_name = services()
+					.users
+					.displayname(&event.sender)?
+					.unwrap_or_else(|| event.sender.to_string());
+				let room_name = services()
+					.rooms
+					.state_accessor
+					.get_name(&event.room_id)?
+					.unwrap_or_else(|| event.room_id.to_string());
+
+				let notice = render_email_notice(event, user, &sender_name, &room_name);
+				// the pushkey of an email pusher is the address to notify
+				if let Err(e) = send_email(transport, from, &pusher.ids.pushkey, notice).await {
+					warn!("Failed to email {user} about {}: {e}", event.event_id);
 				}
 
 				Ok(())
 			},
-			// TODO: Handle email
-			//PusherKind::Email(_) => Ok(()),
 			_ => Ok(()),
 		}
 	}
 }
+
+/// Builds the power levels context used by push rule evaluation. This is what
+/// lets `sender_notification_permission` conditions (e.g. `@room` mentions)
+/// check the sender's level against `notifications.room`.
+fn power_levels_ctx(power_levels: &RoomPowerLevelsEventContent) -> PushConditionPowerLevelsCtx {
+	PushConditionPowerLevelsCtx {
+		users: power_levels.users.clone(),
+		users_default: power_levels.users_default,
+		notifications: power_levels.notifications.clone(),
+	}
+}
+
+impl Service {
+	/// Returns the number of consecutive failures of the push gateway at `url`
+	/// while its cooldown has not elapsed yet.
+	async fn gateway_backed_off(&self, url: &str) -> Option<u32> {
+		let (last_failure, tries) = *self.gateway_ratelimiter.read().await.get(url)?;
+
+		let config = &services().globals.config;
+		let backoff = gateway_backoff(
+			tries,
+			Duration::from_secs(config.pusher_gateway_backoff_base_s),
+			Duration::from_secs(config.pusher_gateway_backoff_max_s),
+		);
+
+		(last_failure.elapsed() < backoff).then_some(tries)
+	}
+
+	/// Resets the failure counts of the push gateway and the pusher once the
+	/// gateway answers, or removes the pusher once the gateway rejects it or
+	/// pushes to it have failed `max_failures` times in a row. Failures of the
+	/// gateway only back off pushes to it, so an outage doesn't remove every
+	/// pusher using it.
+	async fn record_push_outcome(
+		&self, user: &UserId, pusher: &Pusher, url: &str, outcome: &PushOutcome, max_failures: u32,
+	) -> Result<()> {
+		let key = (user.to_owned(), pusher.ids.pushkey.clone());
+		let remove = match outcome {
+			PushOutcome::Delivered => {
+				self.gateway_ratelimiter.write().await.remove(url);
+				self.pusher_failures.write().await.remove(&key);
+				false
+			},
+			PushOutcome::Rejected => {
+				info!("Push gateway rejected pushkey of pusher {} for {user}", pusher.ids.app_id);
+				self.gateway_ratelimiter.write().await.remove(url);
+				true
+			},
+			PushOutcome::Failed => {
+				match self.gateway_ratelimiter.write().await.entry(url.to_owned()) {
+					hash_map::Entry::Vacant(e) => {
+						e.insert((Instant::now(), 1));
+					},
+					hash_map::Entry::Occupied(mut e) => {
+						*e.get_mut() = (Instant::now(), e.get().1.saturating_add(1));
+					},
+				}
+
+				let mut failures = self.pusher_failures.write().await;
+				let tries = failures.entry(key.clone()).or_default();
+				*tries = tries.saturating_add(1);
+
+				let remove = max_failures > 0 && *tries >= max_failures;
+				if remove {
+					info!(
+						"Removing pusher {} for {user} after {tries} consecutive failed pushes",
+						pusher.ids.app_id
+					);
+				}
+				remove
+			},
+		};
+
+		if remove {
+			self.pusher_failures.write().await.remove(&key);
+			self.db
+				.set_pusher(user, None, set_pusher::v3::PusherAction::Delete(pusher.ids.clone()))?;
+		}
+
+		Ok(())
+	}
+}
+
+fn push_outcome(
+	pushkey: &str, response: &Result<GatewayResponse<send_event_notification::v1::Response>>,
+) -> PushOutcome {
+	match response {
+		Ok(GatewayResponse::Accepted(response)) if response.rejected.iter().any(|rejected| rejected == pushkey) => {
+			PushOutcome::Rejected
+		},
+		Ok(GatewayResponse::Accepted(_)) => PushOutcome::Delivered,
+		Ok(GatewayResponse::Gone) => PushOutcome::Rejected,
+		Err(_) => PushOutcome::Failed,
+	}
+}
+
+/// How long to wait after the last failure before pushing to a push gateway
+/// again. Doubles with each consecutive failure, starting at `base`, up to
+/// `max`.
+fn gateway_backoff(tries: u32, base: Duration, max: Duration) -> Duration {
+	let factor = 2_u32.saturating_pow(tries.saturating_sub(1));
+	cmp::min(max, base.saturating_mul(factor))
+}
+
+/// Rejects HTTP pusher URLs which don't point at the push gateway path, or
+/// whose host is or resolves to an address in the CIDR denylist. The same
+/// check is repeated at send time in case DNS changes afterwards.
+async fn check_pusher_url<R, F>(
+	url: &str, push_path: &str, resolve: R, allowed: impl Fn(&IPAddress) -> bool,
+) -> Result<()>
+where
+	R: FnOnce(String) -> F,
+	F: Future<Output = Vec<IpAddr>>,
+{
+	let url = Url::parse(url).map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Pusher URL is invalid."))?;
+	if !matches!(url.scheme(), "http" | "https") {
+		return Err(Error::BadRequest(ErrorKind::InvalidParam, "Pusher URL must use http or https."));
+	}
+
+	if !url.path().ends_with(push_path) {
+		return Err(Error::BadRequest(
+			ErrorKind::InvalidParam,
+			"Pusher URL must point at the push gateway's notify endpoint.",
+		));
+	}
+
+	let ips = match url.host() {
+		Some(Host::Ipv4(ip)) => vec![ip.into()],
+		Some(Host::Ipv6(ip)) => vec![ip.into()],
+		Some(Host::Domain(domain)) => resolve(domain.to_owned()).await,
+		None => return Err(Error::BadRequest(ErrorKind::InvalidParam, "Pusher URL has no host.")),
+	};
+
+	for ip in ips {
+		if IPAddress::parse(ip.to_string()).is_ok_and(|ip| !allowed(&ip)) {
+			info!("Rejecting pusher with URL {url} resolving to denied IP {ip}");
+			return Err(Error::BadRequest(
+				ErrorKind::forbidden(),
+				"Pusher URL points at a disallowed IP address.",
+			));
+		}
+	}
+
+	Ok(())
+}
+
+/// Builds the SMTP transport for email pushers and validating email addresses
+/// from `smtp_server`, `smtp_username`, `smtp_password` and `smtp_from`. Email
+/// is disabled if any of these is missing or invalid.
+pub(crate) fn mailer(config: &Config) -> Option<(AsyncSmtpTransport<Tokio1Executor>, Mailbox)> {
+	let (Some(server), Some(from)) = (&config.smtp_server, &config.smtp_from) else {
+		return None;
+	};
+
+	let from: Mailbox = match from.parse() {
+		Ok(from) => from,
+		Err(e) => {
+			error!("Invalid smtp_from address {from:?}, email is disabled: {e}");
+			return None;
+		},
+	};
+
+	let mut builder = match AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(server) {
+		Ok(builder) => builder,
+		Err(e) => {
+			error!("Invalid smtp_server {server:?}, email is disabled: {e}");
+			return None;
+		},
+	};
+
+	if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+		builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+	}
+
+	Some((builder.build(), from))
+}
+
+#[derive(Deserialize)]
+struct MessageBody {
+	body: Option<String>,
+}
+
+/// Renders the notification email for `event`, as seen by `user`.
+fn render_email_notice(event: &PduEvent, user: &UserId, sender_name: &str, room_name: &str) -> EmailNotice {
+	let invite = event.kind == TimelineEventType::RoomMember
+		&& event.state_key.as_deref() == Some(user.as_str())
+		&& serde_json::from_str::<RoomMemberEventContent>(event.content.get())
+			.is_ok_and(|content| content.membership == MembershipState::Invite);
+
+	let (subject, text) = if invite {
+		(
+			format!("{sender_name} invited you to {room_name}"),
+			format!("{sender_name} has invited you to join {room_name}."),
+		)
+	} else {
+		let text = match event.kind {
+			TimelineEventType::RoomEncrypted => "sent an encrypted message".to_owned(),
+			TimelineEventType::RoomMessage => serde_json::from_str::<MessageBody>(event.content.get())
+				.ok()
+				.and_then(|content| content.body)
+				.unwrap_or_else(|| "sent a message".to_owned()),
+			_ => format!("sent a {} event", event.kind),
+		};
+
+		(format!("New message in {room_name}"), format!("{sender_name}: {text}"))
+	};
+
+	let html = format!("<p>{}</p>", HtmlEscape(&text));
+	EmailNotice {
+		subject,
+		plain: text,
+		html,
+	}
+}
+
+/// Renders the email validating an address the user asked to add.
+fn render_validation_email(link: &str) -> EmailNotice {
+	let text = format!(
+		"Open this link to add this email address to your Matrix account: {link}\n\nIf you did not ask for this, you \
+		 can ignore this email."
+	);
+
+	let html = format!(
+		"<p>Open <a href=\"{link}\">this link</a> to add this email address to your Matrix account.</p><p>If you \
+		 did not ask for this, you can ignore this email.</p>",
+		link = HtmlEscape(link),
+	);
+	EmailNotice {
+		subject: "Validate your email address".to_owned(),
+		plain: text,
+		html,
+	}
+}
+
+async fn send_email<T>(transport: &T, from: &Mailbox, to: &str, notice: EmailNotice) -> Result<()>
+where
+	T: AsyncTransport + Sync,
+	T::Error: std::fmt::Display,
+{
+	let to: Mailbox = to
+		.parse()
+		.map_err(|e| Error::Err(format!("invalid email address {to:?}: {e}")))?;
+
+	let message = Message::builder()
+		.from(from.clone())
+		.to(to)
+		.subject(notice.subject)
+		.multipart(MultiPart::alternative_plain_html(notice.plain, notice.html))
+		.map_err(|e| Error::Err(format!("failed to build notification email: {e}")))?;
+
+	transport
+		.send(message)
+		.await
+		.map_err(|e| Error::Err(format!("failed to send notification email: {e}")))?;
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::BTreeMap,
+		net::IpAddr,
+		sync::{Arc, Mutex},
+		time::Duration,
+	};
+
+	use ipaddress::IPAddress;
+	use lettre::{message::Mailbox, transport::stub::AsyncStubTransport};
+	use ruma::{
+		api::{
+			client::push::{set_pusher, Pusher, PusherIds, PusherInit, PusherKind},
+			push_gateway::send_event_notification,
+		},
+		events::{room::power_levels::RoomPowerLevelsEventContent, AnySyncTimelineEvent},
+		int, owned_room_id, owned_user_id,
+		push::{Action, HttpPusherData, PushConditionRoomCtx, Ruleset, Tweak},
+		serde::Raw,
+		uint, user_id, DeviceId, Int, OwnedUserId, UserId,
+	};
+	use serde_json::{json, value::to_raw_value};
+	use tokio::sync::RwLock;
+
+	use super::{
+		check_pusher_url, gateway_backoff, power_levels_ctx, push_outcome, render_email_notice,
+		render_validation_email, send_email, Data, Error, GatewayResponse, PushOutcome, Service,
+	};
+	use crate::{PduEvent, Result};
+
+	const GATEWAY: &str = "https://push.example.com/_matrix/push/v1/notify";
+
+	#[derive(Default)]
+	struct Pushers(Mutex<BTreeMap<(OwnedUserId, String), Pusher>>);
+
+	impl Data for Pushers {
+		fn set_pusher(
+			&self, sender: &UserId, _device: Option<&DeviceId>, pusher: set_pusher::v3::PusherAction,
+		) -> Result<()> {
+			let mut pushers = self.0.lock().unwrap();
+			match pusher {
+				set_pusher::v3::PusherAction::Post(data) => {
+					pushers.insert((sender.to_owned(), data.pusher.ids.pushkey.clone()), data.pusher);
+				},
+				set_pusher::v3::PusherAction::Delete(ids) => {
+					pushers.remove(&(sender.to_owned(), ids.pushkey));
+				},
+			}
+
+			Ok(())
+		}
+
+		fn get_pusher(&self, sender: &UserId, pushkey: &str) -> Result<Option<Pusher>> {
+			Ok(self
+				.0
+				.lock()
+				.unwrap()
+				.get(&(sender.to_owned(), pushkey.to_owned()))
+				.cloned())
+		}
+
+		fn get_pushers(&self, _sender: &UserId) -> Result<Vec<Pusher>> { Ok(Vec::new()) }
+
+		fn get_pushkeys<'a>(&'a self, _sender: &UserId) -> Box<dyn Iterator<Item = Result<String>> + 'a> {
+			Box::new(std::iter::empty())
+		}
+
+		fn get_device_pushkeys(&self, _sender: &UserId, _device: &DeviceId) -> Result<Vec<String>> { Ok(Vec::new()) }
+	}
+
+	fn pusher_service() -> Service {
+		Service {
+			db: Arc::new(Pushers::default()),
+			mailer: None,
+			pusher_failures: RwLock::default(),
+			gateway_ratelimiter: RwLock::default(),
+		}
+	}
+
+	/// Stores an HTTP pusher with `pushkey` for `user`, returning it.
+	fn add_pusher(service: &Service, user: &UserId, pushkey: &str) -> Pusher {
+		let pusher: Pusher = PusherInit {
+			ids: PusherIds::new(pushkey.to_owned(), "org.example.app".to_owned()),
+			kind: PusherKind::Http(HttpPusherData::new(GATEWAY.to_owned())),
+			app_display_name: "Example".to_owned(),
+			device_display_name: "Phone".to_owned(),
+			profile_tag: None,
+			lang: "en".to_owned(),
+		}
+		.into();
+
+		service
+			.db
+			.set_pusher(
+				user,
+				None,
+				set_pusher::v3::PusherAction::Post(set_pusher::v3::PusherPostData {
+					pusher: pusher.clone(),
+					append: false,
+				}),
+			)
+			.unwrap();
+
+		pusher
+	}
+
+	fn pdu(kind: &str, state_key: Option<&str>, content: serde_json::Value) -> PduEvent {
+		PduEvent::test_event(json!({ "type": kind, "state_key": state_key, "content": content }))
+	}
+
+	fn room_mention_highlights(sender_level: Int) -> bool {
+		let alice = owned_user_id!("@alice:example.com");
+		let bob = owned_user_id!("@bob:example.com");
+
+		let mut power_levels = RoomPowerLevelsEventContent::new();
+		power_levels.users.insert(alice, sender_level);
+
+		let ctx = PushConditionRoomCtx {
+			room_id: owned_room_id!("!room:example.com"),
+			member_count: uint!(3),
+			user_id: bob.clone(),
+			user_display_name: "bob".to_owned(),
+			power_levels: Some(power_levels_ctx(&power_levels)),
+		};
+
+		let event: Raw<AnySyncTimelineEvent> = Raw::from_json(
+			to_raw_value(&json!({
+				"type": "m.room.message",
+				"event_id": "$mention:example.com",
+				"sender": "@alice:example.com",
+				"origin_server_ts": 1,
+				"content": {
+					"msgtype": "m.text",
+					"body": "@room hello",
+					"m.mentions": { "room": true },
+				},
+			}))
+			.unwrap(),
+		);
+
+		Ruleset::server_default(&bob)
+			.get_actions(&event, &ctx)
+			.iter()
+			.any(|action| matches!(action, Action::SetTweak(Tweak::Highlight(true))))
+	}
+
+	#[test]
+	fn room_mention_below_notifications_level_does_not_highlight() {
+		assert!(!room_mention_highlights(int!(0)));
+	}
+
+	#[test]
+	fn room_mention_at_notifications_level_highlights() {
+		assert!(room_mention_highlights(int!(50)));
+	}
+
+	async fn check(url: &str, resolves_to: &str) -> bool {
+		let denylist = IPAddress::parse("127.0.0.0/8").unwrap();
+		let resolved: IpAddr = resolves_to.parse().unwrap();
+
+		check_pusher_url(
+			url,
+			"/_matrix/push/v1/notify",
+			|_| async move { vec![resolved] },
+			|ip| !denylist.includes(ip),
+		)
+		.await
+		.is_ok()
+	}
+
+	#[tokio::test]
+	async fn pusher_url_resolving_to_denied_ip_is_rejected() {
+		assert!(!check("https://push.example.com/_matrix/push/v1/notify", "127.0.0.1").await);
+		assert!(!check("http://127.0.0.1/_matrix/push/v1/notify", "192.0.2.1").await);
+		assert!(check("https://push.example.com/_matrix/push/v1/notify", "192.0.2.1").await);
+	}
+
+	#[tokio::test]
+	async fn pusher_url_must_end_with_push_path() {
+		assert!(!check("https://push.example.com/", "192.0.2.1").await);
+		assert!(!check("ftp://push.example.com/_matrix/push/v1/notify", "192.0.2.1").await);
+	}
+
+	#[tokio::test]
+	async fn pusher_rejected_by_gateway_is_removed() {
+		let mut response = send_event_notification::v1::Response::new();
+		response.rejected = vec!["dead-key".to_owned()];
+		let response = Ok(GatewayResponse::Accepted(response));
+
+		assert_eq!(push_outcome("dead-key", &response), PushOutcome::Rejected);
+		assert_eq!(push_outcome("live-key", &response), PushOutcome::Delivered);
+		assert_eq!(push_outcome("dead-key", &Ok(GatewayResponse::Gone)), PushOutcome::Rejected);
+		assert_eq!(
+			push_outcome(
+				"live-key",
+				&Err(Error::BadServerResponse("Push gateway returned unsuccessful response"))
+			),
+			PushOutcome::Failed
+		);
+
+		let service = pusher_service();
+		let alice = user_id!("@alice:example.com");
+		let dead = add_pusher(&service, alice, "dead-key");
+		let live = add_pusher(&service, alice, "live-key");
+
+		service
+			.record_push_outcome(alice, &dead, GATEWAY, &PushOutcome::Rejected, 3)
+			.await
+			.unwrap();
+		assert!(service.get_pusher(alice, "dead-key").unwrap().is_none());
+		assert!(service.get_pusher(alice, "live-key").unwrap().is_some());
+
+		service
+			.record_push_outcome(alice, &live, GATEWAY, &PushOutcome::Delivered, 3)
+			.await
+			.unwrap();
+		assert!(service.get_pusher(alice, "live-key").unwrap().is_some());
+	}
+
+	#[tokio::test]
+	async fn pusher_is_removed_after_its_own_consecutive_failures() {
+		let service = pusher_service();
+		let alice = user_id!("@alice:example.com");
+		let bob = user_id!("@bob:example.com");
+		let flaky = add_pusher(&service, alice, "flaky-key");
+		let healthy = add_pusher(&service, bob, "healthy-key");
+		let record = |user, pusher, outcome| service.record_push_outcome(user, pusher, GATEWAY, outcome, 3);
+
+		// failures of a pusher are forgotten once a push to it is delivered
+		record(alice, &flaky, &PushOutcome::Failed).await.unwrap();
+		record(alice, &flaky, &PushOutcome::Failed).await.unwrap();
+		assert_eq!(
+			service
+				.gateway_ratelimiter
+				.read()
+				.await
+				.get(GATEWAY)
+				.map(|(_, tries)| *tries),
+			Some(2)
+		);
+		record(alice, &flaky, &PushOutcome::Delivered)
+			.await
+			.unwrap();
+		assert!(service
+			.gateway_ratelimiter
+			.read()
+			.await
+			.get(GATEWAY)
+			.is_none());
+		record(alice, &flaky, &PushOutcome::Failed).await.unwrap();
+		record(alice, &flaky, &PushOutcome::Failed).await.unwrap();
+		assert!(service.get_pusher(alice, "flaky-key").unwrap().is_some());
+
+		// the other pusher on the same gateway only removes itself
+		record(bob, &healthy, &PushOutcome::Failed).await.unwrap();
+		record(alice, &flaky, &PushOutcome::Failed).await.unwrap();
+		assert!(service.get_pusher(alice, "flaky-key").unwrap().is_none());
+		assert!(service.get_pusher(bob, "healthy-key").unwrap().is_some());
+	}
+
+	#[test]
+	fn gateway_backoff_doubles_up_to_ceiling() {
+		let base = Duration::from_secs(30);
+		let max = Duration::from_secs(60 * 60);
+		assert_eq!(gateway_backoff(1, base, max), base);
+		assert_eq!(gateway_backoff(2, base, max), Duration::from_secs(60));
+		assert_eq!(gateway_backoff(4, base, max), Duration::from_secs(240));
+		assert_eq!(gateway_backoff(100, base, max), max);
+	}
+
+	#[tokio::test]
+	async fn room_message_email_is_rendered_and_sent() {
+		let event = pdu("m.room.message", None, json!({ "msgtype": "m.text", "body": "hi <b>bob</b>" }));
+		let notice = render_email_notice(&event, user_id!("@bob:example.com"), "Alice", "Chat");
+		assert_eq!(notice.subject, "New message in Chat");
+		assert_eq!(notice.plain, "Alice: hi <b>bob</b>");
+		assert_eq!(notice.html, "<p>Alice: hi &lt;b&gt;bob&lt;/b&gt;</p>");
+
+		let transport = AsyncStubTransport::new_ok();
+		let from: Mailbox = "conduwuit <notifications@example.com>".parse().unwrap();
+		send_email(&transport, &from, "bob@example.org", notice)
+			.await
+			.unwrap();
+
+		let messages = transport.messages().await;
+		assert_eq!(messages.len(), 1);
+		let (envelope, message) = &messages[0];
+		assert_eq!(envelope.to()[0].to_string(), "bob@example.org");
+		assert!(message.contains("Subject: New message in Chat"));
+		assert!(message.contains("Alice: hi <b>bob</b>"));
+	}
+
+	#[tokio::test]
+	async fn invite_email_is_rendered() {
+		let bob = user_id!("@bob:example.com");
+		let event = pdu("m.room.member", Some(bob.as_str()), json!({ "membership": "invite" }));
+		let notice = render_email_notice(&event, bob, "Alice", "Chat");
+		assert_eq!(notice.subject, "Alice invited you to Chat");
+		assert_eq!(notice.plain, "Alice has invited you to join Chat.");
+
+		// someone else's invite is just an event in the room
+		let event = pdu("m.room.member", Some("@carol:example.com"), json!({ "membership": "invite" }));
+		let notice = render_email_notice(&event, bob, "Alice", "Chat");
+		assert_eq!(notice.subject, "New message in Chat");
+
+		let transport = AsyncStubTransport::new_ok();
+		let from: Mailbox = "notifications@example.com".parse().unwrap();
+		assert!(send_email(&transport, &from, "not an address", notice)
+			.await
+			.is_err());
+		assert!(transport.messages().await.is_empty());
+	}
+
+	#[test]
+	fn validation_email_links_to_session() {
+		let link = "https://example.com/_conduwuit/3pid/email/validate?sid=abc&token=def";
+		let notice = render_validation_email(link);
+		assert!(notice.plain.contains(link));
+		assert!(notice
+			.html
+			.contains("href=\"https://example.com/_conduwuit/3pid/email/validate?sid=abc&amp;token=def\""));
+	}
+}
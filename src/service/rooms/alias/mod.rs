@@ -6,13 +6,17 @@ use data::Data;
 use ruma::{
 	api::client::error::ErrorKind,
 	events::{
-		room::power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
-		StateEventType,
+		room::{
+			canonical_alias::RoomCanonicalAliasEventContent,
+			power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+		},
+		StateEventType, TimelineEventType,
 	},
 	OwnedRoomAliasId, OwnedRoomId, RoomAliasId, RoomId, UserId,
 };
+use serde_json::value::to_raw_value;
 
-use crate::{services, Error, Result};
+use crate::{pdu::PduBuilder, services, Error, Result};
 
 pub struct Service {
 	pub db: Arc<dyn Data>,
@@ -60,6 +64,46 @@ impl Service {
 		self.db.all_local_aliases()
 	}
 
+	/// Drops the alias from the room's `m.room.canonical_alias` event, sending
+	/// the update as the server user. Returns whether the event changed.
+	#[tracing::instrument(skip(self))]
+	pub async fn remove_from_canonical_alias(&self, alias: &RoomAliasId, room_id: &RoomId) -> Result<bool> {
+		let Some(event) =
+			services()
+				.rooms
+				.state_accessor
+				.room_state_get(room_id, &StateEventType::RoomCanonicalAlias, "")?
+		else {
+			return Ok(false);
+		};
+
+		let mut content: RoomCanonicalAliasEventContent = serde_json::from_str(event.content.get())
+			.map_err(|_| Error::bad_database("Invalid canonical alias event in database."))?;
+		if !without_alias(&mut content, alias) {
+			return Ok(false);
+		}
+
+		let state_lock = services().globals.roomid_mutex_state.lock(room_id).await;
+		services()
+			.rooms
+			.timeline
+			.build_and_append_pdu(
+				PduBuilder {
+					event_type: TimelineEventType::RoomCanonicalAlias,
+					content: to_raw_value(&content).expect("event is valid, we just created it"),
+					unsigned: None,
+					state_key: Some(String::new()),
+					redacts: None,
+				},
+				&services().globals.server_user,
+				room_id,
+				&state_lock,
+			)
+			.await?;
+
+		Ok(true)
+	}
+
 	async fn user_can_remove_alias(&self, alias: &RoomAliasId, user_id: &UserId) -> Result<bool> {
 		let Some(room_id) = self.resolve_local_alias(alias)? else {
 			return Err(Error::BadRequest(ErrorKind::NotFound, "Alias not found."));
@@ -105,3 +149,41 @@ impl Service {
 		}
 	}
 }
+
+/// Removes the alias from the canonical and alternative aliases, returning
+/// whether it was present.
+fn without_alias(content: &mut RoomCanonicalAliasEventContent, alias: &RoomAliasId) -> bool {
+	let mut changed = false;
+	if content.alias.as_deref() == Some(alias) {
+		content.alias = None;
+		changed = true;
+	}
+
+	let alt_aliases = content.alt_aliases.len();
+	content.alt_aliases.retain(|alt_alias| *alt_alias != alias);
+
+	changed || content.alt_aliases.len() != alt_aliases
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{events::room::canonical_alias::RoomCanonicalAliasEventContent, owned_room_alias_id, room_alias_id};
+
+	use super::without_alias;
+
+	#[test]
+	fn removes_canonical_and_alt_aliases() {
+		let mut content = RoomCanonicalAliasEventContent::new();
+		content.alias = Some(owned_room_alias_id!("#main:example.com"));
+		content.alt_aliases = vec![
+			owned_room_alias_id!("#alt:example.com"),
+			owned_room_alias_id!("#other:example.com"),
+		];
+
+		assert!(!without_alias(&mut content, room_alias_id!("#unused:example.com")));
+		assert!(without_alias(&mut content, room_alias_id!("#alt:example.com")));
+		assert_eq!(content.alt_aliases, vec![owned_room_alias_id!("#other:example.com")]);
+		assert!(without_alias(&mut content, room_alias_id!("#main:example.com")));
+		assert_eq!(content.alias, None);
+	}
+}
@@ -1,6 +1,7 @@
 mod data;
 use std::{
 	collections::{BTreeSet, HashSet},
+	hash::Hash,
 	sync::Arc,
 };
 
@@ -120,42 +121,47 @@ impl Service {
 		Ok(full_auth_chain)
 	}
 
+	/// Walks the auth events of `event_id`. Fails rather than returning a
+	/// partial chain once more than `max_auth_chain_fetch` events are found.
 	#[tracing::instrument(skip(self, room_id))]
 	fn get_auth_chain_inner(&self, room_id: &RoomId, event_id: &EventId) -> Result<HashSet<u64>> {
-		let mut todo = vec![Arc::from(event_id)];
-		let mut found = HashSet::new();
-
-		while let Some(event_id) = todo.pop() {
+		let limit = services().globals.config.max_auth_chain_fetch;
+		collect_auth_chain(Arc::from(event_id), limit, |event_id: &Arc<EventId>| {
 			trace!(?event_id, "processing auth event");
 
-			match services().rooms.timeline.get_pdu(&event_id) {
+			match services().rooms.timeline.get_pdu(event_id) {
 				Ok(Some(pdu)) => {
 					if pdu.room_id != room_id {
 						error!(?event_id, ?pdu, "auth event for incorrect room_id");
 						return Err(Error::BadRequest(ErrorKind::forbidden(), "Evil event in db"));
 					}
-					for auth_event in &pdu.auth_events {
-						let sauthevent = services()
-							.rooms
-							.short
-							.get_or_create_shorteventid(auth_event)?;
-
-						if found.insert(sauthevent) {
-							trace!(?event_id, ?auth_event, "adding auth event to processing queue");
-							todo.push(auth_event.clone());
-						}
-					}
+
+					let auth_events = pdu
+						.auth_events
+						.iter()
+						.map(|auth_event| &**auth_event)
+						.collect::<Vec<_>>();
+					let sauthevents = services()
+						.rooms
+						.short
+						.multi_get_or_create_shorteventid(&auth_events)?;
+
+					Ok(sauthevents
+						.into_iter()
+						.zip(pdu.auth_events.iter().cloned())
+						.collect())
 				},
 				Ok(None) => {
 					warn!(?event_id, "Could not find pdu mentioned in auth events");
+					Ok(Vec::new())
 				},
 				Err(error) => {
 					error!(?event_id, ?error, "Could not load event in auth chain");
+					Ok(Vec::new())
 				},
 			}
-		}
-
-		Ok(found)
+		})
+		.inspect_err(|e| warn!(?event_id, limit, "Could not collect auth chain: {e}"))
 	}
 
 	pub fn get_cached_eventid_authchain(&self, key: &[u64]) -> Result<Option<Arc<[u64]>>> {
@@ -174,3 +180,59 @@ impl Service {
 			.cache_auth_chain(key, auth_chain.iter().copied().collect::<Arc<[u64]>>())
 	}
 }
+
+/// Depth-first walk over auth events starting at `start`. `auth_events` maps an
+/// event to its auth events, each paired with the key it is deduplicated by.
+/// Fails once more than `limit` events are found, so callers never act on an
+/// incomplete chain.
+fn collect_auth_chain<K, T, F>(start: T, limit: usize, mut auth_events: F) -> Result<HashSet<K>>
+where
+	K: Eq + Hash,
+	F: FnMut(&T) -> Result<Vec<(K, T)>>,
+{
+	let mut todo = vec![start];
+	let mut found = HashSet::new();
+
+	while let Some(event) = todo.pop() {
+		for (key, auth_event) in auth_events(&event)? {
+			if found.insert(key) {
+				if found.len() > limit {
+					return Err(Error::BadRequest(
+						ErrorKind::TooLarge,
+						"Auth chain exceeds max_auth_chain_fetch.",
+					));
+				}
+
+				todo.push(auth_event);
+			}
+		}
+	}
+
+	Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::collect_auth_chain;
+
+	/// Every event `n` is authorised by `n - 1`, down to event 0.
+	fn linear_chain(event: &u64) -> crate::Result<Vec<(u64, u64)>> {
+		Ok(event
+			.checked_sub(1)
+			.map(|prev| (prev, prev))
+			.into_iter()
+			.collect())
+	}
+
+	#[test]
+	fn oversized_auth_chain_is_refused() {
+		assert!(collect_auth_chain(10_000_u64, 100, linear_chain).is_err());
+		assert!(collect_auth_chain(101_u64, 100, linear_chain).is_err());
+	}
+
+	#[test]
+	fn auth_chain_within_cap_is_complete() {
+		let found = collect_auth_chain(100_u64, 100, linear_chain).unwrap();
+		assert_eq!(found.len(), 100);
+	}
+}
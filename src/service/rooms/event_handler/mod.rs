@@ -5,6 +5,7 @@ pub struct Service;
 use std::{
 	cmp,
 	collections::{hash_map, BTreeMap, HashMap, HashSet},
+	fmt,
 	pin::Pin,
 	sync::Arc,
 	time::{Duration, Instant},
@@ -25,6 +26,7 @@ use ruma::{
 	serde::Base64,
 	state_res::{self, RoomVersion, StateMap},
 	uint, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedUserId, RoomId, RoomVersionId, ServerName,
+	UserId,
 };
 use tokio::sync::RwLock;
 use tracing::{debug, error, info, trace, warn};
@@ -92,10 +94,15 @@ impl Service {
 			));
 		}
 
-		// 1.3.1 Check room ACL on origin field/server
-		self.acl_check(origin, room_id)?;
+		// 1.2.1 Check if the room is local-only or was created with m.federate false
+		if !services().sending.is_room_federated(room_id)? {
+			return Err(Error::BadRequest(
+				ErrorKind::forbidden(),
+				"This room does not federate with other servers.",
+			));
+		}
 
-		// 1.3.2 Check room ACL on sender's server name
+		// 1.3 Check room ACL on origin field/server and sender's server name
 		let sender: OwnedUserId = serde_json::from_value(
 			value
 				.get("sender")
@@ -105,7 +112,7 @@ impl Service {
 		)
 		.map_err(|_| Error::BadRequest(ErrorKind::BadJson, "User ID in sender is invalid"))?;
 
-		self.acl_check(sender.server_name(), room_id)?;
+		pdu_acl_check(self.room_acl(room_id)?.as_ref(), origin, &sender)?;
 
 		// Fetch create event
 		let create_event = services()
@@ -299,6 +306,12 @@ impl Service {
 			// 1. Remove unsigned field
 			value.remove("unsigned");
 
+			// Drop events over the maximum PDU size
+			if let Err(e) = pdu::check_pdu_size(&value, services().globals.max_event_size()) {
+				warn!("Dropping oversized event {event_id}");
+				return Err(e);
+			}
+
 			// TODO: For RoomVersion6 we must check that Raw<..> is canonical do we anywhere?: https://matrix.org/docs/spec/rooms/v6#canonical-json
 
 			// 2. Check signatures, otherwise drop
@@ -557,15 +570,11 @@ impl Service {
 		});
 		debug!("Retained {} extremities. Compressing state", extremities.len());
 		let state_ids_compressed = Arc::new(
-			state_at_incoming_event
-				.iter()
-				.map(|(shortstatekey, id)| {
-					services()
-						.rooms
-						.state_compressor
-						.compress_state_event(*shortstatekey, id)
-				})
-				.collect::<Result<_>>()?,
+			services().rooms.state_compressor.compress_state_events(
+				state_at_incoming_event
+					.iter()
+					.map(|(shortstatekey, id)| (*shortstatekey, &**id)),
+			)?,
 		);
 
 		if incoming_pdu.state_key.is_some() {
@@ -734,12 +743,15 @@ impl Service {
 					.rooms
 					.short
 					.get_or_create_shortstatekey(&event_type.to_string().into(), &state_key)?;
-				services()
-					.rooms
-					.state_compressor
-					.compress_state_event(shortstatekey, &event_id)
+				Ok((shortstatekey, event_id))
 			})
-			.collect::<Result<_>>()?;
+			.collect::<Result<Vec<_>>>()?;
+
+		let new_room_state = services().rooms.state_compressor.compress_state_events(
+			new_room_state
+				.iter()
+				.map(|(shortstatekey, event_id)| (*shortstatekey, &**event_id)),
+		)?;
 
 		Ok(Arc::new(new_room_state))
 	}
@@ -1048,6 +1060,14 @@ impl Service {
 						continue;
 					}
 
+					if events_all.len() >= services().globals.config.max_auth_chain_fetch {
+						warn!(
+							"Auth chain of {id} exceeds max_auth_chain_fetch ({}), not fetching further auth events",
+							services().globals.config.max_auth_chain_fetch
+						);
+						break;
+					}
+
 					i += 1;
 					if i % 100 == 0 {
 						tokio::task::yield_now().await;
@@ -1275,6 +1295,30 @@ impl Service {
 	/// Returns Ok if the acl allows the server
 	#[tracing::instrument(skip_all)]
 	pub fn acl_check(&self, server_name: &ServerName, room_id: &RoomId) -> Result<()> {
+		let decision = self.acl_decision(server_name, room_id)?;
+		if decision == AclDecision::BrokenAcl {
+			warn!("Ignoring broken ACL event (allow key is empty)");
+		}
+
+		if decision.is_allowed() {
+			trace!("server {server_name} is allowed by ACL: {decision}");
+			Ok(())
+		} else {
+			debug!("Server {} was denied by room ACL in {}: {decision}", server_name, room_id);
+			Err(Error::BadRequest(ErrorKind::forbidden(), "Server was denied by room ACL"))
+		}
+	}
+
+	/// Matches `server_name` against the room's current server ACL without
+	/// enforcing it, reporting which rule decided the outcome.
+	pub fn acl_decision(&self, server_name: &ServerName, room_id: &RoomId) -> Result<AclDecision> {
+		Ok(self
+			.room_acl(room_id)?
+			.map_or(AclDecision::NoAcl, |acl| acl_decision(&acl, server_name)))
+	}
+
+	/// The room's current server ACL, if it has a readable one.
+	fn room_acl(&self, room_id: &RoomId) -> Result<Option<RoomServerAclEventContent>> {
 		let acl_event = if let Some(acl) =
 			services()
 				.rooms
@@ -1285,7 +1329,7 @@ impl Service {
 			acl
 		} else {
 			trace!("No ACL event found");
-			return Ok(());
+			return Ok(None);
 		};
 
 		let acl_event_content: RoomServerAclEventContent = match serde_json::from_str(acl_event.content.get()) {
@@ -1295,23 +1339,11 @@ impl Service {
 			},
 			Err(e) => {
 				warn!("Invalid ACL event: {e}");
-				return Ok(());
+				return Ok(None);
 			},
 		};
 
-		if acl_event_content.allow.is_empty() {
-			warn!("Ignoring broken ACL event (allow key is empty)");
-			// Ignore broken acl events
-			return Ok(());
-		}
-
-		if acl_event_content.is_allowed(server_name) {
-			trace!("server {server_name} is allowed by ACL");
-			Ok(())
-		} else {
-			debug!("Server {} was denied by room ACL in {}", server_name, room_id);
-			Err(Error::BadRequest(ErrorKind::forbidden(), "Server was denied by room ACL"))
-		}
+		Ok(Some(acl_event_content))
 	}
 
 	fn check_room_id(room_id: &RoomId, pdu: &PduEvent) -> Result<()> {
@@ -1336,3 +1368,164 @@ impl Service {
 		RoomVersion::new(room_version_id).expect("room version is supported")
 	}
 }
+
+/// Outcome of matching a server against a room's server ACL, naming the rule
+/// that decided it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AclDecision {
+	/// The room has no (readable) server ACL
+	NoAcl,
+	/// The ACL's allow list is empty, so it is ignored as broken
+	BrokenAcl,
+	/// The server name is an IP literal and the ACL disallows those
+	IpLiteral,
+	/// The server matched this deny rule
+	Denied(String),
+	/// The server matched this allow rule and no deny rule
+	Allowed(String),
+	/// The server matched no allow rule
+	NotAllowed,
+}
+
+impl AclDecision {
+	#[must_use]
+	pub fn is_allowed(&self) -> bool { matches!(self, Self::NoAcl | Self::BrokenAcl | Self::Allowed(_)) }
+}
+
+impl fmt::Display for AclDecision {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::NoAcl => write!(f, "allowed, the room has no server ACL"),
+			Self::BrokenAcl => write!(f, "allowed, the server ACL has an empty allow list and is ignored"),
+			Self::IpLiteral => write!(f, "denied, IP literal server names are not allowed"),
+			Self::Denied(rule) => write!(f, "denied by deny rule `{rule}`"),
+			Self::Allowed(rule) => write!(f, "allowed by allow rule `{rule}`"),
+			Self::NotAllowed => write!(f, "denied, no allow rule matches"),
+		}
+	}
+}
+
+/// Decides whether a room's server ACL lets `server_name` participate. ACLs
+/// with an empty allow list are broken and ignored rather than denying
+/// everyone, but `allow_ip_literals` still applies to them. Matching is on the
+/// host alone, so a port in the server name never lets it slip past a glob.
+pub(crate) fn acl_decision(acl_event_content: &RoomServerAclEventContent, server_name: &ServerName) -> AclDecision {
+	if !acl_event_content.allow_ip_literals && server_name.is_ip_literal() {
+		return AclDecision::IpLiteral;
+	}
+
+	if acl_event_content.allow.is_empty() {
+		return AclDecision::BrokenAcl;
+	}
+
+	// a single-rule ACL reuses ruma's glob matching for each rule in turn
+	let matches = |rule: &&String| {
+		RoomServerAclEventContent::new(true, vec![(*rule).clone()], Vec::new()).is_allowed(server_name)
+	};
+	if let Some(rule) = acl_event_content.deny.iter().find(matches) {
+		return AclDecision::Denied(rule.clone());
+	}
+
+	acl_event_content
+		.allow
+		.iter()
+		.find(matches)
+		.map_or(AclDecision::NotAllowed, |rule| AclDecision::Allowed(rule.clone()))
+}
+
+/// Rejects a PDU when the room's current ACL denies either the server that
+/// sent it to us or its sender's server. This runs for every incoming PDU, so
+/// a server ACL'd after its users joined can't keep sending events.
+fn pdu_acl_check(acl: Option<&RoomServerAclEventContent>, origin: &ServerName, sender: &UserId) -> Result<()> {
+	let Some(acl) = acl else {
+		return Ok(());
+	};
+
+	for server_name in [origin, sender.server_name()] {
+		let decision = acl_decision(acl, server_name);
+		if !decision.is_allowed() {
+			debug!("Server {server_name} was denied by room ACL: {decision}");
+			return Err(Error::BadRequest(ErrorKind::forbidden(), "Server was denied by room ACL"));
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{events::room::server_acl::RoomServerAclEventContent, server_name, user_id};
+
+	use super::{acl_decision, pdu_acl_check, AclDecision};
+
+	#[test]
+	fn acl_banned_server_is_denied() {
+		let acl = RoomServerAclEventContent::new(false, vec!["*".to_owned()], vec!["*.evil.example".to_owned()]);
+		assert!(!acl_decision(&acl, server_name!("matrix.evil.example")).is_allowed());
+		assert!(acl_decision(&acl, server_name!("example.com")).is_allowed());
+
+		let broken = RoomServerAclEventContent::new(false, Vec::new(), vec!["example.com".to_owned()]);
+		assert!(acl_decision(&broken, server_name!("example.com")).is_allowed());
+	}
+
+	#[test]
+	fn acl_ip_literal_servers() {
+		let acl = RoomServerAclEventContent::new(false, vec!["*".to_owned()], Vec::new());
+		assert!(!acl_decision(&acl, server_name!("192.0.2.1")).is_allowed());
+		assert!(!acl_decision(&acl, server_name!("192.0.2.1:8448")).is_allowed());
+		assert!(!acl_decision(&acl, server_name!("[2001:db8::1]:8448")).is_allowed());
+
+		let broken = RoomServerAclEventContent::new(false, Vec::new(), Vec::new());
+		assert!(!acl_decision(&broken, server_name!("192.0.2.1")).is_allowed());
+
+		let acl = RoomServerAclEventContent::new(true, vec!["*".to_owned()], vec!["192.0.2.*".to_owned()]);
+		assert!(!acl_decision(&acl, server_name!("192.0.2.1")).is_allowed());
+		assert!(acl_decision(&acl, server_name!("198.51.100.1")).is_allowed());
+	}
+
+	#[test]
+	fn acl_ignores_ports() {
+		let acl = RoomServerAclEventContent::new(
+			false,
+			vec!["*.example.com".to_owned()],
+			vec!["evil.example.com".to_owned()],
+		);
+		assert!(!acl_decision(&acl, server_name!("evil.example.com:8448")).is_allowed());
+		assert!(acl_decision(&acl, server_name!("matrix.example.com:8448")).is_allowed());
+		assert!(!acl_decision(&acl, server_name!("example.org:443")).is_allowed());
+	}
+
+	#[test]
+	fn acl_decision_names_the_matching_rule() {
+		let acl = RoomServerAclEventContent::new(
+			false,
+			vec!["*.example.com".to_owned()],
+			vec!["evil.example.com".to_owned()],
+		);
+		assert_eq!(
+			acl_decision(&acl, server_name!("evil.example.com")),
+			AclDecision::Denied("evil.example.com".to_owned())
+		);
+		assert_eq!(
+			acl_decision(&acl, server_name!("matrix.example.com")),
+			AclDecision::Allowed("*.example.com".to_owned())
+		);
+		assert_eq!(acl_decision(&acl, server_name!("example.org")), AclDecision::NotAllowed);
+		assert_eq!(acl_decision(&acl, server_name!("192.0.2.1")), AclDecision::IpLiteral);
+	}
+
+	#[test]
+	fn pdus_from_a_newly_denied_server_are_rejected() {
+		let origin = server_name!("evil.example");
+		let sender = user_id!("@spammer:evil.example");
+		let before = RoomServerAclEventContent::new(false, vec!["*".to_owned()], Vec::new());
+		assert!(pdu_acl_check(Some(&before), origin, sender).is_ok());
+
+		let after = RoomServerAclEventContent::new(false, vec!["*".to_owned()], vec!["evil.example".to_owned()]);
+		assert!(pdu_acl_check(Some(&after), origin, sender).is_err());
+
+		// relayed by an allowed server, the sender's server is still checked
+		assert!(pdu_acl_check(Some(&after), server_name!("example.com"), sender).is_err());
+		assert!(pdu_acl_check(None, origin, sender).is_ok());
+	}
+}
@@ -23,6 +23,12 @@ use tracing::{debug, error, info, trace, warn};
 
 use crate::{services, Error, Result};
 
+/// Key IDs to query per server, in the shape of a notary batch request.
+type ServerKeyQuery = BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, QueryCriteria>>;
+
+/// Public keys by server name and key ID, as used for signature checks.
+type PubKeyMap = BTreeMap<String, BTreeMap<String, Base64>>;
+
 impl super::Service {
 	pub async fn fetch_required_signing_keys<'a, E>(
 		&'a self, events: E, pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
@@ -66,56 +72,31 @@ impl super::Service {
 				.join(", ")
 		);
 
-		let mut server_keys: FuturesUnordered<_> = server_key_ids
-			.into_iter()
-			.map(|(signature_server, signature_ids)| async {
-				let fetch_res = self
-					.fetch_signing_keys_for_server(
-						signature_server.as_str().try_into().map_err(|_| {
-							(
-								signature_server.clone(),
-								Error::BadServerResponse("Invalid servername in signatures of server response pdu."),
-							)
-						})?,
-						signature_ids.into_iter().collect(), // HashSet to Vec
-					)
-					.await;
-
-				match fetch_res {
-					Ok(keys) => Ok((signature_server, keys)),
-					Err(e) => {
-						warn!("Signature verification failed: Could not fetch signing key for {signature_server}: {e}",);
-						Err((signature_server, e))
-					},
-				}
-			})
-			.collect();
+		let (mut servers, cached) = group_missing_keys(server_key_ids, |origin| {
+			Ok(services()
+				.globals
+				.signing_keys_for(origin)?
+				.into_iter()
+				.map(|(k, v)| (k.to_string(), v.key))
+				.collect())
+		})?;
+		pub_key_map.write().await.extend(cached);
 
-		while let Some(fetch_res) = server_keys.next().await {
-			match fetch_res {
-				Ok((signature_server, keys)) => {
-					pub_key_map
-						.write()
-						.await
-						.insert(signature_server.clone(), keys);
-				},
-				Err((signature_server, e)) => {
-					warn!("Failed to fetch keys for {}: {:?}", signature_server, e);
-				},
-			}
+		if servers.is_empty() {
+			trace!("We had all keys cached locally, not fetching any keys from remote servers");
+			return Ok(());
 		}
 
-		Ok(())
+		self.fetch_missing_signing_keys(&mut servers, pub_key_map)
+			.await
 	}
 
 	// Gets a list of servers for which we don't have the signing key yet. We go
 	// over the PDUs and either cache the key or add it to the list that needs to be
 	// retrieved.
 	async fn get_server_keys_from_cache(
-		&self, pdu: &RawJsonValue,
-		servers: &mut BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, QueryCriteria>>,
-		_room_version: &RoomVersionId,
-		pub_key_map: &mut RwLockWriteGuard<'_, BTreeMap<String, BTreeMap<String, Base64>>>,
+		&self, pdu: &RawJsonValue, servers: &mut ServerKeyQuery, _room_version: &RoomVersionId,
+		pub_key_map: &mut RwLockWriteGuard<'_, PubKeyMap>,
 	) -> Result<()> {
 		let value: CanonicalJsonObject = serde_json::from_str(pdu.get()).map_err(|e| {
 			error!("Invalid PDU in server response: {:?}: {:?}", pdu, e);
@@ -168,10 +149,13 @@ impl super::Service {
 	/// Batch requests homeserver signing keys from trusted notary key servers
 	/// (`trusted_servers` config option)
 	async fn batch_request_signing_keys(
-		&self, mut servers: BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, QueryCriteria>>,
-		pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
+		&self, servers: &mut ServerKeyQuery, pub_key_map: &RwLock<PubKeyMap>,
 	) -> Result<()> {
 		for server in services().globals.trusted_servers() {
+			if servers.is_empty() {
+				break;
+			}
+
 			debug!("Asking batch signing keys from trusted server {}", server);
 			match services()
 				.sending
@@ -197,8 +181,6 @@ impl super::Service {
 						};
 
 						// TODO: Check signature from trusted server?
-						servers.remove(&k.server_name);
-
 						let result = services()
 							.globals
 							.add_signing_key(&k.server_name, k.clone())?
@@ -206,6 +188,13 @@ impl super::Service {
 							.map(|(k, v)| (k.to_string(), v.key))
 							.collect::<BTreeMap<_, _>>();
 
+						if servers
+							.get(&k.server_name)
+							.is_some_and(|key_ids| has_requested_keys(&result, key_ids))
+						{
+							servers.remove(&k.server_name);
+						}
+
 						pkm.insert(k.server_name.to_string(), result);
 					}
 				},
@@ -224,12 +213,10 @@ impl super::Service {
 
 	/// Requests multiple homeserver signing keys from individual servers (not
 	/// trused notary servers)
-	async fn request_signing_keys(
-		&self, servers: BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, QueryCriteria>>,
-		pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
-	) -> Result<()> {
+	async fn request_signing_keys(&self, servers: &mut ServerKeyQuery, pub_key_map: &RwLock<PubKeyMap>) -> Result<()> {
 		debug!("Asking individual servers for signing keys: {servers:?}");
 		let mut futures: FuturesUnordered<_> = servers
+			.clone()
 			.into_keys()
 			.map(|server| async move {
 				(
@@ -253,6 +240,14 @@ impl super::Service {
 						.into_iter()
 						.map(|(k, v)| (k.to_string(), v.key))
 						.collect();
+
+					if servers
+						.get(&origin)
+						.is_some_and(|key_ids| has_requested_keys(&result, key_ids))
+					{
+						servers.remove(&origin);
+					}
+
 					pub_key_map.write().await.insert(origin.to_string(), result);
 				}
 			}
@@ -266,7 +261,7 @@ impl super::Service {
 		&self, event: &create_join_event::v2::Response, room_version: &RoomVersionId,
 		pub_key_map: &RwLock<BTreeMap<String, BTreeMap<String, Base64>>>,
 	) -> Result<()> {
-		let mut servers: BTreeMap<OwnedServerName, BTreeMap<OwnedServerSigningKeyId, QueryCriteria>> = BTreeMap::new();
+		let mut servers = ServerKeyQuery::new();
 
 		{
 			let mut pkm = pub_key_map.write().await;
@@ -295,13 +290,24 @@ impl super::Service {
 			return Ok(());
 		}
 
+		self.fetch_missing_signing_keys(&mut servers, pub_key_map)
+			.await
+	}
+
+	/// Fetches the signing keys still missing for `servers`, batching them into
+	/// a single query per trusted notary server and falling back to asking
+	/// each remaining server directly (or the other way around if
+	/// `query_trusted_key_servers_first` is false).
+	async fn fetch_missing_signing_keys(
+		&self, servers: &mut ServerKeyQuery, pub_key_map: &RwLock<PubKeyMap>,
+	) -> Result<()> {
 		if services().globals.query_trusted_key_servers_first() {
 			info!(
 				"query_trusted_key_servers_first is set to true, querying notary trusted key servers first for \
 				 homeserver signing keys."
 			);
 
-			self.batch_request_signing_keys(servers.clone(), pub_key_map)
+			self.batch_request_signing_keys(servers, pub_key_map)
 				.await?;
 
 			if servers.is_empty() {
@@ -311,13 +317,11 @@ impl super::Service {
 
 			debug!("Remaining servers left that the notary/trusted servers did not provide: {servers:?}");
 
-			self.request_signing_keys(servers.clone(), pub_key_map)
-				.await?;
+			self.request_signing_keys(servers, pub_key_map).await?;
 		} else {
 			debug!("query_trusted_key_servers_first is set to false, querying individual homeservers first");
 
-			self.request_signing_keys(servers.clone(), pub_key_map)
-				.await?;
+			self.request_signing_keys(servers, pub_key_map).await?;
 
 			if servers.is_empty() {
 				debug!("Individual homeservers supplied all signing keys, no more keys to fetch");
@@ -326,7 +330,7 @@ impl super::Service {
 
 			debug!("Remaining servers left the individual homeservers did not provide: {servers:?}");
 
-			self.batch_request_signing_keys(servers.clone(), pub_key_map)
+			self.batch_request_signing_keys(servers, pub_key_map)
 				.await?;
 		}
 
@@ -523,3 +527,102 @@ impl super::Service {
 		Err(Error::BadServerResponse("Failed to find public key for server"))
 	}
 }
+
+/// Splits the key IDs each server signed with into the keys we already have
+/// cached and a single query covering every key that is still missing.
+fn group_missing_keys<F>(
+	server_key_ids: HashMap<String, HashSet<String>>, cached: F,
+) -> Result<(ServerKeyQuery, PubKeyMap)>
+where
+	F: Fn(&ServerName) -> Result<BTreeMap<String, Base64>>,
+{
+	let mut missing = ServerKeyQuery::new();
+	let mut found = PubKeyMap::new();
+	for (server, key_ids) in server_key_ids {
+		let Ok(origin) = <&ServerName>::try_from(server.as_str()) else {
+			warn!("Invalid servername {server} in signatures of server response pdu");
+			continue;
+		};
+
+		let keys = cached(origin)?;
+		let query: BTreeMap<_, _> = key_ids
+			.iter()
+			.filter(|id| !keys.contains_key(*id))
+			.filter_map(|id| OwnedServerSigningKeyId::try_from(id.as_str()).ok())
+			.map(|id| (id, QueryCriteria::new()))
+			.collect();
+
+		if !query.is_empty() {
+			missing.insert(origin.to_owned(), query);
+		}
+
+		found.insert(server, keys);
+	}
+
+	Ok((missing, found))
+}
+
+/// Checks `keys` has every key ID that was requested; an empty request is
+/// satisfied by any keys.
+fn has_requested_keys(
+	keys: &BTreeMap<String, Base64>, key_ids: &BTreeMap<OwnedServerSigningKeyId, QueryCriteria>,
+) -> bool {
+	key_ids.keys().all(|id| keys.contains_key(id.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::{BTreeMap, HashMap, HashSet};
+
+	use ruma::{
+		api::federation::discovery::get_remote_server_keys_batch::v2::QueryCriteria, serde::Base64, server_name,
+		OwnedServerSigningKeyId,
+	};
+
+	use super::{group_missing_keys, has_requested_keys};
+
+	#[test]
+	fn missing_keys_are_batched_into_one_query() {
+		let server_key_ids = HashMap::from([
+			("one.org".to_owned(), HashSet::from(["ed25519:a".to_owned()])),
+			("two.org".to_owned(), HashSet::from(["ed25519:b".to_owned()])),
+			("cached.org".to_owned(), HashSet::from(["ed25519:c".to_owned()])),
+		]);
+
+		let (missing, found) = group_missing_keys(server_key_ids, |origin| {
+			Ok(if origin.as_str() == "cached.org" {
+				BTreeMap::from([("ed25519:c".to_owned(), Base64::new(b"c".to_vec()))])
+			} else {
+				BTreeMap::new()
+			})
+		})
+		.unwrap();
+
+		assert_eq!(
+			missing.keys().collect::<Vec<_>>(),
+			[server_name!("one.org"), server_name!("two.org")]
+		);
+		assert!(missing[server_name!("one.org")]
+			.keys()
+			.all(|id| id.as_str() == "ed25519:a"));
+		assert!(missing[server_name!("two.org")]
+			.keys()
+			.all(|id| id.as_str() == "ed25519:b"));
+		assert!(found["cached.org"].contains_key("ed25519:c"));
+	}
+
+	fn requested(ids: &[&str]) -> BTreeMap<OwnedServerSigningKeyId, QueryCriteria> {
+		ids.iter()
+			.map(|id| ((*id).try_into().unwrap(), QueryCriteria::new()))
+			.collect()
+	}
+
+	#[test]
+	fn requested_keys_must_all_be_present() {
+		let keys = BTreeMap::from([("ed25519:a".to_owned(), Base64::new(b"a".to_vec()))]);
+
+		assert!(has_requested_keys(&keys, &requested(&[])));
+		assert!(has_requested_keys(&keys, &requested(&["ed25519:a"])));
+		assert!(!has_requested_keys(&keys, &requested(&["ed25519:a", "ed25519:b"])));
+	}
+}
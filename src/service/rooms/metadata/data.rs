@@ -11,6 +11,8 @@ pub trait Data: Send + Sync {
 	fn is_banned(&self, room_id: &RoomId) -> Result<bool>;
 	fn ban_room(&self, room_id: &RoomId, banned: bool) -> Result<()>;
 	fn list_banned_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a>;
+	fn is_local_only(&self, room_id: &RoomId) -> Result<bool>;
+	fn set_local_only(&self, room_id: &RoomId, local_only: bool) -> Result<()>;
 }
 
 impl Data for KeyValueDatabase {
@@ -83,4 +85,18 @@ impl Data for KeyValueDatabase {
 			},
 		))
 	}
+
+	fn is_local_only(&self, room_id: &RoomId) -> Result<bool> {
+		Ok(self.localonlyroomids.get(room_id.as_bytes())?.is_some())
+	}
+
+	fn set_local_only(&self, room_id: &RoomId, local_only: bool) -> Result<()> {
+		if local_only {
+			self.localonlyroomids.insert(room_id.as_bytes(), &[])?;
+		} else {
+			self.localonlyroomids.remove(room_id.as_bytes())?;
+		}
+
+		Ok(())
+	}
 }
@@ -2,7 +2,7 @@ mod data;
 
 use std::sync::Arc;
 
-use data::Data;
+pub use data::Data;
 use ruma::{OwnedRoomId, RoomId};
 
 use crate::Result;
@@ -33,4 +33,12 @@ impl Service {
 	pub fn list_banned_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
 		self.db.list_banned_rooms()
 	}
+
+	/// Checks if a room has been marked local-only, in which case its events
+	/// are neither sent to nor accepted from other servers.
+	pub fn is_local_only(&self, room_id: &RoomId) -> Result<bool> { self.db.is_local_only(room_id) }
+
+	pub fn set_local_only(&self, room_id: &RoomId, local_only: bool) -> Result<()> {
+		self.db.set_local_only(room_id, local_only)
+	}
 }
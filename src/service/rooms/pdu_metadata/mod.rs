@@ -1,6 +1,6 @@
 mod data;
 
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 
 use data::Data;
 use ruma::{
@@ -9,8 +9,12 @@ use ruma::{
 	uint, EventId, RoomId, UInt, UserId,
 };
 use serde::Deserialize;
+use serde_json::value::to_raw_value;
 
-use crate::{services, PduCount, PduEvent, Result};
+use crate::{services, Error, PduCount, PduEvent, Result};
+
+/// Most relations of an event looked at, newest first
+const RELATIONS_LIMIT: usize = 1000;
 
 pub struct Service {
 	pub db: Arc<dyn Data>,
@@ -19,6 +23,7 @@ pub struct Service {
 #[derive(Clone, Debug, Deserialize)]
 struct ExtractRelType {
 	rel_type: RelationType,
+	key: Option<String>,
 }
 #[derive(Clone, Debug, Deserialize)]
 struct ExtractRelatesToEventId {
@@ -73,7 +78,8 @@ impl Service {
 
 		match dir {
 			Direction::Forward => {
-				let relations_until = &self.relations_until(sender_user, room_id, target, from, depth)?;
+				let relations_until =
+					&self.relations_until(sender_user, room_id, target, from, depth, RELATIONS_LIMIT)?;
 				let events_after: Vec<_> = relations_until // TODO: should be relations_after
                     .iter()
                     .filter(|(_, pdu)| {
@@ -119,7 +125,8 @@ impl Service {
 				})
 			},
 			Direction::Backward => {
-				let relations_until = &self.relations_until(sender_user, room_id, target, from, depth)?;
+				let relations_until =
+					&self.relations_until(sender_user, room_id, target, from, depth, RELATIONS_LIMIT)?;
 				let events_before: Vec<_> = relations_until
                     .iter()
                     .filter(|(_, pdu)| {
@@ -166,8 +173,12 @@ impl Service {
 		}
 	}
 
+	/// The relations of `target` before `until`, oldest first, following
+	/// relations of relations up to `max_depth`. At most `limit` relations are
+	/// collected, the newest direct ones first.
 	pub fn relations_until<'a>(
 		&'a self, user_id: &'a UserId, room_id: &'a RoomId, target: &'a EventId, until: PduCount, max_depth: u8,
+		limit: usize,
 	) -> Result<Vec<(PduCount, PduEvent)>> {
 		let room_id = services().rooms.short.get_or_create_shortroomid(room_id)?;
 		#[allow(unknown_lints)]
@@ -180,11 +191,15 @@ impl Service {
 
 		self.db
 			.relations_until(user_id, room_id, target, until)
-			.map(|mut relations| {
-				let mut pdus: Vec<_> = (*relations).into_iter().filter_map(Result::ok).collect();
+			.map(|relations| {
+				let mut pdus: Vec<_> = relations.filter_map(Result::ok).take(limit).collect();
 				let mut stack: Vec<_> = pdus.clone().iter().map(|pdu| (pdu.to_owned(), 1)).collect();
 
 				while let Some(stack_pdu) = stack.pop() {
+					if pdus.len() >= limit {
+						break;
+					}
+
 					let target = match stack_pdu.0 .0 {
 						PduCount::Normal(c) => c,
 						// TODO: Support backfilled relations
@@ -192,7 +207,7 @@ impl Service {
 					};
 
 					if let Ok(relations) = self.db.relations_until(user_id, room_id, target, until) {
-						for relation in relations.flatten() {
+						for relation in relations.flatten().take(limit.saturating_sub(pdus.len())) {
 							if stack_pdu.1 < max_depth {
 								stack.push((relation.clone(), stack_pdu.1 + 1));
 							}
@@ -207,6 +222,47 @@ impl Service {
 			})
 	}
 
+	/// Adds the server-side aggregations of `pdu` visible to `user_id` into its
+	/// `unsigned.m.relations`: the latest edit, reaction counts and any
+	/// references. Thread summaries are already stored on the thread root by
+	/// the threads service.
+	pub fn add_bundled_relations(&self, user_id: &UserId, pdu: &mut PduEvent) -> Result<()> {
+		let relations: Vec<_> = self
+			.relations_until(user_id, &pdu.room_id, &pdu.event_id, PduCount::max(), 1, RELATIONS_LIMIT)?
+			.into_iter()
+			.map(|(_, relation)| relation)
+			.filter(|relation| {
+				services()
+					.rooms
+					.state_accessor
+					.user_can_see_event(user_id, &relation.room_id, &relation.event_id)
+					.unwrap_or(false)
+			})
+			.collect();
+
+		let bundled = bundled_relations(pdu, &relations);
+		if bundled.is_empty() {
+			return Ok(());
+		}
+
+		let mut unsigned: BTreeMap<String, serde_json::Value> = pdu
+			.unsigned
+			.as_ref()
+			.map_or_else(|| Ok(BTreeMap::new()), |u| serde_json::from_str(u.get()))
+			.map_err(|_| Error::bad_database("Invalid unsigned in pdu event"))?;
+
+		let existing = unsigned
+			.entry("m.relations".to_owned())
+			.or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+		if let serde_json::Value::Object(existing) = existing {
+			existing.extend(bundled);
+		}
+
+		pdu.unsigned = Some(to_raw_value(&unsigned).expect("unsigned is valid"));
+
+		Ok(())
+	}
+
 	#[tracing::instrument(skip(self, room_id, event_ids))]
 	pub fn mark_as_referenced(&self, room_id: &RoomId, event_ids: &[Arc<EventId>]) -> Result<()> {
 		self.db.mark_as_referenced(room_id, event_ids)
@@ -223,3 +279,147 @@ impl Service {
 	#[tracing::instrument(skip(self))]
 	pub fn is_event_soft_failed(&self, event_id: &EventId) -> Result<bool> { self.db.is_event_soft_failed(event_id) }
 }
+
+/// Builds the `m.replace`, `m.annotation` and `m.reference` aggregations for
+/// `original` from its direct relations, which are sorted oldest first. Only
+/// edits by the original sender count as replacements, and annotations are
+/// counted per event type and key, most used first.
+fn bundled_relations(original: &PduEvent, relations: &[PduEvent]) -> serde_json::Map<String, serde_json::Value> {
+	let relates_to = |pdu: &PduEvent| {
+		serde_json::from_str::<ExtractRelatesToEventId>(pdu.content.get())
+			.ok()
+			.map(|content| content.relates_to)
+	};
+	let rel_type = |pdu: &PduEvent| relates_to(pdu).map(|relates_to| relates_to.rel_type);
+
+	let mut bundled = serde_json::Map::new();
+
+	let replacement = relations.iter().rev().find(|pdu| {
+		rel_type(pdu) == Some(RelationType::Replacement)
+			&& pdu.sender == original.sender
+			&& pdu.room_id == original.room_id
+			&& pdu.kind == original.kind
+	});
+	if let Some(replacement) = replacement {
+		bundled.insert(
+			RelationType::Replacement.to_string(),
+			serde_json::to_value(replacement.to_message_like_event()).expect("event is valid JSON"),
+		);
+	}
+
+	let mut annotations = BTreeMap::<(String, String), u64>::new();
+	for pdu in relations {
+		if let Some(ExtractRelType {
+			rel_type: RelationType::Annotation,
+			key: Some(key),
+		}) = relates_to(pdu)
+		{
+			let count = annotations.entry((pdu.kind.to_string(), key)).or_default();
+			*count = count.saturating_add(1);
+		}
+	}
+	if !annotations.is_empty() {
+		let mut chunk: Vec<_> = annotations.into_iter().collect();
+		chunk.sort_by(|(_, a), (_, b)| b.cmp(a));
+		let chunk: Vec<_> = chunk
+			.into_iter()
+			.map(|((kind, key), count)| serde_json::json!({ "type": kind, "key": key, "count": count }))
+			.collect();
+		bundled.insert(RelationType::Annotation.to_string(), serde_json::json!({ "chunk": chunk }));
+	}
+
+	let references: Vec<_> = relations
+		.iter()
+		.filter(|pdu| rel_type(pdu) == Some(RelationType::Reference))
+		.map(|pdu| serde_json::json!({ "event_id": pdu.event_id }))
+		.collect();
+	if !references.is_empty() {
+		bundled.insert(RelationType::Reference.to_string(), serde_json::json!({ "chunk": references }));
+	}
+
+	bundled
+}
+
+#[cfg(test)]
+mod tests {
+	use serde_json::json;
+
+	use super::bundled_relations;
+	use crate::PduEvent;
+
+	fn pdu(event_id: &str, sender: &str, content: serde_json::Value) -> PduEvent {
+		PduEvent::test_event(json!({ "event_id": event_id, "sender": sender, "content": content }))
+	}
+
+	fn edit(event_id: &str, sender: &str, body: &str) -> PduEvent {
+		pdu(
+			event_id,
+			sender,
+			json!({
+				"msgtype": "m.text",
+				"body": format!("* {body}"),
+				"m.new_content": { "msgtype": "m.text", "body": body },
+				"m.relates_to": { "rel_type": "m.replace", "event_id": "$original" },
+			}),
+		)
+	}
+
+	fn reaction(event_id: &str, sender: &str, key: &str) -> PduEvent {
+		PduEvent::test_event(json!({
+			"event_id": event_id,
+			"sender": sender,
+			"type": "m.reaction",
+			"content": { "m.relates_to": { "rel_type": "m.annotation", "event_id": "$original", "key": key } },
+		}))
+	}
+
+	#[test]
+	fn edited_event_bundles_latest_replacement() {
+		let original = pdu(
+			"$original",
+			"@alice:example.com",
+			json!({ "msgtype": "m.text", "body": "helo" }),
+		);
+		let relations = [
+			edit("$edit1", "@alice:example.com", "hello"),
+			edit("$edit2", "@alice:example.com", "hello!"),
+			edit("$hijack", "@mallory:example.com", "goodbye"),
+		];
+
+		let bundled = bundled_relations(&original, &relations);
+		assert_eq!(bundled["m.replace"]["event_id"], "$edit2");
+		assert_eq!(bundled["m.replace"]["content"]["m.new_content"]["body"], "hello!");
+		assert!(!bundled.contains_key("m.reference"));
+	}
+
+	#[test]
+	fn reactions_are_counted_per_key() {
+		let original = pdu("$original", "@alice:example.com", json!({ "msgtype": "m.text", "body": "hi" }));
+		let relations = [
+			reaction("$r1", "@bob:example.com", "👍"),
+			reaction("$r2", "@carol:example.com", "🎉"),
+			reaction("$r3", "@carol:example.com", "👍"),
+		];
+
+		let bundled = bundled_relations(&original, &relations);
+		assert_eq!(
+			bundled["m.annotation"]["chunk"],
+			json!([
+				{ "type": "m.reaction", "key": "👍", "count": 2 },
+				{ "type": "m.reaction", "key": "🎉", "count": 1 },
+			])
+		);
+	}
+
+	#[test]
+	fn unrelated_events_bundle_nothing() {
+		let original = pdu("$original", "@alice:example.com", json!({ "msgtype": "m.text", "body": "hi" }));
+		let reply = pdu(
+			"$reply",
+			"@bob:example.com",
+			json!({ "msgtype": "m.text", "body": "hey", "m.relates_to": { "m.in_reply_to": { "event_id": "$original" } } }),
+		);
+
+		assert!(bundled_relations(&original, &[reply]).is_empty());
+	}
+}
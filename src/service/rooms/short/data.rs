@@ -3,6 +3,7 @@ use std::sync::Arc;
 use ruma::{events::StateEventType, EventId, RoomId};
 use tracing::warn;
 
+use super::ShortEventIdIssue;
 use crate::{services, utils, Error, KeyValueDatabase, Result};
 
 pub trait Data: Send + Sync {
@@ -24,6 +25,15 @@ pub trait Data: Send + Sync {
 	fn get_shortroomid(&self, room_id: &RoomId) -> Result<Option<u64>>;
 
 	fn get_or_create_shortroomid(&self, room_id: &RoomId) -> Result<u64>;
+
+	/// Checks that event ID to short ID mappings exist in both directions and
+	/// agree. With `event_ids` only those events are checked, otherwise both
+	/// trees are scanned in full.
+	fn verify_shorteventids(&self, event_ids: Option<&[&EventId]>) -> Result<Vec<ShortEventIdIssue>>;
+
+	/// Restores the missing half of a mapping. Returns false for issues that
+	/// can't be repaired safely.
+	fn repair_shorteventid(&self, issue: &ShortEventIdIssue) -> Result<bool>;
 }
 
 impl Data for KeyValueDatabase {
@@ -182,4 +192,192 @@ impl Data for KeyValueDatabase {
 			short
 		})
 	}
+
+	fn verify_shorteventids(&self, event_ids: Option<&[&EventId]>) -> Result<Vec<ShortEventIdIssue>> {
+		let get_forward = |event_id: &[u8]| self.eventid_shorteventid.get(event_id);
+		let get_reverse = |shorteventid: &[u8]| self.shorteventid_eventid.get(shorteventid);
+
+		match event_ids {
+			Some(event_ids) => {
+				let forward = event_ids
+					.iter()
+					.map(|event_id| Ok((event_id.as_bytes().to_vec(), get_forward(event_id.as_bytes())?)))
+					.collect::<Result<Vec<_>>>()?;
+
+				shorteventid_issues(forward.into_iter(), std::iter::empty(), get_forward, get_reverse)
+			},
+			None => shorteventid_issues(
+				self.eventid_shorteventid
+					.iter()
+					.map(|(event_id, short)| (event_id, Some(short))),
+				self.shorteventid_eventid.iter(),
+				get_forward,
+				get_reverse,
+			),
+		}
+	}
+
+	fn repair_shorteventid(&self, issue: &ShortEventIdIssue) -> Result<bool> {
+		match issue {
+			ShortEventIdIssue::MissingReverse {
+				event_id,
+				shorteventid,
+			} => {
+				let key = shorteventid.to_be_bytes();
+				if self.shorteventid_eventid.get(&key)?.is_some() {
+					return Ok(false);
+				}
+
+				self.shorteventid_eventid
+					.insert(&key, event_id.as_bytes())?;
+			},
+			ShortEventIdIssue::MissingForward {
+				shorteventid,
+				event_id,
+			} => {
+				if self
+					.eventid_shorteventid
+					.get(event_id.as_bytes())?
+					.is_some()
+				{
+					return Ok(false);
+				}
+
+				self.eventid_shorteventid
+					.insert(event_id.as_bytes(), &shorteventid.to_be_bytes())?;
+			},
+			_ => return Ok(false),
+		}
+
+		Ok(true)
+	}
+}
+
+/// Checks each `(event_id, shorteventid)` in `forward` maps back to itself,
+/// and each `(shorteventid, event_id)` in `reverse` has a forward mapping.
+fn shorteventid_issues<F, R>(
+	forward: impl Iterator<Item = (Vec<u8>, Option<Vec<u8>>)>, reverse: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+	get_forward: F, get_reverse: R,
+) -> Result<Vec<ShortEventIdIssue>>
+where
+	F: Fn(&[u8]) -> Result<Option<Vec<u8>>>,
+	R: Fn(&[u8]) -> Result<Option<Vec<u8>>>,
+{
+	let lossy = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+	let mut issues = Vec::new();
+
+	for (event_id, short) in forward {
+		let Some(short) = short else {
+			issues.push(ShortEventIdIssue::MissingShortId {
+				event_id: lossy(&event_id),
+			});
+			continue;
+		};
+
+		let Ok(shorteventid) = utils::u64_from_bytes(&short) else {
+			issues.push(ShortEventIdIssue::InvalidShortId {
+				event_id: lossy(&event_id),
+			});
+			continue;
+		};
+
+		match get_reverse(&short)? {
+			None => issues.push(ShortEventIdIssue::MissingReverse {
+				event_id: lossy(&event_id),
+				shorteventid,
+			}),
+			Some(reverse_event_id) if reverse_event_id != event_id => issues.push(ShortEventIdIssue::Mismatch {
+				event_id: lossy(&event_id),
+				shorteventid,
+				reverse_event_id: lossy(&reverse_event_id),
+			}),
+			Some(_) => {},
+		}
+	}
+
+	for (short, event_id) in reverse {
+		let Ok(shorteventid) = utils::u64_from_bytes(&short) else {
+			continue;
+		};
+
+		if get_forward(&event_id)?.is_none() {
+			issues.push(ShortEventIdIssue::MissingForward {
+				shorteventid,
+				event_id: lossy(&event_id),
+			});
+		}
+	}
+
+	Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+
+	use super::{shorteventid_issues, ShortEventIdIssue};
+
+	fn trees(events: &[(&str, u64)]) -> (BTreeMap<Vec<u8>, Vec<u8>>, BTreeMap<Vec<u8>, Vec<u8>>) {
+		let forward = events
+			.iter()
+			.map(|(event_id, short)| (event_id.as_bytes().to_vec(), short.to_be_bytes().to_vec()))
+			.collect::<BTreeMap<_, _>>();
+		let reverse = forward
+			.iter()
+			.map(|(event_id, short)| (short.clone(), event_id.clone()))
+			.collect();
+
+		(forward, reverse)
+	}
+
+	fn issues(forward: &BTreeMap<Vec<u8>, Vec<u8>>, reverse: &BTreeMap<Vec<u8>, Vec<u8>>) -> Vec<ShortEventIdIssue> {
+		shorteventid_issues(
+			forward
+				.iter()
+				.map(|(event_id, short)| (event_id.clone(), Some(short.clone()))),
+			reverse
+				.iter()
+				.map(|(short, event_id)| (short.clone(), event_id.clone())),
+			|event_id| Ok(forward.get(event_id).cloned()),
+			|short| Ok(reverse.get(short).cloned()),
+		)
+		.unwrap()
+	}
+
+	#[test]
+	fn consistent_mappings_have_no_issues() {
+		let (forward, reverse) = trees(&[("$a", 1), ("$b", 2)]);
+		assert!(issues(&forward, &reverse).is_empty());
+	}
+
+	#[test]
+	fn injected_inconsistencies_are_detected() {
+		let (mut forward, mut reverse) = trees(&[("$a", 1), ("$b", 2), ("$c", 3)]);
+		reverse.remove(1_u64.to_be_bytes().as_slice());
+		forward.remove(b"$b".as_slice());
+		reverse.insert(3_u64.to_be_bytes().to_vec(), b"$other".to_vec());
+
+		assert_eq!(
+			issues(&forward, &reverse),
+			vec![
+				ShortEventIdIssue::MissingReverse {
+					event_id: "$a".to_owned(),
+					shorteventid: 1,
+				},
+				ShortEventIdIssue::Mismatch {
+					event_id: "$c".to_owned(),
+					shorteventid: 3,
+					reverse_event_id: "$other".to_owned(),
+				},
+				ShortEventIdIssue::MissingForward {
+					shorteventid: 2,
+					event_id: "$b".to_owned(),
+				},
+				ShortEventIdIssue::MissingForward {
+					shorteventid: 3,
+					event_id: "$other".to_owned(),
+				},
+			]
+		);
+	}
 }
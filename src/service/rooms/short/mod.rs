@@ -1,10 +1,40 @@
 mod data;
-use std::sync::Arc;
+use std::{fmt, sync::Arc};
 
 use data::Data;
 use ruma::{events::StateEventType, EventId, RoomId};
 
-use crate::Result;
+use crate::{services, Result};
+
+/// An inconsistency between the `eventid_shorteventid` and
+/// `shorteventid_eventid` mappings.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ShortEventIdIssue {
+	/// An event in the room has no short ID
+	MissingShortId {
+		event_id: String,
+	},
+	/// The short ID stored for an event is malformed
+	InvalidShortId {
+		event_id: String,
+	},
+	/// An event's short ID doesn't map back to any event
+	MissingReverse {
+		event_id: String,
+		shorteventid: u64,
+	},
+	/// A short ID maps to an event which doesn't map to any short ID
+	MissingForward {
+		shorteventid: u64,
+		event_id: String,
+	},
+	/// An event's short ID maps back to a different event
+	Mismatch {
+		event_id: String,
+		shorteventid: u64,
+		reverse_event_id: String,
+	},
+}
 
 pub struct Service {
 	pub db: Arc<dyn Data>,
@@ -45,4 +75,56 @@ impl Service {
 	pub fn get_or_create_shortroomid(&self, room_id: &RoomId) -> Result<u64> {
 		self.db.get_or_create_shortroomid(room_id)
 	}
+
+	/// Checks the short event ID mappings of every event in `room_id`, or of
+	/// every event we know of.
+	pub fn verify_shorteventids(&self, room_id: Option<&RoomId>) -> Result<Vec<ShortEventIdIssue>> {
+		let Some(room_id) = room_id else {
+			return self.db.verify_shorteventids(None);
+		};
+
+		let event_ids = services()
+			.rooms
+			.timeline
+			.all_pdus(&services().globals.server_user, room_id)?
+			.filter_map(Result::ok)
+			.map(|(_, pdu)| pdu.event_id)
+			.collect::<Vec<_>>();
+		let event_ids = event_ids
+			.iter()
+			.map(|event_id| &**event_id)
+			.collect::<Vec<_>>();
+
+		self.db.verify_shorteventids(Some(&event_ids))
+	}
+
+	pub fn repair_shorteventid(&self, issue: &ShortEventIdIssue) -> Result<bool> {
+		self.db.repair_shorteventid(issue)
+	}
+}
+
+impl fmt::Display for ShortEventIdIssue {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::MissingShortId {
+				event_id,
+			} => write!(f, "{event_id} has no short ID"),
+			Self::InvalidShortId {
+				event_id,
+			} => write!(f, "{event_id} has a malformed short ID"),
+			Self::MissingReverse {
+				event_id,
+				shorteventid,
+			} => write!(f, "{event_id} has short ID {shorteventid} which doesn't map back to it"),
+			Self::MissingForward {
+				shorteventid,
+				event_id,
+			} => write!(f, "short ID {shorteventid} maps to {event_id} which has no short ID"),
+			Self::Mismatch {
+				event_id,
+				shorteventid,
+				reverse_event_id,
+			} => write!(f, "{event_id} has short ID {shorteventid} which maps to {reverse_event_id}"),
+		}
+	}
 }
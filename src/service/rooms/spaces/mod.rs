@@ -1,4 +1,5 @@
 use std::{
+	collections::{HashSet, VecDeque},
 	fmt::{Display, Formatter},
 	str::FromStr,
 };
@@ -132,8 +133,9 @@ impl Arena {
 		}
 	}
 
-	/// Adds all the given nodes as children of the parent node
-	fn push(&mut self, parent: NodeId, mut children: Vec<(OwnedRoomId, Vec<OwnedServerName>)>) {
+	/// Adds all the given nodes as children of the parent node. Returns false
+	/// if they were left out for being beyond `max_depth`.
+	fn push(&mut self, parent: NodeId, mut children: Vec<(OwnedRoomId, Vec<OwnedServerName>)>) -> bool {
 		if children.is_empty() {
 			self.traverse(parent);
 		} else if self.nodes.get(parent.index).is_some() {
@@ -159,7 +161,7 @@ impl Arena {
 
 			// If at max_depth, don't add new rooms
 			if self.max_depth < parents.len() {
-				return;
+				return false;
 			}
 
 			children.reverse();
@@ -203,6 +205,8 @@ impl Arena {
 
 			node.first_child = next_id;
 		}
+
+		true
 	}
 
 	fn new(root: OwnedRoomId, max_depth: usize) -> Self {
@@ -345,7 +349,13 @@ impl Service {
 				let mut children = Vec::new();
 				let mut inaccessible_children = Vec::new();
 
+				let mut seen = HashSet::from([room.room_id.clone()]);
 				for (child, _via) in get_parent_children_via(&room, suggested_only) {
+					// a space listing itself or the same child twice isn't walked again
+					if !seen.insert(child.clone()) {
+						continue;
+					}
+
 					match self
 						.get_summary_and_children_local(&child, Identifier::ServerName(server_name))
 						.await?
@@ -657,7 +667,9 @@ impl Service {
 					.first_untraversed()
 					.expect("The node just added is not traversed");
 
-				arena.push(root, get_parent_children_via(&summary, suggested_only));
+				// a room reachable through several subspaces is only walked once
+				let mut visited = HashSet::from([summary.room_id.clone()]);
+				let mut partial = !arena.push(root, get_parent_children_via(&summary, suggested_only));
 				if left_to_skip > 0 {
 					left_to_skip -= 1;
 				} else {
@@ -669,12 +681,16 @@ impl Service {
 						let node = arena
 							.get(current_room)
 							.expect("We added this node, it must exist");
+						if !visited.insert(node.room_id.clone()) {
+							continue;
+						}
+
 						if let Some(SummaryAccessibility::Accessible(summary)) = self
 							.get_summary_and_children_client(&node.room_id, suggested_only, sender_user, &node.via)
 							.await?
 						{
 							let children = get_parent_children_via(&summary, suggested_only);
-							arena.push(current_room, children);
+							partial |= !arena.push(current_room, children);
 
 							if left_to_skip > 0 {
 								left_to_skip -= 1;
@@ -687,6 +703,10 @@ impl Service {
 					}
 				}
 
+				if partial {
+					debug_info!("Space hierarchy of {room_id} is partial, rooms deeper than {max_depth} were left out");
+				}
+
 				Ok(client::space::get_hierarchy::v1::Response {
 					next_batch: if results.len() < limit {
 						None
@@ -762,73 +782,98 @@ fn is_accessable_child(
 	current_room: &OwnedRoomId, join_rule: &SpaceRoomJoinRule, identifier: &Identifier<'_>,
 	allowed_room_ids: &Vec<OwnedRoomId>,
 ) -> Result<bool, Error> {
-	is_accessable_child_recurse(current_room, join_rule, identifier, allowed_room_ids, 0)
-}
+	let max_depth = services().globals.config.federation_hierarchy_max_depth;
+
+	walk_room_access(current_room, max_depth, |room| {
+		// the room being checked uses the join rule we were given, which may come from
+		// a remote summary; rooms it defers to are looked up locally
+		let (join_rule, allowed_room_ids) = if room == current_room {
+			(join_rule.clone(), allowed_room_ids.clone())
+		} else if let Ok(join_rule) = get_join_rule(room) {
+			join_rule
+		} else {
+			return Ok(RoomAccess::Denied);
+		};
 
-fn is_accessable_child_recurse(
-	current_room: &OwnedRoomId, join_rule: &SpaceRoomJoinRule, identifier: &Identifier<'_>,
-	allowed_room_ids: &Vec<OwnedRoomId>, recurse_num: usize,
-) -> Result<bool, Error> {
-	// Set limit at 10, as we cannot keep going up parents forever
-	// and it is very unlikely to have 10 space parents
-	if recurse_num < 10 {
 		match identifier {
 			Identifier::ServerName(server_name) => {
-				let room_id: &RoomId = current_room;
-
 				// Checks if ACLs allow for the server to participate
 				if services()
 					.rooms
 					.event_handler
-					.acl_check(server_name, room_id)
+					.acl_check(server_name, room)
 					.is_err()
 				{
-					return Ok(false);
+					return Ok(RoomAccess::Denied);
 				}
 			},
 			Identifier::UserId(user_id) => {
-				if services()
-					.rooms
-					.state_cache
-					.is_joined(user_id, current_room)?
-					|| services()
-						.rooms
-						.state_cache
-						.is_invited(user_id, current_room)?
+				if services().rooms.state_cache.is_joined(user_id, room)?
+					|| services().rooms.state_cache.is_invited(user_id, room)?
 				{
-					return Ok(true);
+					return Ok(RoomAccess::Granted);
 				}
 			},
 			Identifier::None => (),
 		} // Takes care of joinrules
+
 		Ok(match join_rule {
-			SpaceRoomJoinRule::Restricted => {
-				for room in allowed_room_ids {
-					if let Ok((join_rule, allowed_room_ids)) = get_join_rule(room) {
-						if matches!(
-							is_accessable_child_recurse(
-								room,
-								&join_rule,
-								identifier,
-								&allowed_room_ids,
-								recurse_num + 1,
-							),
-							Ok(true)
-						) {
-							return Ok(true);
-						}
-					}
-				}
-				false
+			SpaceRoomJoinRule::Restricted => RoomAccess::Restricted(allowed_room_ids),
+			SpaceRoomJoinRule::Public | SpaceRoomJoinRule::Knock | SpaceRoomJoinRule::KnockRestricted => {
+				RoomAccess::Granted
 			},
-			SpaceRoomJoinRule::Public | SpaceRoomJoinRule::Knock | SpaceRoomJoinRule::KnockRestricted => true,
 			// Custom join rules, Invite, or Private
-			_ => false,
+			_ => RoomAccess::Denied,
 		})
-	} else {
-		// If you need to go up 10 parents, we just assume it is inaccessable
-		Ok(false)
+	})
+}
+
+/// Whether a single room grants access on its own, or defers to the rooms in
+/// its restricted join rule.
+enum RoomAccess {
+	Granted,
+	Denied,
+	Restricted(Vec<OwnedRoomId>),
+}
+
+/// Walks from `room` through restricted join rules until some room grants
+/// access. Each room is visited at most once, so cycles between restricted
+/// rooms terminate, and rooms `max_depth` or more steps away are not visited;
+/// hitting the cap is treated as inaccessible.
+fn walk_room_access<F>(room: &OwnedRoomId, max_depth: usize, mut access: F) -> Result<bool>
+where
+	F: FnMut(&OwnedRoomId) -> Result<RoomAccess>,
+{
+	let mut visited = HashSet::new();
+	let mut todo = VecDeque::from([(room.clone(), 0_usize)]);
+
+	while let Some((room, depth)) = todo.pop_front() {
+		if depth >= max_depth {
+			debug!(
+				?room,
+				max_depth, "Reached federation_hierarchy_max_depth, assuming inaccessible"
+			);
+			continue;
+		}
+
+		if !visited.insert(room.clone()) {
+			continue;
+		}
+
+		match access(&room)? {
+			RoomAccess::Granted => return Ok(true),
+			RoomAccess::Denied => {},
+			RoomAccess::Restricted(allowed_room_ids) => {
+				todo.extend(
+					allowed_room_ids
+						.into_iter()
+						.map(|allowed| (allowed, depth.saturating_add(1))),
+				);
+			},
+		}
 	}
+
+	Ok(false)
 }
 
 /// Returns the join rule for a given room
@@ -1017,7 +1062,8 @@ mod tests {
 			index: 0,
 		};
 
-		arena.push(root, vec![(owned_room_id!("!too_deep:example.org"), vec![])]);
+		// reported so the hierarchy can be noted as partial
+		assert!(!arena.push(root, vec![(owned_room_id!("!too_deep:example.org"), vec![])]));
 
 		assert_eq!(arena.first_child(root), None);
 		assert_eq!(arena.nodes.len(), 1);
@@ -1280,4 +1326,42 @@ mod tests {
 		first(&mut arena, &owned_room_id!("!subspace2:example.org"));
 		assert!(arena.first_untraversed().is_none());
 	}
+
+	#[test]
+	fn cyclic_restricted_rooms_terminate() {
+		let (a, b) = (owned_room_id!("!a:example.com"), owned_room_id!("!b:example.com"));
+		let mut checked = Vec::new();
+
+		let accessible = walk_room_access(&a, 10, |room| {
+			checked.push(room.clone());
+			Ok(if *room == a {
+				RoomAccess::Restricted(vec![b.clone(), a.clone()])
+			} else {
+				RoomAccess::Restricted(vec![a.clone()])
+			})
+		})
+		.unwrap();
+
+		assert!(!accessible);
+		assert_eq!(checked, [a.clone(), b.clone()]);
+	}
+
+	#[test]
+	fn deeply_nested_restricted_rooms_stop_at_max_depth() {
+		let room = |n: usize| OwnedRoomId::try_from(format!("!{n}:example.com")).unwrap();
+		let nested = |granted_at: usize| {
+			move |current: &OwnedRoomId| {
+				let (n, _) = current.as_str()[1..].split_once(':').unwrap();
+				let n: usize = n.parse().unwrap();
+				Ok(if n == granted_at {
+					RoomAccess::Granted
+				} else {
+					RoomAccess::Restricted(vec![room(n + 1)])
+				})
+			}
+		};
+
+		assert!(walk_room_access(&room(0), 10, nested(5)).unwrap());
+		assert!(!walk_room_access(&room(0), 10, nested(1_000)).unwrap());
+	}
 }
@@ -12,17 +12,19 @@ use ruma::{
 		room::{
 			avatar::RoomAvatarEventContent,
 			canonical_alias::RoomCanonicalAliasEventContent,
+			create::RoomCreateEventContent,
 			guest_access::{GuestAccess, RoomGuestAccessEventContent},
 			history_visibility::{HistoryVisibility, RoomHistoryVisibilityEventContent},
 			member::{MembershipState, RoomMemberEventContent},
 			name::RoomNameEventContent,
+			power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
 			topic::RoomTopicEventContent,
 		},
 		StateEventType,
 	},
 	EventId, OwnedRoomAliasId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
-use serde_json::value::to_raw_value;
+use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
 use tracing::{error, warn};
 
 use crate::{service::pdu::PduBuilder, services, Error, PduEvent, Result};
@@ -215,23 +217,9 @@ impl Service {
 	#[tracing::instrument(skip(self, user_id, room_id))]
 	pub fn user_can_see_state_events(&self, user_id: &UserId, room_id: &RoomId) -> Result<bool> {
 		let currently_member = services().rooms.state_cache.is_joined(user_id, room_id)?;
+		let history_visibility = self.room_state_get(room_id, &StateEventType::RoomHistoryVisibility, "")?;
 
-		let history_visibility = self
-			.room_state_get(room_id, &StateEventType::RoomHistoryVisibility, "")?
-			.map_or(Ok(HistoryVisibility::Shared), |s| {
-				serde_json::from_str(s.content.get())
-					.map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
-					.map_err(|e| {
-						error!(
-							"Invalid history visibility event in database for room {room_id}, assuming is \"shared\": \
-							 {e}"
-						);
-						Error::bad_database("Invalid history visibility event in database.")
-					})
-			})
-			.unwrap_or(HistoryVisibility::Shared);
-
-		Ok(currently_member || history_visibility == HistoryVisibility::WorldReadable)
+		Ok(state_visible_to(currently_member, history_visibility.as_deref()))
 	}
 
 	/// Returns the state hash for this pdu.
@@ -305,6 +293,47 @@ impl Service {
 			.is_ok())
 	}
 
+	/// Whether `sender` may redact `redacts` in the room. Redacting events of
+	/// other users also needs the `redact` power level; without a power levels
+	/// event only the room creator may do so.
+	pub fn user_can_redact(&self, redacts: &EventId, sender: &UserId, room_id: &RoomId) -> Result<bool> {
+		let target_sender = services()
+			.rooms
+			.timeline
+			.get_pdu(redacts)?
+			.filter(|pdu| pdu.room_id == room_id)
+			.map(|pdu| pdu.sender.clone());
+
+		if let Some(event) = self.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")? {
+			serde_json::from_str(event.content.get())
+				.map_err(|_| Error::bad_database("Invalid event content for m.room.power_levels"))
+				.map(|content: RoomPowerLevelsEventContent| {
+					redaction_allowed(&content.into(), sender, target_sender.as_deref())
+				})
+		} else if let Some(event) = self.room_state_get(room_id, &StateEventType::RoomCreate, "")? {
+			Ok(target_sender.as_deref() == Some(sender) || event.sender == sender)
+		} else {
+			Err(Error::bad_database("Room has no m.room.create event"))
+		}
+	}
+
+	/// Whether `user_id` may vouch for a restricted join through
+	/// `join_authorised_via_users_server`: they must be joined to the room and
+	/// have the power to invite, as the restricted join auth rules require.
+	pub fn user_can_authorise_join(&self, room_id: &RoomId, user_id: &UserId) -> Result<bool> {
+		let joined = services().rooms.state_cache.is_joined(user_id, room_id)?;
+		let power_levels: RoomPowerLevelsEventContent = self
+			.room_state_get(room_id, &StateEventType::RoomPowerLevels, "")?
+			.map(|event| {
+				serde_json::from_str(event.content.get())
+					.map_err(|_| Error::bad_database("Invalid event content for m.room.power_levels"))
+			})
+			.transpose()?
+			.unwrap_or_default();
+
+		Ok(join_authorisation_allowed(joined, &power_levels.into(), user_id))
+	}
+
 	/// Checks if guests are able to view room content without joining
 	pub fn is_world_readable(&self, room_id: &RoomId) -> Result<bool, Error> {
 		self.room_state_get(room_id, &StateEventType::RoomHistoryVisibility, "")?
@@ -327,8 +356,7 @@ impl Service {
 	pub fn guest_can_join(&self, room_id: &RoomId) -> Result<bool, Error> {
 		self.room_state_get(room_id, &StateEventType::RoomGuestAccess, "")?
 			.map_or(Ok(false), |s| {
-				serde_json::from_str(s.content.get())
-					.map(|c: RoomGuestAccessEventContent| c.guest_access == GuestAccess::CanJoin)
+				guest_access_allows_join(&s.content)
 					.map_err(|_| Error::bad_database("Invalid room guest access event in database."))
 			})
 	}
@@ -355,4 +383,177 @@ impl Service {
 					})
 			})
 	}
+
+	/// Checks the `m.federate` flag of the room's create event. Rooms without
+	/// the flag are federated.
+	pub fn is_federated(&self, room_id: &RoomId) -> Result<bool, Error> {
+		create_event_federates(
+			self.room_state_get(room_id, &StateEventType::RoomCreate, "")?
+				.as_deref(),
+		)
+	}
+}
+
+/// Checks the `m.federate` flag of a room's create event, if it has one.
+pub fn create_event_federates(create_event: Option<&PduEvent>) -> Result<bool, Error> {
+	create_event.map_or(Ok(true), |s| {
+		create_content_federates(&s.content).map_err(|e| {
+			error!("Invalid room create event in database for room {}: {e}", s.room_id);
+			Error::bad_database("Invalid room create event in database.")
+		})
+	})
+}
+
+/// Reads `m.federate` from `m.room.create` content; it defaults to true.
+fn create_content_federates(content: &RawJsonValue) -> serde_json::Result<bool> {
+	serde_json::from_str(content.get()).map(|c: RoomCreateEventContent| c.federate)
+}
+
+/// Reads `m.room.guest_access` content; only `can_join` lets guests join.
+fn guest_access_allows_join(content: &RawJsonValue) -> serde_json::Result<bool> {
+	serde_json::from_str(content.get()).map(|c: RoomGuestAccessEventContent| c.guest_access == GuestAccess::CanJoin)
+}
+
+/// Checks the sender's power level for redacting an event sent by
+/// `target_sender`; an unknown target is treated as another user's event.
+fn redaction_allowed(power_levels: &RoomPowerLevels, sender: &UserId, target_sender: Option<&UserId>) -> bool {
+	if target_sender == Some(sender) {
+		power_levels.user_can_redact_own_event(sender)
+	} else {
+		power_levels.user_can_redact_event_of_other(sender)
+	}
+}
+
+/// Joined members can always read the current state; everyone else only when
+/// the room's `m.room.history_visibility` event makes it `world_readable`.
+/// Rooms without a valid one are `shared`.
+fn state_visible_to(currently_member: bool, history_visibility: Option<&PduEvent>) -> bool {
+	let history_visibility = history_visibility.map_or(HistoryVisibility::Shared, |s| {
+		serde_json::from_str(s.content.get())
+			.map(|c: RoomHistoryVisibilityEventContent| c.history_visibility)
+			.unwrap_or_else(|e| {
+				error!(
+					"Invalid history visibility event in database for room {}, assuming is \"shared\": {e}",
+					s.room_id
+				);
+				HistoryVisibility::Shared
+			})
+	});
+
+	currently_member || history_visibility == HistoryVisibility::WorldReadable
+}
+
+fn join_authorisation_allowed(joined: bool, power_levels: &RoomPowerLevels, user_id: &UserId) -> bool {
+	joined && power_levels.user_can_invite(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		events::room::{
+			create::RoomCreateEventContent,
+			power_levels::{RoomPowerLevels, RoomPowerLevelsEventContent},
+		},
+		int, user_id,
+	};
+	use serde_json::{
+		json,
+		value::{to_raw_value, RawValue as RawJsonValue},
+	};
+
+	use super::{
+		create_content_federates, guest_access_allows_join, join_authorisation_allowed, redaction_allowed,
+		state_visible_to,
+	};
+	use crate::PduEvent;
+
+	#[test]
+	fn create_content_federates_by_default() {
+		let content = RawJsonValue::from_string(r#"{"room_version":"10"}"#.to_owned()).unwrap();
+		assert!(create_content_federates(&content).unwrap());
+	}
+
+	#[test]
+	fn non_federated_room_is_detected() {
+		let mut content = RoomCreateEventContent::new_v11();
+		content.federate = false;
+		let content = to_raw_value(&content).unwrap();
+		assert!(!create_content_federates(&content).unwrap());
+	}
+
+	#[test]
+	fn guest_access_forbidden_room_rejects_guests() {
+		let content = RawJsonValue::from_string(r#"{"guest_access":"forbidden"}"#.to_owned()).unwrap();
+		assert!(!guest_access_allows_join(&content).unwrap());
+	}
+
+	#[test]
+	fn guest_access_can_join_room_admits_guests() {
+		let content = RawJsonValue::from_string(r#"{"guest_access":"can_join"}"#.to_owned()).unwrap();
+		assert!(guest_access_allows_join(&content).unwrap());
+	}
+
+	fn history_visibility(content: serde_json::Value) -> PduEvent {
+		PduEvent::test_event(json!({
+			"type": "m.room.history_visibility",
+			"state_key": "",
+			"content": content,
+		}))
+	}
+
+	#[test]
+	fn non_member_can_read_world_readable_state() {
+		let world_readable = history_visibility(json!({ "history_visibility": "world_readable" }));
+		assert!(state_visible_to(false, Some(&world_readable)));
+	}
+
+	#[test]
+	fn non_member_cannot_read_private_state() {
+		for visibility in ["shared", "invited", "joined"] {
+			let private = history_visibility(json!({ "history_visibility": visibility }));
+			assert!(!state_visible_to(false, Some(&private)), "{visibility}");
+			assert!(state_visible_to(true, Some(&private)), "{visibility}");
+		}
+
+		// rooms without a valid history visibility are shared
+		assert!(!state_visible_to(false, None));
+		let invalid = history_visibility(json!({ "history_visibility": 1 }));
+		assert!(!state_visible_to(false, Some(&invalid)));
+	}
+
+	fn power_levels() -> RoomPowerLevels {
+		let mut content = RoomPowerLevelsEventContent::new();
+		content
+			.users
+			.insert(user_id!("@mod:example.com").to_owned(), int!(50));
+		content.into()
+	}
+
+	#[test]
+	fn moderator_can_redact_others() {
+		let (moderator, user) = (user_id!("@mod:example.com"), user_id!("@user:example.com"));
+		assert!(redaction_allowed(&power_levels(), moderator, Some(user)));
+	}
+
+	#[test]
+	fn regular_user_cannot_redact_others() {
+		let (moderator, user) = (user_id!("@mod:example.com"), user_id!("@user:example.com"));
+		assert!(!redaction_allowed(&power_levels(), user, Some(moderator)));
+		assert!(!redaction_allowed(&power_levels(), user, None));
+		assert!(redaction_allowed(&power_levels(), user, Some(user)));
+	}
+
+	#[test]
+	fn join_authorised_by_user_without_invite_power_is_rejected() {
+		let mut content = RoomPowerLevelsEventContent::new();
+		content.invite = int!(50);
+		content
+			.users
+			.insert(user_id!("@mod:example.com").to_owned(), int!(50));
+		let power_levels: RoomPowerLevels = content.into();
+
+		assert!(!join_authorisation_allowed(true, &power_levels, user_id!("@user:example.com")));
+		assert!(!join_authorisation_allowed(false, &power_levels, user_id!("@mod:example.com")));
+		assert!(join_authorisation_allowed(true, &power_levels, user_id!("@mod:example.com")));
+	}
 }
@@ -1,4 +1,7 @@
-use std::collections::HashSet;
+use std::{
+	collections::{HashMap, HashSet},
+	sync::RwLock,
+};
 
 use itertools::Itertools;
 use ruma::{
@@ -16,6 +19,7 @@ use crate::{
 
 type StrippedStateEventIter<'a> = Box<dyn Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnyStrippedStateEvent>>)>> + 'a>;
 type AnySyncStateEventIter<'a> = Box<dyn Iterator<Item = Result<(OwnedRoomId, Vec<Raw<AnySyncStateEvent>>)>> + 'a>;
+type AppserviceInRoomCache = RwLock<HashMap<OwnedRoomId, HashMap<String, bool>>>;
 
 pub trait Data: Send + Sync {
 	fn mark_as_once_joined(&self, user_id: &UserId, room_id: &RoomId) -> Result<()>;
@@ -28,8 +32,14 @@ pub trait Data: Send + Sync {
 
 	fn update_joined_count(&self, room_id: &RoomId) -> Result<()>;
 
+	/// Overwrites the cached joined and invited member counts of a room.
+	fn set_member_counts(&self, room_id: &RoomId, joined: u64, invited: u64) -> Result<()>;
+
 	fn appservice_in_room(&self, room_id: &RoomId, appservice: &RegistrationInfo) -> Result<bool>;
 
+	/// Drops every cached appservice room membership answer.
+	fn clear_appservice_in_room_cache(&self);
+
 	/// Makes a user forget a room.
 	fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()>;
 
@@ -206,6 +216,13 @@ impl Data for KeyValueDatabase {
 		Ok(())
 	}
 
+	fn set_member_counts(&self, room_id: &RoomId, joined: u64, invited: u64) -> Result<()> {
+		self.roomid_joinedcount
+			.insert(room_id.as_bytes(), &joined.to_be_bytes())?;
+		self.roomid_invitedcount
+			.insert(room_id.as_bytes(), &invited.to_be_bytes())
+	}
+
 	fn update_joined_count(&self, room_id: &RoomId) -> Result<()> {
 		let mut joinedcount = 0_u64;
 		let mut invitedcount = 0_u64;
@@ -256,49 +273,34 @@ impl Data for KeyValueDatabase {
 			self.serverroomids.insert(&serverroom_id, &[])?;
 		}
 
-		self.appservice_in_room_cache
-			.write()
-			.unwrap()
-			.remove(room_id);
+		forget_appservices_in_room(&self.appservice_in_room_cache, room_id);
 
 		Ok(())
 	}
 
 	#[tracing::instrument(skip(self, room_id, appservice))]
 	fn appservice_in_room(&self, room_id: &RoomId, appservice: &RegistrationInfo) -> Result<bool> {
-		let maybe = self
-			.appservice_in_room_cache
-			.read()
-			.unwrap()
-			.get(room_id)
-			.and_then(|map| map.get(&appservice.registration.id))
-			.copied();
-
-		if let Some(b) = maybe {
-			Ok(b)
-		} else {
-			let bridge_user_id = UserId::parse_with_server_name(
-				appservice.registration.sender_localpart.as_str(),
-				services().globals.server_name(),
-			)
-			.ok();
-
-			let in_room = bridge_user_id.map_or(false, |id| self.is_joined(&id, room_id).unwrap_or(false))
-				|| self
-					.room_members(room_id)
-					.any(|userid| userid.map_or(false, |userid| appservice.users.is_match(userid.as_str())));
-
-			self.appservice_in_room_cache
-				.write()
-				.unwrap()
-				.entry(room_id.to_owned())
-				.or_default()
-				.insert(appservice.registration.id.clone(), in_room);
-
-			Ok(in_room)
-		}
+		Ok(cached_appservice_in_room(
+			&self.appservice_in_room_cache,
+			room_id,
+			&appservice.registration.id,
+			|| {
+				let bridge_user_id = UserId::parse_with_server_name(
+					appservice.registration.sender_localpart.as_str(),
+					services().globals.server_name(),
+				)
+				.ok();
+
+				bridge_user_id.map_or(false, |id| self.is_joined(&id, room_id).unwrap_or(false))
+					|| self
+						.room_members(room_id)
+						.any(|userid| userid.map_or(false, |userid| appservice.users.is_match(userid.as_str())))
+			},
+		))
 	}
 
+	fn clear_appservice_in_room_cache(&self) { self.appservice_in_room_cache.write().unwrap().clear(); }
+
 	/// Makes a user forget a room.
 	#[tracing::instrument(skip(self))]
 	fn forget(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
@@ -686,3 +688,64 @@ impl Data for KeyValueDatabase {
 		Ok(())
 	}
 }
+
+/// Whether the appservice `registration_id` is in the room, as cached or as
+/// worked out by `in_room` and then cached.
+fn cached_appservice_in_room(
+	cache: &AppserviceInRoomCache, room_id: &RoomId, registration_id: &str, in_room: impl FnOnce() -> bool,
+) -> bool {
+	let maybe = cache
+		.read()
+		.unwrap()
+		.get(room_id)
+		.and_then(|map| map.get(registration_id))
+		.copied();
+
+	maybe.unwrap_or_else(|| {
+		let in_room = in_room();
+		cache
+			.write()
+			.unwrap()
+			.entry(room_id.to_owned())
+			.or_default()
+			.insert(registration_id.to_owned(), in_room);
+
+		in_room
+	})
+}
+
+/// Drops the cached answers for a room once its membership changes.
+fn forget_appservices_in_room(cache: &AppserviceInRoomCache, room_id: &RoomId) {
+	cache.write().unwrap().remove(room_id);
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, sync::RwLock};
+
+	use ruma::{room_id, user_id};
+
+	use super::{cached_appservice_in_room, forget_appservices_in_room};
+
+	#[test]
+	fn leaving_a_room_removes_the_user_from_the_cache() {
+		let cache = RwLock::default();
+		let room_id = room_id!("!room:example.com");
+		let members = RefCell::new(vec![user_id!("@bridge_alice:example.com")]);
+		let in_room = || {
+			members
+				.borrow()
+				.iter()
+				.any(|member| member.localpart().starts_with("bridge_"))
+		};
+
+		assert!(cached_appservice_in_room(&cache, room_id, "bridge", in_room));
+
+		// what update_joined_count does after every membership change
+		members.borrow_mut().clear();
+		forget_appservices_in_room(&cache, room_id);
+
+		assert!(!cached_appservice_in_room(&cache, room_id, "bridge", in_room));
+		assert!(!cache.read().unwrap()[room_id]["bridge"]);
+	}
+}
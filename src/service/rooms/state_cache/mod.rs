@@ -4,7 +4,7 @@ use data::Data;
 use itertools::Itertools;
 use ruma::{
 	events::{
-		direct::DirectEvent,
+		direct::{DirectEvent, DirectEventContent},
 		ignored_user_list::IgnoredUserListEvent,
 		room::{
 			create::RoomCreateEventContent,
@@ -17,12 +17,30 @@ use ruma::{
 	serde::Raw,
 	OwnedRoomId, OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
+use serde::Deserialize;
+use serde_json::value::RawValue as RawJsonValue;
 use tracing::{error, warn};
 
-use crate::{service::appservice::RegistrationInfo, services, user_is_local, Error, Result};
+use crate::{service::appservice::RegistrationInfo, services, user_is_local, Error, PduEvent, Result};
 
 mod data;
 
+/// Global account data event in which users opt out of invites from users they
+/// don't share a room with.
+pub const INVITE_PERMISSION_EVENT_TYPE: &str = "im.conduwuit.invite_permission_config";
+
+#[derive(Default, Deserialize)]
+struct InvitePermissionEvent {
+	#[serde(default)]
+	content: InvitePermissionConfig,
+}
+
+#[derive(Default, Deserialize)]
+struct InvitePermissionConfig {
+	#[serde(default)]
+	shared_rooms_only: bool,
+}
+
 pub struct Service {
 	pub db: Arc<dyn Data>,
 }
@@ -161,6 +179,10 @@ impl Service {
 					}
 				}
 
+				if user_is_local(user_id) && services().globals.config.mark_accepted_direct_invites {
+					self.mark_accepted_direct_invite(room_id, user_id)?;
+				}
+
 				self.db.mark_as_joined(user_id, room_id)?;
 			},
 			MembershipState::Invite => {
@@ -209,14 +231,113 @@ impl Service {
 		Ok(())
 	}
 
+	/// Adds the room to the user's `m.direct` under the inviter if the
+	/// membership they are joining from is an invite with `is_direct` set. Must
+	/// run before the join is in the room state and the invite is forgotten.
+	fn mark_accepted_direct_invite(&self, room_id: &RoomId, user_id: &UserId) -> Result<()> {
+		let invite =
+			services()
+				.rooms
+				.state_accessor
+				.room_state_get(room_id, &StateEventType::RoomMember, user_id.as_str())?;
+		let invite_state = self.invite_state(user_id, room_id)?;
+		let Some(inviter) = direct_inviter(user_id, invite.as_deref(), invite_state.as_deref()) else {
+			return Ok(());
+		};
+
+		let mut direct_event = services()
+			.account_data
+			.get(None, user_id, GlobalAccountDataEventType::Direct.to_string().into())?
+			.map(|event| {
+				serde_json::from_str::<DirectEvent>(event.get()).map_err(|e| {
+					warn!("Invalid account data event in db: {e:?}");
+					Error::BadDatabase("Invalid account data event in db.")
+				})
+			})
+			.transpose()?
+			.unwrap_or_else(|| DirectEvent {
+				content: DirectEventContent::default(),
+			});
+
+		if add_direct_room(&mut direct_event.content, &inviter, room_id) {
+			services().account_data.update(
+				None,
+				user_id,
+				GlobalAccountDataEventType::Direct.to_string().into(),
+				&serde_json::to_value(&direct_event).expect("to json always works"),
+			)?;
+		}
+
+		Ok(())
+	}
+
+	/// Whether `recipient` has opted out of invites from strangers and shares no
+	/// room with `sender`.
+	pub fn invite_refused_by_recipient(&self, sender: &UserId, recipient: &UserId) -> Result<bool> {
+		let config = invite_permission_config(
+			services()
+				.account_data
+				.get(None, recipient, INVITE_PERMISSION_EVENT_TYPE.into())?
+				.as_deref(),
+		);
+
+		if !config.shared_rooms_only {
+			return Ok(false);
+		}
+
+		let shares_room = services()
+			.rooms
+			.user
+			.get_shared_rooms(vec![sender.to_owned(), recipient.to_owned()])?
+			.next()
+			.is_some();
+
+		Ok(invite_refused(&config, shares_room))
+	}
+
 	#[tracing::instrument(skip(self, room_id))]
 	pub fn update_joined_count(&self, room_id: &RoomId) -> Result<()> { self.db.update_joined_count(room_id) }
 
+	/// Recounts joined and invited members from the room's current
+	/// `m.room.member` state and rewrites the cached counters if they drifted.
+	/// Returns the previously cached and the recounted `(joined, invited)`.
+	pub async fn recalculate_room_counts(&self, room_id: &RoomId) -> Result<((u64, u64), (u64, u64))> {
+		let cached = (
+			self.room_joined_count(room_id)?.unwrap_or(0),
+			self.room_invited_count(room_id)?.unwrap_or(0),
+		);
+
+		let state = services()
+			.rooms
+			.state_accessor
+			.room_state_full(room_id)
+			.await?;
+		let recounted = count_memberships(
+			state
+				.iter()
+				.filter(|((event_type, _), _)| *event_type == StateEventType::RoomMember)
+				.filter_map(|(_, pdu)| serde_json::from_str::<RoomMemberEventContent>(pdu.content.get()).ok())
+				.map(|content| content.membership),
+		);
+
+		if cached != recounted {
+			warn!("Member counts of {room_id} drifted: cached {cached:?} (joined, invited), recounted {recounted:?}");
+			self.db
+				.set_member_counts(room_id, recounted.0, recounted.1)?;
+		}
+
+		Ok((cached, recounted))
+	}
+
 	#[tracing::instrument(skip(self, room_id, appservice))]
 	pub fn appservice_in_room(&self, room_id: &RoomId, appservice: &RegistrationInfo) -> Result<bool> {
 		self.db.appservice_in_room(room_id, appservice)
 	}
 
+	/// Forgets which rooms appservices are in, e.g. after their namespaces
+	/// change. Answers are recomputed from room membership on next use.
+	pub fn clear_appservice_in_room_cache(&self) { self.db.clear_appservice_in_room_cache(); }
+
 	/// Direct DB function to directly mark a user as left. It is not
 	/// recommended to use this directly. You most likely should use
 	/// `update_membership` instead
@@ -433,3 +554,203 @@ impl Service {
 		Ok(servers)
 	}
 }
+
+/// Counts `(joined, invited)` members among the given membership states.
+fn count_memberships<I: Iterator<Item = MembershipState>>(memberships: I) -> (u64, u64) {
+	memberships.fold((0_u64, 0_u64), |(joined, invited), membership| match membership {
+		MembershipState::Join => (joined.saturating_add(1), invited),
+		MembershipState::Invite => (joined, invited.saturating_add(1)),
+		_ => (joined, invited),
+	})
+}
+
+/// Reads the invite permission config from the recipient's account data. The
+/// client sets this event, so a malformed one is logged and skipped rather
+/// than failing every invite sent to them.
+fn invite_permission_config(event: Option<&RawJsonValue>) -> InvitePermissionConfig {
+	event
+		.and_then(|event| {
+			serde_json::from_str::<InvitePermissionEvent>(event.get())
+				.map_err(|_| Error::bad_database("Invalid invite permission account data in db."))
+				.ok()
+		})
+		.unwrap_or_default()
+		.content
+}
+
+fn invite_refused(config: &InvitePermissionConfig, shares_room: bool) -> bool {
+	config.shared_rooms_only && !shares_room
+}
+
+fn is_direct_invite(content: &RoomMemberEventContent) -> bool {
+	content.membership == MembershipState::Invite && content.is_direct == Some(true)
+}
+
+/// Finds who sent the `is_direct` invite `user_id` is accepting. The invite is
+/// in the room state when we are already in the room; when joining over
+/// federation only the stripped state we received with the invite has it.
+fn direct_inviter(
+	user_id: &UserId, invite: Option<&PduEvent>, invite_state: Option<&[Raw<AnyStrippedStateEvent>]>,
+) -> Option<OwnedUserId> {
+	if let Some(invite) = invite {
+		let content = serde_json::from_str::<RoomMemberEventContent>(invite.content.get()).ok()?;
+		return is_direct_invite(&content).then(|| invite.sender.clone());
+	}
+
+	invite_state?
+		.iter()
+		.find_map(|event| match event.deserialize() {
+			Ok(AnyStrippedStateEvent::RoomMember(member))
+				if member.state_key == user_id && is_direct_invite(&member.content) =>
+			{
+				Some(member.sender)
+			},
+			_ => None,
+		})
+}
+
+/// Adds `room_id` to the `m.direct` rooms listed under `user_id`, keeping what
+/// is already there. Returns whether anything changed.
+fn add_direct_room(direct: &mut DirectEventContent, user_id: &UserId, room_id: &RoomId) -> bool {
+	let room_ids = direct.0.entry(user_id.to_owned()).or_default();
+	if room_ids.iter().any(|r| r == room_id) {
+		return false;
+	}
+
+	room_ids.push(room_id.to_owned());
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		events::{
+			direct::DirectEventContent,
+			room::member::{MembershipState, RoomMemberEventContent},
+			AnyStrippedStateEvent,
+		},
+		room_id,
+		serde::Raw,
+		user_id,
+	};
+	use serde_json::{json, value::RawValue as RawJsonValue};
+
+	use super::{
+		add_direct_room, count_memberships, direct_inviter, invite_permission_config, invite_refused, is_direct_invite,
+		InvitePermissionEvent,
+	};
+	use crate::PduEvent;
+
+	#[test]
+	fn only_joins_and_invites_are_counted() {
+		let memberships = [
+			MembershipState::Join,
+			MembershipState::Invite,
+			MembershipState::Leave,
+			MembershipState::Join,
+			MembershipState::Ban,
+			MembershipState::Knock,
+		];
+
+		assert_eq!(count_memberships(memberships.into_iter()), (2, 1));
+	}
+
+	#[test]
+	fn invites_from_strangers_are_refused_when_opted_out() {
+		let event: InvitePermissionEvent = serde_json::from_str(
+			r#"{"type":"im.conduwuit.invite_permission_config","content":{"shared_rooms_only":true}}"#,
+		)
+		.unwrap();
+
+		assert!(invite_refused(&event.content, false));
+		// users sharing a room with the recipient can still invite them
+		assert!(!invite_refused(&event.content, true));
+	}
+
+	#[test]
+	fn invites_are_allowed_by_default() {
+		let event: InvitePermissionEvent = serde_json::from_str(r#"{"content":{}}"#).unwrap();
+		assert!(!invite_refused(&event.content, false));
+		assert!(!invite_refused(&invite_permission_config(None), false));
+	}
+
+	#[test]
+	fn malformed_invite_permission_config_is_skipped() {
+		let event = RawJsonValue::from_string(r#"{"content":{"shared_rooms_only":"yes"}}"#.to_owned()).unwrap();
+		assert!(!invite_refused(&invite_permission_config(Some(&event)), false));
+
+		let event = RawJsonValue::from_string(r#"{"content":{"shared_rooms_only":true}}"#.to_owned()).unwrap();
+		assert!(invite_refused(&invite_permission_config(Some(&event)), false));
+	}
+
+	#[test]
+	fn accepting_direct_invite_updates_m_direct() {
+		let mut invite = RoomMemberEventContent::new(MembershipState::Invite);
+		assert!(!is_direct_invite(&invite));
+		invite.is_direct = Some(true);
+		assert!(is_direct_invite(&invite));
+
+		let inviter = user_id!("@alice:example.com");
+		let mut direct = DirectEventContent::default();
+		direct
+			.0
+			.insert(inviter.to_owned(), vec![room_id!("!old:example.com").to_owned()]);
+
+		assert!(add_direct_room(&mut direct, inviter, room_id!("!dm:example.com")));
+		assert_eq!(direct.0[inviter], [room_id!("!old:example.com"), room_id!("!dm:example.com")]);
+
+		// joining again doesn't list the room twice
+		assert!(!add_direct_room(&mut direct, inviter, room_id!("!dm:example.com")));
+		assert_eq!(direct.0[inviter].len(), 2);
+	}
+
+	#[test]
+	fn direct_inviter_is_read_from_room_state_or_invite_state() {
+		let invitee = user_id!("@bob:example.com");
+		let invite = |is_direct: bool| {
+			PduEvent::test_event(json!({
+				"type": "m.room.member",
+				"state_key": invitee,
+				"sender": "@alice:example.com",
+				"content": { "membership": "invite", "is_direct": is_direct },
+			}))
+		};
+
+		// a local room has the invite in its state
+		assert_eq!(
+			direct_inviter(invitee, Some(&invite(true)), None).unwrap(),
+			"@alice:example.com"
+		);
+		assert_eq!(direct_inviter(invitee, Some(&invite(false)), None), None);
+
+		// a remote join only has the stripped state sent with the invite
+		let stripped = |state_key: &str| {
+			Raw::new(&json!({
+				"type": "m.room.member",
+				"state_key": state_key,
+				"sender": "@alice:remote.example.com",
+				"content": { "membership": "invite", "is_direct": true },
+			}))
+			.unwrap()
+			.cast::<AnyStrippedStateEvent>()
+		};
+		let invite_state = [
+			Raw::new(&json!({
+				"type": "m.room.name",
+				"state_key": "",
+				"sender": "@alice:remote.example.com",
+				"content": { "name": "DM" },
+			}))
+			.unwrap()
+			.cast::<AnyStrippedStateEvent>(),
+			stripped("@carol:example.com"),
+			stripped(invitee.as_str()),
+		];
+		assert_eq!(
+			direct_inviter(invitee, None, Some(&invite_state)).unwrap(),
+			"@alice:remote.example.com"
+		);
+		assert_eq!(direct_inviter(invitee, None, Some(&invite_state[..2])), None);
+		assert_eq!(direct_inviter(invitee, None, None), None);
+	}
+}
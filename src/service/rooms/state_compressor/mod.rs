@@ -98,15 +98,26 @@ impl Service {
 	}
 
 	pub fn compress_state_event(&self, shortstatekey: u64, event_id: &EventId) -> Result<CompressedStateEvent> {
-		let mut v = shortstatekey.to_be_bytes().to_vec();
-		v.extend_from_slice(
-			&services()
+		let shorteventid = services()
+			.rooms
+			.short
+			.get_or_create_shorteventid(event_id)?;
+
+		Ok(compressed_state_event(shortstatekey, shorteventid))
+	}
+
+	/// Compresses a whole state map, looking up or creating all the short
+	/// event IDs in one batch rather than one database round-trip per event.
+	pub fn compress_state_events<'a, I>(&self, state: I) -> Result<HashSet<CompressedStateEvent>>
+	where
+		I: IntoIterator<Item = (u64, &'a EventId)>,
+	{
+		compress_state_events_with(state, |event_ids| {
+			services()
 				.rooms
 				.short
-				.get_or_create_shorteventid(event_id)?
-				.to_be_bytes(),
-		);
-		Ok(v.try_into().expect("we checked the size above"))
+				.multi_get_or_create_shorteventid(event_ids)
+		})
 	}
 
 	/// Returns shortstatekey, event id
@@ -119,6 +130,64 @@ impl Service {
 		))
 	}
 
+	/// Returns the shortstatekey and event ID of every state entry in
+	/// `shortstatehash` that differs from `since_shortstatehash`: the same
+	/// entries a diff of the two full states would find, but only the delta is
+	/// resolved to event IDs instead of both states in full.
+	pub fn state_delta(&self, since_shortstatehash: u64, shortstatehash: u64) -> Result<Vec<(u64, Arc<EventId>)>> {
+		self.compressed_state_delta(since_shortstatehash, shortstatehash)?
+			.iter()
+			.map(|compressed| self.parse_compressed_state_event(compressed))
+			.collect()
+	}
+
+	/// Entries of `shortstatehash` not in `since_shortstatehash`, worked out
+	/// from the diff layers above the closest layer both states are built on,
+	/// so neither full state is loaded unless they share no layer at all.
+	fn compressed_state_delta(
+		&self, since_shortstatehash: u64, shortstatehash: u64,
+	) -> Result<HashSet<CompressedStateEvent>> {
+		let mut since_layers: Vec<(u64, StateDiff)> = Vec::new();
+		let mut current_layers: Vec<(u64, StateDiff)> = Vec::new();
+		let mut since_next = Some(since_shortstatehash);
+		let mut current_next = Some(shortstatehash);
+
+		// Walk down both stacks in step until one reaches a layer the other has
+		// already been through, which is as deep as either needs to go
+		while since_next.is_some() || current_next.is_some() {
+			if let Some(next) = since_next.take() {
+				if let Some(shared) = current_layers.iter().position(|(hash, _)| *hash == next) {
+					current_layers.truncate(shared);
+					break;
+				}
+				let diff = self.db.get_statediff(next)?;
+				since_next = diff.parent;
+				since_layers.push((next, diff));
+			}
+
+			if let Some(next) = current_next.take() {
+				if let Some(shared) = since_layers.iter().position(|(hash, _)| *hash == next) {
+					since_layers.truncate(shared);
+					break;
+				}
+				let diff = self.db.get_statediff(next)?;
+				current_next = diff.parent;
+				current_layers.push((next, diff));
+			}
+		}
+
+		let (since_added, since_removed) = layers_diff(&since_layers);
+		let (current_added, current_removed) = layers_diff(&current_layers);
+
+		// Entries added since the shared layer, and entries of the shared layer only
+		// `since_shortstatehash` removed
+		Ok(current_added
+			.difference(&since_added)
+			.chain(since_removed.difference(&current_removed))
+			.copied()
+			.collect())
+	}
+
 	/// Creates a new shortstatehash that often is just a diff to an already
 	/// existing shortstatehash and therefore very efficient.
 	///
@@ -304,3 +373,208 @@ impl Service {
 		Ok((new_shortstatehash, statediffnew, statediffremoved))
 	}
 }
+
+fn compress_state_events_with<'a, I, F>(state: I, get_shorteventids: F) -> Result<HashSet<CompressedStateEvent>>
+where
+	I: IntoIterator<Item = (u64, &'a EventId)>,
+	F: FnOnce(&[&EventId]) -> Result<Vec<u64>>,
+{
+	let (shortstatekeys, event_ids): (Vec<_>, Vec<_>) = state.into_iter().unzip();
+	let shorteventids = get_shorteventids(&event_ids)?;
+	debug_assert_eq!(shorteventids.len(), event_ids.len(), "one short ID per event");
+
+	Ok(shortstatekeys
+		.into_iter()
+		.zip(shorteventids)
+		.map(|(shortstatekey, shorteventid)| compressed_state_event(shortstatekey, shorteventid))
+		.collect())
+}
+
+/// Combines a stack of layers, topmost first, into what they add to and remove
+/// from the layer below them, the same way [`Service::save_state_from_diff`]
+/// merges layers.
+fn layers_diff(layers: &[(u64, StateDiff)]) -> (HashSet<CompressedStateEvent>, HashSet<CompressedStateEvent>) {
+	let mut added = HashSet::new();
+	let mut removed = HashSet::new();
+
+	for (_, layer) in layers.iter().rev() {
+		for r in layer.removed.iter() {
+			if !added.remove(r) {
+				removed.insert(*r);
+			}
+		}
+
+		for a in layer.added.iter() {
+			if !removed.remove(a) {
+				added.insert(*a);
+			}
+		}
+	}
+
+	(added, removed)
+}
+
+fn compressed_state_event(shortstatekey: u64, shorteventid: u64) -> CompressedStateEvent {
+	let mut v = shortstatekey.to_be_bytes().to_vec();
+	v.extend_from_slice(&shorteventid.to_be_bytes());
+	v.try_into().expect("we checked the size above")
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		cell::Cell,
+		collections::{HashMap, HashSet},
+		sync::{Arc, Mutex},
+	};
+
+	use lru_cache::LruCache;
+	use ruma::event_id;
+
+	use super::{
+		compress_state_events_with, compressed_state_event,
+		data::{Data, StateDiff},
+		CompressedStateEvent, Service,
+	};
+	use crate::{Error, Result};
+
+	type Layer = (
+		Option<u64>,
+		Arc<HashSet<CompressedStateEvent>>,
+		Arc<HashSet<CompressedStateEvent>>,
+	);
+
+	/// Stands in for shortstatehash_statediff
+	#[derive(Default)]
+	struct Layers(Mutex<HashMap<u64, Layer>>);
+
+	impl Data for Layers {
+		fn get_statediff(&self, shortstatehash: u64) -> Result<StateDiff> {
+			let (parent, added, removed) = self
+				.0
+				.lock()
+				.unwrap()
+				.get(&shortstatehash)
+				.cloned()
+				.ok_or_else(|| Error::bad_database("State hash does not exist"))?;
+
+			Ok(StateDiff {
+				parent,
+				added,
+				removed,
+			})
+		}
+
+		fn save_statediff(&self, shortstatehash: u64, diff: StateDiff) -> Result<()> {
+			self.0
+				.lock()
+				.unwrap()
+				.insert(shortstatehash, (diff.parent, diff.added, diff.removed));
+
+			Ok(())
+		}
+	}
+
+	/// Saves `state` on top of `previous` the way `Service::save_state` does
+	fn save(service: &Service, shortstatehash: u64, previous: Option<u64>, state: &HashMap<u64, u64>) {
+		let state: HashSet<_> = state
+			.iter()
+			.map(|(&key, &id)| compressed_state_event(key, id))
+			.collect();
+		let parents = previous.map_or_else(Vec::new, |previous| service.load_shortstatehash_info(previous).unwrap());
+		let (added, removed): (HashSet<_>, HashSet<_>) = match parents.last() {
+			Some((_, parent, ..)) => (
+				state.difference(parent).copied().collect(),
+				parent.difference(&state).copied().collect(),
+			),
+			None => (state, HashSet::new()),
+		};
+
+		service
+			.save_state_from_diff(shortstatehash, Arc::new(added), Arc::new(removed), 2, parents)
+			.unwrap();
+	}
+
+	#[test]
+	fn state_is_compressed_with_one_batched_lookup() {
+		let state = [
+			(1, event_id!("$create:example.com")),
+			(2, event_id!("$member:example.com")),
+			(3, event_id!("$power_levels:example.com")),
+		];
+
+		let lookups = Cell::new(0);
+		let compressed = compress_state_events_with(state, |event_ids| {
+			lookups.set(lookups.get() + 1);
+			assert_eq!(event_ids.len(), 3);
+			Ok(vec![10, 20, 30])
+		})
+		.unwrap();
+
+		assert_eq!(lookups.get(), 1);
+		assert_eq!(compressed.len(), 3);
+		assert!(compressed.contains(&compressed_state_event(2, 20)));
+		assert!(!compressed.contains(&compressed_state_event(2, 10)));
+	}
+
+	#[test]
+	fn state_delta_matches_full_diff() {
+		let layers = Arc::new(Layers::default());
+		let service = Service {
+			db: layers.clone(),
+			stateinfo_cache: Mutex::new(LruCache::new(100)),
+		};
+
+		// shortstatekey -> shorteventid, changing a little at a time with the odd
+		// large change so layers get merged and new full layers written
+		let mut state: HashMap<u64, u64> = (0..20).map(|key| (key, key)).collect();
+		let mut previous = None;
+		for shortstatehash in 1..=40 {
+			match shortstatehash % 13 {
+				0 => state.values_mut().for_each(|id| *id += 1000),
+				n if n % 3 == 0 => {
+					state.remove(&(100 + shortstatehash - 3));
+				},
+				_ => {
+					state.insert(shortstatehash % 7, shortstatehash * 10);
+				},
+			}
+			state.insert(100 + shortstatehash, shortstatehash);
+
+			save(&service, shortstatehash, previous, &state);
+			previous = Some(shortstatehash);
+		}
+
+		// a second room, sharing no layer with the first
+		save(&service, 41, None, &HashMap::from([(1, 1), (2, 20), (500, 500)]));
+
+		let bases = layers
+			.0
+			.lock()
+			.unwrap()
+			.values()
+			.filter(|(parent, ..)| parent.is_none())
+			.count();
+		assert!(bases > 2, "only {bases} full layers written");
+
+		let full_state = |shortstatehash| {
+			service
+				.load_shortstatehash_info(shortstatehash)
+				.unwrap()
+				.pop()
+				.unwrap()
+				.1
+		};
+		for since in 1..=41 {
+			for current in 1..=41 {
+				let full_diff: HashSet<_> = full_state(current)
+					.difference(&full_state(since))
+					.copied()
+					.collect();
+
+				let delta = service.compressed_state_delta(since, current).unwrap();
+				assert_eq!(delta, full_diff, "delta from {since} to {current}");
+			}
+		}
+	}
+}
@@ -2,7 +2,7 @@ mod data;
 
 use std::{
 	collections::{BTreeMap, HashMap, HashSet},
-	sync::Arc,
+	sync::{Arc, Mutex as StdMutex},
 };
 
 use data::Data;
@@ -25,8 +25,8 @@ use ruma::{
 	push::{Action, Ruleset, Tweak},
 	serde::Base64,
 	state_res::{self, Event, RoomVersion},
-	uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, OwnedEventId, OwnedRoomId, OwnedServerName,
-	RoomId, RoomVersionId, ServerName, UserId,
+	uint, user_id, CanonicalJsonObject, CanonicalJsonValue, EventId, MilliSecondsSinceUnixEpoch, OwnedEventId,
+	OwnedRoomId, OwnedServerName, RoomId, RoomVersionId, ServerName, UInt, UserId,
 };
 use serde::Deserialize;
 use serde_json::value::{to_raw_value, RawValue as RawJsonValue};
@@ -40,7 +40,7 @@ use crate::{
 	//api::server_server,
 	service::{
 		appservice::NamespaceRegex,
-		pdu::{EventHash, PduBuilder},
+		pdu::{self, EventHash, PduBuilder},
 		rooms::event_handler::parse_incoming_pdu,
 	},
 	services,
@@ -73,10 +73,25 @@ struct ExtractBody {
 	body: Option<String>,
 }
 
+/// Event types that count as room activity for a room's bump stamp.
+pub const DEFAULT_BUMP_EVENT_TYPES: &[TimelineEventType] = &[
+	TimelineEventType::RoomCreate,
+	TimelineEventType::RoomMessage,
+	TimelineEventType::RoomEncrypted,
+	TimelineEventType::Sticker,
+	TimelineEventType::CallInvite,
+];
+
+/// How far back to look for a bump event before settling for the newest event
+/// of any type.
+const BUMP_STAMP_SEARCH_LIMIT: usize = 100;
+
 pub struct Service {
 	pub db: Arc<dyn Data>,
 
 	pub lasttimelinecount_cache: Mutex<HashMap<OwnedRoomId, PduCount>>,
+	/// Each room's bump stamp, along with the latest PDU count it was found at
+	pub bumpstamp_cache: StdMutex<HashMap<OwnedRoomId, (PduCount, Option<MilliSecondsSinceUnixEpoch>)>>,
 }
 
 impl Service {
@@ -101,6 +116,29 @@ impl Service {
 		self.db.last_timeline_count(sender_user, room_id)
 	}
 
+	/// The timestamp of the room's latest activity, by the newest event of one
+	/// of the [`DEFAULT_BUMP_EVENT_TYPES`]. Cached until another PDU is
+	/// appended to the room.
+	pub fn bump_stamp(&self, sender_user: &UserId, room_id: &RoomId) -> Result<Option<MilliSecondsSinceUnixEpoch>> {
+		let count = self.last_timeline_count(sender_user, room_id)?;
+		if let Some(&(cached_count, stamp)) = self.bumpstamp_cache.lock().expect("locked").get(room_id) {
+			if cached_count == count {
+				return Ok(stamp);
+			}
+		}
+
+		let stamp = newest_bump_stamp(
+			self.pdus_until(sender_user, room_id, PduCount::max())?,
+			DEFAULT_BUMP_EVENT_TYPES,
+		)?;
+		self.bumpstamp_cache
+			.lock()
+			.expect("locked")
+			.insert(room_id.to_owned(), (count, stamp));
+
+		Ok(stamp)
+	}
+
 	/// Returns the `count` of this pdu's id.
 	pub fn get_pdu_count(&self, event_id: &EventId) -> Result<Option<PduCount>> { self.db.get_pdu_count(event_id) }
 
@@ -591,13 +629,17 @@ impl Service {
 			redacts,
 		} = pdu_builder;
 
-		let prev_events: Vec<_> = services()
+		let extremities = services()
 			.rooms
 			.state
 			.get_forward_extremities(room_id)?
 			.into_iter()
-			.take(20)
+			.map(|event_id| {
+				let depth = self.get_pdu(&event_id).ok().flatten().map(|pdu| pdu.depth);
+				(depth, event_id)
+			})
 			.collect();
+		let prev_events = select_prev_events(extremities, services().globals.max_prev_events());
 
 		// If there was no create event yet, assume we are creating a room
 		let room_version_id = services()
@@ -750,6 +792,8 @@ impl Service {
 			CanonicalJsonValue::String(pdu.event_id.as_str().to_owned()),
 		);
 
+		pdu::check_pdu_size(&pdu_json, services().globals.max_event_size())?;
+
 		// Generate short event id
 		let _shorteventid = services()
 			.rooms
@@ -855,9 +899,13 @@ impl Service {
 			.append_pdu(
 				&pdu,
 				pdu_json,
-				// Since this PDU references all pdu_leaves we can update the leaves
-				// of the room
-				vec![(*pdu.event_id).to_owned()],
+				// This PDU references the most recent leaves, any it could not fit
+				// stay leaves of the room alongside it
+				room_leaves_after(
+					services().rooms.state.get_forward_extremities(room_id)?,
+					&pdu.prev_events,
+					&pdu.event_id,
+				),
 				state_lock,
 			)
 			.await?;
@@ -892,6 +940,11 @@ impl Service {
 		// room_servers() and/or the if statement above
 		servers.remove(services().globals.server_name());
 
+		// Local-only and non-federating rooms never leave this server
+		if !services().sending.is_room_federated(room_id)? {
+			servers.clear();
+		}
+
 		services()
 			.sending
 			.send_pdu_servers(servers.into_iter(), &pdu_id)?;
@@ -1185,10 +1238,84 @@ impl Service {
 	}
 }
 
+/// Picks up to `max` forward extremities to reference as prev_events,
+/// preferring the deepest (most recent) ones. Extremities whose PDU is missing
+/// are only used when there is room left.
+fn select_prev_events(mut extremities: Vec<(Option<UInt>, Arc<EventId>)>, max: usize) -> Vec<Arc<EventId>> {
+	extremities.sort_unstable_by(|a, b| b.cmp(a));
+	extremities
+		.into_iter()
+		.take(max)
+		.map(|(_, event_id)| event_id)
+		.collect()
+}
+
+/// The forward extremities of a room after appending a local PDU: the PDU
+/// itself and any previous extremities it did not reference.
+fn room_leaves_after(
+	extremities: HashSet<Arc<EventId>>, prev_events: &[Arc<EventId>], event_id: &EventId,
+) -> Vec<OwnedEventId> {
+	extremities
+		.into_iter()
+		.filter(|extremity| !prev_events.contains(extremity))
+		.map(|extremity| (*extremity).to_owned())
+		.chain(std::iter::once(event_id.to_owned()))
+		.collect()
+}
+
+/// The timestamp of the newest of `pdus`, newest first, whose type is one of
+/// `bump_event_types`, falling back to the newest event of any type if none
+/// turns up within [`BUMP_STAMP_SEARCH_LIMIT`] events.
+pub fn newest_bump_stamp(
+	pdus: impl Iterator<Item = Result<(PduCount, PduEvent)>>, bump_event_types: &[TimelineEventType],
+) -> Result<Option<MilliSecondsSinceUnixEpoch>> {
+	let mut newest = None;
+	for pdu in pdus.take(BUMP_STAMP_SEARCH_LIMIT) {
+		let (_, pdu) = pdu?;
+		let timestamp = MilliSecondsSinceUnixEpoch(pdu.origin_server_ts);
+		if bump_event_types.contains(&pdu.kind) {
+			return Ok(Some(timestamp));
+		}
+		newest.get_or_insert(timestamp);
+	}
+
+	Ok(newest)
+}
+
 #[cfg(test)]
 mod tests {
 	use super::*;
 
+	fn event_id(n: u32) -> Arc<EventId> { EventId::parse_arc(format!("$event{n}:example.com")).expect("valid") }
+
+	#[test]
+	fn prev_events_capped_to_most_recent() {
+		let extremities: Vec<_> = (0..50)
+			.map(|n| (Some(UInt::from(n)), event_id(n)))
+			.chain(std::iter::once((None, event_id(100))))
+			.collect();
+
+		let prev_events = select_prev_events(extremities, 20);
+		assert_eq!(prev_events.len(), 20);
+		assert!(prev_events.contains(&event_id(49)));
+		assert!(prev_events.contains(&event_id(30)));
+		assert!(!prev_events.contains(&event_id(29)));
+		assert!(!prev_events.contains(&event_id(100)));
+
+		let leaves = room_leaves_after(
+			(0..50)
+				.map(event_id)
+				.chain(std::iter::once(event_id(100)))
+				.collect(),
+			&prev_events,
+			&event_id(200),
+		);
+		assert_eq!(leaves.len(), 32);
+		assert!(leaves.contains(&(*event_id(200)).to_owned()));
+		assert!(leaves.contains(&(*event_id(100)).to_owned()));
+		assert!(!leaves.contains(&(*event_id(49)).to_owned()));
+	}
+
 	#[test]
 	fn comparisons() {
 		assert!(PduCount::Normal(1) < PduCount::Normal(2));
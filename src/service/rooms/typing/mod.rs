@@ -165,11 +165,10 @@ impl Service {
 
 	fn federation_send(room_id: &RoomId, user_id: &UserId, typing: bool) -> Result<()> {
 		debug_assert!(user_is_local(user_id), "tried to broadcast typing status of remote user",);
-		if !services().globals.config.allow_outgoing_typing {
+		let Some(edu) = outgoing_typing_edu(room_id, user_id, typing, services().globals.config.allow_outgoing_typing)
+		else {
 			return Ok(());
-		}
-
-		let edu = Edu::Typing(TypingContent::new(room_id.to_owned(), user_id.to_owned(), typing));
+		};
 
 		services()
 			.sending
@@ -178,3 +177,31 @@ impl Service {
 		Ok(())
 	}
 }
+
+/// Builds the typing EDU to federate for a local user, or `None` when
+/// `allow_outgoing_typing` is disabled and nothing should be queued.
+fn outgoing_typing_edu(room_id: &RoomId, user_id: &UserId, typing: bool, allow_outgoing: bool) -> Option<Edu> {
+	allow_outgoing.then(|| Edu::Typing(TypingContent::new(room_id.to_owned(), user_id.to_owned(), typing)))
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{api::federation::transactions::edu::Edu, room_id, user_id};
+
+	use super::outgoing_typing_edu;
+
+	#[test]
+	fn outgoing_typing_edus_are_not_queued_when_disabled() {
+		let (room_id, user_id) = (room_id!("!room:example.com"), user_id!("@alice:example.com"));
+
+		assert!(outgoing_typing_edu(room_id, user_id, true, false).is_none());
+		assert!(outgoing_typing_edu(room_id, user_id, false, false).is_none());
+
+		let Some(Edu::Typing(content)) = outgoing_typing_edu(room_id, user_id, true, true) else {
+			panic!("expected a typing EDU");
+		};
+		assert_eq!(content.room_id, room_id);
+		assert_eq!(content.user_id, user_id);
+		assert!(content.typing);
+	}
+}
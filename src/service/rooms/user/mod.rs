@@ -1,18 +1,51 @@
 mod data;
 
-use std::sync::Arc;
+use std::{
+	collections::HashSet,
+	sync::{Arc, Mutex},
+};
 
 use data::Data;
-use ruma::{OwnedRoomId, OwnedUserId, RoomId, UserId};
+use lru_cache::LruCache;
+use ruma::{events::TimelineEventType, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde::Deserialize;
 
-use crate::Result;
+use crate::{services, PduCount, PduEvent, Result};
+
+/// Most unread events looked at when counting missed calls
+const MISSED_CALLS_SCAN_LIMIT: usize = 1000;
+
+/// Users' rooms whose missed calls are kept between push notifications,
+/// before scaling by `conduit_cache_capacity_modifier`
+pub const MISSED_CALLS_CACHE_CAPACITY: u32 = 10_000;
+
+#[derive(Deserialize)]
+struct CallContent {
+	call_id: String,
+}
 
 pub struct Service {
 	pub db: Arc<dyn Data>,
+	pub missed_calls: Mutex<LruCache<(OwnedUserId, OwnedRoomId), MissedCalls>>,
+}
+
+/// The calls to a user in a room since they last read it, counted from the
+/// events scanned so far so each event is only looked at once, however many
+/// pushers the user has.
+pub struct MissedCalls {
+	since: u64,
+	until: PduCount,
+	scanned: usize,
+	invites: Vec<String>,
+	handled: HashSet<String>,
 }
 
 impl Service {
 	pub fn reset_notification_counts(&self, user_id: &UserId, room_id: &RoomId) -> Result<()> {
+		self.missed_calls
+			.lock()
+			.unwrap()
+			.remove(&(user_id.to_owned(), room_id.to_owned()));
 		self.db.reset_notification_counts(user_id, room_id)
 	}
 
@@ -28,6 +61,42 @@ impl Service {
 		self.db.last_notification_read(user_id, room_id)
 	}
 
+	/// Counts the calls to the user in the room since they last read it which
+	/// were not answered, never more than `unread`. Only events after the ones
+	/// counted for a previous notification are scanned.
+	///
+	/// Calls in encrypted rooms are sent as `m.room.encrypted` events, so
+	/// there are never any missed calls in those.
+	pub fn missed_call_count(&self, user_id: &UserId, room_id: &RoomId, unread: u64) -> Result<u64> {
+		if unread == 0 {
+			return Ok(0);
+		}
+
+		// scanned outside the lock, then put back
+		let key = (user_id.to_owned(), room_id.to_owned());
+		let since = self.last_notification_read(user_id, room_id)?;
+		let mut calls = self
+			.missed_calls
+			.lock()
+			.unwrap()
+			.remove(&key)
+			.filter(|calls| calls.since == since)
+			.unwrap_or_else(|| MissedCalls::new(since));
+
+		let events = services()
+			.rooms
+			.timeline
+			.pdus_after(user_id, room_id, calls.until)?
+			.filter_map(Result::ok)
+			.take(MISSED_CALLS_SCAN_LIMIT.saturating_sub(calls.scanned));
+		calls.scan(user_id, events);
+
+		let missed = calls.missed();
+		self.missed_calls.lock().unwrap().insert(key, calls);
+
+		Ok(missed.min(unread))
+	}
+
 	pub fn associate_token_shortstatehash(&self, room_id: &RoomId, token: u64, shortstatehash: u64) -> Result<()> {
 		self.db
 			.associate_token_shortstatehash(room_id, token, shortstatehash)
@@ -41,3 +110,113 @@ impl Service {
 		self.db.get_shared_rooms(users)
 	}
 }
+
+impl MissedCalls {
+	fn new(since: u64) -> Self {
+		Self {
+			since,
+			until: PduCount::Normal(since),
+			scanned: 0,
+			invites: Vec::new(),
+			handled: HashSet::new(),
+		}
+	}
+
+	/// Notes the `m.call.invite`s from others and the calls `user_id` took up
+	/// by answering, rejecting or starting them themselves (glare) from any of
+	/// their devices. Others answering a call in a group room doesn't mean the
+	/// user didn't miss it.
+	fn scan(&mut self, user_id: &UserId, events: impl Iterator<Item = (PduCount, PduEvent)>) {
+		for (count, pdu) in events {
+			self.until = count;
+			self.scanned = self.scanned.saturating_add(1);
+			let Ok(content) = serde_json::from_str::<CallContent>(pdu.content.get()) else {
+				continue;
+			};
+
+			if pdu.sender != user_id {
+				if pdu.kind == TimelineEventType::CallInvite {
+					self.invites.push(content.call_id);
+				}
+			} else if pdu.kind.to_string().starts_with("m.call.") {
+				self.handled.insert(content.call_id);
+			}
+		}
+	}
+
+	/// The invites scanned so far which the user didn't take up.
+	fn missed(&self) -> u64 {
+		self.invites
+			.iter()
+			.filter(|call_id| !self.handled.contains(*call_id))
+			.count()
+			.try_into()
+			.unwrap_or(u64::MAX)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::user_id;
+	use serde_json::json;
+
+	use super::MissedCalls;
+	use crate::{PduCount, PduEvent};
+
+	fn call(count: u64, kind: &str, sender: &str, call_id: &str) -> (PduCount, PduEvent) {
+		let pdu = PduEvent::test_event(json!({
+			"event_id": format!("${kind}-{call_id}:example.com"),
+			"sender": sender,
+			"type": kind,
+			"content": { "call_id": call_id, "version": "1" },
+		}));
+		(PduCount::Normal(count), pdu)
+	}
+
+	#[test]
+	fn unanswered_call_invites_are_missed() {
+		let bob = user_id!("@bob:example.com");
+		let events = [
+			call(1, "m.call.invite", "@alice:example.com", "1"),
+			call(2, "m.call.hangup", "@alice:example.com", "1"),
+			call(3, "m.call.invite", "@alice:example.com", "2"),
+			// answered by bob on another device
+			call(4, "m.call.invite", "@alice:example.com", "3"),
+			call(5, "m.call.answer", "@bob:example.com", "3"),
+			// glare: bob called alice at the same time
+			call(6, "m.call.invite", "@alice:example.com", "4"),
+			call(7, "m.call.invite", "@bob:example.com", "4"),
+			// bob's own calls aren't missed
+			call(8, "m.call.invite", "@bob:example.com", "5"),
+			// in a group room, carol answering doesn't mean bob did
+			call(9, "m.call.invite", "@alice:example.com", "6"),
+			call(10, "m.call.answer", "@carol:example.com", "6"),
+		];
+
+		let mut calls = MissedCalls::new(0);
+		calls.scan(bob, events.into_iter());
+		assert_eq!(calls.missed(), 3);
+	}
+
+	#[test]
+	fn missed_calls_are_counted_incrementally() {
+		let bob = user_id!("@bob:example.com");
+		let mut calls = MissedCalls::new(0);
+		calls.scan(bob, [call(1, "m.call.invite", "@alice:example.com", "1")].into_iter());
+		assert_eq!(calls.missed(), 1);
+		assert_eq!(calls.until, PduCount::Normal(1));
+
+		// a later notification only scans what came since
+		calls.scan(
+			bob,
+			[
+				call(2, "m.call.answer", "@bob:example.com", "1"),
+				call(3, "m.call.invite", "@alice:example.com", "2"),
+			]
+			.into_iter(),
+		);
+		assert_eq!(calls.missed(), 1);
+		assert_eq!(calls.until, PduCount::Normal(3));
+		assert_eq!(calls.scanned, 3);
+	}
+}
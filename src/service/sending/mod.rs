@@ -4,18 +4,27 @@ pub mod resolve;
 mod send;
 mod sender;
 
-use std::{fmt::Debug, sync::Arc};
+use std::{
+	collections::HashMap,
+	fmt::Debug,
+	sync::{Arc, RwLock},
+	time::{Duration, Instant},
+};
 
 use data::Data;
 pub use resolve::FedDest;
 use ruma::{
 	api::{appservice::Registration, OutgoingRequest},
+	events::StateEventType,
 	OwnedServerName, OwnedUserId, RoomId, ServerName, UserId,
 };
 use tokio::{sync::Mutex, task::JoinHandle};
 use tracing::{error, warn};
 
-use crate::{server_is_ours, services, Config, Error, Result};
+use crate::{
+	rooms::{metadata, state_accessor},
+	server_is_ours, services, Config, Error, PduEvent, Result,
+};
 
 pub struct Service {
 	pub db: Arc<dyn Data>,
@@ -26,6 +35,11 @@ pub struct Service {
 	handler_join: Mutex<Option<JoinHandle<()>>>,
 	startup_netburst: bool,
 	startup_netburst_keep: i64,
+	/// How long to wait for more events before sending a transaction to an
+	/// otherwise idle server.
+	transaction_batch: Duration,
+	/// When a transaction to each server last succeeded, since startup.
+	last_success: RwLock<HashMap<OwnedServerName, Instant>>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -60,9 +74,21 @@ impl Service {
 			handler_join: Mutex::new(None),
 			startup_netburst: config.startup_netburst,
 			startup_netburst_keep: config.startup_netburst_keep,
+			transaction_batch: Duration::from_millis(config.federation_transaction_batch_ms),
+			last_success: RwLock::new(HashMap::new()),
 		})
 	}
 
+	/// When we last successfully sent a transaction to this server, if we have
+	/// since startup.
+	pub fn last_success(&self, server: &ServerName) -> Option<Instant> {
+		self.last_success
+			.read()
+			.expect("locked for reading")
+			.get(server)
+			.copied()
+	}
+
 	pub async fn close(&self) {
 		self.interrupt();
 		if let Some(handler_join) = self.handler_join.lock().await.take() {
@@ -110,10 +136,28 @@ impl Service {
 			.rooms
 			.state_cache
 			.room_servers(room_id)
-			.filter_map(Result::ok)
-			.filter(|server_name| !server_is_ours(server_name));
+			.filter_map(Result::ok);
 
-		self.send_pdu_servers(servers, pdu_id)
+		let federated = self.is_room_federated(room_id)?;
+		self.send_pdu_servers(
+			remote_destinations(servers, services().globals.server_name(), federated),
+			pdu_id,
+		)
+	}
+
+	/// Whether events in the room may be sent to other servers at all. Rooms
+	/// marked local-only by an admin and rooms created with `m.federate` set
+	/// to false are never federated.
+	pub fn is_room_federated(&self, room_id: &RoomId) -> Result<bool> {
+		room_federated(
+			&services().rooms.metadata,
+			room_id,
+			services()
+				.rooms
+				.state_accessor
+				.room_state_get(room_id, &StateEventType::RoomCreate, "")?
+				.as_deref(),
+		)
 	}
 
 	#[tracing::instrument(skip(self, servers, pdu_id))]
@@ -282,3 +326,119 @@ impl Destination {
 		prefix
 	}
 }
+
+fn room_federated(metadata: &metadata::Service, room_id: &RoomId, create_event: Option<&PduEvent>) -> Result<bool> {
+	Ok(!metadata.is_local_only(room_id)? && state_accessor::create_event_federates(create_event)?)
+}
+
+/// Filters a room's servers down to the remote ones its events should be sent
+/// to; a room that isn't federated has no destinations at all.
+fn remote_destinations<'a, I>(
+	servers: I, ours: &'a ServerName, federated: bool,
+) -> impl Iterator<Item = OwnedServerName> + 'a
+where
+	I: Iterator<Item = OwnedServerName> + 'a,
+{
+	servers.filter(move |server_name| federated && server_name != ours)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		collections::HashSet,
+		sync::{Arc, Mutex},
+	};
+
+	use ruma::{room_id, server_name, OwnedRoomId, OwnedServerName, RoomId};
+	use serde_json::json;
+
+	use super::{remote_destinations, room_federated};
+	use crate::{rooms::metadata, PduEvent, Result};
+
+	/// Room metadata that only remembers which rooms are local-only.
+	#[derive(Default)]
+	struct LocalOnlyRooms(Mutex<HashSet<OwnedRoomId>>);
+
+	impl metadata::Data for LocalOnlyRooms {
+		fn exists(&self, _room_id: &RoomId) -> Result<bool> { Ok(true) }
+
+		fn iter_ids<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> { Box::new(std::iter::empty()) }
+
+		fn is_disabled(&self, _room_id: &RoomId) -> Result<bool> { Ok(false) }
+
+		fn disable_room(&self, _room_id: &RoomId, _disabled: bool) -> Result<()> { Ok(()) }
+
+		fn is_banned(&self, _room_id: &RoomId) -> Result<bool> { Ok(false) }
+
+		fn ban_room(&self, _room_id: &RoomId, _banned: bool) -> Result<()> { Ok(()) }
+
+		fn list_banned_rooms<'a>(&'a self) -> Box<dyn Iterator<Item = Result<OwnedRoomId>> + 'a> {
+			Box::new(std::iter::empty())
+		}
+
+		fn is_local_only(&self, room_id: &RoomId) -> Result<bool> { Ok(self.0.lock().unwrap().contains(room_id)) }
+
+		fn set_local_only(&self, room_id: &RoomId, local_only: bool) -> Result<()> {
+			let mut rooms = self.0.lock().unwrap();
+			if local_only {
+				rooms.insert(room_id.to_owned());
+			} else {
+				rooms.remove(room_id);
+			}
+
+			Ok(())
+		}
+	}
+
+	fn room_servers() -> impl Iterator<Item = OwnedServerName> {
+		[
+			server_name!("example.com"),
+			server_name!("remote.org"),
+			server_name!("other.net"),
+		]
+		.into_iter()
+		.map(ToOwned::to_owned)
+	}
+
+	#[test]
+	fn federated_room_sends_to_remote_servers() {
+		let dests: Vec<_> = remote_destinations(room_servers(), server_name!("example.com"), true).collect();
+		assert_eq!(dests, [server_name!("remote.org"), server_name!("other.net")]);
+	}
+
+	#[test]
+	fn local_only_room_queues_nothing() {
+		assert_eq!(
+			remote_destinations(room_servers(), server_name!("example.com"), false).count(),
+			0
+		);
+	}
+
+	#[test]
+	fn local_only_room_events_are_not_queued_for_remote_servers() {
+		let metadata = metadata::Service {
+			db: Arc::new(LocalOnlyRooms::default()),
+		};
+		let room_id = room_id!("!room:example.com");
+		let destinations = |create_event: Option<&PduEvent>| {
+			let federated = room_federated(&metadata, room_id, create_event).unwrap();
+			remote_destinations(room_servers(), server_name!("example.com"), federated).count()
+		};
+
+		assert_eq!(destinations(None), 2);
+
+		metadata.set_local_only(room_id, true).unwrap();
+		assert_eq!(destinations(None), 0);
+
+		metadata.set_local_only(room_id, false).unwrap();
+		assert_eq!(destinations(None), 2);
+
+		// rooms created with `m.federate` set to false stay local regardless
+		let create = PduEvent::test_event(json!({
+			"type": "m.room.create",
+			"state_key": "",
+			"content": { "room_version": "11", "m.federate": false },
+		}));
+		assert_eq!(destinations(Some(&create)), 0);
+	}
+}
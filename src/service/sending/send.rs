@@ -111,14 +111,17 @@ where
 			.insert(OwnedServerName::from(dest), (actual.dest.clone(), actual.host.clone()));
 	}
 
-	match response {
-		Err(_e) => Err(Error::BadServerResponse("Server returned bad 200 response.")),
-		Ok(response) => Ok(response),
-	}
+	response.map_err(|e| Error::FederationRequest {
+		dest: dest.to_owned(),
+		method: method.clone(),
+		path: url.path().to_owned(),
+		status: Some(status),
+		message: format!("Server returned bad {status} response: {e}"),
+	})
 }
 
 fn handle_error<T>(
-	_dest: &ServerName, actual: &ActualDest, method: &Method, url: &Url, mut e: reqwest::Error,
+	dest: &ServerName, actual: &ActualDest, method: &Method, url: &Url, mut e: reqwest::Error,
 ) -> Result<T::IncomingResponse>
 where
 	T: OutgoingRequest + Debug + Send,
@@ -139,7 +142,13 @@ where
 		debug_error!("{e:?}");
 	}
 
-	Err(e.into())
+	Err(Error::FederationRequest {
+		dest: dest.to_owned(),
+		method: method.clone(),
+		path: url.path().to_owned(),
+		status: e.status(),
+		message: e.without_url().to_string(),
+	})
 }
 
 fn sign_request<T>(dest: &ServerName, http_request: &mut http::Request<Vec<u8>>)
@@ -1,6 +1,6 @@
 use std::{
 	cmp,
-	collections::{BTreeMap, HashMap, HashSet},
+	collections::{BTreeMap, HashMap},
 	fmt::Debug,
 	sync::Arc,
 	time::{Duration, Instant},
@@ -18,12 +18,17 @@ use ruma::{
 	},
 	device_id,
 	events::{push_rules::PushRulesEvent, receipt::ReceiptType, AnySyncEphemeralRoomEvent, GlobalAccountDataEventType},
-	push, uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, RoomId, ServerName, UInt,
+	push,
+	serde::Raw,
+	uint, MilliSecondsSinceUnixEpoch, OwnedServerName, OwnedUserId, RoomId, ServerName, UInt,
 };
 use tracing::{debug, error, warn};
 
 use super::{appservice, send, Destination, Msg, SendingEvent, Service};
-use crate::{service::presence::Presence, services, user_is_local, utils::calculate_hash, Error, PduEvent, Result};
+use crate::{
+	rooms::event_handler::AclDecision, service::presence::Presence, services, user_is_local, utils::calculate_hash,
+	Error, PduEvent, Result,
+};
 
 #[derive(Debug)]
 enum TransactionStatus {
@@ -37,9 +42,13 @@ type SendingResult = Result<Destination, SendingError>;
 type SendingFuture<'a> = BoxFuture<'a, SendingResult>;
 type SendingFutures<'a> = FuturesUnordered<SendingFuture<'a>>;
 type CurTransactionStatus = HashMap<Destination, TransactionStatus>;
+type BatchFutures<'a> = FuturesUnordered<BoxFuture<'a, Destination>>;
 
 const DEQUEUE_LIMIT: usize = 48;
 const SELECT_EDU_LIMIT: usize = 16;
+/// Most PDUs and EDUs the spec allows in a single transaction.
+const TRANSACTION_PDU_LIMIT: usize = 50;
+const TRANSACTION_EDU_LIMIT: usize = 100;
 
 impl Service {
 	pub async fn start_handler(self: &Arc<Self>) {
@@ -58,6 +67,7 @@ impl Service {
 	async fn handler(&self) -> Result<()> {
 		let receiver = self.receiver.lock().await;
 		let mut futures: SendingFutures<'_> = FuturesUnordered::new();
+		let mut batches: BatchFutures<'_> = FuturesUnordered::new();
 		let mut statuses: CurTransactionStatus = CurTransactionStatus::new();
 
 		self.initial_transactions(&futures, &mut statuses);
@@ -65,12 +75,15 @@ impl Service {
 			debug_assert!(!receiver.is_closed(), "channel error");
 			tokio::select! {
 				request = receiver.recv_async() => match request {
-					Ok(request) => self.handle_request(request, &futures, &mut statuses),
+					Ok(request) => self.handle_request(request, &futures, &batches, &mut statuses),
 					Err(_) => return Ok(()),
 				},
 				Some(response) = futures.next() => {
 					self.handle_response(response, &mut futures, &mut statuses);
 				},
+				Some(dest) = batches.next() => {
+					self.handle_batch_ready(dest, &futures, &mut statuses);
+				},
 			}
 		}
 	}
@@ -100,6 +113,13 @@ impl Service {
 	fn handle_response_ok(
 		&self, dest: &Destination, futures: &SendingFutures<'_>, statuses: &mut CurTransactionStatus,
 	) {
+		if let Destination::Normal(server) = dest {
+			self.last_success
+				.write()
+				.expect("locked for writing")
+				.insert(server.clone(), Instant::now());
+		}
+
 		let _cork = services().globals.db.cork();
 		self.db
 			.delete_all_active_requests_for(dest)
@@ -125,7 +145,25 @@ impl Service {
 		}
 	}
 
-	fn handle_request(&self, msg: Msg, futures: &SendingFutures<'_>, statuses: &mut CurTransactionStatus) {
+	fn handle_request(
+		&self, msg: Msg, futures: &SendingFutures<'_>, batches: &BatchFutures<'_>, statuses: &mut CurTransactionStatus,
+	) {
+		// Hold the first event for an idle server back for the batching window, so
+		// anything queued meanwhile goes out in the same transaction. Marking the
+		// destination as running leaves later events in the queue.
+		if matches!(msg.dest, Destination::Normal(_))
+			&& !self.transaction_batch.is_zero()
+			&& !statuses.contains_key(&msg.dest)
+		{
+			statuses.insert(msg.dest.clone(), TransactionStatus::Running);
+			let window = self.transaction_batch;
+			batches.push(Box::pin(async move {
+				tokio::time::sleep(window).await;
+				msg.dest
+			}));
+			return;
+		}
+
 		let iv = vec![(msg.event, msg.queue_id)];
 		if let Ok(Some(events)) = self.select_events(&msg.dest, iv, statuses) {
 			if !events.is_empty() {
@@ -136,6 +174,19 @@ impl Service {
 		}
 	}
 
+	fn handle_batch_ready(&self, dest: Destination, futures: &SendingFutures<'_>, statuses: &mut CurTransactionStatus) {
+		statuses.remove(&dest);
+		let queued = self.db.queued_requests(&dest).filter_map(Result::ok);
+		let batch = transaction_batch(queued, TRANSACTION_PDU_LIMIT, TRANSACTION_EDU_LIMIT);
+		if let Ok(Some(events)) = self.select_events(&dest, batch, statuses) {
+			if !events.is_empty() {
+				futures.push(Box::pin(send_events(dest, events)));
+			} else {
+				statuses.remove(&dest);
+			}
+		}
+	}
+
 	fn initial_transactions(&self, futures: &SendingFutures<'_>, statuses: &mut CurTransactionStatus) {
 		let keep = usize::try_from(self.startup_netburst_keep).unwrap_or(usize::MAX);
 		let mut txns = HashMap::<Destination, Vec<SendingEvent>>::new();
@@ -195,11 +246,14 @@ impl Service {
 			}
 		}
 
-		// Add EDU's into the transaction
+		// Add EDU's into the transaction, in the room the queued ones leave
 		if let Destination::Normal(server_name) = dest {
-			if let Ok((select_edus, last_count)) = self.select_edus(server_name) {
-				events.extend(select_edus.into_iter().map(SendingEvent::Edu));
-				self.db.set_latest_educount(server_name, last_count)?;
+			let limit = edu_room(&events);
+			if limit > 0 {
+				if let Ok((select_edus, last_count)) = self.select_edus(server_name, limit) {
+					events.extend(select_edus.into_iter().map(SendingEvent::Edu));
+					self.db.set_latest_educount(server_name, last_count)?;
+				}
 			}
 		}
 
@@ -234,13 +288,15 @@ impl Service {
 		Ok((allow, retry))
 	}
 
+	/// Selects up to `limit` receipt, device list and presence EDUs for the
+	/// next transaction to `server_name`.
 	#[tracing::instrument(skip_all)]
-	fn select_edus(&self, server_name: &ServerName) -> Result<(Vec<Vec<u8>>, u64)> {
+	fn select_edus(&self, server_name: &ServerName, limit: usize) -> Result<(Vec<Vec<u8>>, u64)> {
 		// u64: count of last edu
 		let since = self.db.get_latest_educount(server_name)?;
 		let mut events = Vec::new();
 		let mut max_edu_count = since;
-		let mut device_list_changes = HashSet::new();
+		let mut device_list_changes = Vec::new();
 
 		for room_id in services().rooms.state_cache.server_rooms(server_name) {
 			let room_id = room_id?;
@@ -248,19 +304,40 @@ impl Service {
 			device_list_changes.extend(
 				services()
 					.users
-					.keys_changed(room_id.as_ref(), since, None)
+					.keys_changed_with_count(room_id.as_ref(), since, None)
 					.filter_map(Result::ok)
-					.filter(|user_id| user_is_local(user_id)),
+					.filter(|(_, user_id)| user_is_local(user_id)),
 			);
 
-			if services().globals.allow_outgoing_read_receipts()
-				&& !select_edus_receipts(&room_id, since, &mut max_edu_count, &mut events)?
-			{
+			if !services().globals.allow_outgoing_read_receipts() {
+				continue;
+			}
+
+			// server_rooms only yields rooms the destination is in, but it may still be
+			// banned from some of them by ACL
+			let acl = services()
+				.rooms
+				.event_handler
+				.acl_decision(server_name, &room_id)?;
+			let receipts = services()
+				.rooms
+				.read_receipt
+				.readreceipts_since(&room_id, since);
+			if !select_edus_receipts(
+				&room_id,
+				&acl,
+				receipts,
+				services().globals.server_name(),
+				&mut max_edu_count,
+				&mut events,
+				limit.min(SELECT_EDU_LIMIT),
+			)? {
 				break;
 			}
 		}
 
-		for user_id in device_list_changes {
+		let room = limit.saturating_sub(events.len());
+		for user_id in select_edus_device_changes(device_list_changes.into_iter(), room, &mut max_edu_count) {
 			// Empty prev id forces synapse to resync; because synapse resyncs,
 			// we can just insert placeholder data
 			let edu = Edu::DeviceListUpdate(DeviceListUpdateContent {
@@ -276,7 +353,7 @@ impl Service {
 			events.push(serde_json::to_vec(&edu).expect("json can be serialized"));
 		}
 
-		if services().globals.allow_outgoing_presence() {
+		if services().globals.allow_outgoing_presence() && events.len() < limit {
 			select_edus_presence(server_name, since, &mut max_edu_count, &mut events)?;
 		}
 
@@ -284,6 +361,69 @@ impl Service {
 	}
 }
 
+/// Takes queued events in order for one transaction, stopping before either
+/// the PDU or the EDU limit would be exceeded.
+fn transaction_batch<I>(queued: I, pdu_limit: usize, edu_limit: usize) -> Vec<(SendingEvent, Vec<u8>)>
+where
+	I: Iterator<Item = (SendingEvent, Vec<u8>)>,
+{
+	let (mut pdus, mut edus) = (0_usize, 0_usize);
+	queued
+		.take_while(|(event, _)| {
+			let count = match event {
+				SendingEvent::Pdu(_) => &mut pdus,
+				SendingEvent::Edu(_) => &mut edus,
+				SendingEvent::Flush => return true,
+			};
+			*count = count.saturating_add(1);
+			pdus <= pdu_limit && edus <= edu_limit
+		})
+		.collect()
+}
+
+/// Room left for EDUs in a transaction already holding `events`.
+fn edu_room(events: &[SendingEvent]) -> usize {
+	let edus = events
+		.iter()
+		.filter(|event| matches!(event, SendingEvent::Edu(_)))
+		.count();
+
+	TRANSACTION_EDU_LIMIT.saturating_sub(edus)
+}
+
+/// Picks the device list updates that fit in `room`, oldest first. The EDU
+/// count is advanced past those picked but kept below the first one left out,
+/// so the rest are selected again next time at the cost of repeating any
+/// receipts after it.
+fn select_edus_device_changes<I>(changes: I, room: usize, max_edu_count: &mut u64) -> Vec<OwnedUserId>
+where
+	I: Iterator<Item = (u64, OwnedUserId)>,
+{
+	// a user's devices are resynced in full, so only their latest change counts
+	let mut latest = HashMap::new();
+	for (count, user_id) in changes {
+		let latest = latest.entry(user_id).or_insert(count);
+		*latest = cmp::max(*latest, count);
+	}
+
+	let mut changes: Vec<_> = latest
+		.into_iter()
+		.map(|(user_id, count)| (count, user_id))
+		.collect();
+	changes.sort_unstable();
+
+	if let Some(&(first_left_out, _)) = changes.get(room) {
+		*max_edu_count = cmp::min(*max_edu_count, first_left_out.saturating_sub(1));
+		changes.truncate(room);
+	}
+
+	if let Some(&(last_picked, _)) = changes.last() {
+		*max_edu_count = cmp::max(*max_edu_count, last_picked);
+	}
+
+	changes.into_iter().map(|(_, user_id)| user_id).collect()
+}
+
 /// Look for presence
 fn select_edus_presence(
 	server_name: &ServerName, since: u64, max_edu_count: &mut u64, events: &mut Vec<Vec<u8>>,
@@ -328,19 +468,23 @@ fn select_edus_presence(
 	Ok(true)
 }
 
-/// Look for read receipts in this room
-fn select_edus_receipts(
-	room_id: &RoomId, since: u64, max_edu_count: &mut u64, events: &mut Vec<Vec<u8>>,
-) -> Result<bool> {
-	for r in services()
-		.rooms
-		.read_receipt
-		.readreceipts_since(room_id, since)
-	{
+/// Look for read receipts in this room, until `events` holds `limit` EDUs
+fn select_edus_receipts<I>(
+	room_id: &RoomId, acl: &AclDecision, receipts: I, ours: &ServerName, max_edu_count: &mut u64,
+	events: &mut Vec<Vec<u8>>, limit: usize,
+) -> Result<bool>
+where
+	I: Iterator<Item = Result<(OwnedUserId, u64, Raw<AnySyncEphemeralRoomEvent>)>>,
+{
+	if !acl.is_allowed() {
+		return Ok(true);
+	}
+
+	for r in receipts {
 		let (user_id, count, read_receipt) = r?;
 		*max_edu_count = cmp::max(count, *max_edu_count);
 
-		if !user_is_local(&user_id) {
+		if user_id.server_name() != ours {
 			continue;
 		}
 
@@ -386,7 +530,7 @@ fn select_edus_receipts(
 
 		events.push(serde_json::to_vec(&federation_event).expect("json can be serialized"));
 
-		if events.len() >= SELECT_EDU_LIMIT {
+		if events.len() >= limit {
 			return Ok(false);
 		}
 	}
@@ -616,3 +760,138 @@ async fn send_events_dest_normal(
 	})
 	.map_err(|e| (dest.clone(), e))
 }
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		events::{room::server_acl::RoomServerAclEventContent, AnySyncEphemeralRoomEvent},
+		room_id,
+		serde::Raw,
+		server_name, user_id, OwnedUserId,
+	};
+	use serde_json::json;
+
+	use super::{
+		edu_room, select_edus_device_changes, select_edus_receipts, transaction_batch, SendingEvent,
+		TRANSACTION_EDU_LIMIT, TRANSACTION_PDU_LIMIT,
+	};
+	use crate::{rooms::event_handler::acl_decision, Result};
+
+	fn queued(events: impl Iterator<Item = SendingEvent>) -> Vec<(SendingEvent, Vec<u8>)> {
+		events
+			.enumerate()
+			.map(|(i, event)| (event, i.to_be_bytes().to_vec()))
+			.collect()
+	}
+
+	#[test]
+	fn quickly_queued_pdus_batch_into_one_transaction() {
+		let pdus = queued((0_u8..5).map(|i| SendingEvent::Pdu(vec![i])));
+
+		let batch = transaction_batch(pdus.clone().into_iter(), TRANSACTION_PDU_LIMIT, TRANSACTION_EDU_LIMIT);
+		assert_eq!(batch, pdus);
+	}
+
+	#[test]
+	fn batch_respects_transaction_limits() {
+		let pdus = queued((0_u8..60).map(|i| SendingEvent::Pdu(vec![i])));
+		let batch = transaction_batch(pdus.clone().into_iter(), TRANSACTION_PDU_LIMIT, TRANSACTION_EDU_LIMIT);
+		assert_eq!(batch, pdus[..TRANSACTION_PDU_LIMIT]);
+
+		let mixed = queued(
+			(0_u8..120)
+				.map(|i| SendingEvent::Edu(vec![i]))
+				.chain([SendingEvent::Pdu(vec![0])]),
+		);
+		let batch = transaction_batch(mixed.clone().into_iter(), TRANSACTION_PDU_LIMIT, TRANSACTION_EDU_LIMIT);
+		assert_eq!(batch, mixed[..TRANSACTION_EDU_LIMIT]);
+	}
+
+	#[test]
+	fn selected_edus_fit_beside_queued_ones() {
+		let edus = queued((0_u8..120).map(|i| SendingEvent::Edu(vec![i])));
+		let batch: Vec<_> = transaction_batch(edus.into_iter(), TRANSACTION_PDU_LIMIT, TRANSACTION_EDU_LIMIT)
+			.into_iter()
+			.map(|(event, _)| event)
+			.collect();
+		assert_eq!(edu_room(&batch), 0);
+
+		let mut events: Vec<_> = (0_u8..30).map(|i| SendingEvent::Edu(vec![i])).collect();
+		events.extend((0_u8..40).map(|i| SendingEvent::Pdu(vec![i])));
+		assert_eq!(edu_room(&events), TRANSACTION_EDU_LIMIT - 30);
+	}
+
+	#[test]
+	fn acl_banned_server_gets_no_read_receipts() {
+		let room_id = room_id!("!room:example.com");
+		let receipts = || {
+			[("@alice:example.com", 3), ("@bob:remote.example", 4)]
+				.into_iter()
+				.map(|(user_id, count)| -> Result<_> {
+					let event = Raw::new(&json!({
+						"type": "m.receipt",
+						"content": { "$event:example.com": { "m.read": { user_id: { "ts": 1 } } } },
+					}))
+					.unwrap()
+					.cast::<AnySyncEphemeralRoomEvent>();
+					Ok((OwnedUserId::try_from(user_id).unwrap(), count, event))
+				})
+		};
+		let acl = RoomServerAclEventContent::new(false, vec!["*".to_owned()], vec!["*.evil.example".to_owned()]);
+
+		let (mut max_edu_count, mut events) = (0, Vec::new());
+		let denied = acl_decision(&acl, server_name!("matrix.evil.example"));
+		select_edus_receipts(
+			room_id,
+			&denied,
+			receipts(),
+			server_name!("example.com"),
+			&mut max_edu_count,
+			&mut events,
+			10,
+		)
+		.unwrap();
+		assert!(events.is_empty());
+
+		// an allowed server only gets the receipts of our own users
+		let allowed = acl_decision(&acl, server_name!("remote.example"));
+		select_edus_receipts(
+			room_id,
+			&allowed,
+			receipts(),
+			server_name!("example.com"),
+			&mut max_edu_count,
+			&mut events,
+			10,
+		)
+		.unwrap();
+		assert_eq!(events.len(), 1);
+		assert_eq!(max_edu_count, 4);
+		let edu: serde_json::Value = serde_json::from_slice(&events[0]).unwrap();
+		assert!(edu["content"][room_id.as_str()]["m.read"][user_id!("@alice:example.com").as_str()].is_object());
+	}
+
+	#[test]
+	fn device_list_updates_that_dont_fit_are_selected_again() {
+		let alice = user_id!("@alice:example.com").to_owned();
+		let bob = user_id!("@bob:example.com").to_owned();
+		let carol = user_id!("@carol:example.com").to_owned();
+		let changes = || vec![(5, alice.clone()), (7, bob.clone()), (9, carol.clone()), (6, alice.clone())].into_iter();
+
+		// receipts were selected up to count 20, but carol's update only fits next time
+		let mut max_edu_count = 20;
+		let picked = select_edus_device_changes(changes(), 2, &mut max_edu_count);
+		assert_eq!(picked, [alice.clone(), bob.clone()]);
+		assert_eq!(max_edu_count, 8);
+
+		let mut max_edu_count = 3;
+		let picked = select_edus_device_changes(changes(), 10, &mut max_edu_count);
+		assert_eq!(picked, [alice, bob, carol]);
+		assert_eq!(max_edu_count, 9);
+
+		// nothing fits, so everything from alice's latest update is selected again
+		let mut max_edu_count = 20;
+		assert!(select_edus_device_changes(changes(), 0, &mut max_edu_count).is_empty());
+		assert_eq!(max_edu_count, 5);
+	}
+}
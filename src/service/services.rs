@@ -1,6 +1,7 @@
 use std::{
 	collections::{BTreeMap, HashMap},
 	sync::{atomic, Arc, Mutex as StdMutex},
+	time::Duration,
 };
 
 use conduit::{debug_info, Result, Server};
@@ -39,6 +40,9 @@ impl Services {
 			appservice: appservice::Service::build(db.clone())?,
 			pusher: pusher::Service {
 				db: db.clone(),
+				mailer: pusher::mailer(config),
+				pusher_failures: RwLock::new(HashMap::new()),
+				gateway_ratelimiter: RwLock::new(HashMap::new()),
 			},
 			rooms: rooms::Service {
 				alias: rooms::alias::Service {
@@ -99,6 +103,7 @@ impl Services {
 				timeline: rooms::timeline::Service {
 					db: db.clone(),
 					lasttimelinecount_cache: Mutex::new(HashMap::new()),
+					bumpstamp_cache: StdMutex::new(HashMap::new()),
 				},
 				threads: rooms::threads::Service {
 					db: db.clone(),
@@ -116,6 +121,10 @@ impl Services {
 				},
 				user: rooms::user::Service {
 					db: db.clone(),
+					missed_calls: StdMutex::new(LruCache::new(
+						(f64::from(rooms::user::MISSED_CALLS_CACHE_CAPACITY) * config.conduit_cache_capacity_modifier)
+							as usize,
+					)),
 				},
 			},
 			transaction_ids: transaction_ids::Service {
@@ -126,7 +135,9 @@ impl Services {
 			},
 			users: users::Service {
 				db: db.clone(),
-				connections: StdMutex::new(BTreeMap::new()),
+				connections: users::SyncConnections::default(),
+				profile_cache: users::ProfileCache::new(Duration::from_secs(config.federation_profile_cache_ttl_s)),
+				threepid_sessions: StdMutex::new(users::ThreepidSessions::default()),
 			},
 			account_data: account_data::Service {
 				db: db.clone(),
@@ -189,6 +200,8 @@ impl Services {
 		let bad_event_ratelimiter = self.globals.bad_event_ratelimiter.read().await.len();
 		let bad_query_ratelimiter = self.globals.bad_query_ratelimiter.read().await.len();
 		let bad_signature_ratelimiter = self.globals.bad_signature_ratelimiter.read().await.len();
+		let invite_user_ratelimiter = self.globals.invite_user_ratelimiter.read().await.len();
+		let invite_room_ratelimiter = self.globals.invite_room_ratelimiter.read().await.len();
 
 		format!(
 			"\
@@ -203,6 +216,8 @@ resolver_destinations_cache: {resolver_destinations_cache}
 bad_event_ratelimiter: {bad_event_ratelimiter}
 bad_query_ratelimiter: {bad_query_ratelimiter}
 bad_signature_ratelimiter: {bad_signature_ratelimiter}
+invite_user_ratelimiter: {invite_user_ratelimiter}
+invite_room_ratelimiter: {invite_room_ratelimiter}
 "
 		)
 	}
@@ -295,6 +310,29 @@ bad_signature_ratelimiter: {bad_signature_ratelimiter}
 			}
 		}
 
+		if self.globals.config.database_backup_interval_hours > 0 && self.globals.config.database_backend == "rocksdb" {
+			let handle = globals::backup::start_backup_task(self.globals.config.database_backup_interval_hours);
+
+			#[allow(clippy::let_underscore_must_use)] // needed for shutdown
+			{
+				_ = self.globals.backup_handle.lock().await.insert(handle);
+			}
+		}
+
+		if self.globals.config.to_device_ttl_days > 0 {
+			let handle = globals::to_device::start_to_device_cleanup_task(self.globals.config.to_device_ttl_days);
+
+			#[allow(clippy::let_underscore_must_use)] // needed for shutdown
+			{
+				_ = self
+					.globals
+					.to_device_cleanup_handle
+					.lock()
+					.await
+					.insert(handle);
+			}
+		}
+
 		debug_info!("Services startup complete.");
 		Ok(())
 	}
@@ -325,6 +363,26 @@ bad_signature_ratelimiter: {bad_signature_ratelimiter}
 			}
 		}
 
+		debug!("Waiting for backup worker...");
+		if let Some(backup_handle) = self.globals.backup_handle.lock().await.take() {
+			backup_handle.abort();
+
+			#[allow(clippy::let_underscore_must_use)]
+			{
+				_ = backup_handle.await;
+			}
+		}
+
+		debug!("Waiting for to-device cleanup worker...");
+		if let Some(cleanup_handle) = self.globals.to_device_cleanup_handle.lock().await.take() {
+			cleanup_handle.abort();
+
+			#[allow(clippy::let_underscore_must_use)]
+			{
+				_ = cleanup_handle.await;
+			}
+		}
+
 		debug!("Waiting for admin worker...");
 		self.admin.close().await;
 
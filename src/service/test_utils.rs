@@ -0,0 +1,64 @@
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Mutex};
+
+use crate::{database::KvTree, Result};
+
+/// An in-memory `KvTree` for testing database code without a database.
+#[derive(Default)]
+pub(crate) struct MemoryTree(Mutex<BTreeMap<Vec<u8>, Vec<u8>>>);
+
+impl MemoryTree {
+	fn entries(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+		self.0
+			.lock()
+			.unwrap()
+			.iter()
+			.map(|(k, v)| (k.clone(), v.clone()))
+			.collect()
+	}
+}
+
+impl KvTree for MemoryTree {
+	fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> { Ok(self.0.lock().unwrap().get(key).cloned()) }
+
+	fn insert(&self, key: &[u8], value: &[u8]) -> Result<()> {
+		self.0.lock().unwrap().insert(key.to_vec(), value.to_vec());
+		Ok(())
+	}
+
+	fn remove(&self, key: &[u8]) -> Result<()> {
+		self.0.lock().unwrap().remove(key);
+		Ok(())
+	}
+
+	fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> { Box::new(self.entries().into_iter()) }
+
+	fn iter_from<'a>(&'a self, from: &[u8], backwards: bool) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+		let mut entries = self.entries();
+		entries.retain(|(k, _)| {
+			if backwards {
+				k.as_slice() <= from
+			} else {
+				k.as_slice() >= from
+			}
+		});
+		if backwards {
+			entries.reverse();
+		}
+
+		Box::new(entries.into_iter())
+	}
+
+	fn increment(&self, _key: &[u8]) -> Result<Vec<u8>> { unimplemented!() }
+
+	fn scan_prefix<'a>(&'a self, prefix: Vec<u8>) -> Box<dyn Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> {
+		Box::new(
+			self.entries()
+				.into_iter()
+				.filter(move |(k, _)| k.starts_with(&prefix)),
+		)
+	}
+
+	fn watch_prefix<'a>(&'a self, _prefix: &[u8]) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> {
+		Box::pin(std::future::pending())
+	}
+}
@@ -3,9 +3,9 @@ mod data;
 use std::sync::Arc;
 
 use data::Data;
-use ruma::{DeviceId, TransactionId, UserId};
+use ruma::{api::client::error::ErrorKind, DeviceId, OwnedEventId, TransactionId, UserId};
 
-use crate::Result;
+use crate::{utils, Error, Result};
 
 pub struct Service {
 	pub(super) db: Arc<dyn Data>,
@@ -23,4 +23,130 @@ impl Service {
 	) -> Result<Option<Vec<u8>>> {
 		self.db.existing_txnid(user_id, device_id, txn_id)
 	}
+
+	/// Returns the event ID previously created by this device with `txn_id`,
+	/// so that a retried event send can return it instead of creating a
+	/// duplicate event.
+	pub fn existing_event_id(
+		&self, user_id: &UserId, device_id: Option<&DeviceId>, txn_id: &TransactionId,
+	) -> Result<Option<OwnedEventId>> {
+		let Some(response) = self.existing_txnid(user_id, device_id, txn_id)? else {
+			return Ok(None);
+		};
+
+		// The client might have sent a txnid of the /sendToDevice endpoint
+		// This txnid has no response associated with it
+		if response.is_empty() {
+			return Err(Error::BadRequest(
+				ErrorKind::InvalidParam,
+				"Tried to use txn id already used for an incompatible endpoint.",
+			));
+		}
+
+		let event_id = utils::string_from_bytes(&response)
+			.map_err(|_| Error::bad_database("Invalid txnid bytes in database."))?
+			.try_into()
+			.map_err(|_| Error::bad_database("Invalid event id in txnid data."))?;
+
+		Ok(Some(event_id))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{collections::HashMap, sync::Mutex};
+
+	use ruma::{device_id, event_id, user_id, TransactionId};
+
+	use super::*;
+
+	#[derive(Default)]
+	struct MockedKVDatabase {
+		txnids: Mutex<HashMap<Vec<u8>, Vec<u8>>>,
+	}
+
+	impl MockedKVDatabase {
+		fn key(user_id: &UserId, device_id: Option<&DeviceId>, txn_id: &TransactionId) -> Vec<u8> {
+			[
+				user_id.as_bytes(),
+				device_id.map(DeviceId::as_bytes).unwrap_or_default(),
+				txn_id.as_bytes(),
+			]
+			.join(&0xFF)
+		}
+	}
+
+	impl Data for MockedKVDatabase {
+		fn add_txnid(
+			&self, user_id: &UserId, device_id: Option<&DeviceId>, txn_id: &TransactionId, data: &[u8],
+		) -> Result<()> {
+			self.txnids
+				.lock()
+				.unwrap()
+				.insert(Self::key(user_id, device_id, txn_id), data.to_vec());
+			Ok(())
+		}
+
+		fn existing_txnid(
+			&self, user_id: &UserId, device_id: Option<&DeviceId>, txn_id: &TransactionId,
+		) -> Result<Option<Vec<u8>>> {
+			Ok(self
+				.txnids
+				.lock()
+				.unwrap()
+				.get(&Self::key(user_id, device_id, txn_id))
+				.cloned())
+		}
+	}
+
+	#[test]
+	fn resend_with_same_txnid_returns_same_event_id() {
+		let service = Service {
+			db: Arc::new(MockedKVDatabase::default()),
+		};
+		let user_id = user_id!("@alice:example.com");
+		let device_id = device_id!("ALICEDEVICE");
+		let txn_id: &TransactionId = "m1234.1".into();
+		let event_id = event_id!("$first:example.com");
+
+		assert_eq!(
+			service
+				.existing_event_id(user_id, Some(device_id), txn_id)
+				.unwrap(),
+			None
+		);
+
+		service
+			.add_txnid(user_id, Some(device_id), txn_id, event_id.as_bytes())
+			.unwrap();
+
+		assert_eq!(
+			service
+				.existing_event_id(user_id, Some(device_id), txn_id)
+				.unwrap()
+				.as_deref(),
+			Some(event_id)
+		);
+
+		// a different device may reuse the same txn id
+		assert_eq!(
+			service
+				.existing_event_id(user_id, Some(device_id!("OTHERDEVICE")), txn_id)
+				.unwrap(),
+			None
+		);
+	}
+
+	#[test]
+	fn txnid_from_to_device_is_rejected() {
+		let service = Service {
+			db: Arc::new(MockedKVDatabase::default()),
+		};
+		let user_id = user_id!("@alice:example.com");
+		let txn_id: &TransactionId = "m1234.2".into();
+
+		service.add_txnid(user_id, None, txn_id, &[]).unwrap();
+
+		assert!(service.existing_event_id(user_id, None, txn_id).is_err());
+	}
 }
@@ -5,12 +5,14 @@ use ruma::{
 	encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
 	events::{AnyToDeviceEvent, StateEventType},
 	serde::Raw,
+	thirdparty::{Medium, ThirdPartyIdentifier, ThirdPartyIdentifierInit},
 	uint, DeviceId, DeviceKeyAlgorithm, DeviceKeyId, MilliSecondsSinceUnixEpoch, OwnedDeviceId, OwnedDeviceKeyId,
 	OwnedMxcUri, OwnedUserId, UInt, UserId,
 };
 use tracing::warn;
 
-use crate::{services, users::clean_signatures, utils, Error, KeyValueDatabase, Result};
+use super::SlidingSyncCache;
+use crate::{database::KvTree, services, users::clean_signatures, utils, Error, KeyValueDatabase, Result};
 
 pub trait Data: Send + Sync {
 	/// Check if a user has an account on this homeserver.
@@ -19,6 +21,22 @@ pub trait Data: Send + Sync {
 	/// Check if account is deactivated
 	fn is_deactivated(&self, user_id: &UserId) -> Result<bool>;
 
+	/// Check if account was registered as a guest
+	fn is_guest(&self, user_id: &UserId) -> Result<bool>;
+
+	/// Marks or unmarks an account as a guest account
+	fn set_guest(&self, user_id: &UserId, guest: bool) -> Result<()>;
+
+	/// Associates a validated third party identifier with the account
+	fn add_threepid(&self, user_id: &UserId, threepid: &ThirdPartyIdentifier) -> Result<()>;
+
+	/// Removes a third party identifier from the account, returning whether it
+	/// was associated
+	fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<bool>;
+
+	/// Returns the third party identifiers associated with the account
+	fn threepids(&self, user_id: &UserId) -> Result<Vec<ThirdPartyIdentifier>>;
+
 	/// Returns the number of users registered on this server.
 	fn count(&self) -> Result<usize>;
 
@@ -87,6 +105,24 @@ pub trait Data: Send + Sync {
 	fn count_one_time_keys(&self, user_id: &UserId, device_id: &DeviceId)
 		-> Result<BTreeMap<DeviceKeyAlgorithm, UInt>>;
 
+	/// Stores the device's fallback key for the key's algorithm, replacing any
+	/// previous one and marking it as unused.
+	fn add_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, fallback_key_key: &DeviceKeyId,
+		fallback_key_value: &Raw<OneTimeKey>,
+	) -> Result<()>;
+
+	/// Returns the device's fallback key for the algorithm and marks it as
+	/// used. Unlike one-time keys the fallback key is kept and handed out again
+	/// until the device uploads a new one.
+	fn take_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
+	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>>;
+
+	/// Returns the algorithms for which the device has a fallback key that has
+	/// not been claimed yet.
+	fn unused_fallback_key_types(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<DeviceKeyAlgorithm>>;
+
 	fn add_device_keys(&self, user_id: &UserId, device_id: &DeviceId, device_keys: &Raw<DeviceKeys>) -> Result<()>;
 
 	fn add_cross_signing_keys(
@@ -101,6 +137,11 @@ pub trait Data: Send + Sync {
 		&'a self, user_or_room_id: &str, from: u64, to: Option<u64>,
 	) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a>;
 
+	/// Like `keys_changed`, along with the count of each change
+	fn keys_changed_with_count<'a>(
+		&'a self, user_or_room_id: &str, from: u64, to: Option<u64>,
+	) -> Box<dyn Iterator<Item = Result<(u64, OwnedUserId)>> + 'a>;
+
 	fn mark_device_key_update(&self, user_id: &UserId) -> Result<()>;
 
 	fn get_device_keys(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<Raw<DeviceKeys>>>;
@@ -132,6 +173,27 @@ pub trait Data: Send + Sync {
 
 	fn remove_to_device_events(&self, user_id: &UserId, device_id: &DeviceId, until: u64) -> Result<()>;
 
+	/// Deletes to-device events queued before `older_than` (millis since the
+	/// unix epoch), returning how many were removed
+	fn remove_expired_to_device_events(&self, older_than: u64) -> Result<usize>;
+
+	/// Returns the number of undelivered to-device events for each of the
+	/// user's devices
+	fn to_device_backlog(&self, user_id: &UserId) -> Result<BTreeMap<OwnedDeviceId, usize>>;
+
+	/// Loads the persisted state of a sliding sync connection.
+	fn get_sliding_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str,
+	) -> Result<Option<SlidingSyncCache>>;
+
+	/// Persists the state of a sliding sync connection so it survives a
+	/// restart.
+	fn set_sliding_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, cache: &SlidingSyncCache,
+	) -> Result<()>;
+
+	fn remove_sliding_sync_connection(&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str) -> Result<()>;
+
 	fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()>;
 
 	/// Get device metadata.
@@ -160,6 +222,53 @@ impl Data for KeyValueDatabase {
 			.is_empty())
 	}
 
+	/// Check if account was registered as a guest
+	fn is_guest(&self, user_id: &UserId) -> Result<bool> { Ok(self.guestuserids.get(user_id.as_bytes())?.is_some()) }
+
+	/// Marks or unmarks an account as a guest account
+	fn set_guest(&self, user_id: &UserId, guest: bool) -> Result<()> {
+		if guest {
+			self.guestuserids.insert(user_id.as_bytes(), &[])?;
+		} else {
+			self.guestuserids.remove(user_id.as_bytes())?;
+		}
+
+		Ok(())
+	}
+
+	fn add_threepid(&self, user_id: &UserId, threepid: &ThirdPartyIdentifier) -> Result<()> {
+		let mut value = u64::from(threepid.validated_at.get())
+			.to_be_bytes()
+			.to_vec();
+		value.extend_from_slice(&u64::from(threepid.added_at.get()).to_be_bytes());
+
+		self.userthreepid_addedat
+			.insert(&threepid_key(user_id, &threepid.medium, &threepid.address), &value)
+	}
+
+	fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<bool> {
+		let key = threepid_key(user_id, medium, address);
+		if self.userthreepid_addedat.get(&key)?.is_none() {
+			return Ok(false);
+		}
+
+		self.userthreepid_addedat.remove(&key)?;
+		Ok(true)
+	}
+
+	fn threepids(&self, user_id: &UserId) -> Result<Vec<ThirdPartyIdentifier>> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+
+		self.userthreepid_addedat
+			.scan_prefix(prefix)
+			.map(|(key, value)| {
+				parse_threepid(&key, &value)
+					.ok_or_else(|| Error::bad_database("Third party identifier in userthreepid_addedat is invalid."))
+			})
+			.collect()
+	}
+
 	/// Returns the number of users registered on this server.
 	fn count(&self) -> Result<usize> { Ok(self.userid_password.iter().count()) }
 
@@ -369,12 +478,21 @@ impl Data for KeyValueDatabase {
 		let mut prefix = userdeviceid.clone();
 		prefix.push(0xFF);
 
-		for (key, _) in self.todeviceid_events.scan_prefix(prefix) {
+		for (key, _) in self.todeviceid_events.scan_prefix(prefix.clone()) {
 			self.todeviceid_events.remove(&key)?;
+			self.todeviceid_timestamp.remove(&key)?;
 		}
 
 		// TODO: Remove onetimekeys
 
+		for (key, _) in self.fallbackkeyid_fallbackkey.scan_prefix(prefix.clone()) {
+			self.fallbackkeyid_fallbackkey.remove(&key)?;
+		}
+
+		for (key, _) in self.userdeviceconnid_slidingsync.scan_prefix(prefix) {
+			self.userdeviceconnid_slidingsync.remove(&key)?;
+		}
+
 		self.userid_devicelistversion
 			.increment(user_id.as_bytes())?;
 
@@ -551,6 +669,58 @@ impl Data for KeyValueDatabase {
 		Ok(counts)
 	}
 
+	fn add_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, fallback_key_key: &DeviceKeyId,
+		fallback_key_value: &Raw<OneTimeKey>,
+	) -> Result<()> {
+		let key = fallback_key_id(user_id, device_id, &fallback_key_key.algorithm());
+
+		self.fallbackkeyid_fallbackkey
+			.insert(&key, &fallback_key_value_bytes(false, fallback_key_key, fallback_key_value))?;
+
+		self.userid_lastonetimekeyupdate
+			.insert(user_id.as_bytes(), &services().globals.next_count()?.to_be_bytes())?;
+
+		Ok(())
+	}
+
+	fn take_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
+	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+		let key = fallback_key_id(user_id, device_id, key_algorithm);
+		let Some(value) = self.fallbackkeyid_fallbackkey.get(&key)? else {
+			return Ok(None);
+		};
+
+		let (key_id, fallback_key, newly_used) =
+			use_fallback_key(&value).ok_or_else(|| Error::bad_database("Fallback key in db is invalid."))?;
+
+		if let Some(newly_used) = newly_used {
+			self.fallbackkeyid_fallbackkey.insert(&key, &newly_used)?;
+
+			self.userid_lastonetimekeyupdate
+				.insert(user_id.as_bytes(), &services().globals.next_count()?.to_be_bytes())?;
+		}
+
+		Ok(Some((key_id, fallback_key)))
+	}
+
+	fn unused_fallback_key_types(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<DeviceKeyAlgorithm>> {
+		let mut prefix = user_id.as_bytes().to_vec();
+		prefix.push(0xFF);
+		prefix.extend_from_slice(device_id.as_bytes());
+		prefix.push(0xFF);
+
+		self.fallbackkeyid_fallbackkey
+			.scan_prefix(prefix)
+			.filter_map(|(_, value)| match parse_fallback_key(&value) {
+				Some((false, key_id, _)) => Some(Ok(key_id.algorithm())),
+				Some((true, ..)) => None,
+				None => Some(Err(Error::bad_database("Fallback key in db is invalid."))),
+			})
+			.collect()
+	}
+
 	fn add_device_keys(&self, user_id: &UserId, device_id: &DeviceId, device_keys: &Raw<DeviceKeys>) -> Result<()> {
 		let mut userdeviceid = user_id.as_bytes().to_vec();
 		userdeviceid.push(0xFF);
@@ -688,6 +858,15 @@ impl Data for KeyValueDatabase {
 	fn keys_changed<'a>(
 		&'a self, user_or_room_id: &str, from: u64, to: Option<u64>,
 	) -> Box<dyn Iterator<Item = Result<OwnedUserId>> + 'a> {
+		Box::new(
+			self.keys_changed_with_count(user_or_room_id, from, to)
+				.map(|change| change.map(|(_, user_id)| user_id)),
+		)
+	}
+
+	fn keys_changed_with_count<'a>(
+		&'a self, user_or_room_id: &str, from: u64, to: Option<u64>,
+	) -> Box<dyn Iterator<Item = Result<(u64, OwnedUserId)>> + 'a> {
 		let mut prefix = user_or_room_id.as_bytes().to_vec();
 		prefix.push(0xFF);
 
@@ -713,13 +892,19 @@ impl Data for KeyValueDatabase {
 							false
 						}
 				})
-				.map(|(_, bytes)| {
-					UserId::parse(
-						utils::string_from_bytes(&bytes).map_err(|_| {
+				.map(|(key, bytes)| {
+					let count = key
+						.splitn(2, |&b| b == 0xFF)
+						.nth(1)
+						.and_then(|count| utils::u64_from_bytes(count).ok())
+						.ok_or_else(|| Error::bad_database("Count in keychangeid_userid is invalid."))?;
+					let user_id =
+						UserId::parse(utils::string_from_bytes(&bytes).map_err(|_| {
 							Error::bad_database("User ID in devicekeychangeid_userid is invalid unicode.")
-						})?,
-					)
-					.map_err(|_| Error::bad_database("User ID in devicekeychangeid_userid is invalid."))
+						})?)
+						.map_err(|_| Error::bad_database("User ID in devicekeychangeid_userid is invalid."))?;
+
+					Ok((count, user_id))
 				}),
 		)
 	}
@@ -854,6 +1039,8 @@ impl Data for KeyValueDatabase {
 		let value = serde_json::to_vec(&json).expect("Map::to_vec always works");
 
 		self.todeviceid_events.insert(&key, &value)?;
+		self.todeviceid_timestamp
+			.insert(&key, &utils::millis_since_unix_epoch().to_be_bytes())?;
 
 		Ok(())
 	}
@@ -900,11 +1087,55 @@ impl Data for KeyValueDatabase {
 			.take_while(|&(_, count)| count <= until)
 		{
 			self.todeviceid_events.remove(&key)?;
+			self.todeviceid_timestamp.remove(&key)?;
 		}
 
 		Ok(())
 	}
 
+	fn remove_expired_to_device_events(&self, older_than: u64) -> Result<usize> {
+		expire_to_device_events(
+			&*self.todeviceid_events,
+			&*self.todeviceid_timestamp,
+			utils::millis_since_unix_epoch(),
+			older_than,
+		)
+	}
+
+	fn to_device_backlog(&self, user_id: &UserId) -> Result<BTreeMap<OwnedDeviceId, usize>> {
+		count_to_device_backlog(&*self.todeviceid_events, user_id)
+	}
+
+	fn get_sliding_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str,
+	) -> Result<Option<SlidingSyncCache>> {
+		self.userdeviceconnid_slidingsync
+			.get(&sliding_sync_key(user_id, device_id, conn_id))?
+			.map(|bytes| {
+				serde_json::from_slice(&bytes)
+					.map_err(|_| Error::bad_database("Invalid sliding sync connection in db."))
+			})
+			.transpose()
+	}
+
+	fn set_sliding_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, cache: &SlidingSyncCache,
+	) -> Result<()> {
+		self.userdeviceconnid_slidingsync.insert(
+			&sliding_sync_key(user_id, device_id, conn_id),
+			&serde_json::to_vec(cache).expect("SlidingSyncCache is valid JSON"),
+		)?;
+
+		Ok(())
+	}
+
+	fn remove_sliding_sync_connection(&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str) -> Result<()> {
+		self.userdeviceconnid_slidingsync
+			.remove(&sliding_sync_key(user_id, device_id, conn_id))?;
+
+		Ok(())
+	}
+
 	fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()> {
 		let mut userdeviceid = user_id.as_bytes().to_vec();
 		userdeviceid.push(0xFF);
@@ -1020,3 +1251,272 @@ fn get_username_with_valid_password(username: &[u8], password: &[u8]) -> Option<
 		}
 	}
 }
+
+/// Key for a third party identifier: user ID, medium and address separated by
+/// 0xFF.
+fn threepid_key(user_id: &UserId, medium: &Medium, address: &str) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(medium.as_str().as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(address.as_bytes());
+	key
+}
+
+/// Parses a `threepid_key` and its value (validated_at and added_at as big
+/// endian milliseconds) back into the identifier.
+fn parse_threepid(key: &[u8], value: &[u8]) -> Option<ThirdPartyIdentifier> {
+	let mut parts = key.splitn(3, |&b| b == 0xFF).skip(1);
+	let medium = utils::string_from_bytes(parts.next()?).ok()?;
+	let address = utils::string_from_bytes(parts.next()?).ok()?;
+
+	let timestamp = |bytes: &[u8]| {
+		let millis = u64::from_be_bytes(bytes.try_into().ok()?);
+		Some(MilliSecondsSinceUnixEpoch(UInt::try_from(millis).ok()?))
+	};
+
+	Some(
+		ThirdPartyIdentifierInit {
+			address,
+			medium: medium.into(),
+			validated_at: timestamp(value.get(..8)?)?,
+			added_at: timestamp(value.get(8..16)?)?,
+		}
+		.into(),
+	)
+}
+
+/// Key for a device's fallback key: user ID, device ID and algorithm separated
+/// by 0xFF. A device holds at most one fallback key per algorithm.
+fn fallback_key_id(user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(device_id.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(key_algorithm.as_ref().as_bytes());
+	key
+}
+
+/// Value for a fallback key: a single used flag byte followed by the key ID and
+/// key as JSON.
+pub(super) fn fallback_key_value_bytes(used: bool, key_id: &DeviceKeyId, fallback_key: &Raw<OneTimeKey>) -> Vec<u8> {
+	let mut value = vec![u8::from(used)];
+	value.extend_from_slice(&serde_json::to_vec(&(key_id, fallback_key)).expect("fallback key serializes to JSON"));
+	value
+}
+
+/// Claims a stored fallback key. It stays stored to be handed out again, and
+/// the value marking it used is returned to store if it wasn't already.
+pub(super) fn use_fallback_key(value: &[u8]) -> Option<(OwnedDeviceKeyId, Raw<OneTimeKey>, Option<Vec<u8>>)> {
+	let (used, key_id, fallback_key) = parse_fallback_key(value)?;
+	let newly_used = (!used).then(|| fallback_key_value_bytes(true, &key_id, &fallback_key));
+
+	Some((key_id, fallback_key, newly_used))
+}
+
+/// Parses a `fallback_key_value_bytes` value back into the used flag, key ID and
+/// key.
+pub(super) fn parse_fallback_key(value: &[u8]) -> Option<(bool, OwnedDeviceKeyId, Raw<OneTimeKey>)> {
+	let (used, json) = value.split_first()?;
+	let (key_id, fallback_key) = serde_json::from_slice(json).ok()?;
+	Some((*used != 0, key_id, fallback_key))
+}
+
+fn sliding_sync_key(user_id: &UserId, device_id: &DeviceId, conn_id: &str) -> Vec<u8> {
+	let mut key = user_id.as_bytes().to_vec();
+	key.push(0xFF);
+	key.extend_from_slice(device_id.as_bytes());
+	key.push(0xFF);
+	key.extend_from_slice(conn_id.as_bytes());
+	key
+}
+
+/// Whether a to-device event queued at `timestamp` (big-endian millis) is
+/// older than `older_than`. Unreadable timestamps count as expired.
+fn to_device_expired(timestamp: &[u8], older_than: u64) -> bool {
+	utils::u64_from_bytes(timestamp).map_or(true, |queued| queued < older_than)
+}
+
+/// Removes the to-device events queued before `older_than`, returning how
+/// many were removed. Events queued before timestamps were recorded are
+/// stamped with `now` instead.
+fn expire_to_device_events(events: &dyn KvTree, timestamps: &dyn KvTree, now: u64, older_than: u64) -> Result<usize> {
+	let now = now.to_be_bytes();
+	let mut removed: usize = 0;
+
+	for (key, _) in events.iter() {
+		match timestamps.get(&key)? {
+			// queued before timestamps were recorded; the TTL starts counting now
+			None => timestamps.insert(&key, &now)?,
+			Some(timestamp) if to_device_expired(&timestamp, older_than) => {
+				events.remove(&key)?;
+				timestamps.remove(&key)?;
+				removed = removed.saturating_add(1);
+			},
+			Some(_) => {},
+		}
+	}
+
+	Ok(removed)
+}
+
+/// Counts the to-device events queued for each device of `user_id`.
+fn count_to_device_backlog(events: &dyn KvTree, user_id: &UserId) -> Result<BTreeMap<OwnedDeviceId, usize>> {
+	let mut prefix = user_id.as_bytes().to_vec();
+	prefix.push(0xFF);
+
+	let mut backlog = BTreeMap::<OwnedDeviceId, usize>::new();
+	for (key, _) in events.scan_prefix(prefix.clone()) {
+		// device ID, then 0xFF and the count
+		let device_id = key
+			.get(prefix.len()..key.len().saturating_sub(size_of::<u64>() + 1))
+			.ok_or_else(|| Error::bad_database("ToDeviceId in todeviceid_events is invalid."))?;
+		let device_id = utils::string_from_bytes(device_id)
+			.map_err(|_| Error::bad_database("ToDeviceId in todeviceid_events has invalid device ID."))?;
+
+		let count = backlog.entry(device_id.into()).or_default();
+		*count = count.saturating_add(1);
+	}
+
+	Ok(backlog)
+}
+
+#[cfg(test)]
+mod tests {
+	use ruma::{
+		device_id, device_key_id, encryption::OneTimeKey, serde::Raw, thirdparty::Medium, user_id, DeviceId,
+		DeviceKeyAlgorithm, MilliSecondsSinceUnixEpoch, UInt, UserId,
+	};
+
+	use super::{
+		count_to_device_backlog, expire_to_device_events, fallback_key_id, fallback_key_value_bytes,
+		parse_fallback_key, parse_threepid, threepid_key,
+	};
+	use crate::{database::KvTree, test_utils::MemoryTree};
+
+	/// Queues a to-device event as `add_to_device_event` does, at `timestamp`
+	/// if given.
+	fn queue(
+		events: &MemoryTree, timestamps: &MemoryTree, user: &UserId, device: &DeviceId, count: u64,
+		timestamp: Option<u64>,
+	) {
+		let mut key = user.as_bytes().to_vec();
+		key.push(0xFF);
+		key.extend_from_slice(device.as_bytes());
+		key.push(0xFF);
+		key.extend_from_slice(&count.to_be_bytes());
+
+		events.insert(&key, b"{}").unwrap();
+		if let Some(timestamp) = timestamp {
+			timestamps.insert(&key, &timestamp.to_be_bytes()).unwrap();
+		}
+	}
+
+	#[test]
+	fn threepid_key_round_trips() {
+		let key = threepid_key(user_id!("@alice:example.com"), &Medium::Email, "alice@example.com");
+		let mut value = 1_000_u64.to_be_bytes().to_vec();
+		value.extend_from_slice(&2_000_u64.to_be_bytes());
+
+		let threepid = parse_threepid(&key, &value).unwrap();
+		assert_eq!(threepid.medium, Medium::Email);
+		assert_eq!(threepid.address, "alice@example.com");
+		assert_eq!(threepid.validated_at, MilliSecondsSinceUnixEpoch(UInt::from(1_000_u32)));
+		assert_eq!(threepid.added_at, MilliSecondsSinceUnixEpoch(UInt::from(2_000_u32)));
+	}
+
+	#[test]
+	fn threepid_keys_differ_by_medium_and_address() {
+		let alice = user_id!("@alice:example.com");
+		assert_ne!(
+			threepid_key(alice, &Medium::Email, "alice@example.com"),
+			threepid_key(alice, &Medium::Msisdn, "alice@example.com")
+		);
+		assert_ne!(
+			threepid_key(alice, &Medium::Email, "alice@example.com"),
+			threepid_key(alice, &Medium::Email, "bob@example.com")
+		);
+	}
+
+	#[test]
+	fn fallback_key_round_trips_with_used_flag() {
+		let key_id = device_key_id!("signed_curve25519:AAAAHg");
+		let key =
+			Raw::<OneTimeKey>::from_json_string(r#"{"key":"zKbLg+NrIjpnagy+pIY6uPL4ZwEG2v+8F9lmgsnlZzs"}"#.to_owned())
+				.unwrap();
+
+		let (used, parsed_id, parsed_key) = parse_fallback_key(&fallback_key_value_bytes(false, key_id, &key)).unwrap();
+		assert!(!used);
+		assert_eq!(parsed_id, key_id);
+		assert_eq!(parsed_key.json().get(), key.json().get());
+
+		// claiming marks the key used but hands out the same key again
+		let (used, parsed_id, _) =
+			parse_fallback_key(&fallback_key_value_bytes(true, &parsed_id, &parsed_key)).unwrap();
+		assert!(used);
+		assert_eq!(parsed_id, key_id);
+	}
+
+	#[test]
+	fn fallback_key_ids_are_per_device_and_algorithm() {
+		let alice = user_id!("@alice:example.com");
+		assert_ne!(
+			fallback_key_id(alice, device_id!("ABC"), &DeviceKeyAlgorithm::SignedCurve25519),
+			fallback_key_id(alice, device_id!("DEF"), &DeviceKeyAlgorithm::SignedCurve25519)
+		);
+		assert_ne!(
+			fallback_key_id(alice, device_id!("ABC"), &DeviceKeyAlgorithm::SignedCurve25519),
+			fallback_key_id(alice, device_id!("ABC"), &DeviceKeyAlgorithm::Curve25519)
+		);
+		assert!(parse_fallback_key(b"").is_none());
+	}
+
+	#[test]
+	fn expired_to_device_events_are_removed_and_fresh_ones_remain() {
+		let (events, timestamps) = (MemoryTree::default(), MemoryTree::default());
+		let alice = user_id!("@alice:example.com");
+		let older_than = 1_000_000_u64;
+		let now = older_than + 120_000;
+
+		queue(&events, &timestamps, alice, device_id!("STALE"), 1, Some(10));
+		queue(&events, &timestamps, alice, device_id!("STALE"), 2, Some(older_than - 1));
+		queue(&events, &timestamps, alice, device_id!("FRESH"), 3, Some(older_than));
+		queue(&events, &timestamps, alice, device_id!("FRESH"), 4, Some(older_than + 60_000));
+		// queued before timestamps were recorded
+		queue(&events, &timestamps, alice, device_id!("OLD"), 5, None);
+
+		assert_eq!(expire_to_device_events(&events, &timestamps, now, older_than).unwrap(), 2);
+
+		let backlog = count_to_device_backlog(&events, alice).unwrap();
+		assert_eq!(backlog.get(device_id!("STALE")), None);
+		assert_eq!(backlog.get(device_id!("FRESH")), Some(&2));
+		assert_eq!(backlog.get(device_id!("OLD")), Some(&1));
+
+		// the untimed event's TTL starts counting now rather than expiring it
+		assert_eq!(expire_to_device_events(&events, &timestamps, now, now).unwrap(), 2);
+		assert_eq!(
+			count_to_device_backlog(&events, alice)
+				.unwrap()
+				.get(device_id!("OLD")),
+			Some(&1)
+		);
+	}
+
+	#[test]
+	fn to_device_backlog_is_counted_per_device() {
+		let (events, timestamps) = (MemoryTree::default(), MemoryTree::default());
+		let alice = user_id!("@alice:example.com");
+		let bob = user_id!("@bob:example.com");
+
+		queue(&events, &timestamps, alice, device_id!("PHONE"), 1, Some(1));
+		queue(&events, &timestamps, alice, device_id!("PHONE"), 2, Some(1));
+		// a count containing 0xFF must not be taken for the separator
+		queue(&events, &timestamps, alice, device_id!("LAPTOP"), 0xFF00_u64, Some(1));
+		queue(&events, &timestamps, bob, device_id!("PHONE"), 3, Some(1));
+
+		let backlog = count_to_device_backlog(&events, alice).unwrap();
+		assert_eq!(backlog.len(), 2);
+		assert_eq!(backlog.get(device_id!("PHONE")), Some(&2));
+		assert_eq!(backlog.get(device_id!("LAPTOP")), Some(&1));
+	}
+}
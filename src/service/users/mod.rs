@@ -1,14 +1,16 @@
 mod data;
 use std::{
-	collections::{BTreeMap, BTreeSet},
+	collections::{BTreeMap, BTreeSet, HashMap},
 	mem,
 	sync::{Arc, Mutex},
+	time::{Duration, Instant},
 };
 
 use data::Data;
 use ruma::{
 	api::client::{
 		device::Device,
+		error::ErrorKind,
 		filter::FilterDefinition,
 		sync::sync_events::{
 			self,
@@ -18,24 +20,268 @@ use ruma::{
 	encryption::{CrossSigningKey, DeviceKeys, OneTimeKey},
 	events::AnyToDeviceEvent,
 	serde::Raw,
+	thirdparty::{Medium, ThirdPartyIdentifier},
 	DeviceId, DeviceKeyAlgorithm, DeviceKeyId, OwnedDeviceId, OwnedDeviceKeyId, OwnedMxcUri, OwnedRoomId, OwnedUserId,
 	UInt, UserId,
 };
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::{service, services, Error, Result};
+use crate::{service, services, utils, Error, Result};
 
+/// Sticky parameters and room positions of a sliding sync connection, kept
+/// in memory and persisted so connections survive a restart.
+#[derive(Default, Deserialize, Serialize)]
 pub struct SlidingSyncCache {
 	lists: BTreeMap<String, SyncRequestList>,
 	subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
 	known_rooms: BTreeMap<String, BTreeMap<OwnedRoomId, u64>>, // For every room, the roomsince number
 	extensions: ExtensionsConfig,
+	/// What was last written to the database, so unchanged connections
+	/// aren't rewritten on every request
+	#[serde(skip)]
+	persisted: Vec<u8>,
 }
 
-type DbConnections = Mutex<BTreeMap<(OwnedUserId, OwnedDeviceId, String), Arc<Mutex<SlidingSyncCache>>>>;
+impl SlidingSyncCache {
+	/// Writes the connection with `save` unless it's unchanged since it was
+	/// last loaded or saved.
+	fn persist(&mut self, save: impl FnOnce(&Self) -> Result<()>) -> Result<()> {
+		let json = serde_json::to_vec(self).expect("SlidingSyncCache is valid JSON");
+		if json == self.persisted {
+			return Ok(());
+		}
+
+		save(self)?;
+		self.persisted = json;
+
+		Ok(())
+	}
+}
+
+type ConnectionKey = (OwnedUserId, OwnedDeviceId, String);
+
+/// Sliding sync connections in memory, loaded from the database on first use.
+#[derive(Default)]
+pub struct SyncConnections {
+	connections: Mutex<BTreeMap<ConnectionKey, Arc<Mutex<SlidingSyncCache>>>>,
+}
+
+impl SyncConnections {
+	/// Returns the connection, calling `load` outside the lock if it isn't in
+	/// memory yet so a slow read doesn't hold up every other connection.
+	fn get_or_load(
+		&self, key: ConnectionKey, load: impl FnOnce(&ConnectionKey) -> SlidingSyncCache,
+	) -> Arc<Mutex<SlidingSyncCache>> {
+		if let Some(cached) = self.connections.lock().unwrap().get(&key) {
+			return Arc::clone(cached);
+		}
+
+		let mut loaded = load(&key);
+		loaded.persisted = serde_json::to_vec(&loaded).expect("SlidingSyncCache is valid JSON");
+
+		// another request on this connection may have loaded it meanwhile
+		Arc::clone(
+			self.connections
+				.lock()
+				.unwrap()
+				.entry(key)
+				.or_insert_with(|| Arc::new(Mutex::new(loaded))),
+		)
+	}
+
+	fn remove(&self, key: &ConnectionKey) { self.connections.lock().unwrap().remove(key); }
+}
+
+/// A local user's profile as served to other servers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+	pub displayname: Option<String>,
+	pub avatar_url: Option<OwnedMxcUri>,
+	pub blurhash: Option<String>,
+}
+
+/// Cached profiles at which expired ones are pruned, and new ones are not
+/// cached if none have expired
+const PROFILE_CACHE_MAX: usize = 10_000;
+
+/// Short-lived cache of local profiles for federation profile queries, which
+/// remote servers repeat for every event they see from a user. Only existing
+/// users are cached, entries are dropped once the profile changes, and a zero
+/// TTL disables caching.
+pub struct ProfileCache {
+	ttl: Duration,
+	entries: Mutex<HashMap<OwnedUserId, (Instant, Profile)>>,
+}
+
+impl ProfileCache {
+	pub fn new(ttl: Duration) -> Self {
+		Self {
+			ttl,
+			entries: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn get(&self, user_id: &UserId) -> Option<Profile> {
+		let entries = self.entries.lock().unwrap();
+		let (cached_at, profile) = entries.get(user_id)?;
+		(cached_at.elapsed() < self.ttl).then(|| profile.clone())
+	}
+
+	fn insert(&self, user_id: &UserId, profile: Profile) {
+		if self.ttl.is_zero() {
+			return;
+		}
+
+		let mut entries = self.entries.lock().unwrap();
+		if entries.len() >= PROFILE_CACHE_MAX {
+			entries.retain(|_, (cached_at, _)| cached_at.elapsed() < self.ttl);
+			if entries.len() >= PROFILE_CACHE_MAX {
+				return;
+			}
+		}
+
+		entries.insert(user_id.to_owned(), (Instant::now(), profile));
+	}
+
+	fn invalidate(&self, user_id: &UserId) { self.entries.lock().unwrap().remove(user_id); }
+
+	/// Returns the cached profile, or the one `load` finds, caching it. Users
+	/// `load` doesn't find get an empty profile which isn't cached.
+	fn get_or_load(&self, user_id: &UserId, load: impl FnOnce() -> Result<Option<Profile>>) -> Result<Profile> {
+		if let Some(profile) = self.get(user_id) {
+			return Ok(profile);
+		}
+
+		let Some(profile) = load()? else {
+			return Ok(Profile::default());
+		};
+		self.insert(user_id, profile.clone());
+
+		Ok(profile)
+	}
+
+	/// Runs the profile change `write`, then drops the cached profile so a query
+	/// racing the change can't cache the old one again.
+	fn update<T>(&self, user_id: &UserId, write: impl FnOnce() -> Result<T>) -> Result<T> {
+		let res = write();
+		self.invalidate(user_id);
+		res
+	}
+}
+
+/// How long a client has to validate an email address and add it
+const THREEPID_SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Pending validations at which new ones are refused until some expire
+const THREEPID_SESSIONS_MAX: usize = 10_000;
+
+/// Length of the session IDs and tokens of email validation sessions
+const THREEPID_SESSION_ID_LENGTH: usize = 32;
+
+/// Email validation sessions started through `requestToken`, keyed by session
+/// ID. A session is validated with the token emailed to the address and then
+/// ended by the client adding the address with the same client secret.
+#[derive(Default)]
+pub struct ThreepidSessions {
+	sessions: HashMap<String, ThreepidSession>,
+}
+
+struct ThreepidSession {
+	client_secret: String,
+	address: String,
+	send_attempt: UInt,
+	token: String,
+	validated: bool,
+	started: Instant,
+}
+
+impl ThreepidSessions {
+	/// Starts validating `address`, returning the session ID and the token to
+	/// email. The token is `None` when the client retried a send attempt it
+	/// already made, which must not send another email.
+	pub fn start(
+		&mut self, client_secret: &str, address: &str, send_attempt: UInt,
+	) -> Result<(String, Option<String>)> {
+		self.prune();
+
+		if let Some((sid, session)) = self
+			.sessions
+			.iter_mut()
+			.find(|(_, session)| session.client_secret == client_secret && session.address == address)
+		{
+			if send_attempt <= session.send_attempt {
+				return Ok((sid.clone(), None));
+			}
+
+			session.send_attempt = send_attempt;
+			return Ok((sid.clone(), Some(session.token.clone())));
+		}
+
+		if self.sessions.len() >= THREEPID_SESSIONS_MAX {
+			return Err(Error::BadRequest(
+				ErrorKind::LimitExceeded {
+					retry_after: None,
+				},
+				"Too many email addresses are being validated, try again later.",
+			));
+		}
+
+		let sid = utils::random_string(THREEPID_SESSION_ID_LENGTH);
+		let token = utils::random_string(THREEPID_SESSION_ID_LENGTH);
+		self.sessions.insert(
+			sid.clone(),
+			ThreepidSession {
+				client_secret: client_secret.to_owned(),
+				address: address.to_owned(),
+				send_attempt,
+				token: token.clone(),
+				validated: false,
+				started: Instant::now(),
+			},
+		);
+
+		Ok((sid, Some(token)))
+	}
+
+	/// Marks the session validated if `client_secret` and `token` are the ones
+	/// emailed for it.
+	pub fn validate(&mut self, sid: &str, client_secret: &str, token: &str) -> bool {
+		self.prune();
+
+		match self.sessions.get_mut(sid) {
+			Some(session) if session.client_secret == client_secret && session.token == token => {
+				session.validated = true;
+				true
+			},
+			_ => false,
+		}
+	}
+
+	/// Ends a validated session started with `client_secret`, returning the
+	/// address it validated.
+	pub fn finish(&mut self, sid: &str, client_secret: &str) -> Option<String> {
+		self.prune();
+
+		let session = self.sessions.get(sid)?;
+		if !session.validated || session.client_secret != client_secret {
+			return None;
+		}
+
+		self.sessions.remove(sid).map(|session| session.address)
+	}
+
+	fn prune(&mut self) {
+		self.sessions
+			.retain(|_, session| session.started.elapsed() < THREEPID_SESSION_TTL);
+	}
+}
 
 pub struct Service {
 	pub db: Arc<dyn Data>,
-	pub connections: DbConnections,
+	pub connections: SyncConnections,
+	pub profile_cache: ProfileCache,
+	pub threepid_sessions: Mutex<ThreepidSessions>,
 }
 
 impl Service {
@@ -43,10 +289,42 @@ impl Service {
 	pub fn exists(&self, user_id: &UserId) -> Result<bool> { self.db.exists(user_id) }
 
 	pub fn forget_sync_request_connection(&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String) {
+		if let Err(e) = self
+			.db
+			.remove_sliding_sync_connection(&user_id, &device_id, &conn_id)
+		{
+			warn!("Failed to remove sliding sync connection {conn_id} of {user_id}: {e}");
+		}
+
+		self.connections.remove(&(user_id, device_id, conn_id));
+	}
+
+	/// Returns the state of a sliding sync connection, loading it from the
+	/// database if it isn't in memory yet, e.g. after a restart.
+	fn sync_connection(
+		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String,
+	) -> Arc<Mutex<SlidingSyncCache>> {
 		self.connections
-			.lock()
-			.unwrap()
-			.remove(&(user_id, device_id, conn_id));
+			.get_or_load((user_id, device_id, conn_id), |(user_id, device_id, conn_id)| {
+				self.db
+					.get_sliding_sync_connection(user_id, device_id, conn_id)
+					.unwrap_or_else(|e| {
+						warn!("Failed to load sliding sync connection {conn_id} of {user_id}: {e}");
+						None
+					})
+					.unwrap_or_default()
+			})
+	}
+
+	fn persist_sync_connection(
+		&self, user_id: &UserId, device_id: &DeviceId, conn_id: &str, cached: &mut SlidingSyncCache,
+	) {
+		if let Err(e) = cached.persist(|cached| {
+			self.db
+				.set_sliding_sync_connection(user_id, device_id, conn_id, cached)
+		}) {
+			warn!("Failed to persist sliding sync connection {conn_id} of {user_id}: {e}");
+		}
 	}
 
 	pub fn update_sync_request_with_cache(
@@ -56,21 +334,8 @@ impl Service {
 			return BTreeMap::new();
 		};
 
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
+		let cached = self.sync_connection(user_id.clone(), device_id.clone(), conn_id.clone());
 		let cached = &mut cached.lock().unwrap();
-		drop(cache);
 
 		for (list_id, list) in &mut request.lists {
 			if let Some(cached_list) = cached.lists.get(list_id) {
@@ -146,6 +411,18 @@ impl Service {
 			.enabled
 			.or(cached.extensions.to_device.enabled);
 
+		request.extensions.receipts.enabled = request
+			.extensions
+			.receipts
+			.enabled
+			.or(cached.extensions.receipts.enabled);
+
+		request.extensions.typing.enabled = request
+			.extensions
+			.typing
+			.enabled
+			.or(cached.extensions.typing.enabled);
+
 		request.extensions.account_data.enabled = request
 			.extensions
 			.account_data
@@ -165,6 +442,7 @@ impl Service {
 			.or_else(|| cached.extensions.account_data.rooms.clone());
 
 		cached.extensions = request.extensions.clone();
+		self.persist_sync_connection(&user_id, &device_id, &conn_id, cached);
 
 		cached.known_rooms.clone()
 	}
@@ -173,44 +451,19 @@ impl Service {
 		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String,
 		subscriptions: BTreeMap<OwnedRoomId, sync_events::v4::RoomSubscription>,
 	) {
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
+		let cached = self.sync_connection(user_id.clone(), device_id.clone(), conn_id.clone());
 		let cached = &mut cached.lock().unwrap();
-		drop(cache);
 
 		cached.subscriptions = subscriptions;
+		self.persist_sync_connection(&user_id, &device_id, &conn_id, cached);
 	}
 
 	pub fn update_sync_known_rooms(
 		&self, user_id: OwnedUserId, device_id: OwnedDeviceId, conn_id: String, list_id: String,
 		new_cached_rooms: BTreeSet<OwnedRoomId>, globalsince: u64,
 	) {
-		let mut cache = self.connections.lock().unwrap();
-		let cached = Arc::clone(
-			cache
-				.entry((user_id, device_id, conn_id))
-				.or_insert_with(|| {
-					Arc::new(Mutex::new(SlidingSyncCache {
-						lists: BTreeMap::new(),
-						subscriptions: BTreeMap::new(),
-						known_rooms: BTreeMap::new(),
-						extensions: ExtensionsConfig::default(),
-					}))
-				}),
-		);
+		let cached = self.sync_connection(user_id.clone(), device_id.clone(), conn_id.clone());
 		let cached = &mut cached.lock().unwrap();
-		drop(cache);
 
 		for (roomid, lastsince) in cached
 			.known_rooms
@@ -226,11 +479,41 @@ impl Service {
 		for roomid in new_cached_rooms {
 			list.insert(roomid, globalsince);
 		}
+		self.persist_sync_connection(&user_id, &device_id, &conn_id, cached);
 	}
 
 	/// Check if account is deactivated
 	pub fn is_deactivated(&self, user_id: &UserId) -> Result<bool> { self.db.is_deactivated(user_id) }
 
+	/// Check if account was registered as a guest
+	pub fn is_guest(&self, user_id: &UserId) -> Result<bool> { self.db.is_guest(user_id) }
+
+	/// Marks or unmarks an account as a guest account
+	pub fn set_guest(&self, user_id: &UserId, guest: bool) -> Result<()> { self.db.set_guest(user_id, guest) }
+
+	/// Associates a validated third party identifier with the account
+	pub fn add_threepid(&self, user_id: &UserId, threepid: &ThirdPartyIdentifier) -> Result<()> {
+		self.db.add_threepid(user_id, threepid)
+	}
+
+	/// Removes a third party identifier from the account, returning whether it
+	/// was associated
+	pub fn remove_threepid(&self, user_id: &UserId, medium: &Medium, address: &str) -> Result<bool> {
+		self.db.remove_threepid(user_id, medium, address)
+	}
+
+	/// Returns the third party identifiers associated with the account
+	pub fn threepids(&self, user_id: &UserId) -> Result<Vec<ThirdPartyIdentifier>> { self.db.threepids(user_id) }
+
+	/// Whether `address` is one of the email addresses the user has validated
+	pub fn has_email(&self, user_id: &UserId, address: &str) -> Result<bool> {
+		let address = address.trim();
+		Ok(self
+			.threepids(user_id)?
+			.iter()
+			.any(|threepid| threepid.medium == Medium::Email && threepid.address.eq_ignore_ascii_case(address)))
+	}
+
 	/// Check if a user is an admin
 	pub fn is_admin(&self, user_id: &UserId) -> Result<bool> {
 		if let Some(admin_room_id) = service::admin::Service::get_admin_room()? {
@@ -280,7 +563,8 @@ impl Service {
 	/// Sets a new displayname or removes it if displayname is None. You still
 	/// need to nofify all rooms of this change.
 	pub async fn set_displayname(&self, user_id: &UserId, displayname: Option<String>) -> Result<()> {
-		self.db.set_displayname(user_id, displayname)
+		self.profile_cache
+			.update(user_id, || self.db.set_displayname(user_id, displayname))
 	}
 
 	/// Get the avatar_url of a user.
@@ -288,7 +572,8 @@ impl Service {
 
 	/// Sets a new avatar_url or removes it if avatar_url is None.
 	pub async fn set_avatar_url(&self, user_id: &UserId, avatar_url: Option<OwnedMxcUri>) -> Result<()> {
-		self.db.set_avatar_url(user_id, avatar_url)
+		self.profile_cache
+			.update(user_id, || self.db.set_avatar_url(user_id, avatar_url))
 	}
 
 	/// Get the blurhash of a user.
@@ -296,7 +581,24 @@ impl Service {
 
 	/// Sets a new blurhash or removes it if blurhash is None.
 	pub async fn set_blurhash(&self, user_id: &UserId, blurhash: Option<String>) -> Result<()> {
-		self.db.set_blurhash(user_id, blurhash)
+		self.profile_cache
+			.update(user_id, || self.db.set_blurhash(user_id, blurhash))
+	}
+
+	/// The user's profile for answering federation profile queries, served
+	/// from [`ProfileCache`] so repeated queries don't each hit the database.
+	pub fn federation_profile(&self, user_id: &UserId) -> Result<Profile> {
+		self.profile_cache.get_or_load(user_id, || {
+			if !self.exists(user_id)? {
+				return Ok(None);
+			}
+
+			Ok(Some(Profile {
+				displayname: self.displayname(user_id)?,
+				avatar_url: self.avatar_url(user_id)?,
+				blurhash: self.blurhash(user_id)?,
+			}))
+		})
 	}
 
 	/// Adds a new device to a user.
@@ -307,8 +609,11 @@ impl Service {
 			.create_device(user_id, device_id, token, initial_device_display_name)
 	}
 
-	/// Removes a device from a user.
+	/// Removes a device from a user, along with the pushers it set.
 	pub fn remove_device(&self, user_id: &UserId, device_id: &DeviceId) -> Result<()> {
+		services()
+			.pusher
+			.remove_device_pushers(user_id, device_id)?;
 		self.db.remove_device(user_id, device_id)
 	}
 
@@ -348,6 +653,35 @@ impl Service {
 		self.db.count_one_time_keys(user_id, device_id)
 	}
 
+	pub fn add_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, fallback_key_key: &DeviceKeyId,
+		fallback_key_value: &Raw<OneTimeKey>,
+	) -> Result<()> {
+		self.db
+			.add_fallback_key(user_id, device_id, fallback_key_key, fallback_key_value)
+	}
+
+	pub fn take_fallback_key(
+		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
+	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+		self.db.take_fallback_key(user_id, device_id, key_algorithm)
+	}
+
+	/// Claims one of the device's one-time keys for the algorithm, or its
+	/// fallback key once they have run out.
+	pub fn claim_key(
+		&self, user_id: &UserId, device_id: &DeviceId, key_algorithm: &DeviceKeyAlgorithm,
+	) -> Result<Option<(OwnedDeviceKeyId, Raw<OneTimeKey>)>> {
+		claim_key_with(
+			|| self.take_one_time_key(user_id, device_id, key_algorithm),
+			|| self.take_fallback_key(user_id, device_id, key_algorithm),
+		)
+	}
+
+	pub fn unused_fallback_key_types(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Vec<DeviceKeyAlgorithm>> {
+		self.db.unused_fallback_key_types(user_id, device_id)
+	}
+
 	pub fn add_device_keys(&self, user_id: &UserId, device_id: &DeviceId, device_keys: &Raw<DeviceKeys>) -> Result<()> {
 		self.db.add_device_keys(user_id, device_id, device_keys)
 	}
@@ -372,6 +706,13 @@ impl Service {
 		self.db.keys_changed(user_or_room_id, from, to)
 	}
 
+	/// Like `keys_changed`, along with the count of each change.
+	pub fn keys_changed_with_count<'a>(
+		&'a self, user_or_room_id: &str, from: u64, to: Option<u64>,
+	) -> impl Iterator<Item = Result<(u64, OwnedUserId)>> + 'a {
+		self.db.keys_changed_with_count(user_or_room_id, from, to)
+	}
+
 	pub fn mark_device_key_update(&self, user_id: &UserId) -> Result<()> { self.db.mark_device_key_update(user_id) }
 
 	pub fn get_device_keys(&self, user_id: &UserId, device_id: &DeviceId) -> Result<Option<Raw<DeviceKeys>>> {
@@ -425,6 +766,20 @@ impl Service {
 		self.db.remove_to_device_events(user_id, device_id, until)
 	}
 
+	/// Deletes to-device events that have been waiting for their device for
+	/// longer than `ttl`, returning how many were removed.
+	pub fn remove_expired_to_device_events(&self, ttl: Duration) -> Result<usize> {
+		let ttl = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+		let older_than = utils::millis_since_unix_epoch().saturating_sub(ttl);
+		self.db.remove_expired_to_device_events(older_than)
+	}
+
+	/// Returns the number of undelivered to-device events for each of the
+	/// user's devices
+	pub fn to_device_backlog(&self, user_id: &UserId) -> Result<BTreeMap<OwnedDeviceId, usize>> {
+		self.db.to_device_backlog(user_id)
+	}
+
 	pub fn update_device_metadata(&self, user_id: &UserId, device_id: &DeviceId, device: &Device) -> Result<()> {
 		self.db.update_device_metadata(user_id, device_id, device)
 	}
@@ -455,7 +810,10 @@ impl Service {
 		// account is deactivated.
 		self.db.set_password(user_id, None)?;
 
-		// TODO: Unhook 3PID
+		for threepid in self.threepids(user_id)? {
+			self.remove_threepid(user_id, &threepid.medium, &threepid.address)?;
+		}
+
 		Ok(())
 	}
 
@@ -491,3 +849,203 @@ pub fn clean_signatures<F: Fn(&UserId) -> bool>(
 
 	Ok(())
 }
+
+fn claim_key_with<K>(
+	take_one_time_key: impl FnOnce() -> Result<Option<K>>, take_fallback_key: impl FnOnce() -> Result<Option<K>>,
+) -> Result<Option<K>> {
+	match take_one_time_key()? {
+		Some(one_time_key) => Ok(Some(one_time_key)),
+		None => take_fallback_key(),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, collections::BTreeMap, time::Duration};
+
+	use ruma::{
+		api::client::sync::sync_events::v4::RoomSubscription, device_key_id, encryption::OneTimeKey, owned_device_id,
+		owned_room_id, owned_user_id, serde::Raw, uint, user_id,
+	};
+
+	use super::{
+		claim_key_with,
+		data::{fallback_key_value_bytes, parse_fallback_key, use_fallback_key},
+		ConnectionKey, Profile, ProfileCache, SlidingSyncCache, SyncConnections, ThreepidSessions,
+	};
+
+	fn profile(displayname: &str) -> Profile {
+		Profile {
+			displayname: Some(displayname.to_owned()),
+			..Profile::default()
+		}
+	}
+
+	#[test]
+	fn profile_change_invalidates_cached_profile() {
+		let cache = ProfileCache::new(Duration::from_secs(60));
+		let user = user_id!("@alice:example.com");
+		let stored = RefCell::new(profile("Alice"));
+		let load = || Ok(Some(stored.borrow().clone()));
+		assert_eq!(cache.get_or_load(user, load).unwrap(), profile("Alice"));
+
+		cache
+			.update(user, || {
+				*stored.borrow_mut() = profile("Alicia");
+				Ok(())
+			})
+			.unwrap();
+		assert_eq!(cache.get_or_load(user, load).unwrap(), profile("Alicia"));
+	}
+
+	#[test]
+	fn missing_users_are_not_cached() {
+		let cache = ProfileCache::new(Duration::from_secs(60));
+		let user = user_id!("@nobody:example.com");
+		assert_eq!(cache.get_or_load(user, || Ok(None)).unwrap(), Profile::default());
+		assert_eq!(cache.get(user), None);
+	}
+
+	#[test]
+	fn zero_ttl_disables_profile_cache() {
+		let cache = ProfileCache::new(Duration::ZERO);
+		let user = user_id!("@alice:example.com");
+		cache.insert(user, profile("Alice"));
+		assert_eq!(cache.get(user), None);
+	}
+
+	#[test]
+	fn email_is_added_only_after_validation() {
+		let mut sessions = ThreepidSessions::default();
+		let (sid, token) = sessions
+			.start("secret", "alice@example.com", uint!(1))
+			.unwrap();
+		let token = token.unwrap();
+
+		// not validated yet
+		assert_eq!(sessions.finish(&sid, "secret"), None);
+
+		assert!(!sessions.validate(&sid, "secret", "wrong token"));
+		assert!(!sessions.validate(&sid, "other secret", &token));
+		assert!(sessions.validate(&sid, "secret", &token));
+
+		// only the client that started the session can add the address
+		assert_eq!(sessions.finish(&sid, "other secret"), None);
+		assert_eq!(sessions.finish(&sid, "secret").as_deref(), Some("alice@example.com"));
+		assert_eq!(sessions.finish(&sid, "secret"), None);
+	}
+
+	#[test]
+	fn retried_send_attempt_does_not_resend() {
+		let mut sessions = ThreepidSessions::default();
+		let (sid, token) = sessions
+			.start("secret", "alice@example.com", uint!(1))
+			.unwrap();
+		assert!(token.is_some());
+
+		let (retried, token) = sessions
+			.start("secret", "alice@example.com", uint!(1))
+			.unwrap();
+		assert_eq!(retried, sid);
+		assert!(token.is_none());
+
+		let (next, token) = sessions
+			.start("secret", "alice@example.com", uint!(2))
+			.unwrap();
+		assert_eq!(next, sid);
+		assert!(token.is_some());
+	}
+
+	#[test]
+	fn sliding_sync_connection_survives_restart() {
+		// stands in for userdeviceconnid_slidingsync
+		let db: RefCell<BTreeMap<ConnectionKey, Vec<u8>>> = RefCell::default();
+		let writes = RefCell::new(0);
+		let key = (
+			owned_user_id!("@alice:example.com"),
+			owned_device_id!("DEVICE"),
+			"conn".to_owned(),
+		);
+		let load = |key: &_| {
+			db.borrow()
+				.get(key)
+				.map(|bytes| serde_json::from_slice(bytes).unwrap())
+				.unwrap_or_default()
+		};
+		let save = |cache: &SlidingSyncCache| {
+			*writes.borrow_mut() += 1;
+			db.borrow_mut()
+				.insert(key.clone(), serde_json::to_vec(cache).unwrap());
+			Ok(())
+		};
+
+		let connections = SyncConnections::default();
+		let cached = connections.get_or_load(key.clone(), load);
+		let mut cached = cached.lock().unwrap();
+		cached
+			.known_rooms
+			.entry("all".to_owned())
+			.or_default()
+			.insert(owned_room_id!("!known:example.com"), 42);
+		cached
+			.subscriptions
+			.insert(owned_room_id!("!subscribed:example.com"), RoomSubscription::default());
+		cached.extensions.to_device.enabled = Some(true);
+		cached.persist(save).unwrap();
+
+		// unchanged connections aren't written again
+		cached.persist(save).unwrap();
+		assert_eq!(*writes.borrow(), 1);
+		drop(cached);
+
+		let restarted = SyncConnections::default();
+		let restored = restarted.get_or_load(key.clone(), load);
+		let mut restored = restored.lock().unwrap();
+		assert_eq!(restored.known_rooms["all"][&owned_room_id!("!known:example.com")], 42);
+		assert!(restored
+			.subscriptions
+			.contains_key(&owned_room_id!("!subscribed:example.com")));
+		assert_eq!(restored.extensions.to_device.enabled, Some(true));
+
+		// nor are ones just loaded from the database
+		restored.persist(save).unwrap();
+		assert_eq!(*writes.borrow(), 1);
+	}
+
+	#[test]
+	fn fallback_key_is_claimed_once_one_time_keys_run_out() {
+		let key = |key: &str| Raw::<OneTimeKey>::from_json_string(format!(r#"{{"key":"{key}"}}"#)).unwrap();
+		let one_time_keys = RefCell::new(vec![(device_key_id!("signed_curve25519:AAAAAQ").to_owned(), key("one"))]);
+		// stands in for the device's entry in fallbackkeyid_fallbackkey
+		let fallback_key = RefCell::new(fallback_key_value_bytes(
+			false,
+			device_key_id!("signed_curve25519:AAAAHg"),
+			&key("fallback"),
+		));
+
+		let claim = || {
+			claim_key_with(
+				|| Ok(one_time_keys.borrow_mut().pop()),
+				|| {
+					let (key_id, key, newly_used) = use_fallback_key(&fallback_key.borrow()).unwrap();
+					if let Some(newly_used) = newly_used {
+						*fallback_key.borrow_mut() = newly_used;
+					}
+					Ok(Some((key_id, key)))
+				},
+			)
+			.unwrap()
+			.unwrap()
+		};
+
+		assert_eq!(claim().0, "signed_curve25519:AAAAAQ");
+
+		// the one-time keys have run out, so the fallback key is handed out and
+		// marked used, and handed out again after that
+		let (key_id, fallback) = claim();
+		assert_eq!(key_id, "signed_curve25519:AAAAHg");
+		assert_eq!(fallback.json().get(), key("fallback").json().get());
+		assert!(parse_fallback_key(&fallback_key.borrow()).unwrap().0);
+		assert_eq!(claim().0, "signed_curve25519:AAAAHg");
+	}
+}